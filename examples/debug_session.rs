@@ -0,0 +1,50 @@
+//! Debug a batch file from Rust: stop at a line, look around, then let the
+//! script finish.
+//!
+//!     cargo run --example debug_session -- script.bat 3
+//!
+//! Needs CMD, so it runs on Windows.
+
+use batch_debugger::api::DebugSession;
+use std::env;
+use std::io;
+
+fn main() -> io::Result<()> {
+    let mut args = env::args().skip(1);
+    let (path, line) = match (args.next(), args.next().and_then(|l| l.parse().ok())) {
+        (Some(path), Some(line)) => (path, line),
+        _ => {
+            eprintln!("Usage: debug_session <script.bat> <line>");
+            std::process::exit(2);
+        }
+    };
+
+    let mut session = DebugSession::load(&path)?;
+    let bound = session.set_breakpoint(line, None)?;
+    println!("Breakpoint on line {}", bound);
+
+    if let Some(stop) = session.run_until_stop()? {
+        println!("Stopped ({}) at line {}", stop.reason, stop.line);
+        for frame in session.call_stack()? {
+            println!("  in {} at line {}", frame.name, frame.line);
+        }
+        let mut variables: Vec<_> = session.variables()?.into_iter().collect();
+        variables.sort();
+        for (name, value) in variables {
+            println!("  {}={}", name, value);
+        }
+        println!("  ERRORLEVEL is {}", session.eval("%ERRORLEVEL%")?);
+    }
+    for output in session.take_output() {
+        print!("{}", output.text);
+    }
+
+    let summary = session.finish()?;
+    println!(
+        "Finished ({}) with exit code {} after {} lines",
+        summary.terminated_reason.name(),
+        summary.exit_code,
+        summary.lines_executed
+    );
+    Ok(())
+}
@@ -0,0 +1,328 @@
+//! Debugging a batch file from Rust code, without speaking DAP. A
+//! `DebugSession` drives the same executor the DAP server does: the script
+//! runs on a thread of its own and waits at each stop until told to go on.
+//!
+//! Lines are 1-based physical lines of the file, as an editor shows them.
+
+pub mod report;
+
+use crate::debugger::{
+    BreakpointSpec, Breakpoints, BreakpointsFile, CmdSession, DebugContext, RunMode, RunSummary,
+    Shell,
+};
+use crate::error::BatchDbgError;
+use crate::executor::{self, RunningScript, ScriptOutput};
+use crate::parser::{
+    breakpoint_line, build_label_map, call_graph, condition_error, lint_script, preprocess_lines,
+    CallGraph, Diagnostic, LabelMap, PreprocessResult,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// 1-based physical line of logical line `pc`
+fn line_of(pre: &PreprocessResult, pc: usize) -> usize {
+    pre.logical.get(pc).map_or(1, |l| l.phys_start + 1)
+}
+
+/// What a read of the batch file at `path` finds wrong, in line order
+pub fn lint(path: impl AsRef<Path>) -> io::Result<Vec<Diagnostic>> {
+    let contents = fs::read_to_string(path)?;
+    let physical_lines: Vec<&str> = contents.lines().collect();
+    Ok(lint_script(&preprocess_lines(&physical_lines)))
+}
+
+/// Which labels of the batch file at `path` CALL or GOTO which
+pub fn call_graph_of(path: impl AsRef<Path>) -> io::Result<CallGraph> {
+    let contents = fs::read_to_string(path)?;
+    let physical_lines: Vec<&str> = contents.lines().collect();
+    Ok(call_graph(&preprocess_lines(&physical_lines)))
+}
+
+/// Where and why the script stopped
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopInfo {
+    pub reason: String,          // "breakpoint", "step", "entry", ...
+    pub line: usize,             // Line the script will run next
+    pub script: Option<PathBuf>, // CALLed batch file it stopped in; None for the loaded one
+}
+
+/// One frame of the call stack
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame {
+    pub name: String,            // "main", a :label or a CALLed file's name
+    pub line: usize,             // Line the frame is running (the CALL, for outer frames)
+    pub script: Option<PathBuf>, // None for the loaded batch file
+}
+
+/// A batch file being debugged
+pub struct DebugSession {
+    path: PathBuf,
+    context: Arc<Mutex<DebugContext>>,
+    pre: PreprocessResult,
+    labels: LabelMap,
+    running: Option<RunningScript>, // Started by the first run or step
+    finished: bool,
+    output: Vec<ScriptOutput>, // What the script printed since take_output
+    breakpoints_file: Option<PathBuf>, // Saved to on every breakpoint change
+}
+
+impl DebugSession {
+    /// Prepare `path` to run in a new CMD session
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_shell(path, CmdSession::start()?)
+    }
+
+    /// Prepare `path` to run in `shell` instead of CMD
+    pub fn with_shell(path: impl AsRef<Path>, shell: impl Shell + 'static) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let contents = fs::read_to_string(&path)?;
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_script_args(&path.display().to_string(), Vec::new());
+        Ok(Self {
+            pre: preprocess_lines(&physical_lines),
+            labels: build_label_map(&physical_lines),
+            context: Arc::new(Mutex::new(ctx)),
+            path,
+            running: None,
+            finished: false,
+            output: Vec::new(),
+            breakpoints_file: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stop before `line` each time it runs, or only when `condition` holds.
+    /// A line that never runs by itself (a label, comment or blank) moves
+    /// to the next statement; the line it lands on is returned. A condition
+    /// that doesn't parse is a ParseError.
+    pub fn set_breakpoint(&mut self, line: usize, condition: Option<&str>) -> io::Result<usize> {
+        if let Some(message) = condition.and_then(condition_error) {
+            return Err(BatchDbgError::ParseError { line, message }.into());
+        }
+        let logical = line
+            .checked_sub(1)
+            .and_then(|phys| breakpoint_line(&self.pre, phys))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("No statement at or after line {}", line),
+                )
+            })?;
+        self.context()?
+            .add_breakpoint_with_condition(logical, condition.map(str::to_string));
+        self.save_breakpoints()?;
+        Ok(self.pre.logical[logical].phys_start + 1)
+    }
+
+    pub fn remove_breakpoint(&mut self, line: usize) -> io::Result<()> {
+        if let Some(logical) = line
+            .checked_sub(1)
+            .and_then(|phys| breakpoint_line(&self.pre, phys))
+        {
+            self.context()?.remove_breakpoint(logical);
+        }
+        self.save_breakpoints()
+    }
+
+    /// Set the breakpoints `file` saved for this script in place of any
+    /// set so far, and save them there on every change from now on. Gives
+    /// the saved breakpoints with no statement at or after their line.
+    pub fn use_breakpoints_file(
+        &mut self,
+        file: impl AsRef<Path>,
+    ) -> io::Result<Vec<BreakpointSpec>> {
+        let file = file.as_ref().to_path_buf();
+        let saved = BreakpointsFile::load(&file)?;
+        let specs = saved.get(&self.path).unwrap_or_default();
+        let (breakpoints, unbound) = Breakpoints::from_spec(specs, &self.pre);
+        self.context()?.set_breakpoints(breakpoints);
+        self.breakpoints_file = Some(file);
+        self.save_breakpoints()?;
+        Ok(unbound)
+    }
+
+    /// Run until the next stop. None once the script has ended.
+    pub fn run_until_stop(&mut self) -> io::Result<Option<StopInfo>> {
+        self.resume(RunMode::Continue)
+    }
+
+    /// Run the next line, stepping over CALLs. The first step stops before
+    /// the first line.
+    pub fn step(&mut self) -> io::Result<Option<StopInfo>> {
+        if self.running.is_none() {
+            return self.resume(RunMode::StepInto);
+        }
+        self.resume(RunMode::StepOver)
+    }
+
+    /// Run the next line, stepping into CALLs
+    pub fn step_in(&mut self) -> io::Result<Option<StopInfo>> {
+        self.resume(RunMode::StepInto)
+    }
+
+    /// Run until the current CALL returns
+    pub fn step_out(&mut self) -> io::Result<Option<StopInfo>> {
+        self.resume(RunMode::StepOut)
+    }
+
+    /// Value of an expression such as `%NAME%` or `!COUNT!`, in the session
+    /// the script runs in
+    pub fn eval(&mut self, expression: &str) -> io::Result<String> {
+        Ok(self.context()?.evaluate_expression(expression)?)
+    }
+
+    /// The variables the current frame sees
+    pub fn variables(&self) -> io::Result<HashMap<String, String>> {
+        Ok(self.context()?.get_visible_variables())
+    }
+
+    /// Frames of the call stack, innermost first
+    pub fn call_stack(&self) -> io::Result<Vec<StackFrame>> {
+        let ctx = self.context()?;
+        let main = StackFrame {
+            name: "main".to_string(),
+            line: line_of(&self.pre, ctx.main_pc),
+            script: None,
+        };
+        let mut frames: Vec<StackFrame> = ctx
+            .call_stack
+            .iter()
+            .map(|frame| {
+                let line = match frame.script.as_deref().and_then(|path| ctx.script(path)) {
+                    Some(script) => line_of(&script.pre, frame.current_pc),
+                    None => line_of(&self.pre, frame.current_pc),
+                };
+                StackFrame {
+                    name: frame.name(),
+                    line,
+                    script: frame.script.clone(),
+                }
+            })
+            .rev()
+            .collect();
+        frames.push(main);
+        Ok(frames)
+    }
+
+    /// What the script printed since the last call, stdout and stderr
+    pub fn take_output(&mut self) -> Vec<ScriptOutput> {
+        self.collect_output();
+        std::mem::take(&mut self.output)
+    }
+
+    /// Run the rest of the script, going on past every stop, and say how
+    /// it ended
+    #[allow(dead_code)]
+    pub fn finish(mut self) -> io::Result<RunSummary> {
+        while self.run_until_stop()?.is_some() {}
+        self.summary()
+            .ok_or_else(|| io::Error::other("The script ended without a summary"))
+    }
+
+    /// How the script ended, once it has
+    pub fn summary(&self) -> Option<RunSummary> {
+        self.context().ok()?.run_summary().cloned()
+    }
+
+    /// Write this script's breakpoints to the breakpoints file, if it has one
+    fn save_breakpoints(&self) -> io::Result<()> {
+        let path = match &self.breakpoints_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let specs = self.context()?.breakpoint_specs(&self.pre);
+        let mut file = BreakpointsFile::load(path)?;
+        for (source, breakpoints) in specs {
+            file.set(source.as_deref().unwrap_or(&self.path), breakpoints);
+        }
+        file.save(path)
+    }
+
+    fn context(&self) -> io::Result<MutexGuard<'_, DebugContext>> {
+        self.context
+            .lock()
+            .map_err(|_| BatchDbgError::LockPoisoned.into())
+    }
+
+    /// Start the script in `mode`, or let it go on from its stop in `mode`,
+    /// and wait for where it stops next
+    fn resume(&mut self, mode: RunMode) -> io::Result<Option<StopInfo>> {
+        if self.finished {
+            return Ok(None);
+        }
+        match &self.running {
+            Some(_) => {
+                let mut ctx = self.context()?;
+                ctx.set_mode(mode);
+                ctx.request_continue();
+            }
+            None => {
+                self.context()?.set_mode(mode);
+                let running =
+                    executor::spawn_debugger_dap(self.context.clone(), &self.pre, &self.labels);
+                self.running = Some(running);
+            }
+        }
+
+        let event = match &self.running {
+            Some(running) => running.events.recv().ok(),
+            None => None,
+        };
+        self.collect_output();
+        match event {
+            Some(stop) if stop.reason != "terminated" => {
+                let ctx = self.context()?;
+                let script = ctx.current_script().map(Path::to_path_buf);
+                let line = match script.as_deref().and_then(|path| ctx.script(path)) {
+                    Some(called) => line_of(&called.pre, stop.pc),
+                    None => line_of(&self.pre, stop.pc),
+                };
+                Ok(Some(StopInfo {
+                    reason: stop.reason,
+                    line,
+                    script,
+                }))
+            }
+            _ => {
+                self.finished = true;
+                if let Some(running) = self.running.take() {
+                    if running.thread.join().is_err() {
+                        eprintln!("ERROR: Execution thread panicked");
+                    }
+                    self.output.extend(running.output.try_iter());
+                    self.output.extend(running.stderr.try_iter());
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    fn collect_output(&mut self) {
+        if let Some(running) = &self.running {
+            self.output.extend(running.output.try_iter());
+            self.output.extend(running.stderr.try_iter());
+        }
+    }
+}
+
+/// A script left at a stop would wait forever; end its session instead
+impl Drop for DebugSession {
+    fn drop(&mut self) {
+        if let Some(running) = self.running.take() {
+            if let Ok(ctx) = self.context.lock() {
+                ctx.session_killer().kill();
+                ctx.resume_signal().notify_all();
+            }
+            if running.thread.join().is_err() {
+                eprintln!("ERROR: Execution thread panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! What a whole run of a script did, for `--run script.bat --report out.json`
+//! in CI: how it ended, which lines ran and how long they took, the
+//! variables it set, the programs it ran and what a read of it found wrong.
+
+use super::{line_of, DebugSession};
+use crate::debugger::{CoverageReport, LineProfile};
+use crate::parser::script_warnings;
+use serde::Serialize;
+use std::io;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunReport {
+    pub program: String,
+    pub exit_code: i32,
+    pub terminated_reason: String, // As in the terminated event: "completed", "exit", ...
+    pub lines_executed: usize,
+    pub coverage: CoverageReport,
+    pub timing: Vec<LineProfile>, // Every line that ran, in line order
+    pub variables: Vec<VariableSet>,
+    pub external_commands: Vec<ExternalCommand>,
+    pub warnings: Vec<ReportWarning>,
+}
+
+/// One change of a variable; `None` is undefined
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VariableSet {
+    pub name: String, // Uppercased, as CMD compares them
+    pub line: usize,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
+
+/// A program run by the script rather than a CMD built-in
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExternalCommand {
+    pub line: usize,
+    pub command: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReportWarning {
+    pub line: usize,
+    pub message: String,
+}
+
+impl DebugSession {
+    /// The report of the run, once the script has ended. Variables keep
+    /// their most recent changes only, as their history in the debugger does.
+    pub fn report(&self) -> io::Result<RunReport> {
+        let summary = self
+            .summary()
+            .ok_or_else(|| io::Error::other("The script hasn't ended yet"))?;
+        let ctx = self.context()?;
+
+        let mut timing = ctx.profile().slowest(&self.pre, usize::MAX);
+        timing.sort_by_key(|line| line.line);
+        let variables = ctx
+            .variable_histories()
+            .into_iter()
+            .flat_map(|(name, changes)| {
+                changes.into_iter().map(move |change| VariableSet {
+                    name: name.clone(),
+                    line: line_of(&self.pre, change.line),
+                    old_value: change.old_value,
+                    new_value: change.new_value,
+                })
+            })
+            .collect();
+        let external_commands = ctx
+            .external_commands()
+            .iter()
+            .map(|(pc, command)| ExternalCommand {
+                line: line_of(&self.pre, *pc),
+                command: command.clone(),
+            })
+            .collect();
+        let warnings = script_warnings(&self.pre)
+            .into_iter()
+            .map(|(pc, message)| ReportWarning {
+                line: line_of(&self.pre, pc),
+                message,
+            })
+            .collect();
+
+        Ok(RunReport {
+            program: self.path.display().to_string(),
+            exit_code: summary.exit_code,
+            terminated_reason: summary.terminated_reason.name().to_string(),
+            lines_executed: summary.lines_executed,
+            coverage: ctx.coverage().report(&self.pre),
+            timing,
+            variables,
+            external_commands,
+            warnings,
+        })
+    }
+}
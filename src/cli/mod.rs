@@ -0,0 +1,234 @@
+//! `--cli script.bat`: debug a batch file from the terminal. Commands are
+//! read a line at a time; the script's output, the stops and the answers go
+//! to the terminal, the debugger's diagnostics to stderr as always.
+
+use crate::api::{DebugSession, StopInfo};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// Lines of source shown before and after the line stopped at
+const CONTEXT_LINES: usize = 2;
+
+const HELP: &str = "\
+Commands:
+  b <line> [condition]  set a breakpoint
+  d <line>              delete a breakpoint
+  c                     continue
+  n                     step over
+  s                     step in
+  o                     step out
+  p <expr>              print an expression, e.g. p %NAME%
+  bt                    call stack
+  vars                  variables
+  watch <expr>          print an expression at every stop
+  q                     quit
+";
+
+/// Debug `session` with commands read from `input`, writing to `output`
+/// until the script ends, the user quits or the input runs out
+pub fn run_repl(
+    session: &mut DebugSession,
+    input: impl BufRead,
+    output: impl Write,
+) -> io::Result<()> {
+    let mut repl = Repl {
+        session,
+        output,
+        watches: Vec::new(),
+        sources: HashMap::new(),
+    };
+    let name = repl
+        .session
+        .path()
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    writeln!(
+        repl.output,
+        "Debugging {}; it starts with c, n or s. Type help for commands.",
+        name
+    )?;
+    repl.prompt()?;
+    for line in input.lines() {
+        let line = line?;
+        if !repl.command(line.trim())? {
+            return Ok(());
+        }
+        repl.prompt()?;
+    }
+    writeln!(repl.output)?;
+    Ok(())
+}
+
+struct Repl<'a, W: Write> {
+    session: &'a mut DebugSession,
+    output: W,
+    watches: Vec<String>,
+    sources: HashMap<Option<PathBuf>, Vec<String>>, // Lines of each file stopped in, None for the script
+}
+
+impl<W: Write> Repl<'_, W> {
+    fn prompt(&mut self) -> io::Result<()> {
+        write!(self.output, "> ")?;
+        self.output.flush()
+    }
+
+    /// Carry out one command; false once the session is over
+    fn command(&mut self, line: &str) -> io::Result<bool> {
+        let (name, rest) = match line.split_once(char::is_whitespace) {
+            Some((name, rest)) => (name, rest.trim()),
+            None => (line, ""),
+        };
+        match name {
+            "" => {}
+            "c" => return self.resume(DebugSession::run_until_stop),
+            "n" => return self.resume(DebugSession::step),
+            "s" => return self.resume(DebugSession::step_in),
+            "o" => return self.resume(DebugSession::step_out),
+            "b" => {
+                let (line, condition) = match rest.split_once(char::is_whitespace) {
+                    Some((line, condition)) => (line, Some(condition.trim())),
+                    None => (rest, None),
+                };
+                match line.parse() {
+                    Ok(line) => match self.session.set_breakpoint(line, condition) {
+                        Ok(bound) => writeln!(self.output, "Breakpoint at line {}", bound)?,
+                        Err(e) => writeln!(self.output, "{}", e)?,
+                    },
+                    Err(_) => writeln!(self.output, "Usage: b <line> [condition]")?,
+                }
+            }
+            "d" => match rest.parse() {
+                Ok(line) => {
+                    self.session.remove_breakpoint(line)?;
+                    writeln!(self.output, "Deleted the breakpoint at line {}", line)?;
+                }
+                Err(_) => writeln!(self.output, "Usage: d <line>")?,
+            },
+            "p" if !rest.is_empty() => match self.session.eval(rest) {
+                Ok(value) => writeln!(self.output, "{}", value)?,
+                Err(e) => writeln!(self.output, "Cannot evaluate {}: {}", rest, e)?,
+            },
+            "bt" => {
+                for (depth, frame) in self.session.call_stack()?.iter().enumerate() {
+                    let file = frame
+                        .script
+                        .as_deref()
+                        .and_then(Path::file_name)
+                        .map(|n| format!(" in {}", n.to_string_lossy()))
+                        .unwrap_or_default();
+                    writeln!(
+                        self.output,
+                        "#{} {} at line {}{}",
+                        depth, frame.name, frame.line, file
+                    )?;
+                }
+            }
+            "vars" => {
+                let mut variables: Vec<_> = self.session.variables()?.into_iter().collect();
+                variables.sort_by_cached_key(|(name, _)| name.to_lowercase());
+                for (name, value) in variables {
+                    writeln!(self.output, "{}={}", name, value)?;
+                }
+            }
+            "watch" if !rest.is_empty() => {
+                self.watches.push(rest.to_string());
+                self.print_watches()?;
+            }
+            "q" => return Ok(false),
+            "help" | "h" => write!(self.output, "{}", HELP)?,
+            _ => writeln!(self.output, "Unknown command '{}'; type help", line)?,
+        }
+        Ok(true)
+    }
+
+    /// Let the script go on and report where it stops; false once it ended
+    fn resume(
+        &mut self,
+        go: fn(&mut DebugSession) -> io::Result<Option<StopInfo>>,
+    ) -> io::Result<bool> {
+        let stop = go(self.session);
+        self.print_output()?;
+        match stop? {
+            Some(stop) => {
+                self.print_stop(&stop)?;
+                self.print_watches()?;
+                Ok(true)
+            }
+            None => {
+                match self.session.summary() {
+                    Some(summary) => {
+                        if let Some(error) = summary.terminated_reason.error() {
+                            writeln!(self.output, "Error: {}", error)?;
+                        }
+                        writeln!(
+                            self.output,
+                            "Script finished ({}) with exit code {}",
+                            summary.terminated_reason.name(),
+                            summary.exit_code
+                        )?
+                    }
+                    None => writeln!(self.output, "Script finished")?,
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    fn print_output(&mut self) -> io::Result<()> {
+        for printed in self.session.take_output() {
+            write!(self.output, "{}", printed.text)?;
+        }
+        Ok(())
+    }
+
+    /// Where the script stopped, with the lines around it
+    fn print_stop(&mut self, stop: &StopInfo) -> io::Result<()> {
+        let file = stop
+            .script
+            .as_deref()
+            .unwrap_or(self.session.path())
+            .to_path_buf();
+        writeln!(
+            self.output,
+            "Stopped ({}) at line {} of {}",
+            stop.reason,
+            stop.line,
+            file.file_name().unwrap_or_default().to_string_lossy()
+        )?;
+        let lines = self.sources.entry(stop.script.clone()).or_insert_with(|| {
+            match fs::read_to_string(&file) {
+                Ok(contents) => contents.lines().map(str::to_string).collect(),
+                Err(e) => {
+                    eprintln!("WARNING: Cannot read {}: {}", file.display(), e);
+                    Vec::new()
+                }
+            }
+        });
+        let first = stop.line.saturating_sub(CONTEXT_LINES).max(1);
+        let last = (stop.line + CONTEXT_LINES).min(lines.len());
+        for number in first..=last {
+            let marker = if number == stop.line { "->" } else { "  " };
+            writeln!(
+                self.output,
+                "{} {:>4} {}",
+                marker,
+                number,
+                lines[number - 1]
+            )?;
+        }
+        Ok(())
+    }
+
+    fn print_watches(&mut self) -> io::Result<()> {
+        for expression in &self.watches {
+            match self.session.eval(expression) {
+                Ok(value) => writeln!(self.output, "{} = {}", expression, value)?,
+                Err(e) => writeln!(self.output, "{} = <{}>", expression, e)?,
+            }
+        }
+        Ok(())
+    }
+}
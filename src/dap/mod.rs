@@ -1,140 +1,187 @@
 mod protocol;
 mod server;
+mod transport;
 
-use serde_json::json;
-use std::fs;
-use std::io::{self, Write};
+use crate::debugger::DebugLog;
+use serde_json::{json, Value};
+use std::io;
+use std::net::{Shutdown, TcpListener};
+use std::panic::{self, AssertUnwindSafe};
 use std::thread;
 use std::time::Duration;
 
 pub use protocol::DapMessageContent;
 pub use server::DapServer;
+pub use transport::{StdioTransport, StreamTransport};
 
-pub fn run_dap_mode() -> io::Result<()> {
+pub fn run_dap_mode(log: DebugLog) -> io::Result<()> {
     eprintln!("DAP server starting...");
 
-    let mut log = fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("C:\\temp\\batch-debugger-vscode.log")
-        .ok();
+    log.write(format_args!("DAP mode entered"));
 
-    if let Some(ref mut f) = log {
-        writeln!(f, "DAP mode entered").ok();
+    let mut server = DapServer::new();
+    server.set_log(log.clone());
+    server.set_transport(Box::new(StdioTransport::exclusive()));
+    run_session(&mut server, &log);
+
+    log.write(format_args!("DAP mode exiting"));
+    log.flush();
+    Ok(())
+}
+
+/// Listen on `host:port` and serve the clients that connect, one at a time
+pub fn run_dap_socket(host: &str, port: u16, log: DebugLog) -> io::Result<()> {
+    let listener = TcpListener::bind((host, port))?;
+    eprintln!("DAP server listening on {}", listener.local_addr()?);
+    log.write(format_args!("DAP socket mode on {}:{}", host, port));
+    serve_clients(listener, log, DapServer::new)
+}
+
+/// Serve the clients connecting to `listener` one after another, each with
+/// a fresh server from `new_server`, so a client can reconnect after it
+/// disconnected without the adapter being restarted
+pub fn serve_clients(
+    listener: TcpListener,
+    log: DebugLog,
+    new_server: impl Fn() -> DapServer,
+) -> io::Result<()> {
+    for stream in listener.incoming() {
+        // A connection that fails only loses that client; the next one is
+        // still served
+        let connected = stream.and_then(|stream| {
+            let peer = stream.peer_addr()?;
+            stream.set_nodelay(true)?;
+            let transport = StreamTransport::new(stream.try_clone()?, stream.try_clone()?);
+            Ok((stream, peer, transport))
+        });
+        let (stream, peer, transport) = match connected {
+            Ok(connected) => connected,
+            Err(e) => {
+                eprintln!("ERROR: Failed to accept a DAP client: {}", e);
+                log.write(format_args!("ERROR: Failed to accept a DAP client: {}", e));
+                continue;
+            }
+        };
+        eprintln!("DAP client connected from {}", peer);
+        log.write(format_args!("DAP client connected from {}", peer));
+
+        let mut server = new_server();
+        server.set_log(log.clone());
+        server.set_transport(Box::new(transport));
+        run_session(&mut server, &log);
+        // Ends the transport's reader along with the connection
+        let _ = stream.shutdown(Shutdown::Both);
+
+        eprintln!("DAP client {} gone, waiting for the next one", peer);
+        log.flush();
     }
+    Ok(())
+}
 
+/// Run `program` as a service on `host:port`: the script starts at once and
+/// clients attach to it and detach, one at a time
+pub fn run_dap_service(
+    host: &str,
+    port: u16,
+    program: &str,
+    breakpoints_file: Option<&str>,
+    log: DebugLog,
+) -> io::Result<()> {
+    let listener = TcpListener::bind((host, port))?;
+    eprintln!(
+        "DAP service for {} listening on {}",
+        program,
+        listener.local_addr()?
+    );
+    log.write(format_args!(
+        "DAP service for {} on {}:{}",
+        program, host, port
+    ));
     let mut server = DapServer::new();
+    server.set_log(log.clone());
+    server
+        .start_service(json!({
+            "program": program,
+            "stopOnEntry": false,
+            "breakpointsFile": breakpoints_file,
+        }))
+        .map_err(io::Error::other)?;
+    serve_attached_clients(listener, log, &mut server)
+}
+
+/// Serve the clients connecting to `listener` with the one `server` whose
+/// script runs as a service, until the script has ended. Between clients
+/// what the script reports is passed on to nobody, so the next client
+/// starts from the current state.
+pub fn serve_attached_clients(
+    listener: TcpListener,
+    log: DebugLog,
+    server: &mut DapServer,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    while server.executor_running() {
+        let (stream, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                server.forward_executor_events();
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        eprintln!("DAP client attached from {}", peer);
+        log.write(format_args!("DAP client attached from {}", peer));
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        server.set_transport(Box::new(StreamTransport::new(
+            stream.try_clone()?,
+            stream.try_clone()?,
+        )));
+        run_session(server, &log);
+        let _ = stream.shutdown(Shutdown::Both);
+        log.flush();
+    }
+    eprintln!("The script ended, the DAP service stops");
+    Ok(())
+}
+
+/// Answer one client's requests until it disconnects
+pub fn run_session(server: &mut DapServer, log: &DebugLog) {
     let mut msg_count = 0;
 
     loop {
-        server.check_and_send_output();
-        let mut events = Vec::new();
-        if let Some(ref rx) = server.event_receiver {
-            while let Ok((reason, line)) = rx.try_recv() {
-                events.push((reason, line));
-            }
-        }
-        for (reason, _line) in events {
-            if let Some(ref mut f) = log {
-                writeln!(f, "📥 Event received: {}", reason).ok();
-                f.flush().ok();
-            }
-            if reason != "terminated" {
-                server.send_event(
-                    "stopped".to_string(),
-                    Some(json!({
-                        "reason": reason,
-                        "threadId": 1,
-                        "allThreadsStopped": true
-                    })),
-                );
-                eprintln!("SENT: Stopped event: {}", reason);
-            } else {
-                eprintln!("SENT: Sending terminated event");
-                server.send_event("terminated".to_string(), None);
+        server.forward_executor_events();
+        let msg = match server.try_read_message() {
+            Ok(msg) => msg,
+            Err(e) => {
+                // Gone without a disconnect request; don't leave the script
+                // running, unless running it is the service's job
+                eprintln!("DAP client went away: {}", e);
+                log.write(format_args!("DAP client went away: {}", e));
+                if server.is_service() {
+                    server.detach();
+                } else {
+                    server.terminate_session();
+                }
+                break;
             }
-        }
-        if let Some(msg) = server.try_read_message() {
+        };
+        if let Some(msg) = msg {
             msg_count += 1;
 
-            if let Some(ref mut f) = log {
-                writeln!(f, "Received message #{}: {:?}", msg_count, msg.content).ok();
-                f.flush().ok();
-            }
+            log.write(format_args!(
+                "Received message #{}: {:?}",
+                msg_count, msg.content
+            ));
 
             eprintln!("RECEIVED: {:?}", msg.content);
 
             match msg.content {
-                DapMessageContent::Request { command, arguments } => match command.as_str() {
-                    "initialize" => {
-                        if let Some(ref mut f) = log {
-                            writeln!(f, "Handling initialize").ok();
-                        }
-                        eprintln!("🔧 Handling initialize");
-                        server.handle_initialize(msg.seq, command);
-                    }
-                    "launch" | "attach" => {
-                        if let Some(ref mut f) = log {
-                            writeln!(f, "Handling launch").ok();
-                        }
-                        eprintln!("🚀 Handling launch");
-                        server.handle_launch(msg.seq, command, arguments);
-                    }
-                    "setBreakpoints" => {
-                        server.handle_set_breakpoints(msg.seq, command, arguments);
-                    }
-                    "configurationDone" => {
-                        server.send_response(msg.seq, command, true, None);
-                    }
-                    "threads" => {
-                        server.handle_threads(msg.seq, command);
-                    }
-                    "stackTrace" => {
-                        server.handle_stack_trace(msg.seq, command);
-                    }
-                    "scopes" => {
-                        server.handle_scopes(msg.seq, command);
-                    }
-                    "variables" => {
-                        server.handle_variables(msg.seq, command, arguments);
-                    }
-                    "setVariable" => {
-                        server.handle_set_variable(msg.seq, command, arguments);
-                    }
-                    "evaluate" => {
-                        server.handle_evaluate(msg.seq, command, arguments);
-                    }
-                    "continue" => {
-                        server.handle_continue(msg.seq, command);
-                    }
-                    "next" => {
-                        server.handle_next(msg.seq, command);
-                    }
-                    "stepIn" => {
-                        server.handle_step_in(msg.seq, command);
-                    }
-                    "stepOut" => {
-                        server.handle_step_out(msg.seq, command);
-                    }
-                    "pause" => {
-                        eprintln!("Handling pause");
-                        server.handle_pause(msg.seq, command);
-                    }
-                    "dataBreakpointInfo" => {
-                        server.handle_data_breakpoint_info(msg.seq, command, arguments);
-                    }
-                    "setDataBreakpoints" => {
-                        server.handle_set_data_breakpoints(msg.seq, command, arguments);
-                    }
-                    "disconnect" => {
-                        server.send_response(msg.seq, command, true, None);
+                DapMessageContent::Request { command, arguments } => {
+                    if !handle_request(server, log, msg.seq, command, arguments) {
                         break;
                     }
-                    _ => {
-                        eprintln!("WARNING: Unhandled DAP command: {}", command);
-                        server.send_response(msg.seq, command, false, None);
-                    }
-                },
+                }
                 _ => {
                     eprintln!("📬 Non-request message");
                 }
@@ -142,9 +189,188 @@ pub fn run_dap_mode() -> io::Result<()> {
         }
         thread::sleep(Duration::from_millis(10));
     }
+}
 
-    if let Some(ref mut f) = log {
-        writeln!(f, "DAP mode exiting").ok();
+/// Answer one request, making sure the client gets exactly one response even
+/// when the handler fails. Returns false once the client has disconnected.
+pub fn handle_request(
+    server: &mut DapServer,
+    log: &DebugLog,
+    seq: u64,
+    command: String,
+    arguments: Option<Value>,
+) -> bool {
+    let name = command.clone();
+    let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+        dispatch_request(server, log, seq, command, arguments)
+    }));
+    let keep_going = match handled {
+        Ok(keep_going) => keep_going,
+        Err(_) => {
+            eprintln!("ERROR: Handler for {} panicked", name);
+            log.write(format_args!("ERROR: Handler for {} panicked", name));
+            true
+        }
+    };
+    if !server.has_answered(seq) {
+        server.send_error_response(seq, name, "internal error");
     }
-    Ok(())
+    keep_going
+}
+
+fn dispatch_request(
+    server: &mut DapServer,
+    log: &DebugLog,
+    seq: u64,
+    command: String,
+    arguments: Option<Value>,
+) -> bool {
+    match command.as_str() {
+        "initialize" => {
+            log.write(format_args!("Handling initialize"));
+            eprintln!("🔧 Handling initialize");
+            server.handle_initialize(seq, command, arguments);
+        }
+        "launch" => {
+            log.write(format_args!("Handling launch"));
+            eprintln!("🚀 Handling launch");
+            server.handle_launch(seq, command, arguments);
+        }
+        "attach" => {
+            log.write(format_args!("Handling attach"));
+            server.handle_attach(seq, command);
+        }
+        "restart" => {
+            log.write(format_args!("Handling restart"));
+            server.handle_restart(seq, command, arguments);
+        }
+        "setBreakpoints" => {
+            server.handle_set_breakpoints(seq, command, arguments);
+        }
+        "breakpointLocations" => {
+            server.handle_breakpoint_locations(seq, command, arguments);
+        }
+        "gotoTargets" => {
+            server.handle_goto_targets(seq, command, arguments);
+        }
+        "goto" => {
+            server.handle_goto(seq, command, arguments);
+        }
+        "configurationDone" => {
+            log.write(format_args!("Handling configurationDone"));
+            server.handle_configuration_done(seq, command);
+        }
+        "threads" => {
+            server.handle_threads(seq, command);
+        }
+        "stackTrace" => {
+            server.handle_stack_trace(seq, command, arguments);
+        }
+        "scopes" => {
+            server.handle_scopes(seq, command, arguments);
+        }
+        "variables" => {
+            server.handle_variables(seq, command, arguments);
+        }
+        "setVariable" => {
+            server.handle_set_variable(seq, command, arguments);
+        }
+        "setExpression" => {
+            server.handle_set_expression(seq, command, arguments);
+        }
+        "completions" => {
+            server.handle_completions(seq, command, arguments);
+        }
+        "cancel" => {
+            server.handle_cancel(seq, command, arguments);
+        }
+        "evaluate" => {
+            server.handle_evaluate(seq, command, arguments);
+        }
+        "continue" => {
+            server.handle_continue(seq, command);
+        }
+        "next" => {
+            server.handle_next(seq, command, arguments);
+        }
+        "stepIn" => {
+            server.handle_step_in(seq, command, arguments);
+        }
+        "stepOut" => {
+            server.handle_step_out(seq, command);
+        }
+        "stepBack" => {
+            server.handle_step_back(seq, command);
+        }
+        "pause" => {
+            eprintln!("Handling pause");
+            server.handle_pause(seq, command);
+        }
+        "setExceptionBreakpoints" => {
+            server.handle_set_exception_breakpoints(seq, command, arguments);
+        }
+        "dataBreakpointInfo" => {
+            server.handle_data_breakpoint_info(seq, command, arguments);
+        }
+        "setDataBreakpoints" => {
+            server.handle_set_data_breakpoints(seq, command, arguments);
+        }
+        "batch/runToLine" => {
+            server.handle_run_to_line(seq, command, arguments);
+        }
+        "batch/watches" => {
+            server.handle_watches(seq, command, arguments);
+        }
+        "batch/setBreakOnExternal" => {
+            server.handle_set_break_on_external(seq, command, arguments);
+        }
+        "batch/setOutputBreakpoints" => {
+            server.handle_set_output_breakpoints(seq, command, arguments);
+        }
+        "batch/provideInput" => {
+            server.handle_provide_input(seq, command, arguments);
+        }
+        "batch/variableHistory" => {
+            server.handle_variable_history(seq, command, arguments);
+        }
+        "batch/dumpTranscript" => {
+            server.handle_dump_transcript(seq, command, arguments);
+        }
+        "batch/diagnostics" => {
+            server.handle_diagnostics(seq, command, arguments);
+        }
+        "batch/sessionStats" => {
+            server.handle_session_stats(seq, command);
+        }
+        "batch/coverage" => {
+            server.handle_coverage(seq, command);
+        }
+        "batch/callGraph" => {
+            server.handle_call_graph(seq, command);
+        }
+        "batch/childProcesses" => {
+            server.handle_child_processes(seq, command);
+        }
+        "source" => {
+            server.handle_source(seq, command, arguments);
+        }
+        "loadedSources" => {
+            server.handle_loaded_sources(seq, command);
+        }
+        "batch/profile" => {
+            server.handle_profile(seq, command, arguments);
+        }
+        "terminate" => {
+            server.handle_terminate(seq, command);
+        }
+        "disconnect" => {
+            server.handle_disconnect(seq, command, arguments);
+            return false;
+        }
+        _ => {
+            eprintln!("WARNING: Unhandled DAP command: {}", command);
+            server.send_error_response(seq, command, "unsupported");
+        }
+    }
+    true
 }
@@ -1,83 +1,304 @@
 use super::protocol::{DapMessage, DapMessageContent};
-use crate::debugger::{CmdSession, DebugContext, RunMode};
-use crate::executor;
-use crate::parser::{self, PreprocessResult};
+use super::transport::{StdioTransport, StreamTransport, Transport};
+use crate::debugger::{
+    lock_shell, run_helper_in, source_key, AnsiMode, BreakpointSpec, BreakpointsFile,
+    ChildProcesses, CmdSession, CommandResult, CoverageReport, DebugContext, DebugLog, Evaluation,
+    LineProfile, ProgressEvent, RunMode, SessionKiller, SessionOptions, ShellConfig,
+    StepGranularity, VariableOrigin,
+};
+use crate::error::BatchDbgError;
+use crate::executor::{self, OutputKind, ScriptOutput, StopInfo};
+use crate::parser::{self, EdgeKind, NodeKind, PreprocessResult};
 use serde_json::{json, Value};
-use std::collections::HashMap;
-use std::io::{self, BufRead, Read};
-use std::sync::mpsc::{channel, Receiver, TryRecvError};
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use std::time::Duration;
-struct MessageReader {
-    receiver: Option<Receiver<Option<DapMessage>>>,
+use std::time::{Duration, Instant};
+
+/// How long terminate and disconnect wait for the executor to stop
+const EXECUTOR_STOP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// variablesReference base for a call frame's SETLOCAL locals (plus frame index)
+const FRAME_LOCALS_REF: u64 = 1000;
+/// variablesReference base for a call frame's arguments (plus frame index)
+const FRAME_ARGS_REF: u64 = 2000;
+
+/// variablesReference of the global FOR loop variables node
+const LOOP_VARS_REF: u64 = 5;
+/// variablesReference of the top-level script's arguments
+const SCRIPT_ARGS_REF: u64 = 6;
+/// variablesReference base for a call frame's FOR loop variables node (plus frame index)
+const FRAME_LOOP_VARS_REF: u64 = 3000;
+
+/// variablesReference base for the full value of a long variable, plus its
+/// scope's reference times LONG_VALUE_SLOTS plus its index in the scope
+const LONG_VALUE_REF: u64 = 1_000_000;
+const LONG_VALUE_SLOTS: u64 = 10_000;
+
+/// sourceReference of the launched program as the preprocessor sees it
+const PREPROCESSED_SOURCE_REF: u64 = 1;
+
+/// Values longer than this (in characters) are cut short in variable lists
+const DEFAULT_MAX_VALUE_LENGTH: usize = 2000;
+
+/// Variable values longer than this (in characters) are cut short in
+/// `batch/diagnostics`, which ends up pasted into bug reports
+const DIAGNOSTICS_VALUE_LENGTH: usize = 200;
+
+/// Longest one watch expression may keep CMD busy, so a slow one leaves
+/// time for the others
+const WATCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How much of the debugger's own commentary reaches the client's console
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConsoleVerbosity {
+    /// Only what the script printed, and the debugger's warnings
+    Off,
+    /// Notes on stops, external commands and skipped waits too
+    Info,
+    /// Every IF branch, FOR iteration and redirection as well
+    Debug,
+}
+
+/// Order variables like SET lists them, ignoring case
+fn sort_by_name(variables: &mut [(String, String)]) {
+    variables.sort_by_cached_key(|(name, _)| name.to_lowercase());
 }
 
-impl MessageReader {
-    fn new() -> Self {
-        Self { receiver: None }
+/// presentationHint kind and attribute describing where a value came from
+fn origin_hint(origin: VariableOrigin) -> (&'static str, &'static str) {
+    match origin {
+        VariableOrigin::Environment => ("baseClass", "environment"),
+        VariableOrigin::Script => ("data", "script"),
+        VariableOrigin::FrameLocal => ("data", "local"),
+        VariableOrigin::Injected => ("data", "injected"),
+        VariableOrigin::LoopVariable => ("virtual", "loopVariable"),
     }
+}
 
-    fn start_read(&mut self) {
-        let (tx, rx) = channel();
-        self.receiver = Some(rx);
+/// A plain variable entry with its origin, flagged when its value changed
+/// since the last stop
+fn variable_json(name: &str, value: &str, origin: VariableOrigin, ctx: &DebugContext) -> Value {
+    let (kind, origin_attribute) = origin_hint(origin);
+    let mut attributes = vec![origin_attribute];
+    if ctx.changed_since_last_stop(name) {
+        attributes.push("hasChanged");
+    }
+    json!({
+        "name": name,
+        "value": value,
+        "variablesReference": 0,
+        "presentationHint": {
+            "kind": kind,
+            "attributes": attributes
+        }
+    })
+}
 
-        thread::spawn(move || {
-            let stdin = io::stdin();
-            let mut handle = stdin.lock();
+/// Expandable node grouping FOR loop variables
+fn loop_variables_node(count: usize, reference: u64) -> Value {
+    json!({
+        "name": "Loop Variables",
+        "value": format!("[{} variables]", count),
+        "variablesReference": reference,
+        "namedVariables": count,
+        "presentationHint": {
+            "kind": "virtual",
+            "attributes": ["readOnly"]
+        }
+    })
+}
 
-            let mut content_length = 0;
-            let mut lines = handle.by_ref().lines();
+/// Variable a setExpression request can assign to: NAME, %NAME% or !NAME!.
+/// Substrings, replacements and anything else that computes a value are
+/// refused with why.
+fn assignable_variable(expression: &str) -> Result<&str, String> {
+    let expression = expression.trim();
+    let name = expression
+        .strip_prefix('%')
+        .and_then(|e| e.strip_suffix('%'))
+        .or_else(|| {
+            expression
+                .strip_prefix('!')
+                .and_then(|e| e.strip_suffix('!'))
+        })
+        .unwrap_or(expression);
+    if name.contains(':') {
+        return Err(format!(
+            "{} is a substring or replacement of a variable; only the variable itself can be assigned",
+            expression
+        ));
+    }
+    if name.is_empty()
+        || name
+            .chars()
+            .any(|c| c.is_whitespace() || "%!=~,<>|&^\"()+*/".contains(c))
+    {
+        return Err(format!(
+            "{} is not a variable; only NAME, %NAME% or !NAME! can be assigned",
+            expression
+        ));
+    }
+    if name.eq_ignore_ascii_case("ERRORLEVEL") {
+        return Err("ERRORLEVEL is read-only and cannot be modified".to_string());
+    }
+    Ok(name)
+}
 
-            loop {
-                match lines.next() {
-                    Some(Ok(line)) => {
-                        if line.is_empty() || line == "\r" {
-                            break;
-                        }
-                        if line.starts_with("Content-Length:") {
-                            content_length = line[15..].trim().parse().unwrap_or(0);
-                        }
-                    }
-                    _ => {
-                        let _ = tx.send(None);
-                        return;
-                    }
-                }
+/// `path` as a file:// URI, for clients with pathFormat "uri". Anything
+/// but letters, digits and `-._~/:` is percent-encoded, byte by byte of its
+/// UTF-8.
+fn path_to_uri(path: &str) -> String {
+    let mut uri = String::from("file://");
+    let path = path.replace('\\', "/");
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+    for &b in path.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                uri.push(b as char)
             }
+            _ => uri.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    uri
+}
 
-            if content_length > 0 {
-                let mut buffer = vec![0u8; content_length];
-                drop(lines);
-                if handle.read_exact(&mut buffer).is_ok() {
-                    if let Ok(msg) = serde_json::from_slice(&buffer) {
-                        let _ = tx.send(Some(msg));
-                        return;
-                    }
-                }
+/// The file path a file:// URI names. Anything else is taken as a path.
+fn uri_to_path(uri: &str) -> String {
+    let rest = match uri.strip_prefix("file://") {
+        Some(rest) => rest,
+        None => return uri.to_string(),
+    };
+    let raw = rest.as_bytes();
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < raw.len() {
+        let escaped = match raw[i] {
+            b'%' => rest
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(b) => {
+                bytes.push(b);
+                i += 3;
             }
+            None => {
+                bytes.push(raw[i]);
+                i += 1;
+            }
+        }
+    }
+    let path = String::from_utf8_lossy(&bytes).into_owned();
+    // file:///C:/dir keeps the drive letter first, with Windows separators
+    match path.strip_prefix('/') {
+        Some(windows) if windows.as_bytes().get(1) == Some(&b':') => windows.replace('/', "\\"),
+        _ => path,
+    }
+}
 
-            let _ = tx.send(None);
-        });
+/// Granularity a next or stepIn request asks for. There are no
+/// instructions below a statement, so "instruction" steps statements too.
+fn step_granularity(args: Option<&Value>) -> StepGranularity {
+    match args
+        .and_then(|v| v.get("granularity"))
+        .and_then(|v| v.as_str())
+    {
+        Some("statement") | Some("instruction") => StepGranularity::Statement,
+        _ => StepGranularity::Line,
     }
+}
 
-    fn try_receive(&mut self) -> Option<Option<DapMessage>> {
-        if let Some(ref rx) = self.receiver {
-            match rx.try_recv() {
-                Ok(msg) => {
-                    self.receiver = None;
-                    Some(msg)
-                }
-                Err(TryRecvError::Empty) => None,
-                Err(TryRecvError::Disconnected) => {
-                    self.receiver = None;
-                    Some(None)
-                }
-            }
-        } else {
-            None
+/// Evaluate `expression` without keeping the context locked while CMD works
+/// on it, so other requests are answered meanwhile. `limit` caps the
+/// session's command timeout for the query.
+fn evaluate_unlocked(
+    ctx_arc: &Arc<Mutex<DebugContext>>,
+    expression: &str,
+    frame_id: Option<usize>,
+    limit: Option<Duration>,
+) -> Result<String, BatchDbgError> {
+    let (query, shell, exit_code) = {
+        let mut ctx = ctx_arc.lock().map_err(|_| BatchDbgError::LockPoisoned)?;
+        let evaluation = match frame_id {
+            Some(id) => ctx.plan_evaluation_in_frame(expression, id)?,
+            None => ctx.plan_evaluation(expression)?,
+        };
+        match evaluation {
+            Evaluation::Value(value) => return Ok(value),
+            Evaluation::Query(query) => (query, ctx.query_session(), ctx.last_exit_code),
         }
-    }
+    };
+    let result = {
+        let mut shell = lock_shell(&shell);
+        let timeout = shell.timeout();
+        if let Some(limit) = limit {
+            shell.set_timeout(timeout.min(limit));
+        }
+        let result = run_helper_in(&mut **shell, &query, exit_code);
+        shell.set_timeout(timeout);
+        result?
+    };
+    let mut ctx = ctx_arc.lock().map_err(|_| BatchDbgError::LockPoisoned)?;
+    Ok(ctx.record_query(&query, &result))
+}
+
+/// Run a debug console command with only the shell locked while it runs,
+/// then track what it did
+fn run_repl_unlocked(
+    ctx_arc: &Arc<Mutex<DebugContext>>,
+    input: &str,
+) -> Result<CommandResult, BatchDbgError> {
+    let shell = {
+        let mut ctx = ctx_arc.lock().map_err(|_| BatchDbgError::LockPoisoned)?;
+        // Directory changes are quick and have to be tracked as they run
+        if let Some(result) = ctx.run_directory_command(input) {
+            return Ok(result?);
+        }
+        ctx.shared_session()
+    };
+    let result = lock_shell(&shell).run(input)?;
+    ctx_arc
+        .lock()
+        .map_err(|_| BatchDbgError::LockPoisoned)?
+        .record_command(input, &result);
+    Ok(result)
+}
+
+/// Makes the context a launch runs the script in, given the launch's
+/// session options
+pub type SessionFactory = Box<dyn Fn(SessionOptions) -> io::Result<DebugContext> + Send>;
+
+/// Breakpoints kept in the context, taken over by the one a restart creates
+struct CarriedBreakpoints {
+    data: Vec<(String, Option<String>, bool)>, // Variable, condition, break on delete
+    output: Vec<String>,                       // Output breakpoint patterns
+}
+
+/// Response to a request that ran on a worker thread: request seq, command,
+/// success and body
+type Finished = (u64, String, bool, Value);
+
+/// The state variables requests read, taken from the server so a worker
+/// can answer them
+struct VariableView {
+    context: Option<Arc<Mutex<DebugContext>>>,
+    watch_expressions: Vec<String>,
+    max_value_length: usize,
+}
+
+/// A request running on a worker thread
+struct InFlight {
+    command: String,
+    cancelled: bool,               // Answered "cancelled"; the result is dropped
+    killer: Option<SessionKiller>, // Interrupts the session command it waits on
 }
 
 pub struct DapServer {
@@ -85,16 +306,56 @@ pub struct DapServer {
     context: Option<Arc<Mutex<DebugContext>>>,
     preprocessed: Option<PreprocessResult>,
     labels: Option<HashMap<String, usize>>,
-    breakpoints: HashMap<String, Vec<usize>>,
+    breakpoints: HashMap<PathBuf, Vec<usize>>, // Logical lines per source, keyed by source_key
     program_path: Option<String>,
-    pub event_receiver: Option<Receiver<(String, usize)>>,
-    pub output_receiver: Option<Receiver<String>>,
-    message_reader: MessageReader,
+    pub event_receiver: Option<Receiver<StopInfo>>,
+    pub output_receiver: Option<Receiver<ScriptOutput>>,
+    pub stderr_receiver: Option<Receiver<ScriptOutput>>,
+    session_killer: Option<SessionKiller>, // Stops the script without waiting for the context lock
+    resume_signal: Option<Arc<Condvar>>,   // Wakes an executor stopped at a line when terminating
+    transport: Box<dyn Transport>,
+    session_factory: Option<SessionFactory>, // Stands in for CmdSession on launch
+    last_answered: Option<u64>,              // request_seq of the last response sent
+    in_flight: HashMap<u64, InFlight>,       // Requests still running on a worker
+    finished_tx: Sender<Finished>,
+    finished_rx: Receiver<Finished>,
     watch_expressions: Vec<String>,
+    watches_requested: Vec<String>, // Watch-context evaluations since the last stop
+    data_breakpoint_ids: HashMap<String, u64>, // Variable name -> id given to the client
+    next_breakpoint_id: u64,
+    max_value_length: usize, // Longer values are cut short in variable lists
+    breakpoint_ids: HashMap<Option<PathBuf>, HashMap<usize, u64>>, // Script (None: program) -> line -> id
+    pending_breakpoint_ids: HashMap<PathBuf, Vec<u64>>, // Source -> ids of breakpoints set before launch, in request order
+    show_preprocessed: bool, // Frames of the program point at its logical lines
+    coverage_file: Option<PathBuf>, // lcov file written when the script ends
+    profile_file: Option<PathBuf>,  // Chrome trace written when the script ends
+    breakpoints_file: Option<PathBuf>, // Breakpoints loaded at launch and saved on each change
+    break_on_command_not_found: bool, // "commandNotFound" exception filter
+    child_processes: Option<ChildProcesses>, // Programs the script STARTed without /WAIT
+    kill_spawned_processes: bool,   // Kill those programs on terminate
+    launch_args: Option<Value>,     // Launch configuration, reused by restart
+    breakpoint_requests: HashMap<PathBuf, Value>, // Last setBreakpoints arguments per source
+    carried_breakpoints: Option<CarriedBreakpoints>, // Data and output breakpoints across a restart
+    executor: Option<thread::JoinHandle<()>>, // Thread running the script
+    terminated_sent: bool,          // The terminated event went out for this launch
+    thread_started: bool,           // The script's thread was announced to the client
+    configuration_done: bool,       // The client sent configurationDone
+    launch_pending: bool,           // Launched, waiting for configurationDone to run
+    initialized: bool,              // A client sent initialize
+    lines_start_at1: bool,          // The client counts lines from 1 (the default)
+    columns_start_at1: bool,        // The client counts columns from 1 (the default)
+    path_format_uri: bool,          // The client sends and expects file:// URIs
+    supports_progress: bool,        // The client shows progress events
+    service: bool,                  // Runs the script on its own; clients attach and detach
+    stopped: Option<StopInfo>,      // The last stop, until the script ends
+    loaded_sources: HashMap<PathBuf, Value>, // CALLed batch files the client was told about, keyed by source_key
+    console_verbosity: ConsoleVerbosity,     // Which of the debugger's notes are sent as output
+    log: DebugLog,
 }
 
 impl DapServer {
     pub fn new() -> Self {
+        let (finished_tx, finished_rx) = channel();
         Self {
             seq: 0,
             context: None,
@@ -104,11 +365,65 @@ impl DapServer {
             program_path: None,
             event_receiver: None,
             watch_expressions: Vec::new(),
+            watches_requested: Vec::new(),
             output_receiver: None,
-            message_reader: MessageReader::new(),
+            stderr_receiver: None,
+            session_killer: None,
+            resume_signal: None,
+            transport: Box::new(StdioTransport::new()),
+            session_factory: None,
+            last_answered: None,
+            in_flight: HashMap::new(),
+            finished_tx,
+            finished_rx,
+            data_breakpoint_ids: HashMap::new(),
+            next_breakpoint_id: 1,
+            breakpoint_ids: HashMap::new(),
+            pending_breakpoint_ids: HashMap::new(),
+            max_value_length: DEFAULT_MAX_VALUE_LENGTH,
+            show_preprocessed: false,
+            coverage_file: None,
+            breakpoints_file: None,
+            profile_file: None,
+            break_on_command_not_found: false,
+            child_processes: None,
+            kill_spawned_processes: false,
+            launch_args: None,
+            breakpoint_requests: HashMap::new(),
+            carried_breakpoints: None,
+            executor: None,
+            terminated_sent: false,
+            thread_started: false,
+            configuration_done: false,
+            launch_pending: false,
+            service: false,
+            stopped: None,
+            loaded_sources: HashMap::new(),
+            console_verbosity: ConsoleVerbosity::Info,
+            initialized: false,
+            lines_start_at1: true,
+            columns_start_at1: true,
+            path_format_uri: false,
+            supports_progress: false,
+            log: DebugLog::new(),
         }
     }
 
+    /// Trace log shared with the session; `logFile` at launch redirects it
+    pub fn log(&self) -> DebugLog {
+        self.log.clone()
+    }
+
+    pub fn set_log(&mut self, log: DebugLog) {
+        self.log = log;
+    }
+
+    /// Point the program's stack frames at its preprocessed lines, like the
+    /// showPreprocessed launch option
+    pub fn set_show_preprocessed(&mut self, enabled: bool) {
+        self.show_preprocessed = enabled;
+    }
+
     fn next_seq(&mut self) -> u64 {
         self.seq += 1;
         self.seq
@@ -124,6 +439,7 @@ impl DapServer {
     /// Remove a watch expression
     pub fn remove_watch(&mut self, expression: &str) {
         self.watch_expressions.retain(|e| e != expression);
+        self.watches_requested.retain(|e| e != expression);
     }
 
     /// Get all watch expressions
@@ -131,11 +447,63 @@ impl DapServer {
         &self.watch_expressions
     }
 
+    /// Remove all watch expressions
+    pub fn clear_watches(&mut self) {
+        self.watch_expressions.clear();
+        self.watches_requested.clear();
+    }
+
+    /// Record a watch-context evaluate request. Clients re-evaluate every
+    /// watch after each stop, so these define the watch list.
+    pub fn record_watch_request(&mut self, expression: &str) {
+        if !self.watches_requested.iter().any(|e| e == expression) {
+            self.watches_requested.push(expression.to_string());
+        }
+        if !self.watch_expressions.iter().any(|e| e == expression) {
+            self.watch_expressions.push(expression.to_string());
+            eprintln!("WATCH: Added watch expression: '{}'", expression);
+        }
+    }
+
+    /// Called when a stopped event is sent. DAP has no "remove watch"
+    /// request, so when the client evaluated watches since the previous stop,
+    /// those (in request order) replace the list and anything else is pruned.
+    /// Clients that never send watch evaluations keep their explicit list.
+    pub fn on_stopped(&mut self) {
+        if !self.watches_requested.is_empty() {
+            for stale in self
+                .watch_expressions
+                .iter()
+                .filter(|e| !self.watches_requested.contains(e))
+            {
+                eprintln!("WATCH: Pruned watch expression: '{}'", stale);
+            }
+            self.watch_expressions = std::mem::take(&mut self.watches_requested);
+        }
+    }
+
+    /// Talk to the client over `transport` instead of stdin and stdout
+    pub fn set_transport(&mut self, transport: Box<dyn Transport>) {
+        self.transport = transport;
+    }
+
+    /// Make the context of launched sessions with `factory` instead of
+    /// starting CMD (for testing)
+    pub fn set_session_factory(&mut self, factory: SessionFactory) {
+        self.session_factory = Some(factory);
+    }
+
     /// Set the debug context (for testing)
     pub fn set_context(&mut self, context: Arc<Mutex<DebugContext>>) {
         self.context = Some(context);
     }
 
+    /// Set the launched program and its preprocessed lines (for testing)
+    pub fn set_program(&mut self, program_path: &str, pre: PreprocessResult) {
+        self.program_path = Some(program_path.to_string());
+        self.preprocessed = Some(pre);
+    }
+
     /// Get a reference to the context (for testing)
     pub fn get_context(&self) -> Option<&Arc<Mutex<DebugContext>>> {
         self.context.as_ref()
@@ -148,6 +516,29 @@ impl DapServer {
         success: bool,
         body: Option<Value>,
     ) {
+        self.respond(request_seq, command, success, None, body);
+    }
+
+    /// Fail a request with a short reason, e.g. "unsupported"
+    pub fn send_error_response(&mut self, request_seq: u64, command: String, message: &str) {
+        self.respond(request_seq, command, false, Some(message.to_string()), None);
+    }
+
+    /// Fail a request with `error`; its id tells the client which kind of
+    /// failure it was
+    pub fn send_failure(&mut self, request_seq: u64, command: String, error: &BatchDbgError) {
+        self.respond(request_seq, command, false, None, Some(error.dap_body()));
+    }
+
+    fn respond(
+        &mut self,
+        request_seq: u64,
+        command: String,
+        success: bool,
+        message: Option<String>,
+        body: Option<Value>,
+    ) {
+        self.last_answered = Some(request_seq);
         let msg = DapMessage {
             seq: self.next_seq(),
             msg_type: "response".to_string(),
@@ -155,13 +546,102 @@ impl DapServer {
                 request_seq,
                 success,
                 command,
-                message: None,
+                message,
                 body,
             },
         };
         self.send_message(&msg);
     }
 
+    /// Whether the request `request_seq` got its response, or will once it
+    /// is done on its worker
+    pub fn has_answered(&self, request_seq: u64) -> bool {
+        self.last_answered == Some(request_seq) || self.in_flight.contains_key(&request_seq)
+    }
+
+    /// Run `work` on a thread of its own, so a slow session command doesn't
+    /// hold up other requests. `work` locks the context only to read and
+    /// record state, never while CMD runs. The response goes out from
+    /// `check_finished_requests`, unless the request was cancelled.
+    fn run_on_worker<F>(&mut self, seq: u64, command: String, work: F)
+    where
+        F: FnOnce(&Arc<Mutex<DebugContext>>) -> (bool, Value) + Send + 'static,
+    {
+        let ctx_arc = match &self.context {
+            Some(ctx_arc) => ctx_arc.clone(),
+            None => {
+                self.send_error_response(seq, command, "No context available");
+                return;
+            }
+        };
+        let killer = self
+            .session_killer
+            .clone()
+            .or_else(|| ctx_arc.try_lock().ok().map(|ctx| ctx.session_killer()));
+        self.in_flight.insert(
+            seq,
+            InFlight {
+                command: command.clone(),
+                cancelled: false,
+                killer,
+            },
+        );
+        let finished = self.finished_tx.clone();
+        thread::spawn(move || {
+            let (success, body) = work(&ctx_arc);
+            let _ = finished.send((seq, command, success, body));
+        });
+    }
+
+    /// Send the responses of requests finished on their workers
+    pub fn check_finished_requests(&mut self) {
+        while let Ok((seq, command, success, body)) = self.finished_rx.try_recv() {
+            match self.in_flight.remove(&seq) {
+                Some(InFlight {
+                    cancelled: true,
+                    killer,
+                    ..
+                }) => {
+                    // The interrupt was for the cancelled command only
+                    if let Some(killer) = killer {
+                        killer.take_interrupted();
+                    }
+                    eprintln!("Dropped the result of cancelled request {}", seq);
+                }
+                _ => self.send_response(seq, command, success, Some(body)),
+            }
+        }
+    }
+
+    /// Whether a request is still running on its worker
+    pub fn request_in_flight(&self, request_seq: u64) -> bool {
+        self.in_flight.contains_key(&request_seq)
+    }
+
+    /// Answer a running request with "cancelled" right away and drop its
+    /// result, interrupting the session command it waits on
+    pub fn handle_cancel(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let request_id = args
+            .as_ref()
+            .and_then(|v| v.get("requestId"))
+            .and_then(|v| v.as_u64());
+        let cancelled = match request_id.and_then(|id| self.in_flight.get_mut(&id)) {
+            Some(request) if !request.cancelled => {
+                request.cancelled = true;
+                if let Some(killer) = &request.killer {
+                    killer.interrupt();
+                }
+                Some(request.command.clone())
+            }
+            _ => None,
+        };
+        if let (Some(id), Some(cancelled_command)) = (request_id, cancelled) {
+            eprintln!("Cancelled request {}", id);
+            self.send_error_response(id, cancelled_command, "cancelled");
+        }
+        self.send_response(seq, command, true, None);
+    }
+
     pub fn send_event(&mut self, event: String, body: Option<Value>) {
         let msg = DapMessage {
             seq: self.next_seq(),
@@ -184,305 +664,818 @@ impl DapServer {
         );
     }
 
-    fn send_message(&self, msg: &DapMessage) {
-        let json = serde_json::to_string(msg).unwrap();
-        let content_length = json.len();
-
-        let output = format!("Content-Length: {}\r\n\r\n{}", content_length, json);
-        print!("{}", output);
-
-        use std::io::Write;
-        let _ = std::io::stdout().flush();
-
-        eprintln!("SENT: {} bytes", content_length);
-    }
-
-    // BESKED TIL MIG SELV:
-    // Read message virker ikke af en eller anden grund, jeg har rewrited den til try read message, men der er nogle ting jeg gerne vil debugge ved den her funktion i fremtiden:)
-    pub fn read_message(&self) -> Option<DapMessage> {
-        let stdin = io::stdin();
-        let mut handle = stdin.lock();
-
-        let mut content_length = 0;
-        let mut lines = handle.by_ref().lines();
-
-        loop {
-            if let Some(Ok(line)) = lines.next() {
-                if line.is_empty() || line == "\r" {
-                    break;
-                }
-                if line.starts_with("Content-Length:") {
-                    content_length = line[15..].trim().parse().unwrap_or(0);
-                }
-            } else {
-                return None;
-            }
-        }
-
-        if content_length > 0 {
-            let mut buffer = vec![0u8; content_length];
-            drop(lines);
-            if handle.read_exact(&mut buffer).is_ok() {
-                if let Ok(msg) = serde_json::from_slice(&buffer) {
-                    return Some(msg);
-                }
-            }
+    fn send_message(&mut self, msg: &DapMessage) {
+        if let Err(e) = self.transport.send(msg) {
+            eprintln!("ERROR: Failed to send DAP message: {}", e);
         }
-
-        None
     }
 
-    pub fn try_read_message(&mut self) -> Option<DapMessage> {
-        if let Some(result) = self.message_reader.try_receive() {
-            return result;
-        }
-        if self.message_reader.receiver.is_none() {
-            self.message_reader.start_read();
-        }
+    /// Wait for the client's next message; None once it has gone
+    pub fn read_message(&mut self) -> Option<DapMessage> {
+        self.transport.read_message().ok()
+    }
 
-        None
+    /// The client's next message if one has arrived. Fails once the client
+    /// has gone.
+    pub fn try_read_message(&mut self) -> io::Result<Option<DapMessage>> {
+        self.transport.try_read()
     }
 
-    pub fn handle_initialize(&mut self, seq: u64, command: String) {
+    pub fn handle_initialize(&mut self, seq: u64, command: String, args: Option<Value>) {
+        // How the client counts lines and columns and names files; every
+        // line, column and path crossing the protocol is converted with
+        // the *_to_client / *_from_client helpers below
+        let settings = (
+            self.lines_start_at1,
+            self.columns_start_at1,
+            self.path_format_uri,
+        );
+        self.lines_start_at1 = args
+            .as_ref()
+            .and_then(|v| v.get("linesStartAt1"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+        self.columns_start_at1 = args
+            .as_ref()
+            .and_then(|v| v.get("columnsStartAt1"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+        self.path_format_uri = args
+            .as_ref()
+            .and_then(|v| v.get("pathFormat"))
+            .and_then(|v| v.as_str())
+            == Some("uri");
+        self.supports_progress = args
+            .as_ref()
+            .and_then(|v| v.get("supportsProgressReporting"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+
         let body = json!({
             "supportsConfigurationDoneRequest": true,
-            "supportsStepBack": false,
+            "supportsBreakpointLocationsRequest": true,
+            "supportsStepBack": true,
+            "supportsGotoTargetsRequest": true,
+            "supportsSteppingGranularity": true,
             "supportsStepInTargetsRequest": false,
             "supportsFunctionBreakpoints": false,
             "supportsConditionalBreakpoints": true,
             "supportsSetVariable": true,
+            "supportsSetExpression": true,
+            "supportsCompletionsRequest": true,
+            "supportsCancelRequest": true,
             "supportsDataBreakpoints": true,
             "supportsEvaluateForHovers": true,
+            "supportsTerminateRequest": true,
+            "supportRestartRequest": true,
+            "supportsLoadedSourcesRequest": true,
+            "exceptionBreakpointFilters": [{
+                "filter": "commandNotFound",
+                "label": "Command not found",
+                "description": "Stop after a line runs a command that isn't a builtin or a program on PATH",
+                "default": false
+            }],
         });
-        self.send_response(seq, command, true, Some(body));
+        self.send_response(seq, command, true, Some(body.clone()));
+        // Initialized again with other settings: the client hears the
+        // capabilities again, as they hold under the new ones
+        let changed = settings
+            != (
+                self.lines_start_at1,
+                self.columns_start_at1,
+                self.path_format_uri,
+            );
+        if self.initialized && changed {
+            self.send_event(
+                "capabilities".to_string(),
+                Some(json!({ "capabilities": body })),
+            );
+        }
+        self.initialized = true;
+    }
 
-        eprintln!("📋 Sending initialized event");
-        self.send_event("initialized".to_string(), None);
+    /// A 1-based line as the client counts lines
+    pub fn line_to_client(&self, line: usize) -> usize {
+        if self.lines_start_at1 {
+            line
+        } else {
+            line.saturating_sub(1)
+        }
+    }
+
+    /// The 1-based line a client line number stands for
+    pub fn line_from_client(&self, line: usize) -> usize {
+        if self.lines_start_at1 {
+            line
+        } else {
+            line + 1
+        }
+    }
+
+    /// A 1-based column as the client counts columns
+    pub fn column_to_client(&self, column: usize) -> usize {
+        if self.columns_start_at1 {
+            column
+        } else {
+            column.saturating_sub(1)
+        }
+    }
+
+    /// The 1-based column a client column number stands for
+    pub fn column_from_client(&self, column: usize) -> usize {
+        if self.columns_start_at1 {
+            column
+        } else {
+            column + 1
+        }
+    }
+
+    /// A file path as the client names files
+    pub fn path_to_client(&self, path: &str) -> String {
+        if self.path_format_uri {
+            path_to_uri(path)
+        } else {
+            path.to_string()
+        }
+    }
+
+    /// The file path a client source path stands for
+    pub fn path_from_client(&self, path: &str) -> String {
+        if self.path_format_uri {
+            uri_to_path(path)
+        } else {
+            path.to_string()
+        }
     }
 
     pub fn handle_launch(&mut self, seq: u64, command: String, args: Option<Value>) {
+        self.launch_args = args.clone();
+        self.terminated_sent = false;
+        self.loaded_sources.clear();
         let program = args
             .as_ref()
             .and_then(|v| v.get("program"))
             .and_then(|v| v.as_str())
             .unwrap_or("test.bat");
 
+        if let Some(log_file) = args
+            .as_ref()
+            .and_then(|v| v.get("logFile"))
+            .and_then(|v| v.as_str())
+            .filter(|p| !p.is_empty())
+        {
+            if let Err(e) = self.log.open(Path::new(log_file)) {
+                eprintln!("WARNING: Cannot open log file {}: {}", log_file, e);
+            }
+        }
+
         let stop_on_entry = args
             .as_ref()
             .and_then(|v| v.get("stopOnEntry"))
             .and_then(|v| v.as_bool())
             .unwrap_or(true);
 
-        self.program_path = Some(program.to_string());
+        let lint_on_launch = args
+            .as_ref()
+            .and_then(|v| v.get("lintOnLaunch"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
-        eprintln!("🚀 Launching batch file: {}", program);
-        eprintln!("   Stop on entry: {}", stop_on_entry);
+        let break_on_external = args
+            .as_ref()
+            .and_then(|v| v.get("breakOnExternal"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
-        let mut log = std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open("C:\\temp\\batch-debugger-vscode.log")
-            .ok();
+        let external_allowlist: Option<Vec<String>> = args
+            .as_ref()
+            .and_then(|v| v.get("externalAllowlist"))
+            .and_then(|v| v.as_array())
+            .map(|a| {
+                a.iter()
+                    .filter_map(|n| n.as_str().map(|s| s.to_string()))
+                    .collect()
+            });
 
-        if let Some(ref mut f) = log {
-            use std::io::Write;
-            writeln!(f, "handle_launch called for: {}", program).ok();
-            writeln!(f, "stop_on_entry: {}", stop_on_entry).ok();
-            f.flush().ok();
-        }
+        let show_environment = args
+            .as_ref()
+            .and_then(|v| v.get("showEnvironment"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
-        match std::fs::read_to_string(program) {
-            Ok(contents) => {
-                let physical_lines: Vec<&str> = contents.lines().collect();
-                let pre = parser::preprocess_lines(&physical_lines);
-                let labels_phys = parser::build_label_map(&physical_lines);
+        let wait_on_pause = args
+            .as_ref()
+            .and_then(|v| v.get("waitOnPause"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
-                eprintln!("📝 Parsed {} logical lines", pre.logical.len());
-                if let Some(ref mut f) = log {
-                    use std::io::Write;
-                    writeln!(f, "Parsed {} logical lines", pre.logical.len()).ok();
-                    f.flush().ok();
-                }
+        // verboseConsole predates consoleVerbosity and means "debug"
+        let verbose_console = args
+            .as_ref()
+            .and_then(|v| v.get("verboseConsole"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let default_verbosity = if verbose_console {
+            ConsoleVerbosity::Debug
+        } else {
+            ConsoleVerbosity::Info
+        };
+        self.console_verbosity = match args
+            .as_ref()
+            .and_then(|v| v.get("consoleVerbosity"))
+            .and_then(|v| v.as_str())
+        {
+            Some("off") => ConsoleVerbosity::Off,
+            Some("info") => ConsoleVerbosity::Info,
+            Some("debug") => ConsoleVerbosity::Debug,
+            Some(other) => {
+                eprintln!("WARNING: Unknown consoleVerbosity '{}', ignoring it", other);
+                default_verbosity
+            }
+            None => default_verbosity,
+        };
+        let verbose_console = self.console_verbosity == ConsoleVerbosity::Debug;
 
-                match CmdSession::start() {
-                    Ok(session) => {
-                        eprintln!("CMD session started");
-                        if let Some(ref mut f) = log {
-                            use std::io::Write;
-                            writeln!(f, "CMD session started successfully").ok();
-                            f.flush().ok();
-                        }
+        let fast_forward_delays = args
+            .as_ref()
+            .and_then(|v| v.get("fastForwardDelays"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
-                        let mut ctx = DebugContext::new(session);
+        self.kill_spawned_processes = args
+            .as_ref()
+            .and_then(|v| v.get("killSpawnedProcesses"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
-                        if stop_on_entry {
-                            ctx.set_mode(RunMode::StepInto);
-                            eprintln!("   Mode: StepInto (will stop at first line)");
-                        } else {
-                            ctx.set_mode(RunMode::Continue);
-                            eprintln!("   Mode: Continue (will run until breakpoint)");
-                        }
-                        ctx.continue_requested = false;
+        self.show_preprocessed = args
+            .as_ref()
+            .and_then(|v| v.get("showPreprocessed"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
-                        let ctx_arc = Arc::new(Mutex::new(ctx));
-                        self.context = Some(ctx_arc.clone());
-                        self.preprocessed = Some(pre.clone());
-                        self.labels = Some(labels_phys.clone());
+        self.max_value_length = args
+            .as_ref()
+            .and_then(|v| v.get("maxValueLength"))
+            .and_then(|v| v.as_u64())
+            .map_or(DEFAULT_MAX_VALUE_LENGTH, |n| n as usize);
 
-                        self.send_response(seq, command, true, None);
-                        eprintln!("SENT: Launch response");
+        self.coverage_file = args
+            .as_ref()
+            .and_then(|v| v.get("coverageFile"))
+            .and_then(|v| v.as_str())
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from);
 
-                        let mut thread_log = std::fs::OpenOptions::new()
-                            .create(true)
-                            .append(true)
-                            .open("C:\\temp\\batch-debugger-vscode.log")
-                            .ok();
+        self.profile_file = args
+            .as_ref()
+            .and_then(|v| v.get("profileFile"))
+            .and_then(|v| v.as_str())
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from);
 
-                        if let Some(ref mut f) = thread_log {
-                            use std::io::Write;
-                            writeln!(f, "About to spawn execution thread").ok();
-                            f.flush().ok();
-                        }
+        self.breakpoints_file = args
+            .as_ref()
+            .and_then(|v| v.get("breakpointsFile"))
+            .and_then(|v| v.as_str())
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from);
 
-                        let (tx, rx) = channel::<(String, usize)>();
-                        let (output_tx, output_rx) = channel::<String>();
+        let command_timeout = args
+            .as_ref()
+            .and_then(|v| v.get("commandTimeout"))
+            .and_then(|v| v.as_u64())
+            .map(Duration::from_secs);
 
-                        self.event_receiver = Some(rx);
-                        self.output_receiver = Some(output_rx);
+        // A list is taken as is; a string is split like CMD splits a command line
+        let script_args: Vec<String> = match args.as_ref().and_then(|v| v.get("args")) {
+            Some(Value::Array(a)) => a
+                .iter()
+                .filter_map(|arg| arg.as_str().map(|s| s.to_string()))
+                .collect(),
+            Some(Value::String(line)) => parser::split_batch_arguments(line),
+            _ => Vec::new(),
+        };
 
-                        let exec_ctx = ctx_arc.clone();
-                        let exec_pre = pre.clone();
-                        let exec_labels = labels_phys.clone();
+        let cwd = args
+            .as_ref()
+            .and_then(|v| v.get("cwd"))
+            .and_then(|v| v.as_str())
+            .filter(|s| !s.is_empty());
 
-                        thread::spawn(move || {
-                            let mut tlog = std::fs::OpenOptions::new()
-                                .create(true)
-                                .append(true)
-                                .open("C:\\temp\\batch-debugger-vscode.log")
-                                .ok();
+        let pre_launch_commands: Vec<String> = args
+            .as_ref()
+            .and_then(|v| v.get("preLaunchCommands"))
+            .and_then(|v| v.as_array())
+            .map(|a| {
+                a.iter()
+                    .filter_map(|c| c.as_str().map(|s| s.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
 
-                            if let Some(ref mut f) = tlog {
-                                use std::io::Write;
-                                writeln!(f, "🧵 Execution thread STARTED").ok();
-                                f.flush().ok();
-                            }
+        let fail_on_pre_launch_error = args
+            .as_ref()
+            .and_then(|v| v.get("failOnPreLaunchError"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
-                            eprintln!("🧵 Execution thread started");
-
-                            match executor::run_debugger_dap(
-                                exec_ctx,
-                                &exec_pre,
-                                &exec_labels,
-                                tx,
-                                output_tx,
-                            ) {
-                                Ok(_) => {
-                                    eprintln!("✅ Execution completed successfully");
-                                    if let Some(ref mut f) = tlog {
-                                        use std::io::Write;
-                                        writeln!(f, "✅ Execution completed successfully").ok();
-                                        f.flush().ok();
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("ERROR: Execution error: {}", e);
-                                    if let Some(ref mut f) = tlog {
-                                        use std::io::Write;
-                                        writeln!(f, "ERROR: Execution error: {}", e).ok();
-                                        f.flush().ok();
-                                    }
-                                }
-                            }
+        // "shell": {"path": "C:\\Windows\\System32\\cmd.exe", "args": ["/V:ON"]}
+        let shell_option = args.as_ref().and_then(|v| v.get("shell"));
+        let mut shell = ShellConfig::default();
+        if let Some(path) = shell_option
+            .and_then(|s| s.get("path"))
+            .and_then(|p| p.as_str())
+            .filter(|p| !p.is_empty())
+        {
+            shell.path = PathBuf::from(path);
+        }
+        if let Some(shell_args) = shell_option
+            .and_then(|s| s.get("args"))
+            .and_then(|a| a.as_array())
+        {
+            shell.args = shell_args
+                .iter()
+                .filter_map(|a| a.as_str().map(|s| s.to_string()))
+                .collect();
+        }
+
+        let record_session = args
+            .as_ref()
+            .and_then(|v| v.get("recordSession"))
+            .and_then(|v| v.as_str())
+            .filter(|s| !s.is_empty());
+
+        let ansi = args
+            .as_ref()
+            .and_then(|v| v.get("ansi"))
+            .and_then(|v| v.as_str())
+            .and_then(AnsiMode::from_name)
+            .unwrap_or_default();
+
+        let step_into_called_scripts = args
+            .as_ref()
+            .and_then(|v| v.get("stepIntoCalledScripts"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+
+        let skip_autorun = args
+            .as_ref()
+            .and_then(|v| v.get("skipAutoRun"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+
+        let clear_env = args
+            .as_ref()
+            .and_then(|v| v.get("clearEnv"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+
+        let env: Vec<(String, String)> = args
+            .as_ref()
+            .and_then(|v| v.get("env"))
+            .and_then(|v| v.as_object())
+            .map(|o| {
+                o.iter()
+                    .filter_map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string())))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let code_page = args
+            .as_ref()
+            .and_then(|v| v.get("codepage"))
+            .and_then(|v| v.as_u64())
+            .map(|v| v as u32);
+
+        self.program_path = Some(program.to_string());
+
+        eprintln!("🚀 Launching batch file: {}", program);
+        eprintln!("   Stop on entry: {}", stop_on_entry);
+
+        let log = self.log.clone();
+
+        log.write(format_args!("handle_launch called for: {}", program));
+        log.write(format_args!("stop_on_entry: {}", stop_on_entry));
+
+        match std::fs::read_to_string(program) {
+            Ok(contents) => {
+                let physical_lines: Vec<&str> = contents.lines().collect();
+                let pre = parser::preprocess_lines(&physical_lines);
+                let labels_phys = parser::build_label_map(&physical_lines);
+
+                eprintln!("📝 Parsed {} logical lines", pre.logical.len());
+                log.write(format_args!("Parsed {} logical lines", pre.logical.len()));
 
-                            if let Some(ref mut f) = tlog {
-                                use std::io::Write;
-                                writeln!(f, "🧵 Execution thread EXITING").ok();
-                                f.flush().ok();
+                let options = SessionOptions {
+                    cwd: cwd.map(PathBuf::from),
+                    shell,
+                    ansi,
+                    skip_autorun,
+                    clear_env,
+                    env,
+                };
+                let started = match &self.session_factory {
+                    Some(factory) => factory(options).map(|ctx| (ctx, None)),
+                    None => CmdSession::start_with(options).and_then(|mut session| {
+                        // The transcript is only a diagnostic, the launch goes on without it
+                        if let Some(path) = record_session {
+                            if let Err(e) = session.record_to(Path::new(path)) {
+                                eprintln!("WARNING: Cannot record session to {}: {}", path, e);
                             }
-                            eprintln!("🧵 Execution thread exiting");
-                        });
+                        }
+                        if let Some(code_page) = code_page {
+                            session.set_code_page(code_page)?;
+                        }
+                        let process_id = session.process_id();
+                        Ok((DebugContext::new(session), Some(process_id)))
+                    }),
+                };
+                match started {
+                    Ok((mut ctx, process_id)) => {
+                        eprintln!("CMD session started: {}", ctx.shell_config().path.display());
+                        log.write(format_args!("CMD session started successfully"));
+
+                        ctx.set_script_args(program, script_args);
+                        ctx.set_log(log.clone());
 
-                        if let Some(ref mut f) = log {
-                            use std::io::Write;
-                            writeln!(f, "Execution thread spawned, waiting for first stop").ok();
-                            f.flush().ok();
+                        if stop_on_entry {
+                            ctx.set_mode(RunMode::StepInto);
+                            eprintln!("   Mode: StepInto (will stop at first line)");
+                        } else {
+                            ctx.set_mode(RunMode::Continue);
+                            eprintln!("   Mode: Continue (will run until breakpoint)");
                         }
-                        if let Some(ref output_rx) = self.output_receiver {
-                            let mut outputs = Vec::new();
-                            while let Ok(output) = output_rx.try_recv() {
-                                outputs.push(output);
-                            }
-                            for output in outputs {
-                                self.send_output(&output, "stdout");
-                            }
+                        ctx.continue_requested = false;
+                        if show_environment {
+                            ctx.import_environment(std::env::vars());
+                        }
+                        if let Some(timeout) = command_timeout {
+                            ctx.set_command_timeout(timeout);
+                        }
+                        ctx.set_break_on_external(break_on_external);
+                        ctx.set_step_into_called_scripts(step_into_called_scripts);
+                        ctx.set_wait_on_pause(wait_on_pause);
+                        ctx.set_verbose_console(verbose_console);
+                        ctx.set_fast_forward_delays(fast_forward_delays);
+                        ctx.set_break_on_command_not_found(self.break_on_command_not_found);
+                        if let Some(names) = external_allowlist {
+                            ctx.set_external_allowlist(names);
+                        }
+                        if let Err(message) = self.run_pre_launch_commands(
+                            &mut ctx,
+                            &pre_launch_commands,
+                            fail_on_pre_launch_error,
+                        ) {
+                            eprintln!("ERROR: {}", message);
+                            self.send_response(
+                                seq,
+                                command,
+                                false,
+                                Some(json!({
+                                    "error": {
+                                        "id": 2,
+                                        "format": message
+                                    }
+                                })),
+                            );
+                            return;
                         }
-                        if let Some(ref rx) = self.event_receiver {
-                            if let Ok((reason, line)) = rx.recv_timeout(Duration::from_secs(2)) {
-                                if let Some(ref mut f) = log {
-                                    use std::io::Write;
-                                    writeln!(f, "Received first stop: {} at line {}", reason, line)
-                                        .ok();
-                                    f.flush().ok();
-                                }
 
-                                if reason != "terminated" {
-                                    self.send_event(
-                                        "stopped".to_string(),
-                                        Some(json!({
-                                            "reason": reason,
-                                            "threadId": 1,
-                                            "allThreadsStopped": true
-                                        })),
-                                    );
-                                    eprintln!("SENT: Initial stopped event: {}", reason);
-                                } else {
-                                    eprintln!("WARNING: Script completed before first stop");
-                                    self.send_event("terminated".to_string(), None);
-                                }
-                            } else {
-                                if let Some(ref mut f) = log {
-                                    use std::io::Write;
-                                    writeln!(f, "WARNING: Timeout waiting for first stop event")
-                                        .ok();
-                                    f.flush().ok();
-                                }
-                                eprintln!("WARNING: Timeout waiting for first stop event");
+                        let ctx_arc = Arc::new(Mutex::new(ctx));
+                        self.prepare_launch(ctx_arc.clone(), &pre, &labels_phys);
+                        self.load_breakpoints_file();
+                        if lint_on_launch {
+                            let name = Path::new(program)
+                                .file_name()
+                                .map(|n| n.to_string_lossy().into_owned())
+                                .unwrap_or_default();
+                            for diagnostic in parser::lint_script(&pre) {
+                                self.send_output(
+                                    &format!("{}:{}\r\n", name, diagnostic),
+                                    "console",
+                                );
                             }
                         }
+
+                        self.send_response(seq, command, true, None);
+                        eprintln!("SENT: Launch response");
+
+                        let mut process = json!({
+                            "name": program,
+                            "isLocalProcess": true,
+                            "startMethod": "launch"
+                        });
+                        if let Some(process_id) = process_id {
+                            process["systemProcessId"] = json!(process_id);
+                        }
+                        self.send_event("process".to_string(), Some(process));
+
+                        // A restart has nothing more to wait for; a first
+                        // launch runs once the client has set its breakpoints
+                        if self.configuration_done {
+                            self.begin_execution();
+                        } else {
+                            eprintln!("📋 Sending initialized event");
+                            self.send_event("initialized".to_string(), None);
+                        }
                     }
                     Err(e) => {
                         eprintln!("ERROR: Failed to start CMD session: {}", e);
-                        if let Some(ref mut f) = log {
-                            use std::io::Write;
-                            writeln!(f, "ERROR: Failed to start CMD session: {}", e).ok();
-                            f.flush().ok();
-                        }
-                        self.send_response(seq, command, false, None);
+                        log.write(format_args!("ERROR: Failed to start CMD session: {}", e));
+                        self.send_failure(seq, command, &e.into());
                     }
                 }
             }
             Err(e) => {
                 eprintln!("ERROR: Failed to read batch file: {}", e);
-                if let Some(ref mut f) = log {
-                    use std::io::Write;
-                    writeln!(f, "ERROR: Failed to read batch file: {}", e).ok();
-                    f.flush().ok();
-                }
+                log.write(format_args!("ERROR: Failed to read batch file: {}", e));
                 self.send_response(seq, command, false, None);
             }
         }
     }
 
+    /// Run the script `launch_args` describes without a client, for clients
+    /// to attach to later. Nothing is sent until one does.
+    pub fn start_service(&mut self, launch_args: Value) -> Result<(), String> {
+        self.set_transport(Box::new(StreamTransport::new(io::empty(), io::sink())));
+        self.service = true;
+        self.handle_launch(0, "launch".to_string(), Some(launch_args));
+        if self.context.is_none() {
+            return Err("The script could not be launched".to_string());
+        }
+        self.handle_configuration_done(0, "configurationDone".to_string());
+        Ok(())
+    }
+
+    /// Whether the server runs its script as a service clients attach to
+    pub fn is_service(&self) -> bool {
+        self.service
+    }
+
+    /// Bind the client to the script already running: it hears of the
+    /// breakpoints set, and of the stop if the script is paused
+    pub fn handle_attach(&mut self, seq: u64, command: String) {
+        let ctx_arc = match &self.context {
+            Some(ctx_arc) if self.executor_running() => ctx_arc.clone(),
+            _ => {
+                eprintln!("ERROR: attach without a running script");
+                self.send_error_response(
+                    seq,
+                    command,
+                    "No script is running to attach to; start the adapter with --program",
+                );
+                return;
+            }
+        };
+        self.send_response(seq, command, true, None);
+        self.terminated_sent = false;
+        self.send_event(
+            "process".to_string(),
+            Some(json!({
+                "name": self.program_path,
+                "isLocalProcess": true,
+                "startMethod": "attach"
+            })),
+        );
+        self.send_event(
+            "thread".to_string(),
+            Some(json!({ "reason": "started", "threadId": 1 })),
+        );
+        for breakpoint in self.current_breakpoints() {
+            self.send_event(
+                "breakpoint".to_string(),
+                Some(json!({ "reason": "new", "breakpoint": breakpoint })),
+            );
+        }
+        self.send_event("initialized".to_string(), None);
+
+        let paused = ctx_arc.lock().is_ok_and(|ctx| !ctx.continue_requested);
+        if let (true, Some(stop)) = (paused, self.stopped.clone()) {
+            let body = self.stopped_body(&stop);
+            self.send_event("stopped".to_string(), Some(body));
+        }
+    }
+
+    /// Let the script run on without the client: Continue, resumed if it is
+    /// paused. Its breakpoints stay; one it reaches waits for the next client.
+    pub fn detach(&mut self) {
+        if let Some(ctx_arc) = &self.context {
+            if let Ok(mut ctx) = ctx_arc.lock() {
+                ctx.set_mode(RunMode::Continue);
+                ctx.request_continue();
+            }
+        }
+        self.set_transport(Box::new(StreamTransport::new(io::empty(), io::sink())));
+        eprintln!("Client detached, the script runs on");
+    }
+
+    /// The breakpoints bound now, as breakpoint events name them
+    fn current_breakpoints(&self) -> Vec<Value> {
+        let ctx = match self.context.as_ref().and_then(|c| c.lock().ok()) {
+            Some(ctx) => ctx,
+            None => return Vec::new(),
+        };
+        let mut breakpoints = Vec::new();
+        for (source, lines) in &self.breakpoints {
+            let path = source.display().to_string();
+            let (pre, script_key) = if self.is_program_source(&path) {
+                match &self.preprocessed {
+                    Some(pre) => (pre.clone(), None),
+                    None => continue,
+                }
+            } else {
+                match ctx.script(source) {
+                    Some(script) => (script.pre.clone(), Some(script.path.clone())),
+                    None => continue,
+                }
+            };
+            let ids = self.breakpoint_ids.get(&script_key);
+            for &line in lines {
+                let mut breakpoint = json!({
+                    "verified": true,
+                    "line": self.line_to_client(pre.logical.get(line).map_or(1, |l| l.phys_start + 1)),
+                    "source": { "path": self.path_to_client(&path) }
+                });
+                if let Some(id) = ids.and_then(|ids| ids.get(&line)) {
+                    breakpoint["id"] = json!(id);
+                }
+                breakpoints.push(breakpoint);
+            }
+        }
+        breakpoints
+    }
+
+    /// Take on a launched session, holding the script until configurationDone
+    /// so the breakpoints the client sends first bind before any line runs
+    pub fn prepare_launch(
+        &mut self,
+        ctx_arc: Arc<Mutex<DebugContext>>,
+        pre: &PreprocessResult,
+        labels: &HashMap<String, usize>,
+    ) {
+        if let Ok(ctx) = ctx_arc.lock() {
+            // Terminating before the script starts still ends the session
+            self.session_killer = Some(ctx.session_killer());
+            self.child_processes = Some(ctx.child_processes());
+        }
+        self.context = Some(ctx_arc);
+        self.preprocessed = Some(pre.clone());
+        self.labels = Some(labels.clone());
+        self.restore_breakpoints();
+        self.launch_pending = true;
+    }
+
+    pub fn handle_configuration_done(&mut self, seq: u64, command: String) {
+        self.send_response(seq, command, true, None);
+        self.configuration_done = true;
+        self.begin_execution();
+    }
+
+    /// Start the launched script and report its first stop
+    fn begin_execution(&mut self) {
+        if !self.launch_pending {
+            return;
+        }
+        self.launch_pending = false;
+        let (ctx_arc, pre, labels) = match (&self.context, &self.preprocessed, &self.labels) {
+            (Some(c), Some(p), Some(l)) => (c.clone(), p.clone(), l.clone()),
+            _ => return,
+        };
+        let log = self.log.clone();
+
+        log.write(format_args!("About to spawn execution thread"));
+        self.start_executor(ctx_arc, &pre, &labels);
+        self.thread_started = true;
+        self.send_event(
+            "thread".to_string(),
+            Some(json!({ "reason": "started", "threadId": 1 })),
+        );
+
+        log.write(format_args!(
+            "Execution thread spawned, waiting for first stop"
+        ));
+        self.check_and_send_output();
+        if let Some(ref rx) = self.event_receiver {
+            if let Ok(stop) = rx.recv_timeout(Duration::from_secs(2)) {
+                log.write(format_args!(
+                    "Received first stop: {} at line {}",
+                    stop.reason, stop.pc
+                ));
+
+                // The output from before the stop goes first
+                self.check_and_send_output();
+                if stop.reason != "terminated" {
+                    self.send_stopped(&stop);
+                    eprintln!("SENT: Initial stopped event: {}", stop.reason);
+                } else {
+                    eprintln!("WARNING: Script completed before first stop");
+                    self.send_terminated();
+                }
+            } else {
+                log.write(format_args!(
+                    "WARNING: Timeout waiting for first stop event"
+                ));
+                eprintln!("WARNING: Timeout waiting for first stop event");
+            }
+        }
+    }
+
+    /// Run the script of `ctx_arc` on a thread of its own, reporting stops
+    /// and output through the server's receivers
+    pub fn start_executor(
+        &mut self,
+        ctx_arc: Arc<Mutex<DebugContext>>,
+        pre: &PreprocessResult,
+        labels: &HashMap<String, usize>,
+    ) {
+        let ctx = match ctx_arc.lock() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("ERROR: Failed to lock context: {}", e);
+                return;
+            }
+        };
+        self.session_killer = Some(ctx.session_killer());
+        self.child_processes = Some(ctx.child_processes());
+        self.resume_signal = Some(ctx.resume_signal());
+        drop(ctx);
+        self.context = Some(ctx_arc.clone());
+
+        let running = executor::spawn_debugger_dap(ctx_arc, pre, labels);
+        self.event_receiver = Some(running.events);
+        self.output_receiver = Some(running.output);
+        self.stderr_receiver = Some(running.stderr);
+        self.executor = Some(running.thread);
+    }
+
+    /// Run the launch configuration's bootstrap commands (e.g. `call
+    /// vcvarsall.bat`) in the session before the script starts, showing their
+    /// output in the console. Returns the reason launch should be aborted.
+    pub fn run_pre_launch_commands(
+        &mut self,
+        ctx: &mut DebugContext,
+        commands: &[String],
+        fail_on_error: bool,
+    ) -> Result<(), String> {
+        for cmd in commands {
+            eprintln!("Pre-launch: {}", cmd);
+            let result = ctx
+                .run_command(cmd)
+                .map_err(|e| format!("Pre-launch command '{}' failed: {}", cmd, e))?;
+            self.send_output(&result.stdout, "console");
+            self.send_output(&result.stderr, "stderr");
+            if result.exit_code != 0 {
+                let message = format!(
+                    "Pre-launch command '{}' exited with code {}",
+                    cmd, result.exit_code
+                );
+                if fail_on_error {
+                    return Err(message);
+                }
+                self.send_output(&format!("WARNING: {}\r\n", message), "console");
+            }
+        }
+        Ok(())
+    }
+
     pub fn handle_set_breakpoints(&mut self, seq: u64, command: String, args: Option<Value>) {
-        let source_path = args
-            .as_ref()
-            .and_then(|v| v.get("source"))
-            .and_then(|v| v.get("path"))
-            .and_then(|v| v.as_str())
-            .unwrap_or("");
+        let verified_breakpoints = self.apply_breakpoints(args.as_ref());
+        // Kept to set them again in the session a restart starts
+        if let Some(args) = args {
+            let source_path = self.path_from_client(
+                args.get("source")
+                    .and_then(|v| v.get("path"))
+                    .and_then(|v| v.as_str())
+                    .unwrap_or(""),
+            );
+            self.breakpoint_requests
+                .insert(source_key(Path::new(&source_path)), args);
+        }
+        self.save_breakpoints_file();
+
+        self.send_response(
+            seq,
+            command,
+            true,
+            Some(json!({
+                "breakpoints": verified_breakpoints
+            })),
+        );
+    }
+
+    /// Replace the breakpoints of the source setBreakpoints `args` names,
+    /// returning each as the client should show it
+    fn apply_breakpoints(&mut self, args: Option<&Value>) -> Vec<Value> {
+        let source_path = self.path_from_client(
+            args.and_then(|v| v.get("source"))
+                .and_then(|v| v.get("path"))
+                .and_then(|v| v.as_str())
+                .unwrap_or(""),
+        );
+        let source_path = source_path.as_str();
 
         let breakpoints_array = args
-            .as_ref()
             .and_then(|v| v.get("breakpoints"))
             .and_then(|v| v.as_array())
             .cloned()
@@ -490,19 +1483,79 @@ impl DapServer {
 
         let mut verified_breakpoints = Vec::new();
         let mut logical_lines = Vec::new();
+        let mut bound = Vec::new(); // Index in verified_breakpoints, logical line
 
         eprintln!("BREAKPOINT: Setting breakpoints for: {}", source_path);
 
-        if let Some(pre) = &self.preprocessed {
+        // Breakpoints in a batch file the script CALLs map through that
+        // file's own lines
+        let mut unbound_reason = "pending".to_string();
+        let mut not_launched = true; // Nothing to bind to until launch
+        let called_script = if self.is_program_source(source_path) {
+            None
+        } else {
+            self.context
+                .as_ref()
+                .and_then(|c| c.lock().ok())
+                .and_then(
+                    |mut ctx| match ctx.load_script(&source_key(Path::new(source_path))) {
+                        Ok(script) => Some(script),
+                        Err(e) => {
+                            eprintln!("   Cannot read {}: {}", source_path, e);
+                            unbound_reason = format!("Cannot read this file: {}", e);
+                            not_launched = false;
+                            None
+                        }
+                    },
+                )
+        };
+        let pre = match &called_script {
+            Some(script) => Some(&script.pre),
+            None if self.is_program_source(source_path) => self.preprocessed.as_ref(),
+            None => None,
+        };
+        let pending = pre.is_none() && not_launched;
+
+        // Ids given to the breakpoints while they were pending, by position
+        let pending_ids = self
+            .pending_breakpoint_ids
+            .remove(&source_key(Path::new(source_path)))
+            .unwrap_or_default();
+
+        // Nothing to map the lines through; mapping them through another
+        // file's lines would put them anywhere
+        if pre.is_none() {
+            eprintln!("   Not binding breakpoints: {}", unbound_reason);
+            for bp in &breakpoints_array {
+                if let Some(line) = bp.get("line").and_then(|v| v.as_u64()) {
+                    verified_breakpoints.push(json!({
+                        "verified": false,
+                        "line": line,
+                        "message": unbound_reason
+                    }));
+                }
+            }
+        }
+
+        if let Some(pre) = pre {
             for bp in breakpoints_array {
                 if let Some(line) = bp.get("line").and_then(|v| v.as_u64()) {
-                    let phys_line = (line as usize).saturating_sub(1);
+                    let phys_line = self.line_from_client(line as usize).saturating_sub(1);
 
                     // Extract condition if present
                     let condition = bp
                         .get("condition")
                         .and_then(|v| v.as_str())
                         .map(|s| s.to_string());
+                    let option =
+                        |name: &str| bp.get(name).and_then(|v| v.as_str()).map(|s| s.to_string());
+                    let spec = BreakpointSpec {
+                        line: phys_line + 1,
+                        condition: condition.clone(),
+                        hit_condition: option("hitCondition"),
+                        log_message: option("logMessage"),
+                        enabled: bp.get("enabled").and_then(|v| v.as_bool()).unwrap_or(true),
+                    };
 
                     eprintln!(
                         "   Breakpoint request: physical line {} (0-indexed: {})",
@@ -513,35 +1566,131 @@ impl DapServer {
                         eprintln!("   Condition: {}", cond);
                     }
 
-                    if phys_line < pre.phys_to_logical.len() {
-                        let logical_line = pre.phys_to_logical[phys_line];
-                        logical_lines.push((logical_line, condition.clone()));
+                    // Labels, comments and blank lines never stop, so the
+                    // breakpoint moves to the next line that does
+                    if let Some(logical_line) = parser::breakpoint_line(pre, phys_line) {
+                        // A ( block runs as one command, so a line inside it
+                        // stops where the block starts
+                        let block_start = parser::block_start(pre, logical_line);
+                        let logical_line = block_start.unwrap_or(logical_line);
+                        logical_lines.push((logical_line, spec));
 
                         eprintln!("   Mapped to logical line {}", logical_line);
                         eprintln!("   Line content: {}", pre.logical[logical_line].text);
 
-                        verified_breakpoints.push(json!({
+                        let mut verified = json!({
                             "verified": true,
-                            "line": line
-                        }));
+                            "line": self.line_to_client(pre.logical[logical_line].phys_start + 1)
+                        });
+                        let mut messages = Vec::new();
+                        if block_start.is_some() {
+                            messages.push(
+                                "Inside a ( ) block, which runs as one command: stops where the block starts"
+                                    .to_string(),
+                            );
+                        }
+                        // A typo shows now rather than as a surprise stop
+                        if let Some(error) = condition.as_deref().and_then(parser::condition_error)
+                        {
+                            messages.push(format!(
+                                "Invalid condition: {}. The breakpoint stops every time.",
+                                error
+                            ));
+                        }
+                        if !messages.is_empty() {
+                            verified["message"] = json!(messages.join(" "));
+                        }
+                        bound.push((verified_breakpoints.len(), logical_line));
+                        verified_breakpoints.push(verified);
                     } else {
-                        eprintln!("   Physical line {} out of range", phys_line);
+                        eprintln!("   No statement at or after physical line {}", phys_line);
+                        verified_breakpoints.push(json!({
+                            "verified": false,
+                            "line": line,
+                            "message": "No statement at or after this line"
+                        }));
                     }
                 }
             }
         }
 
-        self.breakpoints.insert(
-            source_path.to_string(),
-            logical_lines.iter().map(|(l, _)| *l).collect(),
-        );
+        // A breakpoint keeps its id for as long as its line has one
+        let script_key = called_script.as_ref().map(|script| script.path.clone());
+        let previous_ids = self.breakpoint_ids.remove(&script_key).unwrap_or_default();
+        let mut ids = HashMap::new();
+        for (index, logical_line) in bound {
+            let id = match pending_ids
+                .get(index)
+                .or(previous_ids.get(&logical_line))
+                .or(ids.get(&logical_line))
+            {
+                Some(id) => *id,
+                None => {
+                    let id = self.next_breakpoint_id;
+                    self.next_breakpoint_id += 1;
+                    id
+                }
+            };
+            ids.insert(logical_line, id);
+            verified_breakpoints[index]["id"] = json!(id);
+        }
+        self.breakpoint_ids.insert(script_key, ids);
+
+        // Pending breakpoints get ids too, so the breakpoint events that
+        // bind them after launch can name them
+        if pending {
+            let mut ids = Vec::new();
+            for (index, bp) in verified_breakpoints.iter_mut().enumerate() {
+                let id = match pending_ids.get(index) {
+                    Some(id) => *id,
+                    None => {
+                        let id = self.next_breakpoint_id;
+                        self.next_breakpoint_id += 1;
+                        id
+                    }
+                };
+                bp["id"] = json!(id);
+                ids.push(id);
+            }
+            self.pending_breakpoint_ids
+                .insert(source_key(Path::new(source_path)), ids);
+        } else {
+            for (bp, id) in verified_breakpoints.iter_mut().zip(&pending_ids) {
+                if bp.get("id").is_none() {
+                    bp["id"] = json!(id);
+                }
+            }
+        }
+
+        // setBreakpoints replaces the whole set for the source
+        let previous = self
+            .breakpoints
+            .insert(
+                source_key(Path::new(source_path)),
+                logical_lines.iter().map(|(l, _)| *l).collect(),
+            )
+            .unwrap_or_default();
 
         if let Some(ctx_arc) = &self.context {
             if let Ok(mut ctx) = ctx_arc.lock() {
+                for logical_line in previous {
+                    match &called_script {
+                        Some(script) => ctx.remove_breakpoint_in(&script.path, logical_line),
+                        None => ctx.remove_breakpoint(logical_line),
+                    }
+                }
                 eprintln!("   Adding {} breakpoints to context", logical_lines.len());
-                for (logical_line, condition) in &logical_lines {
-                    ctx.add_breakpoint_with_condition(*logical_line, condition.clone());
-                    if let Some(cond) = condition {
+                for (logical_line, spec) in &logical_lines {
+                    let source = called_script.as_ref().map(|script| script.path.as_path());
+                    ctx.add_breakpoint_spec(source, *logical_line, spec);
+                    if let Some(id) = self
+                        .breakpoint_ids
+                        .get(&source.map(Path::to_path_buf))
+                        .and_then(|ids| ids.get(logical_line))
+                    {
+                        ctx.set_breakpoint_id(source, *logical_line, *id);
+                    }
+                    if let Some(cond) = &spec.condition {
                         eprintln!(
                             "   Added conditional breakpoint at logical line {}: {}",
                             logical_line, cond
@@ -553,216 +1702,594 @@ impl DapServer {
             }
         }
 
-        self.send_response(
-            seq,
-            command,
-            true,
-            Some(json!({
-                "breakpoints": verified_breakpoints
-            })),
-        );
+        verified_breakpoints
     }
 
-    pub fn handle_threads(&mut self, seq: u64, command: String) {
+    pub fn handle_breakpoint_locations(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let locations = self.breakpoint_locations(args.as_ref());
         self.send_response(
             seq,
             command,
             true,
             Some(json!({
-                "threads": [
-                    {
-                        "id": 1,
-                        "name": "Batch Script"
-                    }
-                ]
+                "breakpoints": locations
             })),
         );
     }
 
-    pub fn handle_stack_trace(&mut self, seq: u64, command: String) {
-        let mut frames = Vec::new();
-
-        let program_path = self.program_path.as_deref().unwrap_or("test.bat");
-        let program_name = std::path::Path::new(program_path)
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("test.bat");
+    /// Lines in the range a breakpointLocations request asks about where a
+    /// breakpoint stops as placed: the first line of each statement outside
+    /// ( blocks, and the line opening a block. Labels, comments and blank
+    /// lines have none. Nothing before launch.
+    pub fn breakpoint_locations(&self, args: Option<&Value>) -> Vec<Value> {
+        let source_path = self.path_from_client(
+            args.and_then(|v| v.get("source"))
+                .and_then(|v| v.get("path"))
+                .and_then(|v| v.as_str())
+                .unwrap_or(""),
+        );
+        let source_path = source_path.as_str();
+        let line = args
+            .and_then(|v| v.get("line"))
+            .and_then(|v| v.as_u64())
+            .map_or(1, |l| self.line_from_client(l as usize));
+        let end_line = args
+            .and_then(|v| v.get("endLine"))
+            .and_then(|v| v.as_u64())
+            .map_or(line, |l| self.line_from_client(l as usize));
 
-        if let Some(ctx_arc) = &self.context {
-            if let Ok(ctx) = ctx_arc.lock() {
-                if let Some(pre) = &self.preprocessed {
-                    let current_pc = ctx.current_line.unwrap_or(0);
+        let called_script = if self.is_program_source(source_path) {
+            None
+        } else {
+            self.context
+                .as_ref()
+                .and_then(|c| c.lock().ok())
+                .and_then(|mut ctx| ctx.load_script(&source_key(Path::new(source_path))).ok())
+        };
+        let pre = match &called_script {
+            Some(script) => &script.pre,
+            None if self.is_program_source(source_path) => match &self.preprocessed {
+                Some(pre) => pre,
+                None => return Vec::new(),
+            },
+            None => return Vec::new(),
+        };
 
-                    let physical_line = if current_pc < pre.logical.len() {
-                        pre.logical[current_pc].phys_start + 1
-                    } else {
-                        1
-                    };
+        (line.max(1) - 1..end_line)
+            .filter(|&phys| {
+                let logical = match pre.phys_to_logical.get(phys) {
+                    Some(&l) => l,
+                    None => return false,
+                };
+                pre.logical[logical].phys_start == phys
+                    && parser::breakpoint_line(pre, phys) == Some(logical)
+                    && parser::block_start(pre, logical).is_none()
+            })
+            .map(|phys| json!({ "line": self.line_to_client(phys + 1) }))
+            .collect()
+    }
 
-                    eprintln!(
-                        "📊 Stack trace: logical PC={}, physical line={}",
-                        current_pc, physical_line
+    /// Set the breakpoints of the previous session (or those the client sent
+    /// before launch) in a newly launched one
+    fn restore_breakpoints(&mut self) {
+        let requests: Vec<Value> = self.breakpoint_requests.values().cloned().collect();
+        let pending: HashSet<u64> = self
+            .pending_breakpoint_ids
+            .values()
+            .flatten()
+            .copied()
+            .collect();
+        for args in &requests {
+            // The client hears how each pending breakpoint bound
+            for bp in self.apply_breakpoints(Some(args)) {
+                if bp
+                    .get("id")
+                    .and_then(|v| v.as_u64())
+                    .is_some_and(|id| pending.contains(&id))
+                {
+                    self.send_event(
+                        "breakpoint".to_string(),
+                        Some(json!({
+                            "reason": "changed",
+                            "breakpoint": bp
+                        })),
                     );
+                }
+            }
+        }
+        let carried = match self.carried_breakpoints.take() {
+            Some(c) => c,
+            None => return,
+        };
+        if let Some(mut ctx) = self.context.as_ref().and_then(|c| c.lock().ok()) {
+            for (name, condition, break_on_delete) in carried.data {
+                let id = self.data_breakpoint_ids.get(&name).copied();
+                ctx.add_data_breakpoint_with_condition(name.clone(), condition, break_on_delete);
+                if let Some(id) = id {
+                    ctx.set_data_breakpoint_id(&name, id);
+                }
+            }
+            for pattern in &carried.output {
+                if let Err(e) = ctx.add_output_breakpoint(pattern) {
+                    eprintln!(
+                        "WARNING: Cannot restore output breakpoint {}: {}",
+                        pattern, e
+                    );
+                }
+            }
+        }
+    }
 
-                    frames.push(json!({
-                        "id": 0,
-                        "name": "main",
-                        "line": physical_line,
-                        "column": 1,
-                        "source": {
-                            "name": program_name,
-                            "path": program_path
-                        }
-                    }));
-
-                    for (i, frame) in ctx.call_stack.iter().enumerate() {
-                        let return_line = frame.return_pc.saturating_sub(1);
-                        if return_line < pre.logical.len() {
-                            let logical = &pre.logical[return_line];
-                            frames.push(json!({
-                                "id": i + 1,
-                                "name": format!("frame_{}", i + 1),
-                                "line": logical.phys_start + 1,
-                                "column": 1,
-                                "source": {
-                                    "name": program_name,
-                                    "path": program_path
-                                }
-                            }));
-                        }
-                    }
+    /// Set the breakpoints the launch's breakpoints file saved, in every
+    /// source the client hasn't set breakpoints in itself: the client's own
+    /// win. The client hears of each one, and of those that can't be bound.
+    fn load_breakpoints_file(&mut self) {
+        let path = match &self.breakpoints_file {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let file = match BreakpointsFile::load(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("WARNING: Cannot read {}: {}", path.display(), e);
+                self.send_output(
+                    &format!(
+                        "WARNING: Cannot read breakpoints file {}: {}\r\n",
+                        path.display(),
+                        e
+                    ),
+                    "important",
+                );
+                return;
+            }
+        };
+        for group in file.sources {
+            let key = source_key(&group.path);
+            if self.breakpoint_requests.contains_key(&key) {
+                continue;
+            }
+            let source_path = self.path_to_client(&group.path.display().to_string());
+            let args = json!({
+                "source": { "path": source_path },
+                "breakpoints": group.breakpoints.iter().map(|spec| json!({
+                    "line": self.line_to_client(spec.line),
+                    "condition": spec.condition,
+                    "hitCondition": spec.hit_condition,
+                    "logMessage": spec.log_message,
+                    "enabled": spec.enabled,
+                })).collect::<Vec<Value>>(),
+            });
+            for mut bp in self.apply_breakpoints(Some(&args)) {
+                if bp["verified"] == true {
+                    bp["source"] = json!({ "path": source_path });
+                    self.send_event(
+                        "breakpoint".to_string(),
+                        Some(json!({
+                            "reason": "new",
+                            "breakpoint": bp
+                        })),
+                    );
+                } else {
+                    self.send_output(
+                        &format!(
+                            "WARNING: Saved breakpoint at line {} of {} not set: {}\r\n",
+                            bp["line"],
+                            group.path.display(),
+                            bp["message"].as_str().unwrap_or("")
+                        ),
+                        "important",
+                    );
+                }
+            }
+            self.breakpoint_requests.insert(key, args);
+        }
+        self.save_breakpoints_file();
+    }
+
+    /// Write the breakpoints of every source this session knows of to the
+    /// breakpoints file, leaving other sources' alone
+    fn save_breakpoints_file(&mut self) {
+        let (path, program, pre) = match (
+            &self.breakpoints_file,
+            &self.program_path,
+            &self.preprocessed,
+            &self.context,
+        ) {
+            (Some(path), Some(program), Some(pre), Some(_)) => (path.clone(), program.clone(), pre),
+            _ => return,
+        };
+        let specs = match self.context.as_ref().and_then(|c| c.lock().ok()) {
+            Some(ctx) => ctx.breakpoint_specs(pre),
+            None => return,
+        };
+        let mut file = match BreakpointsFile::load(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!(
+                    "WARNING: Not saving breakpoints, cannot read {}: {}",
+                    path.display(),
+                    e
+                );
+                return;
+            }
+        };
+        // Sources the client cleared have no breakpoints left to list
+        for source in self.breakpoint_requests.keys() {
+            file.set(source, Vec::new());
+        }
+        for (source, breakpoints) in specs {
+            let source = source.unwrap_or_else(|| PathBuf::from(&program));
+            file.set(&source, breakpoints);
+        }
+        if let Err(e) = file.save(&path) {
+            eprintln!(
+                "WARNING: Cannot save breakpoints to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    /// Whether a client source path is the launched script (or there is
+    /// nothing to tell it apart from)
+    fn is_program_source(&self, source_path: &str) -> bool {
+        match &self.program_path {
+            Some(program) if !source_path.is_empty() => {
+                source_key(Path::new(program)) == source_key(Path::new(source_path))
+            }
+            _ => true,
+        }
+    }
+
+    /// Content of a source the client can't read itself: the program as
+    /// preprocessed, or a file by its path (e.g. a CALLed script)
+    pub fn handle_source(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let reference = args
+            .as_ref()
+            .and_then(|v| v.get("sourceReference"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0);
+        let path = args
+            .as_ref()
+            .and_then(|v| v.get("source"))
+            .and_then(|v| v.get("path"))
+            .and_then(|v| v.as_str())
+            .filter(|p| !p.is_empty())
+            .map(|p| self.path_from_client(p));
+
+        let content = match (reference, path) {
+            (PREPROCESSED_SOURCE_REF, _) => self.preprocessed_source(),
+            (0, Some(path)) => match std::fs::read_to_string(&path) {
+                Ok(content) => Some(content),
+                Err(e) => {
+                    eprintln!("ERROR: Cannot read source {}: {}", path, e);
+                    None
                 }
+            },
+            _ => None,
+        };
+        match content {
+            Some(content) => {
+                self.send_response(seq, command, true, Some(json!({ "content": content })))
+            }
+            None => {
+                eprintln!("ERROR: No content for source request {:?}", args);
+                self.send_response(seq, command, false, None);
             }
         }
+    }
+
+    /// The launched program's logical lines, one per line, each with the
+    /// physical lines it came from and its ( block depth
+    pub fn preprocessed_source(&self) -> Option<String> {
+        let pre = self.preprocessed.as_ref()?;
+        let mut content = String::new();
+        for line in &pre.logical {
+            let range = if line.phys_end > line.phys_start {
+                format!("L{}-{}", line.phys_start + 1, line.phys_end + 1)
+            } else {
+                format!("L{}", line.phys_start + 1)
+            };
+            content.push_str(&format!(
+                "{:<9} d{} | {}\r\n",
+                range, line.group_depth, line.text
+            ));
+        }
+        Some(content)
+    }
+
+    /// The batch files of the session: the launched program, then every
+    /// file it CALLed so far
+    pub fn handle_loaded_sources(&mut self, seq: u64, command: String) {
+        let mut sources: Vec<Value> = self
+            .program_path
+            .iter()
+            .map(|path| self.source_json(Path::new(path)))
+            .collect();
+        let mut called: Vec<_> = self.loaded_sources.iter().collect();
+        called.sort_by(|a, b| a.0.cmp(b.0));
+        sources.extend(called.into_iter().map(|(_, source)| source.clone()));
+        self.send_response(seq, command, true, Some(json!({ "sources": sources })));
+    }
 
+    /// A file as a DAP source: its name and the path the client knows it by
+    fn source_json(&self, path: &Path) -> Value {
+        json!({
+            "name": path.file_name().map(|n| n.to_string_lossy().into_owned()),
+            "path": self.path_to_client(&path.display().to_string())
+        })
+    }
+
+    pub fn handle_threads(&mut self, seq: u64, command: String) {
         self.send_response(
             seq,
             command,
             true,
             Some(json!({
-                "stackFrames": frames,
-                "totalFrames": frames.len()
+                "threads": [
+                    {
+                        "id": 1,
+                        "name": "Batch Script"
+                    }
+                ]
             })),
         );
     }
 
-    pub fn handle_scopes(&mut self, seq: u64, command: String) {
+    pub fn handle_stack_trace(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let start_frame = args
+            .as_ref()
+            .and_then(|v| v.get("startFrame"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as usize;
+        // Levels of 0 (or none) ask for every frame
+        let levels = args
+            .as_ref()
+            .and_then(|v| v.get("levels"))
+            .and_then(|v| v.as_u64())
+            .filter(|&l| l > 0)
+            .map(|l| l as usize);
+        let (frames, total_frames) = self.stack_frames(start_frame, levels);
+
         self.send_response(
             seq,
             command,
             true,
             Some(json!({
-                "scopes": [
-                    {
-                        "name": "Local",
-                        "variablesReference": 1,
-                        "expensive": false
-                    },
-                    {
-                        "name": "Global",
-                        "variablesReference": 2,
-                        "expensive": false
-                    },
-                    {
-                        "name": "Watch",
-                        "variablesReference": 3,
-                        "expensive": false
-                    }
-                ]
+                "stackFrames": frames,
+                "totalFrames": total_frames
             })),
         );
     }
 
-    pub fn handle_variables(&mut self, seq: u64, command: String, args: Option<Value>) {
-        let var_ref = args
-            .as_ref()
-            .and_then(|v| v.get("variablesReference"))
-            .and_then(|v| v.as_u64())
-            .unwrap_or(0);
+    /// Build the stack frames, innermost first. Frame ids are 0 for the
+    /// top-level script and `i + 1` for `call_stack[i]`.
+    pub fn collect_stack_frames(&self) -> Vec<Value> {
+        self.stack_frames(0, None).0
+    }
 
-        let mut variables = Vec::new();
+    /// `levels` frames (all without a limit) of the stack from `start_frame`
+    /// on, counted innermost first, with the depth of the whole stack. Only
+    /// the frames asked for are built.
+    pub fn stack_frames(&self, start_frame: usize, levels: Option<usize>) -> (Vec<Value>, usize) {
+        let mut frames = Vec::new();
+        let mut total_frames = 0;
 
-        if let Some(ctx_arc) = &self.context {
-            if let Ok(mut ctx) = ctx_arc.lock() {
-                match var_ref {
-                    1 => {
-                        // Add ERRORLEVEL as a special variable
-                        variables.push(json!({
-                            "name": "ERRORLEVEL",
-                            "value": ctx.last_exit_code.to_string(),
-                            "variablesReference": 0,
-                            "presentationHint": {
-                                "kind": "property",
-                                "attributes": ["readOnly"]
-                            }
-                        }));
+        let program_path = self.program_path.as_deref().unwrap_or("test.bat");
+        let program_name = std::path::Path::new(program_path)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("test.bat");
 
-                        let visible = ctx.get_visible_variables();
-                        for (key, val) in visible {
-                            variables.push(json!({
-                                "name": key,
-                                "value": val,
-                                "variablesReference": 0
-                            }));
+        if let Some(ctx_arc) = &self.context {
+            if let Ok(ctx) = ctx_arc.lock() {
+                if let Some(pre) = &self.preprocessed {
+                    // The preprocessed source has a line per logical line
+                    let physical_line = |pc: usize| {
+                        if self.show_preprocessed {
+                            pc + 1
+                        } else if pc < pre.logical.len() {
+                            pre.logical[pc].phys_start + 1
+                        } else {
+                            1
                         }
-                    }
-                    2 => {
-                        // Add ERRORLEVEL as a special variable
-                        variables.push(json!({
-                            "name": "ERRORLEVEL",
-                            "value": ctx.last_exit_code.to_string(),
-                            "variablesReference": 0,
-                            "presentationHint": {
-                                "kind": "property",
-                                "attributes": ["readOnly"]
-                            }
-                        }));
+                    };
+                    let program_source = if self.show_preprocessed {
+                        json!({
+                            "name": format!("{} (preprocessed)", program_name),
+                            "sourceReference": PREPROCESSED_SOURCE_REF,
+                            "origin": "preprocessed"
+                        })
+                    } else {
+                        json!({
+                            "name": program_name,
+                            "path": self.path_to_client(program_path)
+                        })
+                    };
 
-                        for (key, val) in &ctx.variables {
-                            variables.push(json!({
-                                "name": key,
-                                "value": val,
-                                "variablesReference": 0
-                            }));
-                        }
-                    }
-                    3 => {
-                        // Watch expressions
-                        for watch_expr in &self.watch_expressions {
-                            let value = match ctx.evaluate_expression(watch_expr) {
-                                Ok(result) => result,
-                                Err(e) => format!("<error: {}>", e),
-                            };
-                            variables.push(json!({
-                                "name": watch_expr,
-                                "value": value,
-                                "variablesReference": 0,
-                                "presentationHint": {
-                                    "kind": "property"
-                                }
-                            }));
+                    // A statement step points at the command it stopped before
+                    let column = ctx.statement_column().unwrap_or(1);
+
+                    eprintln!(
+                        "📊 Stack trace: {} frame(s), main at logical PC={}",
+                        ctx.call_stack.len() + 1,
+                        ctx.main_pc
+                    );
+
+                    total_frames = ctx.call_stack.len() + 1;
+                    let end = match levels {
+                        Some(levels) => total_frames.min(start_frame.saturating_add(levels)),
+                        None => total_frames,
+                    };
+                    for depth in start_frame..end {
+                        // Depth 0 is the innermost frame, the last one main
+                        let id = total_frames - 1 - depth;
+                        let column = if depth == 0 { column } else { 1 };
+                        let frame = match id {
+                            0 => None,
+                            id => Some(&ctx.call_stack[id - 1]),
+                        };
+                        let pc = frame.map_or(ctx.main_pc, |f| f.current_pc);
+                        // Frames in a CALLed batch file point into that file;
+                        // one the debugger can't place is shown subtly
+                        let (line, source, placed) = match frame.and_then(|f| f.script.as_deref()) {
+                            Some(path) => match ctx.script(path) {
+                                Some(script) => (
+                                    script.pre.logical.get(pc).map_or(1, |l| l.phys_start + 1),
+                                    json!({
+                                        "name": script.name(),
+                                        "path": self.path_to_client(&script.path.display().to_string())
+                                    }),
+                                    pc < script.pre.logical.len(),
+                                ),
+                                None => (
+                                    1,
+                                    json!({ "path": self.path_to_client(&path.display().to_string()) }),
+                                    false,
+                                ),
+                            },
+                            None => (
+                                physical_line(pc),
+                                program_source.clone(),
+                                pc < pre.logical.len(),
+                            ),
+                        };
+                        let mut stack_frame = json!({
+                            "id": id,
+                            "name": frame.map_or("main".to_string(), |f| f.name()),
+                            "line": self.line_to_client(line),
+                            "column": self.column_to_client(column),
+                            "source": source
+                        });
+                        if !placed {
+                            stack_frame["presentationHint"] = json!("subtle");
                         }
+                        frames.push(stack_frame);
                     }
-                    _ => {}
                 }
             }
         }
 
+        (frames, total_frames)
+    }
+
+    pub fn handle_scopes(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let frame_id = args
+            .as_ref()
+            .and_then(|v| v.get("frameId"))
+            .and_then(|v| v.as_u64());
+
+        let scopes = self.collect_scopes(frame_id);
+
         self.send_response(
             seq,
             command,
             true,
             Some(json!({
-                "variables": variables
+                "scopes": scopes
             })),
         );
     }
 
+    /// Build the scopes for a stack frame. Call frames get their own Local and
+    /// Arguments scopes; the top-level script (frame 0) has Global and its
+    /// launch arguments. Without a frameId the innermost frame is used.
+    pub fn collect_scopes(&self, frame_id: Option<u64>) -> Vec<Value> {
+        let depth = self
+            .context
+            .as_ref()
+            .and_then(|ctx_arc| ctx_arc.lock().ok().map(|ctx| ctx.call_stack.len()))
+            .unwrap_or(0) as u64;
+        let frame_id = frame_id.unwrap_or(depth).min(depth);
+
+        let mut scopes = Vec::new();
+        if frame_id == 0 {
+            scopes.push(json!({
+                "name": "Arguments",
+                "variablesReference": SCRIPT_ARGS_REF,
+                "expensive": false
+            }));
+        }
+        if frame_id > 0 {
+            let frame_index = frame_id - 1;
+            scopes.push(json!({
+                "name": "Local",
+                "variablesReference": FRAME_LOCALS_REF + frame_index,
+                "expensive": false
+            }));
+            scopes.push(json!({
+                "name": "Arguments",
+                "variablesReference": FRAME_ARGS_REF + frame_index,
+                "expensive": false
+            }));
+        }
+        scopes.push(json!({
+            "name": "Global",
+            "variablesReference": 2,
+            "expensive": false
+        }));
+        // So the client can page through big scopes
+        let view = self.variable_view();
+        for scope in scopes.iter_mut() {
+            if let Some(reference) = scope["variablesReference"].as_u64() {
+                scope["namedVariables"] = json!(view.scope_variables(reference).len());
+            }
+        }
+        scopes.push(json!({
+            "name": "Watch",
+            "variablesReference": 3,
+            "expensive": false
+        }));
+        scopes
+    }
+
+    pub fn handle_variables(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let var_ref = args
+            .as_ref()
+            .and_then(|v| v.get("variablesReference"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0);
+
+        // Watches run queries in the session, so the request is answered on
+        // a worker like an evaluate request
+        let view = self.variable_view();
+        if self.context.is_none() {
+            let variables = view.variables_page(var_ref, args.as_ref());
+            self.send_response(seq, command, true, Some(json!({ "variables": variables })));
+            return;
+        }
+        self.run_on_worker(seq, command, move |_| {
+            let variables = view.variables_page(var_ref, args.as_ref());
+            (true, json!({ "variables": variables }))
+        });
+    }
+
+    /// The part of a variablesReference's list a variables request asks
+    /// for with its filter, start and count
+    pub fn variables_page(&self, var_ref: u64, args: Option<&Value>) -> Vec<Value> {
+        self.variable_view().variables_page(var_ref, args)
+    }
+
+    /// Build the variable list for a variablesReference, see
+    /// `VariableView::collect_variables`
+    pub fn collect_variables(&self, var_ref: u64) -> Vec<Value> {
+        self.variable_view().collect_variables(var_ref)
+    }
+
+    /// What variables requests read, for answering them on a worker
+    fn variable_view(&self) -> VariableView {
+        VariableView {
+            context: self.context.clone(),
+            watch_expressions: self.watch_expressions.clone(),
+            max_value_length: self.max_value_length,
+        }
+    }
+
     pub fn handle_continue(&mut self, seq: u64, command: String) {
         if let Some(ctx_arc) = &self.context {
             if let Ok(mut ctx) = ctx_arc.lock() {
                 ctx.set_mode(RunMode::Continue);
-                ctx.continue_requested = true;
+                ctx.request_continue();
             }
         }
         self.send_response(
@@ -773,21 +2300,35 @@ impl DapServer {
         );
     }
 
-    pub fn handle_next(&mut self, seq: u64, command: String) {
+    pub fn handle_next(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let granularity = step_granularity(args.as_ref());
         if let Some(ctx_arc) = &self.context {
             if let Ok(mut ctx) = ctx_arc.lock() {
                 ctx.set_mode(RunMode::StepOver);
-                ctx.continue_requested = true;
+                ctx.set_step_granularity(granularity);
+                ctx.request_continue();
             }
         }
         self.send_response(seq, command, true, None);
     }
 
-    pub fn handle_step_in(&mut self, seq: u64, command: String) {
+    pub fn handle_step_in(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let granularity = step_granularity(args.as_ref());
         if let Some(ctx_arc) = &self.context {
             if let Ok(mut ctx) = ctx_arc.lock() {
                 ctx.set_mode(RunMode::StepInto);
-                ctx.continue_requested = true;
+                ctx.set_step_granularity(granularity);
+                ctx.request_continue();
+            }
+        }
+        self.send_response(seq, command, true, None);
+    }
+
+    pub fn handle_step_back(&mut self, seq: u64, command: String) {
+        if let Some(ctx_arc) = &self.context {
+            if let Ok(mut ctx) = ctx_arc.lock() {
+                ctx.set_mode(RunMode::StepBack);
+                ctx.request_continue();
             }
         }
         self.send_response(seq, command, true, None);
@@ -796,21 +2337,171 @@ impl DapServer {
     pub fn handle_step_out(&mut self, seq: u64, command: String) {
         if let Some(ctx_arc) = &self.context {
             if let Ok(mut ctx) = ctx_arc.lock() {
-                ctx.set_mode(RunMode::StepOut);
-                ctx.continue_requested = true;
+                ctx.arm_step_out();
+                ctx.request_continue();
+            }
+        }
+        self.send_response(seq, command, true, None);
+    }
+
+    /// Kill the script's CMD session and everything it started. The executor
+    /// notices and finishes with a terminated event.
+    pub fn terminate_session(&mut self) {
+        self.launch_pending = false;
+        if let Some(killer) = &self.session_killer {
+            if !killer.is_cancelled() {
+                eprintln!("Terminating CMD session");
+                killer.kill();
+            }
+        }
+        if self.kill_spawned_processes {
+            if let Some(children) = &self.child_processes {
+                children.kill_all();
+            }
+        }
+        // An executor waiting at a stop has nothing else to wake it up
+        if let Some(signal) = &self.resume_signal {
+            signal.notify_all();
+        }
+    }
+
+    pub fn handle_terminate(&mut self, seq: u64, command: String) {
+        self.terminate_session();
+        self.wait_for_executor();
+        self.send_response(seq, command, true, None);
+        self.finish_session();
+    }
+
+    /// Run the script again from the start in a fresh CMD session, without
+    /// restarting the adapter. The program is read again so edits take
+    /// effect; breakpoints, data and output breakpoints and watches stay.
+    pub fn handle_restart(&mut self, seq: u64, command: String, args: Option<Value>) {
+        // The client may send the launch configuration again, edited
+        let launch_args = args
+            .as_ref()
+            .and_then(|v| v.get("arguments"))
+            .cloned()
+            .or_else(|| self.launch_args.clone());
+        let launch_args = match launch_args {
+            Some(a) => a,
+            None => {
+                eprintln!("ERROR: Restart requested before launch");
+                self.send_response(seq, command, false, None);
+                return;
             }
+        };
+        eprintln!("🔄 Restarting the debug session");
+
+        if let Some(ctx) = self.context.as_ref().and_then(|c| c.lock().ok()) {
+            self.carried_breakpoints = Some(CarriedBreakpoints {
+                data: ctx
+                    .get_data_breakpoints()
+                    .iter()
+                    .map(|(name, bp)| (name.clone(), bp.condition.clone(), bp.break_on_delete))
+                    .collect(),
+                output: ctx
+                    .get_output_breakpoints()
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect(),
+            });
+        }
+        self.stop_executor();
+        self.handle_launch(seq, command, Some(launch_args));
+    }
+
+    /// End the running script and wait for its thread, dropping what it
+    /// still had to report (a terminated event would end the client's
+    /// session)
+    fn stop_executor(&mut self) {
+        self.terminate_session();
+        self.wait_for_executor();
+        self.check_and_send_output();
+        self.event_receiver = None;
+        self.output_receiver = None;
+        self.stderr_receiver = None;
+        self.context = None;
+        self.session_killer = None;
+        self.resume_signal = None;
+        self.child_processes = None;
+        // Line numbers of the old program; the restored requests map again
+        self.breakpoints.clear();
+    }
+
+    pub fn handle_disconnect(&mut self, seq: u64, command: String, args: Option<Value>) {
+        // A service's script outlives its clients unless one asks otherwise
+        let terminate = args
+            .as_ref()
+            .and_then(|v| v.get("terminateDebuggee"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(!self.service);
+        // Without terminateDebuggee the session is left running
+        if terminate {
+            self.terminate_session();
+            self.wait_for_executor();
+            self.finish_session();
         }
         self.send_response(seq, command, true, None);
+        if !terminate && self.service {
+            self.detach();
+        }
+    }
+
+    /// Wait a little for the executor to wind down after `terminate_session`.
+    /// One that doesn't is left behind rather than holding up the client.
+    fn wait_for_executor(&mut self) {
+        let executor = match self.executor.take() {
+            Some(e) => e,
+            None => return,
+        };
+        let deadline = Instant::now() + EXECUTOR_STOP_TIMEOUT;
+        while !executor.is_finished() {
+            if Instant::now() >= deadline {
+                eprintln!("WARNING: Execution thread didn't stop, leaving it behind");
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        if executor.join().is_err() {
+            eprintln!("ERROR: Execution thread panicked");
+        }
+    }
+
+    /// Pass on what the executor reported before it ended, and make sure the
+    /// client hears of the end even if the executor couldn't say so
+    fn finish_session(&mut self) {
+        self.check_and_send_output();
+        if let Some(rx) = self.event_receiver.take() {
+            // Stops from before the end are moot now
+            while rx.try_recv().is_ok() {}
+        }
+        self.send_terminated();
+    }
+
+    /// Whether the script's thread is still running
+    pub fn executor_running(&self) -> bool {
+        self.executor.as_ref().is_some_and(|e| !e.is_finished())
     }
 
     pub fn handle_pause(&mut self, seq: u64, command: String) {
+        let mut interrupted = false;
         if let Some(ctx_arc) = &self.context {
             if let Ok(mut ctx) = ctx_arc.lock() {
                 ctx.set_mode(RunMode::StepInto);
+                // A long-running command would hold the pause until it ends;
+                // interrupt it and let the executor report the stop
+                if ctx.session_busy() {
+                    eprintln!("Pause: interrupting the running command");
+                    ctx.session_killer().interrupt();
+                    interrupted = true;
+                }
             }
         }
 
         self.send_response(seq, command, true, None);
+        if interrupted {
+            return;
+        }
 
         self.send_event(
             "stopped".to_string(),
@@ -867,17 +2558,12 @@ impl DapServer {
         let result = if let Some(ctx_arc) = &self.context {
             if let Ok(mut ctx) = ctx_arc.lock() {
                 ctx.set_variable(var_name, var_value)
+                    .map_err(BatchDbgError::from)
             } else {
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Failed to lock context",
-                ))
+                Err(BatchDbgError::LockPoisoned)
             }
         } else {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "No context available",
-            ))
+            Err(io::Error::other("No context available").into())
         };
 
         // Send response after releasing the lock
@@ -896,89 +2582,322 @@ impl DapServer {
             }
             Err(e) => {
                 eprintln!("ERROR: Failed to set variable: {}", e);
-                self.send_response(seq, command, false, None);
+                self.send_failure(seq, command, &e);
             }
         }
     }
 
-    pub fn handle_evaluate(&mut self, seq: u64, command: String, args: Option<Value>) {
-        eprintln!("EVAL: Handling evaluate request");
-
-        // Extract expression and context from arguments
+    /// Assign to a watch or hover expression. Only a plain variable, as
+    /// NAME, %NAME% or !NAME!, can be assigned; the value is taken as typed.
+    pub fn handle_set_expression(&mut self, seq: u64, command: String, args: Option<Value>) {
         let expression = args
             .as_ref()
             .and_then(|v| v.get("expression"))
             .and_then(|v| v.as_str())
             .unwrap_or("");
-
-        let context = args
+        let value = args
             .as_ref()
-            .and_then(|v| v.get("context"))
+            .and_then(|v| v.get("value"))
             .and_then(|v| v.as_str())
-            .unwrap_or("hover");
-
-        if expression.is_empty() {
-            eprintln!("ERROR: Expression is empty");
-            self.send_response(seq, command, false, None);
-            return;
-        }
+            .unwrap_or("");
+        let frame_id = args
+            .as_ref()
+            .and_then(|v| v.get("frameId"))
+            .and_then(|v| v.as_u64())
+            .map(|id| id as usize);
 
-        eprintln!("   Expression: '{}', Context: {}", expression, context);
+        let name = match assignable_variable(expression) {
+            Ok(name) => name,
+            Err(message) => {
+                eprintln!("ERROR: setExpression: {}", message);
+                self.send_error_response(seq, command, &message);
+                return;
+            }
+        };
 
-        // If context is "watch", add to watch expressions list
-        if context == "watch" {
-            if !self.watch_expressions.contains(&expression.to_string()) {
-                self.watch_expressions.push(expression.to_string());
-                eprintln!("WATCH: Added watch expression: '{}'", expression);
-            }
-        }
-
-        // Evaluate the expression in the context
-        let result = if let Some(ctx_arc) = &self.context {
-            if let Ok(mut ctx) = ctx_arc.lock() {
-                ctx.evaluate_expression(expression)
-            } else {
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Failed to lock context",
-                ))
+        let result = match self.context.as_ref().and_then(|c| c.lock().ok()) {
+            Some(mut ctx) => {
+                let frame_id = frame_id.unwrap_or(ctx.call_stack.len());
+                ctx.set_variable_in_frame(name, value, frame_id).map(|_| {
+                    ctx.get_frame_visible_variables(frame_id)
+                        .into_iter()
+                        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                        .map_or_else(String::new, |(_, stored)| stored)
+                })
             }
-        } else {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "No context available",
-            ))
+            None => Err(io::Error::other("The script hasn't been launched yet")),
         };
 
-        // Send response after releasing the lock
         match result {
-            Ok(value) => {
-                eprintln!("Evaluation successful: '{}'", value);
+            Ok(stored) => {
+                eprintln!("Expression {} set to {}", expression, stored);
                 self.send_response(
                     seq,
                     command,
                     true,
                     Some(json!({
-                        "result": value,
+                        "value": stored,
                         "variablesReference": 0
                     })),
                 );
             }
             Err(e) => {
-                eprintln!("ERROR: Evaluation failed: {}", e);
-                self.send_response(
-                    seq,
-                    command,
-                    false,
-                    Some(json!({
-                        "error": {
-                            "id": 1,
-                            "format": format!("Evaluation failed: {}", e)
+                eprintln!("ERROR: setExpression: {}", e);
+                self.send_error_response(seq, command, &e.to_string());
+            }
+        }
+    }
+
+    pub fn handle_evaluate(&mut self, seq: u64, command: String, args: Option<Value>) {
+        eprintln!("EVAL: Handling evaluate request");
+
+        // Extract expression and context from arguments
+        let expression = args
+            .as_ref()
+            .and_then(|v| v.get("expression"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+
+        let context = args
+            .as_ref()
+            .and_then(|v| v.get("context"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("hover");
+
+        if expression.is_empty() {
+            eprintln!("ERROR: Expression is empty");
+            self.send_response(seq, command, false, None);
+            return;
+        }
+
+        eprintln!("   Expression: '{}', Context: {}", expression, context);
+
+        // If context is "watch", add to watch expressions list
+        if context == "watch" {
+            self.record_watch_request(expression);
+        }
+
+        // While the script waits at a SET /P prompt, console input answers it
+        let awaiting_input = context == "repl"
+            && self
+                .context
+                .as_ref()
+                .and_then(|c| c.lock().ok().map(|ctx| ctx.awaiting_input()))
+                .unwrap_or(false);
+        if awaiting_input {
+            self.provide_input(expression);
+            self.send_response(
+                seq,
+                command,
+                true,
+                Some(json!({
+                    "result": format!("Input: {}", expression),
+                    "variablesReference": 0
+                })),
+            );
+            return;
+        }
+
+        // Console input is a command to run, not an expression
+        if context == "repl" {
+            self.run_repl_command(seq, command, expression);
+            return;
+        }
+
+        // Hovers follow the selected frame; watches always show the
+        // current frame
+        let frame_id = args
+            .as_ref()
+            .and_then(|v| v.get("frameId"))
+            .and_then(|v| v.as_u64())
+            .filter(|_| context != "watch");
+
+        // Evaluating may run commands in the session
+        let expression = expression.to_string();
+        self.run_on_worker(seq, command, move |ctx_arc| {
+            match evaluate_unlocked(ctx_arc, &expression, frame_id.map(|id| id as usize), None) {
+                Ok(value) => {
+                    eprintln!("Evaluation successful: '{}'", value);
+                    (
+                        true,
+                        json!({
+                            "result": value,
+                            "variablesReference": 0
+                        }),
+                    )
+                }
+                Err(e) => {
+                    eprintln!("ERROR: Evaluation failed: {}", e);
+                    (false, e.dap_body())
+                }
+            }
+        });
+    }
+
+    pub fn handle_completions(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let text = args
+            .as_ref()
+            .and_then(|v| v.get("text"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+        let column = args
+            .as_ref()
+            .and_then(|v| v.get("column"))
+            .and_then(|v| v.as_u64())
+            .map_or(text.chars().count() + 1, |c| {
+                self.column_from_client(c as usize)
+            });
+        let targets = self.completions(text, column);
+        self.send_response(seq, command, true, Some(json!({ "targets": targets })));
+    }
+
+    /// Completions for the Debug Console input `text` with the cursor at
+    /// 1-based `column`: variable names inside `%...` or `!...`, labels
+    /// after `goto ` or `call :`, builtin commands at the start of the line.
+    /// Each replaces the word before the cursor.
+    pub fn completions(&self, text: &str, column: usize) -> Vec<Value> {
+        let before: Vec<char> = text.chars().take(column.saturating_sub(1)).collect();
+        let word_start = before
+            .iter()
+            .rposition(|c| c.is_whitespace() || "%!:=\"()&|<>".contains(*c))
+            .map_or(0, |i| i + 1);
+        let word: String = before[word_start..].iter().collect();
+        let head: String = before[..word_start].iter().collect();
+
+        // An opening % or ! has an even number of its kind before it
+        let opening = head.chars().last().filter(|&delim| {
+            (delim == '%' || delim == '!') && head.chars().filter(|&c| c == delim).count() % 2 == 1
+        });
+        let label_context = {
+            let rest = head.trim_end();
+            let (rest, colon) = match rest.strip_suffix(':') {
+                Some(r) => (r.trim_end(), true),
+                None => (rest, false),
+            };
+            let keyword = rest
+                .rsplit(|c: char| c.is_whitespace() || c == '@' || c == '(' || c == '&')
+                .next()
+                .unwrap_or("");
+            (keyword.eq_ignore_ascii_case("goto") && rest.len() < head.len())
+                || (keyword.eq_ignore_ascii_case("call") && colon)
+        };
+
+        let (candidates, kind, rank): (Vec<String>, &str, &str) = if opening.is_some() {
+            let names = match self.context.as_ref().and_then(|c| c.lock().ok()) {
+                Some(ctx) => ctx.get_visible_variables().into_keys().collect(),
+                None => Vec::new(),
+            };
+            (names, "variable", "0")
+        } else if label_context {
+            let mut names: Vec<String> = match self.context.as_ref().and_then(|c| c.lock().ok()) {
+                Some(ctx) => match ctx.current_script().and_then(|p| ctx.script(p)) {
+                    Some(script) => script.labels.keys().cloned().collect(),
+                    None => self.labels.iter().flat_map(|l| l.keys().cloned()).collect(),
+                },
+                None => Vec::new(),
+            };
+            names.push("eof".to_string());
+            (names, "reference", "1")
+        } else if head
+            .trim_start_matches(|c: char| c.is_whitespace() || c == '@')
+            .is_empty()
+        {
+            let names = parser::BUILTIN_COMMANDS
+                .iter()
+                .map(|c| c.to_string())
+                .collect();
+            (names, "keyword", "2")
+        } else {
+            return Vec::new();
+        };
+
+        let mut matches: Vec<String> = candidates
+            .into_iter()
+            .filter(|name| name.to_lowercase().starts_with(&word.to_lowercase()))
+            .collect();
+        matches.sort_by_key(|name| name.to_lowercase());
+        matches.dedup();
+        matches
+            .into_iter()
+            .map(|name| {
+                json!({
+                    "label": name,
+                    "text": name,
+                    "type": kind,
+                    "sortText": format!("{}{}", rank, name.to_lowercase()),
+                    "start": self.column_to_client(word_start + 1),
+                    "length": word.chars().count()
+                })
+            })
+            .collect()
+    }
+
+    /// Run a Debug Console command verbatim in the script's CMD session and
+    /// answer with everything it printed
+    fn run_repl_command(&mut self, seq: u64, command: String, input: &str) {
+        let input = input.to_string();
+        self.run_on_worker(seq, command, move |ctx_arc| {
+            match run_repl_unlocked(ctx_arc, &input) {
+                Ok(result) => {
+                    let mut text = result.stdout.trim_end().to_string();
+                    if !result.stderr.trim().is_empty() {
+                        if !text.is_empty() {
+                            text.push('\n');
                         }
-                    })),
-                );
+                        text.push_str(result.stderr.trim_end());
+                    }
+                    (
+                        true,
+                        json!({
+                            "result": text,
+                            "variablesReference": 0
+                        }),
+                    )
+                }
+                Err(e) => {
+                    eprintln!("ERROR: Console command failed: {}", e);
+                    (
+                        false,
+                        json!({
+                            "error": {
+                                "id": 1,
+                                "format": format!("Command failed: {}", e)
+                            }
+                        }),
+                    )
+                }
+            }
+        });
+    }
+
+    /// Exception filters; "commandNotFound" is the only one. Applies to the
+    /// running session and to one launched later.
+    pub fn handle_set_exception_breakpoints(
+        &mut self,
+        seq: u64,
+        command: String,
+        args: Option<Value>,
+    ) {
+        let filters: Vec<&str> = args
+            .as_ref()
+            .and_then(|v| v.get("filters"))
+            .and_then(|v| v.as_array())
+            .map(|a| a.iter().filter_map(|f| f.as_str()).collect())
+            .unwrap_or_default();
+        self.break_on_command_not_found = filters.contains(&"commandNotFound");
+
+        if let Some(ctx_arc) = &self.context {
+            if let Ok(mut ctx) = ctx_arc.lock() {
+                ctx.set_break_on_command_not_found(self.break_on_command_not_found);
             }
         }
+        let breakpoints: Vec<Value> = filters.iter().map(|_| json!({"verified": true})).collect();
+        self.send_response(
+            seq,
+            command,
+            true,
+            Some(json!({ "breakpoints": breakpoints })),
+        );
     }
 
     pub fn handle_data_breakpoint_info(&mut self, seq: u64, command: String, args: Option<Value>) {
@@ -1031,15 +2950,49 @@ impl DapServer {
                 for var_name in existing {
                     ctx.remove_data_breakpoint(&var_name);
                 }
+                self.data_breakpoint_ids.clear();
 
                 // Add new data breakpoints
                 if let Some(bps) = breakpoints {
                     for bp in bps {
                         if let Some(data_id) = bp.get("dataId").and_then(|v| v.as_str()) {
+                            // Only writes can be observed, reads leave no trace
+                            let access_type = bp
+                                .get("accessType")
+                                .and_then(|v| v.as_str())
+                                .unwrap_or("write");
+                            if access_type == "read" {
+                                eprintln!("   Rejecting read data breakpoint on: {}", data_id);
+                                result_breakpoints.push(json!({
+                                    "verified": false,
+                                    "message": "Read access breakpoints are not supported"
+                                }));
+                                continue;
+                            }
+
+                            let condition = bp
+                                .get("condition")
+                                .and_then(|v| v.as_str())
+                                .filter(|c| !c.trim().is_empty())
+                                .map(|c| c.to_string());
+                            let break_on_delete = bp
+                                .get("breakOnDelete")
+                                .and_then(|v| v.as_bool())
+                                .unwrap_or(false);
+
                             eprintln!("   Adding data breakpoint on: {}", data_id);
-                            ctx.add_data_breakpoint(data_id.to_string());
+                            ctx.add_data_breakpoint_with_condition(
+                                data_id.to_string(),
+                                condition,
+                                break_on_delete,
+                            );
 
+                            let id = self.next_breakpoint_id;
+                            self.next_breakpoint_id += 1;
+                            self.data_breakpoint_ids.insert(data_id.to_string(), id);
+                            ctx.set_data_breakpoint_id(data_id, id);
                             result_breakpoints.push(json!({
+                                "id": id,
                                 "verified": true
                             }));
                         }
@@ -1068,15 +3021,1275 @@ impl DapServer {
         }
     }
 
-    pub fn check_and_send_output(&mut self) {
-        let mut outputs = Vec::new();
-        if let Some(ref output_rx) = self.output_receiver {
-            while let Ok(output) = output_rx.try_recv() {
-                outputs.push(output);
+    /// Queue input for the next SET /P or CHOICE prompt, resuming execution
+    /// if it is stopped at one. Returns whether execution resumed.
+    pub fn provide_input(&mut self, value: &str) -> bool {
+        if let Some(ctx_arc) = &self.context {
+            if let Ok(mut ctx) = ctx_arc.lock() {
+                ctx.provide_input(value);
+                if ctx.awaiting_input() {
+                    ctx.request_continue();
+                    return true;
+                }
             }
         }
-        for output in outputs {
-            self.send_output(&output, "stdout");
+        false
+    }
+
+    pub fn handle_provide_input(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let value = args
+            .as_ref()
+            .and_then(|v| v.get("value"))
+            .and_then(|v| v.as_str());
+
+        match value {
+            Some(value) => {
+                let resumed = self.provide_input(value);
+                self.send_response(seq, command, true, Some(json!({ "resumed": resumed })));
+            }
+            None => {
+                eprintln!("ERROR: provideInput without a value");
+                self.send_response(seq, command, false, None);
+            }
+        }
+    }
+
+    /// Custom `batch/runToLine` request: continue until the physical `line`
+    /// (1-based) is reached, using a breakpoint that removes itself
+    pub fn handle_run_to_line(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let line = args
+            .as_ref()
+            .and_then(|v| v.get("line"))
+            .and_then(|v| v.as_u64())
+            .map_or(0, |l| self.line_from_client(l as usize));
+
+        let logical = match self
+            .preprocessed
+            .as_ref()
+            .filter(|_| line >= 1)
+            .and_then(|pre| {
+                let logical = parser::breakpoint_line(pre, line - 1)?;
+                Some(parser::block_start(pre, logical).unwrap_or(logical))
+            }) {
+            Some(logical) => logical,
+            None => {
+                eprintln!("ERROR: runToLine: line {} is not in the program", line);
+                self.send_response(seq, command, false, None);
+                return;
+            }
+        };
+
+        let started = match &self.context {
+            Some(ctx_arc) => match ctx_arc.lock() {
+                Ok(mut ctx) => {
+                    ctx.add_temporary_breakpoint(logical);
+                    ctx.set_mode(RunMode::Continue);
+                    ctx.request_continue();
+                    true
+                }
+                Err(_) => false,
+            },
+            None => false,
+        };
+
+        if started {
+            eprintln!("Running to line {} (logical {})", line, logical);
+            self.send_response(
+                seq,
+                command,
+                true,
+                Some(json!({"allThreadsContinued": true})),
+            );
+        } else {
+            self.send_response(seq, command, false, None);
+        }
+    }
+
+    pub fn handle_goto_targets(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let source_path = args
+            .as_ref()
+            .and_then(|v| v.get("source"))
+            .and_then(|v| v.get("path"))
+            .and_then(|v| v.as_str())
+            .map(|p| self.path_from_client(p));
+        let line = args
+            .as_ref()
+            .and_then(|v| v.get("line"))
+            .and_then(|v| v.as_u64())
+            .map_or(0, |l| self.line_from_client(l as usize));
+
+        match self.goto_line(source_path.as_deref(), line) {
+            // The target is named by its 1-based line, so a goto request can
+            // check it again against the frame it stops in
+            Ok(_) => self.send_response(
+                seq,
+                command,
+                true,
+                Some(json!({
+                    "targets": [{
+                        "id": line,
+                        "label": format!("Line {}", line),
+                        "line": self.line_to_client(line)
+                    }]
+                })),
+            ),
+            Err(message) => {
+                eprintln!("ERROR: gotoTargets: {}", message);
+                self.send_error_response(seq, command, &message);
+            }
+        }
+    }
+
+    /// Go on from another line of the innermost frame. The line the script
+    /// is paused at doesn't run; it stops again at the target with reason
+    /// "goto".
+    pub fn handle_goto(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let line = args
+            .as_ref()
+            .and_then(|v| v.get("targetId"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as usize;
+
+        let logical = match self.goto_line(None, line) {
+            Ok(logical) => logical,
+            Err(message) => {
+                eprintln!("ERROR: goto: {}", message);
+                self.send_error_response(seq, command, &message);
+                return;
+            }
+        };
+        if let Some(mut ctx) = self.context.as_ref().and_then(|c| c.lock().ok()) {
+            ctx.set_pending_jump(logical);
+            ctx.set_mode(RunMode::StepInto);
+            ctx.request_continue();
+        }
+        eprintln!("Jumping to line {} (logical {})", line, logical);
+        self.send_response(seq, command, true, None);
+    }
+
+    /// Logical line the innermost frame can go on from at `line` of
+    /// `source_path` (its own file when None), or why it can't: the line has
+    /// to be a statement outside ( blocks, in the file the frame runs in, and
+    /// for a CALLed label between the label and the routine's GOTO :EOF or
+    /// EXIT
+    fn goto_line(&self, source_path: Option<&str>, line: usize) -> Result<usize, String> {
+        let ctx = match self.context.as_ref().and_then(|c| c.lock().ok()) {
+            Some(ctx) => ctx,
+            None => return Err("The script hasn't been launched yet".to_string()),
+        };
+        let called = ctx.current_script().and_then(|p| ctx.script(p));
+        let pre = match (&called, &self.preprocessed) {
+            (Some(script), _) => &script.pre,
+            (None, Some(pre)) => pre,
+            (None, None) => return Err("The script hasn't been launched yet".to_string()),
+        };
+
+        let same_file = match (source_path.filter(|p| !p.is_empty()), &called) {
+            (None, _) => true,
+            (Some(path), Some(script)) => source_key(Path::new(path)) == source_key(&script.path),
+            (Some(path), None) => self.is_program_source(path),
+        };
+        if !same_file {
+            return Err("Can only jump within the file the script is paused in".to_string());
+        }
+
+        // The preprocessed view of the program has a line per logical line
+        let logical = if called.is_none() && self.show_preprocessed {
+            line.checked_sub(1).filter(|&l| l < pre.logical.len())
+        } else {
+            line.checked_sub(1)
+                .and_then(|phys| pre.phys_to_logical.get(phys).copied())
+        };
+        let logical = match logical.filter(|&l| {
+            parser::breakpoint_line(pre, pre.logical[l].phys_start) == Some(l)
+                && parser::block_start(pre, l).is_none()
+        }) {
+            Some(logical) => logical,
+            None => return Err(format!("Line {} is not a statement to go on from", line)),
+        };
+
+        if let Some(frame) = ctx.call_stack.last() {
+            if let Some(start) = frame.label_pc {
+                let end = (start..pre.logical.len())
+                    .find(|&l| {
+                        let text = pre.logical[l].text.trim_start_matches('@').to_uppercase();
+                        pre.logical[l].group_depth == 0
+                            && (text.starts_with("GOTO :EOF") || text.starts_with("EXIT"))
+                    })
+                    .unwrap_or(pre.logical.len() - 1);
+                if logical <= start || logical > end {
+                    return Err(format!(
+                        "Line {} is outside {}, the routine the script is paused in",
+                        line,
+                        frame.name()
+                    ));
+                }
+            }
+        }
+        Ok(logical)
+    }
+
+    /// Custom `batch/watches` request for clients without watch-context
+    /// evaluate support. `action` is "add", "remove", "clear" or "list"
+    /// (default); add/remove take `expression`. Responds with the watch list.
+    pub fn handle_watches(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let action = args
+            .as_ref()
+            .and_then(|v| v.get("action"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("list");
+        let expression = args
+            .as_ref()
+            .and_then(|v| v.get("expression"))
+            .and_then(|v| v.as_str())
+            .filter(|e| !e.trim().is_empty());
+
+        let ok = match (action, expression) {
+            ("add", Some(expr)) => {
+                self.add_watch(expr.to_string());
+                true
+            }
+            ("remove", Some(expr)) => {
+                self.remove_watch(expr);
+                true
+            }
+            ("clear", _) => {
+                self.clear_watches();
+                true
+            }
+            ("list", _) => true,
+            _ => false,
+        };
+
+        if ok {
+            let watches = self.watch_expressions.clone();
+            self.send_response(
+                seq,
+                command,
+                true,
+                Some(json!({
+                    "watches": watches
+                })),
+            );
+        } else {
+            eprintln!("ERROR: Invalid batch/watches request: {}", action);
+            self.send_response(seq, command, false, None);
+        }
+    }
+
+    /// Custom `batch/setBreakOnExternal` request: `enabled` toggles pausing
+    /// before external commands, optional `allowlist` replaces the skipped names
+    pub fn handle_set_break_on_external(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let enabled = args
+            .as_ref()
+            .and_then(|v| v.get("enabled"))
+            .and_then(|v| v.as_bool());
+        let allowlist: Option<Vec<String>> = args
+            .as_ref()
+            .and_then(|v| v.get("allowlist"))
+            .and_then(|v| v.as_array())
+            .map(|a| {
+                a.iter()
+                    .filter_map(|n| n.as_str().map(|s| s.to_string()))
+                    .collect()
+            });
+
+        let result = match (&self.context, enabled) {
+            (Some(ctx_arc), Some(enabled)) => match ctx_arc.lock() {
+                Ok(mut ctx) => {
+                    ctx.set_break_on_external(enabled);
+                    if let Some(names) = allowlist {
+                        ctx.set_external_allowlist(names);
+                    }
+                    Some(enabled)
+                }
+                Err(_) => None,
+            },
+            _ => None,
+        };
+
+        match result {
+            Some(enabled) => self.send_response(
+                seq,
+                command,
+                true,
+                Some(json!({
+                    "enabled": enabled
+                })),
+            ),
+            None => {
+                eprintln!("ERROR: setBreakOnExternal needs `enabled` and a running session");
+                self.send_response(seq, command, false, None);
+            }
+        }
+    }
+
+    /// Custom `batch/setOutputBreakpoints` request: replaces the output
+    /// breakpoints with `patterns` (regexes matched against each output line)
+    pub fn handle_set_output_breakpoints(
+        &mut self,
+        seq: u64,
+        command: String,
+        args: Option<Value>,
+    ) {
+        let patterns: Vec<String> = args
+            .as_ref()
+            .and_then(|v| v.get("patterns"))
+            .and_then(|v| v.as_array())
+            .map(|a| {
+                a.iter()
+                    .filter_map(|p| p.as_str().map(|s| s.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let ctx_arc = match self.context.clone() {
+            Some(c) => c,
+            None => {
+                eprintln!("ERROR: No context for setOutputBreakpoints");
+                self.send_response(seq, command, false, None);
+                return;
+            }
+        };
+
+        let mut results = Vec::new();
+        if let Ok(mut ctx) = ctx_arc.lock() {
+            ctx.clear_output_breakpoints();
+            for pattern in &patterns {
+                match ctx.add_output_breakpoint(pattern) {
+                    Ok(()) => results.push(json!({
+                        "pattern": pattern,
+                        "verified": true
+                    })),
+                    Err(e) => results.push(json!({
+                        "pattern": pattern,
+                        "verified": false,
+                        "message": format!("Invalid pattern: {}", e)
+                    })),
+                }
+            }
         }
+
+        self.send_response(
+            seq,
+            command,
+            true,
+            Some(json!({
+                "breakpoints": results
+            })),
+        );
+    }
+
+    /// Pass on the progress the executor reported, for clients that show it.
+    /// Sent ahead of a stop or the end, so no spinner outlives the loop.
+    /// Called on every turn of the event loop, so it doesn't wait for the
+    /// executor: while it holds the context the events stay queued.
+    pub fn send_progress(&mut self) {
+        let events = match self.context.as_ref().and_then(|c| c.try_lock().ok()) {
+            Some(mut ctx) => ctx.progress().take(),
+            None => return,
+        };
+        if !self.supports_progress {
+            return;
+        }
+        for event in events {
+            let (name, body) = match event {
+                ProgressEvent::Start { id, title } => (
+                    "progressStart",
+                    json!({
+                        "progressId": id.to_string(),
+                        "title": title,
+                        "cancellable": false
+                    }),
+                ),
+                ProgressEvent::Update {
+                    id,
+                    message,
+                    percentage,
+                } => {
+                    let mut body = json!({
+                        "progressId": id.to_string(),
+                        "message": message
+                    });
+                    if let Some(percentage) = percentage {
+                        body["percentage"] = json!(percentage);
+                    }
+                    ("progressUpdate", body)
+                }
+                ProgressEvent::End { id } => {
+                    ("progressEnd", json!({ "progressId": id.to_string() }))
+                }
+            };
+            self.send_event(name.to_string(), Some(body));
+        }
+    }
+
+    /// Pass on the executor's warnings (a breakpoint condition that is
+    /// invalid or failed) as console output naming the line
+    pub fn send_warnings(&mut self) {
+        let warnings = match self.context.as_ref().and_then(|c| c.lock().ok()) {
+            Some(mut ctx) => ctx.take_warnings(),
+            None => return,
+        };
+        for (script, pc, text) in warnings {
+            let output = ScriptOutput::at(text.clone(), pc, script.as_deref())
+                .with_kind(OutputKind::DebuggerWarning);
+            if let Some(mut body) = self.output_body(&output) {
+                body["output"] = json!(match body.get("line") {
+                    Some(line) => format!("WARNING: Breakpoint on line {}: {}\r\n", line, text),
+                    None => format!("WARNING: Breakpoint: {}\r\n", text),
+                });
+                self.send_event("output".to_string(), Some(body));
+            }
+        }
+    }
+
+    /// Tell the client about batch files CALLed for the first time, so it
+    /// can bind breakpoints set in them
+    pub fn send_loaded_sources(&mut self) {
+        let called = match self.context.as_ref().and_then(|c| c.lock().ok()) {
+            Some(mut ctx) => ctx.take_called_scripts(),
+            None => return,
+        };
+        for script in called {
+            if self.loaded_sources.contains_key(&script.path) {
+                continue;
+            }
+            let source = self.source_json(&script.path);
+            self.loaded_sources
+                .insert(script.path.clone(), source.clone());
+            self.send_event(
+                "loadedSource".to_string(),
+                Some(json!({ "reason": "new", "source": source })),
+            );
+        }
+    }
+
+    /// Forward what the executor reported since the last call: its output,
+    /// then each stop as a stopped event and its end as terminated. The
+    /// executor sends a stop's output before the stop, so output is passed
+    /// on again ahead of every event and the client sees them in order.
+    pub fn forward_executor_events(&mut self) {
+        self.check_and_send_output();
+        self.check_finished_requests();
+        self.send_progress();
+        self.send_loaded_sources();
+        loop {
+            let event = match &self.event_receiver {
+                Some(rx) => rx.try_recv().ok(),
+                None => None,
+            };
+            let stop = match event {
+                Some(event) => event,
+                None => break,
+            };
+            self.log
+                .write(format_args!("📥 Event received: {}", stop.reason));
+            self.check_and_send_output();
+            if stop.reason != "terminated" {
+                self.send_stopped(&stop);
+                eprintln!("SENT: Stopped event: {}", stop.reason);
+            } else {
+                eprintln!("SENT: Sending terminated event");
+                self.send_terminated();
+            }
+        }
+    }
+
+    /// Tell the client the script stopped, with the stop's detail text if
+    /// the executor left one
+    pub fn send_stopped(&mut self, stop: &StopInfo) {
+        self.send_progress();
+        self.send_loaded_sources();
+        self.send_warnings();
+        self.on_stopped();
+        let body = self.stopped_body(stop);
+        self.stopped = Some(stop.clone());
+        self.send_event("stopped".to_string(), Some(body));
+    }
+
+    /// Body of the stopped event for `stop`, with the breakpoint that caused
+    /// it and the stop's detail text if the executor left one
+    pub fn stopped_body(&self, stop: &StopInfo) -> Value {
+        let text = self
+            .context
+            .as_ref()
+            .and_then(|c| c.lock().ok())
+            .and_then(|ctx| ctx.stop_text().map(String::from));
+        let mut body = json!({
+            "reason": stop.reason,
+            "threadId": 1,
+            "allThreadsStopped": true
+        });
+        if let Some(text) = text {
+            if stop.reason == "pause" {
+                body["description"] = json!(text);
+            }
+            body["text"] = json!(text);
+        }
+        if let Some(description) = &stop.description {
+            body["description"] = json!(description);
+        }
+        if let Some(id) = stop.hit_breakpoint_id {
+            body["hitBreakpointIds"] = json!([id]);
+        }
+        body
+    }
+
+    /// Tell the client the script ended: its exit code (the ERRORLEVEL it
+    /// finished with, or what EXIT gave), then how much work the session
+    /// did. Only the first call for a launch does anything.
+    pub fn send_terminated(&mut self) {
+        if self.terminated_sent {
+            return;
+        }
+        self.terminated_sent = true;
+        self.stopped = None;
+        self.send_progress();
+        self.send_loaded_sources();
+        // thread exited, exited, terminated: in that order, once per launch
+        if std::mem::take(&mut self.thread_started) {
+            self.send_event(
+                "thread".to_string(),
+                Some(json!({ "reason": "exited", "threadId": 1 })),
+            );
+        }
+        let finished = self.context.as_ref().and_then(|c| {
+            c.lock().ok().map(|ctx| {
+                let exit_code = ctx
+                    .run_summary()
+                    .map(|summary| summary.exit_code)
+                    .unwrap_or(ctx.last_exit_code);
+                (exit_code, ctx.session_stats(), ctx.run_summary().cloned())
+            })
+        });
+        if let Some((exit_code, _, _)) = &finished {
+            self.send_event("exited".to_string(), Some(json!({ "exitCode": exit_code })));
+        }
+        if let Some(report) = self.coverage_report() {
+            if let (Some(path), Some(program)) = (&self.coverage_file, &self.program_path) {
+                if let Err(e) = report.write_lcov(Path::new(program), path) {
+                    eprintln!(
+                        "WARNING: Cannot write coverage to {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+            self.send_event("batch/coverage".to_string(), Some(json!(report)));
+        }
+        if let (Some(path), Some(pre), Some(ctx_arc)) =
+            (&self.profile_file, &self.preprocessed, &self.context)
+        {
+            if let Ok(ctx) = ctx_arc.lock() {
+                if let Err(e) = ctx.profile().write_chrome_trace(pre, path) {
+                    eprintln!("WARNING: Cannot write profile to {}: {}", path.display(), e);
+                }
+            }
+        }
+        let body = finished.map(|(_, stats, summary)| {
+            let mut body = json!({ "stats": stats });
+            if let Some(summary) = summary {
+                body["terminatedReason"] = json!(summary.terminated_reason.name());
+                body["detail"] = json!(summary.terminated_reason.detail());
+                body["exitCode"] = json!(summary.exit_code);
+                body["linesExecuted"] = json!(summary.lines_executed);
+                body["maxStackDepth"] = json!(summary.max_stack_depth);
+            }
+            body
+        });
+        self.send_event("terminated".to_string(), body);
+    }
+
+    /// Hit counts for the launched script's lines so far
+    pub fn coverage_report(&self) -> Option<CoverageReport> {
+        let pre = self.preprocessed.as_ref()?;
+        let ctx = self.context.as_ref()?.lock().ok()?;
+        Some(ctx.coverage().report(pre))
+    }
+
+    /// The `count` lines of the launched script that took longest so far
+    pub fn profile_report(&self, count: usize) -> Option<Vec<LineProfile>> {
+        let pre = self.preprocessed.as_ref()?;
+        let ctx = self.context.as_ref()?.lock().ok()?;
+        Some(ctx.profile().slowest(pre, count))
+    }
+
+    pub fn handle_profile(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let count = args
+            .as_ref()
+            .and_then(|v| v.get("count"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(10) as usize;
+
+        match self.profile_report(count) {
+            Some(lines) => self.send_response(seq, command, true, Some(json!({ "lines": lines }))),
+            None => {
+                eprintln!("ERROR: profile needs a launched script");
+                self.send_response(seq, command, false, None);
+            }
+        }
+    }
+
+    pub fn handle_coverage(&mut self, seq: u64, command: String) {
+        match self.coverage_report() {
+            Some(report) => self.send_response(seq, command, true, Some(json!(report))),
+            None => {
+                eprintln!("ERROR: coverage needs a launched script");
+                self.send_response(seq, command, false, None);
+            }
+        }
+    }
+
+    /// Which labels of the launched script CALL or GOTO which, as nodes and
+    /// edges and as Graphviz DOT for an extension to render
+    pub fn handle_call_graph(&mut self, seq: u64, command: String) {
+        let (program, pre) = match (&self.program_path, &self.preprocessed) {
+            (Some(program), Some(pre)) => (program, pre),
+            _ => {
+                eprintln!("ERROR: callGraph needs a launched script");
+                self.send_response(seq, command, false, None);
+                return;
+            }
+        };
+        let graph = parser::call_graph(pre);
+        let title = Path::new(program)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let nodes: Vec<Value> = graph
+            .nodes
+            .iter()
+            .map(|node| {
+                json!({
+                    "name": node.name,
+                    "kind": match node.kind {
+                        NodeKind::Main => "main",
+                        NodeKind::Label => "label",
+                        NodeKind::Eof => "eof",
+                        NodeKind::Script => "script",
+                    },
+                    "line": node.line.map(|line| self.line_to_client(line)),
+                    "unreachable": node.unreachable,
+                })
+            })
+            .collect();
+        let edges: Vec<Value> = graph
+            .edges
+            .iter()
+            .map(|edge| {
+                json!({
+                    "from": edge.from,
+                    "to": edge.to,
+                    "kind": match edge.kind {
+                        EdgeKind::Call => "call",
+                        EdgeKind::Goto => "goto",
+                        EdgeKind::FallThrough => "fallThrough",
+                    },
+                    "line": self.line_to_client(edge.line),
+                })
+            })
+            .collect();
+        let body = json!({
+            "nodes": nodes,
+            "edges": edges,
+            "dot": graph.to_dot(&title),
+        });
+        self.send_response(seq, command, true, Some(body));
+    }
+
+    pub fn handle_child_processes(&mut self, seq: u64, command: String) {
+        match &self.child_processes {
+            Some(children) => {
+                let processes = children.list();
+                self.send_response(seq, command, true, Some(json!({ "processes": processes })));
+            }
+            None => {
+                eprintln!("ERROR: childProcesses needs a launched script");
+                self.send_response(seq, command, false, None);
+            }
+        }
+    }
+
+    pub fn handle_session_stats(&mut self, seq: u64, command: String) {
+        // A session running the script's command can't be pinged without
+        // waiting for it; it counts as alive until the command fails
+        let checked = match &self.context {
+            Some(ctx_arc) => ctx_arc
+                .lock()
+                .ok()
+                .map(|mut ctx| (ctx.ping_idle_session(), ctx.session_stats())),
+            None => None,
+        };
+        let (ping, stats) = match checked {
+            Some(checked) => checked,
+            None => {
+                eprintln!("ERROR: sessionStats needs a running session");
+                self.send_response(seq, command, false, None);
+                return;
+            }
+        };
+
+        let ping_ms = ping
+            .as_ref()
+            .and_then(|p| p.as_ref().ok())
+            .map(|d| d.as_millis() as u64);
+        if let Some(Err(e)) = &ping {
+            eprintln!("WARNING: CMD session health check failed: {}", e);
+        }
+        self.send_response(
+            seq,
+            command,
+            true,
+            Some(json!({
+                "stats": stats,
+                "alive": ping.as_ref().is_none_or(|p| p.is_ok()),
+                "busy": ping.is_none(),
+                "pingMs": ping_ms
+            })),
+        );
+    }
+
+    pub fn handle_dump_transcript(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let count = args
+            .as_ref()
+            .and_then(|v| v.get("count"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(50) as usize;
+
+        let entries = match &self.context {
+            Some(ctx_arc) => match ctx_arc.lock() {
+                Ok(ctx) => ctx.transcript(count),
+                Err(_) => Vec::new(),
+            },
+            None => {
+                eprintln!("ERROR: dumpTranscript needs a running session");
+                self.send_response(seq, command, false, None);
+                return;
+            }
+        };
+        self.send_response(
+            seq,
+            command,
+            true,
+            Some(json!({
+                "entries": entries
+            })),
+        );
+    }
+
+    /// Custom `batch/diagnostics` request: a snapshot of the adapter's state
+    /// to attach to a bug report. `includeVariables: false` leaves out the
+    /// script's variables.
+    pub fn handle_diagnostics(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let include_variables = args
+            .as_ref()
+            .and_then(|v| v.get("includeVariables"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+        let body = self.diagnostics(include_variables);
+        self.send_response(seq, command, true, Some(body));
+    }
+
+    /// What `batch/diagnostics` reports
+    pub fn diagnostics(&self, include_variables: bool) -> Value {
+        let mut body = json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "program": self.program_path,
+            "launched": self.context.is_some(),
+            "breakpoints": self.breakpoints.values().map(Vec::len).sum::<usize>(),
+            "dataBreakpoints": self.data_breakpoint_ids.len(),
+            "watches": self.watch_expressions.len()
+        });
+        let top = self.collect_stack_frames().into_iter().next();
+        let ctx = match self.context.as_ref().map(|c| c.lock()) {
+            Some(Ok(ctx)) => ctx,
+            Some(Err(_)) => {
+                body["error"] = json!("the debugger's state is unavailable");
+                return body;
+            }
+            None => return body,
+        };
+
+        body["pc"] = json!(ctx.current_pc());
+        body["line"] = json!(top.as_ref().map(|frame| frame["line"].clone()));
+        body["source"] = json!(top.as_ref().map(|frame| frame["source"]["path"].clone()));
+        body["mode"] = json!(format!("{:?}", ctx.mode()));
+
+        // Innermost first, like the stack trace
+        let mut frames: Vec<Value> = ctx
+            .call_stack
+            .iter()
+            .rev()
+            .map(|frame| {
+                json!({
+                    "name": frame.name(),
+                    "label": frame.label,
+                    "args": frame.args,
+                    "setlocal": frame.has_setlocal,
+                    "locals": frame.locals.len()
+                })
+            })
+            .collect();
+        frames.push(json!({
+            "name": "<script>",
+            "args": ctx.script_args(),
+            "setlocal": false,
+            "locals": 0
+        }));
+        body["frames"] = json!(frames);
+        body["directoryStack"] = json!(ctx.get_directory_stack());
+        body["transcript"] = json!(ctx.transcript(20));
+
+        if include_variables {
+            let variables: serde_json::Map<String, Value> = ctx
+                .get_visible_variables()
+                .into_iter()
+                .map(|(name, value)| {
+                    let length = value.chars().count();
+                    let value = if length > DIAGNOSTICS_VALUE_LENGTH {
+                        let kept: String = value.chars().take(DIAGNOSTICS_VALUE_LENGTH).collect();
+                        format!("{}… ({} characters)", kept, length)
+                    } else {
+                        value
+                    };
+                    (name, json!(value))
+                })
+                .collect();
+            body["variables"] = json!(variables);
+        }
+        body
+    }
+
+    /// Custom `batch/variableHistory` request: returns the recorded changes of
+    /// one variable, oldest first
+    pub fn handle_variable_history(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let name = args
+            .as_ref()
+            .and_then(|v| v.get("name"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+
+        if name.is_empty() || self.context.is_none() {
+            eprintln!("ERROR: variableHistory needs a variable name and a running session");
+            self.send_response(seq, command, false, None);
+            return;
+        }
+
+        let history = self.collect_variable_history(name);
+        self.send_response(
+            seq,
+            command,
+            true,
+            Some(json!({
+                "name": name,
+                "history": history
+            })),
+        );
+    }
+
+    /// Build the `{line, value}` entries for a variable's history. Lines are
+    /// 1-based physical lines; an undefined value is reported as null.
+    pub fn collect_variable_history(&self, name: &str) -> Vec<Value> {
+        let mut entries = Vec::new();
+
+        if let Some(ctx_arc) = &self.context {
+            if let Ok(ctx) = ctx_arc.lock() {
+                for change in ctx.variable_history(name) {
+                    let line = match &self.preprocessed {
+                        Some(pre) if change.line < pre.logical.len() => {
+                            pre.logical[change.line].phys_start + 1
+                        }
+                        _ => change.line + 1,
+                    };
+                    entries.push(json!({
+                        "line": self.line_to_client(line),
+                        "value": change.new_value
+                    }));
+                }
+            }
+        }
+
+        entries
+    }
+
+    pub fn check_and_send_output(&mut self) {
+        let mut outputs = Vec::new();
+        if let Some(ref output_rx) = self.output_receiver {
+            outputs.extend(output_rx.try_iter());
+        }
+        if let Some(ref stderr_rx) = self.stderr_receiver {
+            outputs.extend(stderr_rx.try_iter());
+        }
+        for output in outputs {
+            if output.kind == OutputKind::DebuggerInfo
+                && self.console_verbosity == ConsoleVerbosity::Off
+            {
+                continue;
+            }
+            let body = self.output_body(&output);
+            if let Some(body) = body {
+                self.send_event("output".to_string(), Some(body));
+            }
+        }
+    }
+
+    /// Body of the output event for `output`, in the category for who
+    /// printed it and naming the source and physical line that printed it
+    /// when it is known. None for no text.
+    pub fn output_body(&self, output: &ScriptOutput) -> Option<Value> {
+        if output.text.is_empty() {
+            return None;
+        }
+        let mut body = json!({
+            "category": output.kind.category(),
+            "output": output.text
+        });
+        if let Some((source, line)) = output.pc.and_then(|pc| self.output_source(output, pc)) {
+            body["source"] = source;
+            body["line"] = json!(self.line_to_client(line));
+        }
+        Some(body)
+    }
+
+    /// Source and 1-based physical line of logical line `pc` of the file
+    /// `output` came from
+    fn output_source(&self, output: &ScriptOutput, pc: usize) -> Option<(Value, usize)> {
+        match &output.script {
+            Some(path) => {
+                let script = self.context.as_ref()?.lock().ok()?.script(path)?;
+                let line = script.pre.logical.get(pc)?.phys_start + 1;
+                Some((
+                    json!({
+                        "name": script.name(),
+                        "path": self.path_to_client(&path.display().to_string())
+                    }),
+                    line,
+                ))
+            }
+            None => {
+                let line = self.preprocessed.as_ref()?.logical.get(pc)?.phys_start + 1;
+                let path = self.program_path.as_deref()?;
+                let name = Path::new(path)
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or(path);
+                Some((
+                    json!({
+                        "name": name,
+                        "path": self.path_to_client(path)
+                    }),
+                    line,
+                ))
+            }
+        }
+    }
+}
+
+impl VariableView {
+    /// The part of a variablesReference's list a variables request asks
+    /// for with its filter, start and count
+    fn variables_page(&self, var_ref: u64, args: Option<&Value>) -> Vec<Value> {
+        let mut variables = self.collect_variables(var_ref);
+        // Only the directory stack's entries are indexed
+        let indexed = var_ref == 4;
+        match args.and_then(|v| v.get("filter")).and_then(|v| v.as_str()) {
+            Some("named") if indexed => variables.clear(),
+            Some("indexed") if !indexed => variables.clear(),
+            _ => {}
+        }
+        let start = args
+            .and_then(|v| v.get("start"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as usize;
+        // A count of 0 asks for everything
+        let count = args
+            .and_then(|v| v.get("count"))
+            .and_then(|v| v.as_u64())
+            .filter(|&c| c > 0)
+            .map_or(usize::MAX, |c| c as usize);
+        variables.into_iter().skip(start).take(count).collect()
+    }
+
+    /// One entry per watch expression, in order. A watch that fails, times
+    /// out or panics shows its error without costing the others theirs.
+    fn watch_variables(&self) -> Vec<Value> {
+        let entry = |expression: &str, value: Result<String, String>| match value {
+            Ok(value) => json!({
+                "name": expression,
+                "value": value,
+                "variablesReference": 0,
+                "presentationHint": {
+                    "kind": "property"
+                }
+            }),
+            Err(e) => json!({
+                "name": expression,
+                "value": format!("<error: {}>", e),
+                "variablesReference": 0,
+                "presentationHint": {
+                    "kind": "property",
+                    "attributes": ["failedEvaluation"]
+                }
+            }),
+        };
+
+        let ctx_arc = match &self.context {
+            Some(ctx_arc) if ctx_arc.is_poisoned() => {
+                eprintln!("ERROR: Debug context unavailable for watches");
+                return self
+                    .watch_expressions
+                    .iter()
+                    .map(|e| entry(e, Err("the debugger's state is unavailable".to_string())))
+                    .collect();
+            }
+            Some(ctx_arc) => ctx_arc,
+            None => {
+                return self
+                    .watch_expressions
+                    .iter()
+                    .map(|e| entry(e, Err("The script hasn't been launched yet".to_string())))
+                    .collect();
+            }
+        };
+
+        self.watch_expressions
+            .iter()
+            .map(|watch_expr| {
+                let value = panic::catch_unwind(AssertUnwindSafe(|| {
+                    evaluate_unlocked(ctx_arc, watch_expr, None, Some(WATCH_TIMEOUT))
+                }));
+                let value = match value {
+                    Ok(Ok(result)) => Ok(result),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(_) => {
+                        eprintln!("ERROR: Evaluating watch '{}' panicked", watch_expr);
+                        Err("internal error".to_string())
+                    }
+                };
+                entry(watch_expr, value)
+            })
+            .collect()
+    }
+
+    /// Build the variable list for a variablesReference. Values longer than
+    /// the launch's maxValueLength are cut short and expand to the full one.
+    fn collect_variables(&self, var_ref: u64) -> Vec<Value> {
+        if var_ref >= LONG_VALUE_REF {
+            let scope_ref = (var_ref - LONG_VALUE_REF) / LONG_VALUE_SLOTS;
+            let index = ((var_ref - LONG_VALUE_REF) % LONG_VALUE_SLOTS) as usize;
+            return self
+                .scope_variables(scope_ref)
+                .get(index)
+                .map(|variable| {
+                    vec![json!({
+                        "name": "value",
+                        "value": variable["value"],
+                        "variablesReference": 0,
+                        "presentationHint": {
+                            "kind": "data",
+                            "attributes": ["readOnly", "rawString"]
+                        }
+                    })]
+                })
+                .unwrap_or_default();
+        }
+
+        let mut variables = self.scope_variables(var_ref);
+        for (index, variable) in variables.iter_mut().enumerate() {
+            let long_value = variable["value"]
+                .as_str()
+                .filter(|value| value.chars().count() > self.max_value_length)
+                .map(|value| {
+                    value
+                        .chars()
+                        .take(self.max_value_length)
+                        .collect::<String>()
+                });
+            if let Some(mut value) = long_value {
+                if variable["variablesReference"] == 0 && (index as u64) < LONG_VALUE_SLOTS {
+                    value.push('…');
+                    variable["value"] = json!(value);
+                    variable["variablesReference"] =
+                        json!(LONG_VALUE_REF + var_ref * LONG_VALUE_SLOTS + index as u64);
+                }
+            }
+        }
+        variables
+    }
+
+    /// The variables of a variablesReference, values in full
+    fn scope_variables(&self, var_ref: u64) -> Vec<Value> {
+        if var_ref == 3 {
+            return self.watch_variables();
+        }
+        let mut variables = Vec::new();
+
+        if let Some(ctx_arc) = &self.context {
+            if let Ok(ctx) = ctx_arc.lock() {
+                match var_ref {
+                    2 => {
+                        // Add ERRORLEVEL as a special variable
+                        let mut attributes = vec!["readOnly"];
+                        if ctx.changed_since_last_stop("ERRORLEVEL") {
+                            attributes.push("hasChanged");
+                        }
+                        variables.push(json!({
+                            "name": "ERRORLEVEL",
+                            "value": ctx.last_exit_code.to_string(),
+                            "variablesReference": 0,
+                            "presentationHint": {
+                                "kind": "property",
+                                "attributes": attributes
+                            }
+                        }));
+
+                        variables.push(json!({
+                            "name": "CWD",
+                            "value": ctx.get_current_dir().to_string_lossy(),
+                            "variablesReference": 0,
+                            "presentationHint": {
+                                "kind": "property",
+                                "attributes": ["readOnly"]
+                            }
+                        }));
+
+                        // Directory stack expands into variablesReference 4
+                        let depth = ctx.get_directory_stack().len();
+                        variables.push(json!({
+                            "name": "DIRSTACK",
+                            "value": format!("[{} entries]", depth + 1),
+                            "variablesReference": 4,
+                            "indexedVariables": depth + 1,
+                            "presentationHint": {
+                                "kind": "property",
+                                "attributes": ["readOnly"]
+                            }
+                        }));
+
+                        let mut globals: Vec<_> = ctx.top_level_variables().into_iter().collect();
+                        sort_by_name(&mut globals);
+                        let mut loop_count = 0;
+                        for (key, val) in globals {
+                            let origin = ctx.variable_origin(&key, None);
+                            if origin == VariableOrigin::LoopVariable {
+                                loop_count += 1;
+                            } else {
+                                variables.push(variable_json(&key, &val, origin, &ctx));
+                            }
+                        }
+                        if loop_count > 0 {
+                            variables.push(loop_variables_node(loop_count, LOOP_VARS_REF));
+                        }
+                    }
+                    LOOP_VARS_REF => {
+                        let mut globals: Vec<_> = ctx.top_level_variables().into_iter().collect();
+                        sort_by_name(&mut globals);
+                        for (key, val) in globals {
+                            let origin = ctx.variable_origin(&key, None);
+                            if origin == VariableOrigin::LoopVariable {
+                                variables.push(variable_json(&key, &val, origin, &ctx));
+                            }
+                        }
+                    }
+                    4 => {
+                        // Directory stack: current directory first, then PUSHD entries
+                        // from the most recent to the oldest
+                        let current = ctx.get_current_dir().to_string_lossy().to_string();
+                        let stacked = ctx.get_directory_stack().iter().rev().cloned();
+                        for (i, dir) in std::iter::once(current).chain(stacked).enumerate() {
+                            variables.push(json!({
+                                "name": format!("[{}]", i),
+                                "value": dir,
+                                "variablesReference": 0,
+                                "presentationHint": {
+                                    "kind": "property",
+                                    "attributes": ["readOnly"]
+                                }
+                            }));
+                        }
+                    }
+                    r if (FRAME_LOCALS_REF..FRAME_ARGS_REF).contains(&r) => {
+                        // SETLOCAL variables of one call frame
+                        let frame_index = (r - FRAME_LOCALS_REF) as usize;
+                        let mut locals: Vec<_> =
+                            ctx.get_frame_variables(frame_index).into_iter().collect();
+                        sort_by_name(&mut locals);
+                        let mut loop_count = 0;
+                        for (key, val) in locals {
+                            let origin = ctx.variable_origin(&key, Some(frame_index));
+                            if origin == VariableOrigin::LoopVariable {
+                                loop_count += 1;
+                            } else {
+                                variables.push(variable_json(&key, &val, origin, &ctx));
+                            }
+                        }
+                        if loop_count > 0 {
+                            variables.push(loop_variables_node(
+                                loop_count,
+                                FRAME_LOOP_VARS_REF + frame_index as u64,
+                            ));
+                        }
+                    }
+                    r if (FRAME_LOOP_VARS_REF..FRAME_LOOP_VARS_REF + 1000).contains(&r) => {
+                        // FOR loop variables of one call frame's SETLOCAL scope
+                        let frame_index = (r - FRAME_LOOP_VARS_REF) as usize;
+                        let mut locals: Vec<_> =
+                            ctx.get_frame_variables(frame_index).into_iter().collect();
+                        sort_by_name(&mut locals);
+                        for (key, val) in locals {
+                            let origin = ctx.variable_origin(&key, Some(frame_index));
+                            if origin == VariableOrigin::LoopVariable {
+                                variables.push(variable_json(&key, &val, origin, &ctx));
+                            }
+                        }
+                    }
+                    SCRIPT_ARGS_REF => {
+                        let names = std::iter::once(ctx.script_path().to_string())
+                            .chain(ctx.script_args().iter().cloned());
+                        for (i, arg) in names.enumerate() {
+                            variables.push(json!({
+                                "name": format!("%{}", i),
+                                "value": arg,
+                                "variablesReference": 0,
+                                "presentationHint": {
+                                    "kind": "property",
+                                    "attributes": ["readOnly"]
+                                }
+                            }));
+                        }
+                    }
+                    r if (FRAME_ARGS_REF..FRAME_ARGS_REF + 1000).contains(&r) => {
+                        // %0..%n of one call frame
+                        let frame_index = (r - FRAME_ARGS_REF) as usize;
+                        if let Some(frame) = ctx.call_stack.get(frame_index) {
+                            variables.push(json!({
+                                "name": "%0",
+                                "value": frame.name(),
+                                "variablesReference": 0,
+                                "presentationHint": {
+                                    "kind": "property",
+                                    "attributes": ["readOnly"]
+                                }
+                            }));
+                            for (i, arg) in frame.args.iter().flatten().enumerate() {
+                                variables.push(json!({
+                                    "name": format!("%{}", i + 1),
+                                    "value": arg,
+                                    "variablesReference": 0,
+                                    "presentationHint": {
+                                        "kind": "property",
+                                        "attributes": ["readOnly"]
+                                    }
+                                }));
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        variables
     }
 }
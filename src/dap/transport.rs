@@ -0,0 +1,224 @@
+//! How DAP messages get to and from the client: Content-Length framed JSON
+//! over stdin/stdout, or over a TCP connection with `--port`.
+
+use super::protocol::DapMessage;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+
+/// A connection to one DAP client
+pub trait Transport: Send {
+    /// Wait for the next message. Fails once the client has gone.
+    fn read_message(&mut self) -> io::Result<DapMessage>;
+
+    /// The next message if one has arrived, without waiting. Fails once the
+    /// client has gone.
+    fn try_read(&mut self) -> io::Result<Option<DapMessage>>;
+
+    fn send(&mut self, msg: &DapMessage) -> io::Result<()>;
+}
+
+/// The client talks over the adapter's stdin and stdout
+#[derive(Default)]
+pub struct StdioTransport {
+    incoming: Option<Receiver<DapMessage>>, // Started on the first read
+    outgoing: Option<Sender<Vec<u8>>>,      // Writer holding stdout, when exclusive
+}
+
+impl StdioTransport {
+    /// Messages go out with print!, so the test harness captures them
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Owns stdout for as long as it lives: a thread holds the stdout lock
+    /// and writes every message through one buffer. Anything else writing
+    /// to stdout waits instead of slipping bytes into the protocol stream.
+    pub fn exclusive() -> Self {
+        Self {
+            incoming: None,
+            outgoing: Some(spawn_stdout_writer()),
+        }
+    }
+
+    fn incoming(&mut self) -> &Receiver<DapMessage> {
+        self.incoming
+            .get_or_insert_with(|| spawn_reader(io::stdin()))
+    }
+}
+
+impl Transport for StdioTransport {
+    fn read_message(&mut self) -> io::Result<DapMessage> {
+        self.incoming().recv().map_err(|_| client_gone())
+    }
+
+    fn try_read(&mut self) -> io::Result<Option<DapMessage>> {
+        try_receive(self.incoming())
+    }
+
+    #[allow(clippy::print_stdout)]
+    fn send(&mut self, msg: &DapMessage) -> io::Result<()> {
+        let mut framed = Vec::new();
+        write_framed(&mut framed, msg)?;
+        match &self.outgoing {
+            Some(outgoing) => outgoing
+                .send(framed)
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "stdout closed")),
+            None => {
+                // print! rather than io::stdout(), so the test harness captures it
+                print!("{}", String::from_utf8_lossy(&framed));
+                io::stdout().flush()
+            }
+        }
+    }
+}
+
+/// Write the framed messages sent to the returned channel to stdout, holding
+/// the stdout lock until the channel closes. Messages queued together go
+/// out with one flush.
+fn spawn_stdout_writer() -> Sender<Vec<u8>> {
+    let (tx, rx) = channel::<Vec<u8>>();
+    thread::spawn(move || {
+        let mut stdout = BufWriter::new(io::stdout().lock());
+        while let Ok(framed) = rx.recv() {
+            let mut result = stdout.write_all(&framed);
+            while result.is_ok() {
+                match rx.try_recv() {
+                    Ok(framed) => result = stdout.write_all(&framed),
+                    Err(_) => break,
+                }
+            }
+            if let Err(e) = result.and_then(|_| stdout.flush()) {
+                eprintln!("ERROR: Failed to write to stdout: {}", e);
+                return;
+            }
+        }
+    });
+    tx
+}
+
+/// The client talks over a pair of streams, like the two halves of a TCP
+/// connection. Messages are read on a thread of their own from the start.
+pub struct StreamTransport {
+    incoming: Receiver<DapMessage>,
+    writer: Box<dyn Write + Send>,
+}
+
+impl StreamTransport {
+    pub fn new(reader: impl Read + Send + 'static, writer: impl Write + Send + 'static) -> Self {
+        Self {
+            incoming: spawn_reader(reader),
+            writer: Box::new(writer),
+        }
+    }
+}
+
+impl Transport for StreamTransport {
+    fn read_message(&mut self) -> io::Result<DapMessage> {
+        self.incoming.recv().map_err(|_| client_gone())
+    }
+
+    fn try_read(&mut self) -> io::Result<Option<DapMessage>> {
+        try_receive(&self.incoming)
+    }
+
+    fn send(&mut self, msg: &DapMessage) -> io::Result<()> {
+        write_framed(&mut self.writer, msg)
+    }
+}
+
+fn client_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "DAP client disconnected")
+}
+
+fn try_receive(incoming: &Receiver<DapMessage>) -> io::Result<Option<DapMessage>> {
+    match incoming.try_recv() {
+        Ok(msg) => Ok(Some(msg)),
+        Err(TryRecvError::Empty) => Ok(None),
+        Err(TryRecvError::Disconnected) => Err(client_gone()),
+    }
+}
+
+/// Read messages from `reader` on a thread of its own until the client
+/// closes it. The one buffered reader lives as long as the thread, so
+/// requests arriving back to back in one read all get through.
+fn spawn_reader<R: Read + Send + 'static>(reader: R) -> Receiver<DapMessage> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        loop {
+            match read_framed(&mut reader) {
+                Ok(Some(msg)) => {
+                    if tx.send(msg).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::UnexpectedEof {
+                        eprintln!("ERROR: Failed to read DAP message: {}", e);
+                    }
+                    return;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// The largest message body read, well past any real request
+const MAX_CONTENT_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Read one framed message. Headers other than Content-Length are skipped.
+/// Gives None for a message that isn't valid DAP, and an UnexpectedEof
+/// error when the stream ends. A length that can't be read or is over
+/// MAX_CONTENT_LENGTH is an InvalidData error, since the body can't be
+/// skipped to find the next message.
+fn read_framed(reader: &mut impl BufRead) -> io::Result<Option<DapMessage>> {
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(client_gone());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                content_length = match value.trim().parse() {
+                    Ok(length) if length <= MAX_CONTENT_LENGTH => length,
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Bad Content-Length: {}", value.trim()),
+                        ))
+                    }
+                };
+            }
+        }
+    }
+    if content_length == 0 {
+        return Ok(None);
+    }
+
+    let mut buffer = vec![0u8; content_length];
+    reader.read_exact(&mut buffer)?;
+    match serde_json::from_slice(&buffer) {
+        Ok(msg) => Ok(Some(msg)),
+        Err(e) => {
+            eprintln!("WARNING: Ignoring malformed DAP message: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Write `msg` with its Content-Length header
+fn write_framed(writer: &mut impl Write, msg: &DapMessage) -> io::Result<()> {
+    let json = serde_json::to_string(msg)?;
+    write!(writer, "Content-Length: {}\r\n\r\n{}", json.len(), json)?;
+    writer.flush()?;
+    eprintln!("SENT: {} bytes", json.len());
+    Ok(())
+}
@@ -0,0 +1,65 @@
+//! Removing ANSI escape sequences (colors, cursor movement) from output
+
+/// How escape sequences in command output are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnsiMode {
+    /// Remove them from output as well as from values
+    Strip,
+    /// Leave output as printed; values are still cleaned
+    #[default]
+    Passthrough,
+}
+
+impl AnsiMode {
+    /// Parse the `ansi` launch option
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "strip" => Some(AnsiMode::Strip),
+            "passthrough" => Some(AnsiMode::Passthrough),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Text,
+    Escape, // After ESC
+    Csi,    // ESC [ parameters, up to a final byte
+    Osc,    // ESC ] string, up to BEL or ESC \
+    OscEscape,
+}
+
+/// `text` without escape sequences: CSI (`ESC [ ... m` and friends), OSC
+/// (`ESC ] ... BEL`) and two-character escapes. A bare CSI byte (0x9B) is
+/// treated like `ESC [`.
+pub fn strip(text: &str) -> String {
+    if !text.contains(['\x1b', '\u{9b}']) {
+        return text.to_string();
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut state = State::Text;
+    for ch in text.chars() {
+        state = match (state, ch) {
+            (State::Text, '\x1b') => State::Escape,
+            (State::Text, '\u{9b}') => State::Csi,
+            (State::Text, c) => {
+                out.push(c);
+                State::Text
+            }
+            (State::Escape, '[') => State::Csi,
+            (State::Escape, ']') => State::Osc,
+            // Any other escape is two characters long
+            (State::Escape, _) => State::Text,
+            (State::Csi, '\x40'..='\x7e') => State::Text,
+            (State::Csi, _) => State::Csi,
+            (State::Osc, '\x07') => State::Text,
+            (State::Osc, '\x1b') => State::OscEscape,
+            (State::Osc, _) => State::Osc,
+            (State::OscEscape, '\\') => State::Text,
+            (State::OscEscape, _) => State::Osc,
+        };
+    }
+    out
+}
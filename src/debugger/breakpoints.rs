@@ -1,10 +1,117 @@
+use super::source_key;
+use crate::parser::{self, PreprocessResult};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone)]
 pub struct Breakpoint {
     pub line: usize,
     pub condition: Option<String>,
     pub hit_count: usize,
+    pub enabled: bool,
+    pub temporary: bool, // Removed after the first stop (run to line)
+    pub condition_error: Option<String>, // Why the condition doesn't parse; it then always stops
+    pub warned: bool,    // The client was told its condition is broken
+    pub hit_condition: Option<String>, // Kept for the breakpoints file; not acted on
+    pub log_message: Option<String>, // Kept for the breakpoints file; not acted on
+    pub source_line: Option<usize>, // 1-based physical line it was set on, if not its own
+    pub id: Option<u64>,            // Id the DAP client knows it by
+}
+
+/// A breakpoint as a breakpoints file keeps it, on a 1-based physical line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BreakpointSpec {
+    pub line: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hit_condition: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_message: Option<String>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl BreakpointSpec {
+    pub fn at(line: usize) -> Self {
+        Self {
+            line,
+            condition: None,
+            hit_condition: None,
+            log_message: None,
+            enabled: true,
+        }
+    }
+}
+
+/// The breakpoints of one batch file in a breakpoints file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceBreakpoints {
+    pub path: PathBuf,
+    pub breakpoints: Vec<BreakpointSpec>,
+}
+
+/// Breakpoints saved between sessions, grouped by batch file. Several
+/// scripts can share one file; each only replaces its own group.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BreakpointsFile {
+    pub sources: Vec<SourceBreakpoints>,
+}
+
+impl BreakpointsFile {
+    /// Read `path`; a file that doesn't exist yet holds no breakpoints
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        fs::write(path, json)
+    }
+
+    /// Breakpoints saved for `source`
+    pub fn get(&self, source: &Path) -> Option<&[BreakpointSpec]> {
+        let key = source_key(source);
+        self.sources
+            .iter()
+            .find(|group| source_key(&group.path) == key)
+            .map(|group| group.breakpoints.as_slice())
+    }
+
+    /// Replace the breakpoints saved for `source`; none drops its group
+    pub fn set(&mut self, source: &Path, breakpoints: Vec<BreakpointSpec>) {
+        let key = source_key(source);
+        self.sources.retain(|group| source_key(&group.path) != key);
+        if !breakpoints.is_empty() {
+            self.sources.push(SourceBreakpoints {
+                path: key,
+                breakpoints,
+            });
+            self.sources.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+    }
+}
+
+/// A breakpoint on a variable, checked after each executed command
+#[derive(Debug, Clone, Default)]
+pub struct DataBreakpoint {
+    pub previous: Option<String>,  // Last seen value, None while undefined
+    pub condition: Option<String>, // IF-style condition over $NEW and $OLD
+    pub break_on_delete: bool,     // Only fire when the variable is deleted
+    pub id: Option<u64>,           // Id the DAP client knows it by
 }
 
 pub struct Breakpoints {
@@ -25,8 +132,16 @@ impl Breakpoints {
     pub fn add_with_condition(&mut self, logical_line: usize, condition: Option<String>) {
         let bp = Breakpoint {
             line: logical_line,
+            condition_error: condition.as_deref().and_then(parser::condition_error),
             condition: condition.clone(),
             hit_count: 0,
+            enabled: true,
+            temporary: false,
+            warned: false,
+            hit_condition: None,
+            log_message: None,
+            source_line: None,
+            id: None,
         };
         self.points.insert(logical_line, bp);
 
@@ -40,11 +155,119 @@ impl Breakpoints {
         }
     }
 
+    /// Add the breakpoint `spec` describes on `logical_line`, remembering
+    /// the line `spec` set it on so it is saved there again
+    pub fn add_spec(&mut self, logical_line: usize, spec: &BreakpointSpec) {
+        self.add_with_condition(logical_line, spec.condition.clone());
+        if let Some(bp) = self.points.get_mut(&logical_line) {
+            bp.hit_condition = spec.hit_condition.clone();
+            bp.log_message = spec.log_message.clone();
+            bp.enabled = spec.enabled;
+            bp.source_line = Some(spec.line);
+        }
+    }
+
+    /// Bind saved breakpoints to the lines of `pre`, the way a client's
+    /// are bound. Also gives the ones with no statement at or after their
+    /// line.
+    pub fn from_spec(
+        specs: &[BreakpointSpec],
+        pre: &PreprocessResult,
+    ) -> (Self, Vec<BreakpointSpec>) {
+        let mut breakpoints = Self::new();
+        let mut unbound = Vec::new();
+        for spec in specs {
+            match spec
+                .line
+                .checked_sub(1)
+                .and_then(|phys| parser::breakpoint_line(pre, phys))
+            {
+                Some(logical_line) => {
+                    let logical_line =
+                        parser::block_start(pre, logical_line).unwrap_or(logical_line);
+                    breakpoints.add_spec(logical_line, spec);
+                }
+                None => unbound.push(spec.clone()),
+            }
+        }
+        (breakpoints, unbound)
+    }
+
+    /// The breakpoints as a breakpoints file keeps them, by line: the one
+    /// they were set on rather than the statement they moved to. Run to line
+    /// targets aren't kept.
+    pub fn to_spec(&self, pre: &PreprocessResult) -> Vec<BreakpointSpec> {
+        let mut specs: Vec<BreakpointSpec> = self
+            .points
+            .values()
+            .filter(|bp| !bp.temporary)
+            .filter_map(|bp| {
+                let logical = pre.logical.get(bp.line)?;
+                Some(BreakpointSpec {
+                    line: bp.source_line.unwrap_or(logical.phys_start + 1),
+                    condition: bp.condition.clone(),
+                    hit_condition: bp.hit_condition.clone(),
+                    log_message: bp.log_message.clone(),
+                    enabled: bp.enabled,
+                })
+            })
+            .collect();
+        specs.sort_by_key(|spec| spec.line);
+        specs
+    }
+
+    /// Add a breakpoint that is removed after its first stop. A persistent
+    /// breakpoint already on the line is left alone.
+    pub fn add_temporary(&mut self, logical_line: usize) {
+        if self.points.contains_key(&logical_line) {
+            return;
+        }
+        self.points.insert(
+            logical_line,
+            Breakpoint {
+                line: logical_line,
+                condition: None,
+                hit_count: 0,
+                enabled: true,
+                temporary: true,
+                condition_error: None,
+                warned: false,
+                hit_condition: None,
+                log_message: None,
+                source_line: None,
+                id: None,
+            },
+        );
+        eprintln!("Temporary breakpoint set at logical line {}", logical_line);
+    }
+
+    /// Drop all temporary breakpoints that were never reached
+    pub fn clear_temporary(&mut self) {
+        self.points.retain(|_, bp| !bp.temporary);
+    }
+
     pub fn remove(&mut self, logical_line: usize) {
         self.points.remove(&logical_line);
         eprintln!("Breakpoint removed from logical line {}", logical_line);
     }
 
+    /// Enable or disable a breakpoint without removing it. Returns false if
+    /// there is no breakpoint on the line.
+    pub fn set_enabled(&mut self, logical_line: usize, enabled: bool) -> bool {
+        match self.points.get_mut(&logical_line) {
+            Some(bp) => {
+                bp.enabled = enabled;
+                eprintln!(
+                    "Breakpoint at logical line {} {}",
+                    logical_line,
+                    if enabled { "enabled" } else { "disabled" }
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn contains(&self, logical_line: usize) -> bool {
         self.points.contains_key(&logical_line)
     }
@@ -1,30 +1,344 @@
-use super::breakpoints::Breakpoints;
-use super::{CmdSession, Frame, RunMode};
-use crate::parser::{ForLoopType, IfCondition, LogicalLine};
-use std::collections::HashMap;
+use super::breakpoints::{BreakpointSpec, Breakpoints, DataBreakpoint};
+use super::stepping::{has_external_side_effects, StateSnapshot};
+use super::{
+    ansi, lock_shell, ChildProcesses, CommandResult, Coverage, DebugLog, Frame, LocalScope,
+    Profile, Progress, RunMode, RunSummary, Script, SessionKiller, SessionRecord, SessionStats,
+    SharedShell, Shell, ShellConfig, StepGranularity, TranscriptEntry, VariableOrigin,
+    INTERRUPTED_EXIT_CODE,
+};
+use crate::error::BatchDbgError;
+use crate::parser::{
+    command_name, is_builtin_command, parse_if_statement, strip_cd_command, CommandPart,
+    ForLoopType, IfCondition, LogicalLine, PreprocessResult, BUILTIN_COMMANDS,
+};
+use regex::Regex;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+/// Maximum number of changes remembered per variable
+const MAX_HISTORY_PER_VARIABLE: usize = 50;
+
+/// Maximum number of executed lines that can be stepped back over
+const MAX_STEP_BACK: usize = 100;
+
+/// One recorded change of a variable's value. `None` means the variable was
+/// undefined before (or deleted by) the change.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VariableChange {
+    pub line: usize, // Logical line that made the change
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
+
+/// What evaluating an expression takes: the answer, when tracked state or
+/// the cache has it, or the query to run in the session
+#[derive(Debug, Clone, PartialEq)]
+pub enum Evaluation {
+    Value(String),
+    Query(String), // An `echo` command; its output goes to `record_query`
+}
+
+/// Run a helper command in `shell`, then put the script's ERRORLEVEL
+/// `exit_code` back if the helper changed it
+pub fn run_helper_in(
+    shell: &mut dyn Shell,
+    cmd: &str,
+    exit_code: i32,
+) -> io::Result<CommandResult> {
+    let result = shell.run_helper(cmd)?;
+    if result.exit_code != exit_code {
+        shell.run_helper(&format!("cmd /c exit {}", exit_code))?;
+    }
+    Ok(result)
+}
+
+/// A temporary drive letter CMD assigned when PUSHD was given a UNC path
+#[derive(Debug, Clone, PartialEq)]
+pub struct UncMapping {
+    pub depth: usize,
+    pub drive: String,
+    pub unc_path: String,
+}
+
+fn is_unc_path(path: &str) -> bool {
+    path.starts_with("\\\\") || path.starts_with("//")
+}
+
+fn has_drive_prefix(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+/// Compare two IF operands with EQU/NEQ/LSS/LEQ/GTR/GEQ, numerically when
+/// both sides are numbers and case-insensitively as strings otherwise
+fn compare_values(left: &str, op: &str, right: &str) -> bool {
+    // Try to parse as numbers for numeric comparison
+    let left_num = left.trim().parse::<i32>();
+    let right_num = right.trim().parse::<i32>();
+
+    match (left_num, right_num) {
+        (Ok(l), Ok(r)) => {
+            // Numeric comparison
+            match op.to_uppercase().as_str() {
+                "EQU" => l == r,
+                "NEQ" => l != r,
+                "LSS" => l < r,
+                "LEQ" => l <= r,
+                "GTR" => l > r,
+                "GEQ" => l >= r,
+                _ => false,
+            }
+        }
+        _ => {
+            // String comparison (case-insensitive)
+            let (l, r) = (left.to_lowercase(), right.to_lowercase());
+            match op.to_uppercase().as_str() {
+                "EQU" => l == r,
+                "NEQ" => l != r,
+                "LSS" => l < r,
+                "LEQ" => l <= r,
+                "GTR" => l > r,
+                "GEQ" => l >= r,
+                _ => false,
+            }
+        }
+    }
+}
+
+/// An argument as CMD receives it: quoted when it holds spaces
+fn quote_argument(arg: &str) -> String {
+    if arg.contains([' ', '\t']) && !arg.starts_with('"') {
+        format!("\"{}\"", arg)
+    } else {
+        arg.to_string()
+    }
+}
+
+/// Lay a SETLOCAL scope over the variables visible outside it: its own
+/// values win and the ones it cleared are gone
+fn overlay_scope(
+    visible: &mut HashMap<String, String>,
+    locals: &HashMap<String, String>,
+    undefined: &HashSet<String>,
+) {
+    visible.retain(|name, _| !undefined.contains(name));
+    visible.extend(locals.clone());
+}
+
+/// Whether `line` is a SET /A assignment
+fn is_set_arithmetic(line: &str) -> bool {
+    let upper = line.trim_start().to_uppercase();
+    upper.starts_with("SET ") && upper[3..].trim_start().starts_with("/A") && upper.contains('=')
+}
+
+/// The variable the SET /A command `line` assigns, without the operator of
+/// a compound assignment (`+=`, `-=`, ...)
+fn set_arithmetic_key(line: &str) -> String {
+    let l = line.trim_start();
+    let expr = l[3..].trim_start()[2..].trim_start();
+    let key = expr.find('=').map_or("", |eq_pos| expr[..eq_pos].trim());
+    key.strip_suffix(['+', '-', '*', '/', '%', '&', '|', '^'])
+        .unwrap_or(key)
+        .trim()
+        .to_string()
+}
+
+/// Whether echoing `text` would do more than print it: an &, |, < or >
+/// outside quotes and not escaped with ^ starts a command or a redirection
+fn runs_commands(text: &str) -> bool {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for ch in text.chars() {
+        match ch {
+            _ if escaped => escaped = false,
+            '^' if !in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '&' | '|' | '<' | '>' | '\n' if !in_quotes => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Levenshtein distance between two strings, counted in chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = previous[j] + usize::from(ca != *cb);
+            current.push(substitute.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Apply `%~` modifiers (`f d p n x s a t z`, as in `%~dp0` or `%~nx1`) to
+/// an argument value. No modifiers just removes the quotes; relative paths
+/// are taken from `cwd`. `s`, `a` and `t` aren't emulated.
+pub fn expand_argument_modifiers(modifiers: &str, value: &str, cwd: &Path) -> String {
+    let value = value.trim_matches('"');
+    let modifiers = modifiers.to_ascii_lowercase();
+    if modifiers.is_empty() || value.is_empty() {
+        return value.to_string();
+    }
+
+    let full = resolve_path(cwd, value).to_string_lossy().into_owned();
+    let (drive, rest) = if has_drive_prefix(&full) {
+        full.split_at(2)
+    } else {
+        ("", full.as_str())
+    };
+    let (dir, file) = match rest.rfind(['\\', '/']) {
+        Some(i) => rest.split_at(i + 1),
+        None => ("", rest),
+    };
+    let (name, ext) = match file.rfind('.') {
+        Some(i) => file.split_at(i),
+        None => (file, ""),
+    };
+
+    let mut parts = Vec::new();
+    if modifiers.contains('z') {
+        if let Ok(meta) = std::fs::metadata(&full) {
+            parts.push(meta.len().to_string());
+        }
+    }
+    let has = |c: char| modifiers.contains(c);
+    let mut path = String::new();
+    if has('f') && !(has('d') || has('p') || has('n') || has('x')) {
+        path = full.clone();
+    } else {
+        if has('d') {
+            path.push_str(drive);
+        }
+        if has('p') {
+            path.push_str(dir);
+        }
+        if has('n') {
+            path.push_str(name);
+        }
+        if has('x') {
+            path.push_str(ext);
+        }
+    }
+    if !path.is_empty() || parts.is_empty() {
+        parts.push(path);
+    }
+    parts.join(" ")
+}
+
+/// Resolve a PUSHD/CD target against the tracked current directory
+fn resolve_path(base: &Path, path: &str) -> PathBuf {
+    let joined = if has_drive_prefix(path) || Path::new(path).is_absolute() {
+        PathBuf::from(path)
+    } else if path.starts_with('\\') || path.starts_with('/') {
+        // Root-relative: stays on the drive of the current directory
+        let base_str = base.to_string_lossy();
+        if has_drive_prefix(&base_str) {
+            PathBuf::from(format!("{}{}", &base_str[..2], path))
+        } else {
+            PathBuf::from(path)
+        }
+    } else {
+        base.join(path)
+    };
+
+    let mut normalized = PathBuf::new();
+    for component in joined.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other.as_os_str()),
+        }
+    }
+    normalized
+}
 
 pub struct DebugContext {
-    session: CmdSession,
+    session: SharedShell,
+    // Kept outside the shell lock, usable while a command runs
+    killer: SessionKiller,
+    record: SessionRecord,
+    shell_config: ShellConfig,
+    command_timeout: Duration,
     pub variables: HashMap<String, String>,
     pub call_stack: Vec<Frame>,
+    root_scopes: Vec<LocalScope>, // SETLOCALs of the top level, outside any CALL
     pub last_exit_code: i32,
     breakpoints: Breakpoints,
     mode: RunMode,
     step_out_target_depth: usize,
     pub continue_requested: bool,
+    resume_signal: Arc<Condvar>, // Wakes the executor waiting at a stop
     pub current_line: Option<usize>,
-    data_breakpoints: HashMap<String, String>, // variable name -> previous value
+    pub main_pc: usize, // Line the top-level script is executing (CALL site while in a subroutine)
+    data_breakpoints: HashMap<String, DataBreakpoint>, // variable name -> breakpoint
     pub data_breakpoint_hit: Option<(String, String, String)>, // (var_name, old_value, new_value)
     directory_stack: Vec<String>,              // PUSHD/POPD directory stack
+    current_dir: PathBuf,                      // Working directory of the script
+    unc_mappings: Vec<UncMapping>,             // Drives mapped by PUSHD on UNC paths
+    variable_origins: HashMap<String, VariableOrigin>, // Where each global's value came from
+    variable_history: HashMap<String, VecDeque<VariableChange>>, // Bounded per-variable change log
+    stop_snapshot: HashMap<String, String>, // Visible variables at the previous stop
+    changed_since_stop: HashSet<String>, // Variables that differ from the previous stop
+    output_breakpoints: Vec<Regex>, // Patterns that pause when command output matches
+    break_on_external: bool, // Pause before every non-builtin command
+    break_on_command_not_found: bool, // Stop when CMD can't find a command (exception filter)
+    external_allowlist: Vec<String>, // External commands that never pause (uppercase)
+    eval_cache: HashMap<String, String>, // Query command -> output, valid until the script moves
+    eval_cache_hits: usize,
+    eval_cache_misses: usize,
+    snapshots: VecDeque<StateSnapshot>, // State before each recently executed line
+    pending_input: VecDeque<String>,    // Answers for upcoming SET /P and CHOICE prompts
+    awaiting_input: bool,               // Stopped at a prompt until input is provided
+    wait_on_pause: bool,                // PAUSE stops like a breakpoint instead of being skipped
+    script_path: String,                // %0 of the top-level script
+    script_args: Vec<String>,           // %1..%n of the top-level script
+    stop_text: Option<String>,          // Detail for the current stop (e.g. which FOR iteration)
+    script_breakpoints: HashMap<PathBuf, Breakpoints>, // Breakpoints in CALLed batch files
+    scripts: HashMap<PathBuf, Arc<Script>>, // CALLed batch files loaded so far
+    step_into_called_scripts: bool,     // CALL file.bat is stepped into, not run whole
+    called_scripts: Vec<Arc<Script>>,   // Batch files CALLed since the server last looked
+    log: DebugLog,                      // Trace log shared with the DAP server
+    echo_on: bool,                      // ECHO state, CMD starts with it on
+    verbose_console: bool,              // Show the executor's own notes (IF, FOR, redirections)
+    coverage: Coverage,                 // Lines of the launched script that ran
+    profile: Profile,                   // Time each line of the launched script took
+    external_commands: Vec<(usize, String)>, // Programs the launched script ran: logical line, command
+    progress: Progress,                      // Progress of long FOR loops, for the client
+    warnings: Vec<(Option<PathBuf>, usize, String)>, // For the client's console: script, logical line, text
+    children: ChildProcesses,                        // Programs STARTed without /WAIT
+    fast_forward_delays: bool, // Skip TIMEOUT and other waits instead of sitting them out
+    run_summary: Option<RunSummary>, // How the last run ended, once it has
+    step_granularity: StepGranularity, // Whether steps stop between the commands of a line
+    statement_column: Option<usize>, // Column of the command a statement step stopped at
+    pending_jump: Option<usize>, // Line a goto request sends the stopped script to
 }
 
 impl DebugContext {
-    pub fn new(session: CmdSession) -> Self {
+    pub fn new(session: impl Shell + 'static) -> Self {
+        let current_dir = resolve_path(&session.working_dir(), ".");
+        let killer = session.killer();
+        let record = session.record();
+        let shell_config = session.shell_config();
+        let command_timeout = session.timeout();
         Self {
-            session,
+            session: Arc::new(Mutex::new(Box::new(session))),
+            killer,
+            record,
+            shell_config,
+            command_timeout,
             variables: HashMap::new(),
             call_stack: Vec::new(),
+            root_scopes: Vec::new(),
             last_exit_code: 0,
             data_breakpoints: HashMap::new(),
             data_breakpoint_hit: None,
@@ -32,13 +346,171 @@ impl DebugContext {
             mode: RunMode::Continue,
             step_out_target_depth: 0,
             continue_requested: false,
+            resume_signal: Arc::new(Condvar::new()),
             current_line: None,
+            main_pc: 0,
             directory_stack: Vec::new(),
+            current_dir,
+            unc_mappings: Vec::new(),
+            variable_origins: HashMap::new(),
+            variable_history: HashMap::new(),
+            stop_snapshot: HashMap::new(),
+            changed_since_stop: HashSet::new(),
+            output_breakpoints: Vec::new(),
+            break_on_external: false,
+            break_on_command_not_found: false,
+            external_allowlist: vec!["FINDSTR".to_string(), "WHERE".to_string()],
+            eval_cache: HashMap::new(),
+            eval_cache_hits: 0,
+            eval_cache_misses: 0,
+            snapshots: VecDeque::new(),
+            pending_input: VecDeque::new(),
+            awaiting_input: false,
+            wait_on_pause: false,
+            script_path: String::new(),
+            script_args: Vec::new(),
+            stop_text: None,
+            script_breakpoints: HashMap::new(),
+            scripts: HashMap::new(),
+            step_into_called_scripts: true,
+            called_scripts: Vec::new(),
+            log: DebugLog::new(),
+            echo_on: true,
+            verbose_console: false,
+            coverage: Coverage::new(),
+            profile: Profile::new(),
+            external_commands: Vec::new(),
+            progress: Progress::default(),
+            warnings: Vec::new(),
+            children: ChildProcesses::new(),
+            fast_forward_delays: false,
+            run_summary: None,
+            step_granularity: StepGranularity::Line,
+            statement_column: None,
+            pending_jump: None,
         }
     }
 
-    pub fn session_mut(&mut self) -> &mut CmdSession {
-        &mut self.session
+    pub fn session(&self) -> MutexGuard<'_, Box<dyn Shell>> {
+        lock_shell(&self.session)
+    }
+
+    pub fn session_mut(&mut self) -> MutexGuard<'_, Box<dyn Shell>> {
+        // Callers run script code directly, cached evaluations may go stale
+        self.invalidate_eval_cache();
+        lock_shell(&self.session)
+    }
+
+    /// The shell for running a script command without the context locked.
+    /// Whatever the command changes is unknown until its result is recorded,
+    /// so cached evaluations are dropped.
+    pub fn shared_session(&mut self) -> SharedShell {
+        self.invalidate_eval_cache();
+        self.session.clone()
+    }
+
+    /// The shell for running an evaluation query without the context
+    /// locked. Queries have no side effects, so cached evaluations stay.
+    pub fn query_session(&self) -> SharedShell {
+        self.session.clone()
+    }
+
+    /// Whether a command is running in the shell right now
+    pub fn session_busy(&self) -> bool {
+        matches!(self.session.try_lock(), Err(TryLockError::WouldBlock))
+    }
+
+    /// Trace log the executor writes to
+    pub fn log(&self) -> DebugLog {
+        self.log.clone()
+    }
+
+    pub fn set_log(&mut self, log: DebugLog) {
+        self.log = log;
+    }
+
+    /// Whether the executor's notes about what it is doing go to the console
+    /// along with the script's output
+    pub fn verbose_console(&self) -> bool {
+        self.verbose_console
+    }
+
+    pub fn set_verbose_console(&mut self, verbose: bool) {
+        self.verbose_console = verbose;
+    }
+
+    /// Record how the run ended, for the terminated event
+    pub fn set_run_summary(&mut self, summary: RunSummary) {
+        self.run_summary = Some(summary);
+    }
+
+    pub fn run_summary(&self) -> Option<&RunSummary> {
+        self.run_summary.as_ref()
+    }
+
+    /// Skip lines that only wait (TIMEOUT, WAITFOR, ping as a sleep)
+    pub fn set_fast_forward_delays(&mut self, enabled: bool) {
+        self.fast_forward_delays = enabled;
+    }
+
+    pub fn fast_forward_delays(&self) -> bool {
+        self.fast_forward_delays
+    }
+
+    /// What CMD prints for `line` before running it: the prompt and the
+    /// command while ECHO is on and the line has no `@`. Tracks ECHO ON/OFF,
+    /// which takes effect from the next line.
+    pub fn echo_line(&mut self, line: &str) -> Option<String> {
+        let text = line.trim();
+        let command = text.trim_start_matches('@').trim_start();
+        let echoed = (self.echo_on && !text.starts_with('@'))
+            .then(|| format!("{}>{}\r\n", self.current_dir.display(), command));
+
+        let words: Vec<&str> = command.split_whitespace().collect();
+        if let [echo, state] = words.as_slice() {
+            if echo.eq_ignore_ascii_case("echo") {
+                if state.eq_ignore_ascii_case("on") {
+                    self.echo_on = true;
+                } else if state.eq_ignore_ascii_case("off") {
+                    self.echo_on = false;
+                }
+            }
+        }
+        echoed
+    }
+
+    /// Shell the script runs in
+    pub fn shell_config(&self) -> ShellConfig {
+        self.shell_config.clone()
+    }
+
+    /// Handle the DAP server uses to stop the script while it runs
+    pub fn session_killer(&self) -> SessionKiller {
+        self.killer.clone()
+    }
+
+    /// Whether the session was terminated and execution should stop
+    pub fn is_cancelled(&self) -> bool {
+        self.killer.is_cancelled()
+    }
+
+    /// How long a script line may run before it is abandoned
+    pub fn command_timeout(&self) -> Duration {
+        self.command_timeout
+    }
+
+    pub fn set_command_timeout(&mut self, timeout: Duration) {
+        self.command_timeout = timeout;
+        self.session().set_timeout(timeout);
+    }
+
+    /// Restart a session that stopped responding or exited, restoring the
+    /// variables visible to the script and its working directory
+    pub fn recover_session(&mut self) -> io::Result<()> {
+        self.invalidate_eval_cache();
+        let variables = self.get_visible_variables();
+        let cwd = self.current_dir.clone();
+        self.session().restart(&variables, &cwd)
     }
 
     pub fn mode(&self) -> RunMode {
@@ -49,28 +521,100 @@ impl DebugContext {
         self.mode = mode;
     }
 
+    pub fn step_granularity(&self) -> StepGranularity {
+        self.step_granularity
+    }
+
+    pub fn set_step_granularity(&mut self, granularity: StepGranularity) {
+        self.step_granularity = granularity;
+    }
+
+    /// 1-based column of the command on the line the executor stopped
+    /// between, when a statement step stopped there; cleared by `mark_stop`
+    pub fn statement_column(&self) -> Option<usize> {
+        self.statement_column
+    }
+
+    pub fn set_statement_column(&mut self, column: Option<usize>) {
+        self.statement_column = column;
+    }
+
+    /// Have the script go on from logical line `target` of the innermost
+    /// frame's file when it resumes, instead of running the line it is at
+    pub fn set_pending_jump(&mut self, target: usize) {
+        self.pending_jump = Some(target);
+    }
+
+    pub fn has_pending_jump(&self) -> bool {
+        self.pending_jump.is_some()
+    }
+
+    pub fn take_pending_jump(&mut self) -> Option<usize> {
+        self.pending_jump.take()
+    }
+
     pub fn handle_setlocal(&mut self) {
-        if let Some(frame) = self.call_stack.last_mut() {
-            frame.has_setlocal = true;
-            eprintln!("SETLOCAL: Created new variable scope");
+        match self.call_stack.last_mut() {
+            Some(frame) => frame.has_setlocal = true,
+            None => self.root_scopes.push(LocalScope::default()),
         }
+        eprintln!("SETLOCAL: Created new variable scope");
     }
 
+    /// Drop the innermost SETLOCAL scope. The session is interactive, where
+    /// CMD ignores SETLOCAL, so the values the scope hid are put back in it.
     pub fn handle_endlocal(&mut self) {
-        if let Some(frame) = self.call_stack.last_mut() {
-            if frame.has_setlocal {
+        let before = self.get_visible_variables();
+        match self.call_stack.last_mut() {
+            Some(frame) if frame.has_setlocal => {
                 frame.locals.clear();
+                frame.local_origins.clear();
+                frame.local_undefined.clear();
                 frame.has_setlocal = false;
-                eprintln!("ENDLOCAL: Restored previous scope");
             }
+            // ENDLOCAL can't end a scope its caller started
+            Some(_) => return,
+            None => {
+                if self.root_scopes.pop().is_none() {
+                    return;
+                }
+            }
+        }
+        eprintln!("ENDLOCAL: Restored previous scope");
+        let after = self.get_visible_variables();
+        if let Err(e) = self.sync_session_variables(&before, &after) {
+            eprintln!("WARNING: Failed to restore variables after ENDLOCAL: {}", e);
         }
     }
-    pub fn get_visible_variables(&self) -> HashMap<String, String> {
+
+    /// The implicit ENDLOCAL of every scope still open when the script ends
+    pub fn end_script_scopes(&mut self) {
+        while !self.root_scopes.is_empty() {
+            self.handle_endlocal();
+        }
+    }
+
+    /// Number of SETLOCALs open at the top level of the script
+    pub fn root_scope_depth(&self) -> usize {
+        self.root_scopes.len()
+    }
+
+    /// Variables as the top level of the script sees them: globals overlaid
+    /// with its SETLOCAL scopes
+    pub fn top_level_variables(&self) -> HashMap<String, String> {
         let mut visible = self.variables.clone();
+        for scope in &self.root_scopes {
+            overlay_scope(&mut visible, &scope.locals, &scope.undefined);
+        }
+        visible
+    }
+
+    pub fn get_visible_variables(&self) -> HashMap<String, String> {
+        let mut visible = self.top_level_variables();
         // Overlay local variables from current frame if SETLOCAL is active
         if let Some(frame) = self.call_stack.last() {
             if frame.has_setlocal {
-                visible.extend(frame.locals.clone());
+                overlay_scope(&mut visible, &frame.locals, &frame.local_undefined);
             }
         }
 
@@ -87,6 +631,292 @@ impl DebugContext {
         HashMap::new()
     }
 
+    /// Called whenever execution stops: works out which visible variables
+    /// (and ERRORLEVEL) changed since the previous stop
+    pub fn mark_stop(&mut self) {
+        let mut snapshot = self.get_visible_variables();
+        snapshot.insert("ERRORLEVEL".to_string(), self.last_exit_code.to_string());
+
+        self.changed_since_stop = snapshot
+            .iter()
+            .filter(|(name, value)| self.stop_snapshot.get(*name) != Some(*value))
+            .map(|(name, _)| name.clone())
+            .collect();
+        self.stop_snapshot = snapshot;
+        self.stop_text = None;
+        self.statement_column = None;
+        // Only a resume requested from now on is for this stop
+        self.continue_requested = false;
+    }
+
+    /// Describe the current stop beyond its reason; cleared by `mark_stop`
+    pub fn set_stop_text(&mut self, text: Option<String>) {
+        self.stop_text = text;
+    }
+
+    pub fn stop_text(&self) -> Option<&str> {
+        self.stop_text.as_deref()
+    }
+
+    /// Whether a variable changed between the previous stop and this one
+    pub fn changed_since_last_stop(&self, name: &str) -> bool {
+        self.changed_since_stop.contains(name)
+    }
+
+    /// Record the line about to execute in the innermost frame
+    pub fn track_pc(&mut self, pc: usize) {
+        match self.call_stack.last_mut() {
+            Some(frame) => frame.current_pc = pc,
+            None => self.main_pc = pc,
+        }
+    }
+
+    /// Logical line the innermost frame is executing
+    pub fn current_pc(&self) -> usize {
+        self.call_stack
+            .last()
+            .map(|frame| frame.current_pc)
+            .unwrap_or(self.main_pc)
+    }
+
+    /// Store (or with `None`, delete) a variable in the active scope and
+    /// record the change in its history. Returns true when stored locally.
+    fn store_variable(
+        &mut self,
+        name: &str,
+        value: Option<String>,
+        origin: VariableOrigin,
+    ) -> bool {
+        // Values captured from colored tool output shouldn't keep the colors
+        let value = value.map(|v| ansi::strip(&v));
+        let line = self.current_pc();
+        let (old_value, local) = match (self.call_stack.last_mut(), self.root_scopes.last_mut()) {
+            (Some(frame), _) if frame.has_setlocal => {
+                let origin = match origin {
+                    VariableOrigin::Script => VariableOrigin::FrameLocal,
+                    other => other,
+                };
+                // Cleared, the variable stays undefined in the scope
+                // whatever it is outside
+                let old = match value {
+                    Some(ref v) => {
+                        frame.local_undefined.remove(name);
+                        frame.local_origins.insert(name.to_string(), origin);
+                        frame.locals.insert(name.to_string(), v.clone())
+                    }
+                    None => {
+                        frame.local_undefined.insert(name.to_string());
+                        frame.local_origins.remove(name);
+                        frame.locals.remove(name)
+                    }
+                };
+                (old, true)
+            }
+            // A top-level SETLOCAL covers the subroutines it CALLs too
+            (_, Some(scope)) => {
+                let origin = match origin {
+                    VariableOrigin::Script => VariableOrigin::FrameLocal,
+                    other => other,
+                };
+                let old = match value {
+                    Some(ref v) => {
+                        scope.undefined.remove(name);
+                        scope.origins.insert(name.to_string(), origin);
+                        scope.locals.insert(name.to_string(), v.clone())
+                    }
+                    None => {
+                        scope.undefined.insert(name.to_string());
+                        scope.origins.remove(name);
+                        scope.locals.remove(name)
+                    }
+                };
+                (old, true)
+            }
+            _ => {
+                let old = match value {
+                    Some(ref v) => {
+                        self.variable_origins.insert(name.to_string(), origin);
+                        self.variables.insert(name.to_string(), v.clone())
+                    }
+                    None => {
+                        self.variable_origins.remove(name);
+                        self.variables.remove(name)
+                    }
+                };
+                (old, false)
+            }
+        };
+        self.record_change(name, line, old_value, value);
+        local
+    }
+
+    /// Origin of a global variable (frame_index None) or of a SETLOCAL
+    /// variable in `call_stack[frame_index]`
+    pub fn variable_origin(&self, name: &str, frame_index: Option<usize>) -> VariableOrigin {
+        let origin = match frame_index {
+            Some(i) => self
+                .call_stack
+                .get(i)
+                .and_then(|frame| frame.local_origins.get(name)),
+            None => self
+                .root_scopes
+                .iter()
+                .rev()
+                .find_map(|scope| scope.origins.get(name))
+                .or_else(|| self.variable_origins.get(name)),
+        };
+        origin.copied().unwrap_or(VariableOrigin::Script)
+    }
+
+    /// Seed tracking with variables inherited from the environment
+    pub fn import_environment<I: IntoIterator<Item = (String, String)>>(&mut self, vars: I) {
+        for (name, value) in vars {
+            self.variable_origins
+                .insert(name.clone(), VariableOrigin::Environment);
+            self.variables.insert(name, value);
+        }
+    }
+
+    fn record_change(
+        &mut self,
+        name: &str,
+        line: usize,
+        old_value: Option<String>,
+        new_value: Option<String>,
+    ) {
+        if old_value == new_value {
+            return;
+        }
+        let history = self
+            .variable_history
+            .entry(name.to_uppercase())
+            .or_default();
+        if history.len() >= MAX_HISTORY_PER_VARIABLE {
+            history.pop_front();
+        }
+        history.push_back(VariableChange {
+            line,
+            old_value,
+            new_value,
+        });
+    }
+
+    /// Recorded changes of a variable, oldest first (name is case-insensitive)
+    pub fn variable_history(&self, name: &str) -> Vec<VariableChange> {
+        self.variable_history
+            .get(&name.to_uppercase())
+            .map(|h| h.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Recorded changes of every variable, by uppercased name
+    pub fn variable_histories(&self) -> Vec<(String, Vec<VariableChange>)> {
+        let mut histories: Vec<(String, Vec<VariableChange>)> = self
+            .variable_history
+            .iter()
+            .map(|(name, h)| (name.clone(), h.iter().cloned().collect()))
+            .collect();
+        histories.sort_by(|a, b| a.0.cmp(&b.0));
+        histories
+    }
+
+    /// Logical line of the most recent change that gave a variable `value`
+    pub fn line_where_set(&self, name: &str, value: Option<&str>) -> Option<usize> {
+        self.variable_history
+            .get(&name.to_uppercase())?
+            .iter()
+            .rev()
+            .find(|change| change.new_value.as_deref() == value)
+            .map(|change| change.line)
+    }
+
+    /// Remember the tracked state before logical line `pc` (`line`) executes
+    pub fn record_snapshot(&mut self, pc: usize, line: &str) {
+        if self.snapshots.len() >= MAX_STEP_BACK {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(StateSnapshot {
+            pc,
+            variables: self.variables.clone(),
+            variable_origins: self.variable_origins.clone(),
+            call_stack: self.call_stack.clone(),
+            root_scopes: self.root_scopes.clone(),
+            last_exit_code: self.last_exit_code,
+            current_dir: self.current_dir.clone(),
+            directory_stack: self.directory_stack.clone(),
+            side_effects: has_external_side_effects(line),
+        });
+    }
+
+    /// Bring the session's variables from `before` to `after`: changed
+    /// values, then variables that no longer exist
+    fn sync_session_variables(
+        &mut self,
+        before: &HashMap<String, String>,
+        after: &HashMap<String, String>,
+    ) -> io::Result<()> {
+        let mut names: Vec<&String> = before.keys().chain(after.keys()).collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            match after.get(name) {
+                Some(value) if before.get(name) != Some(value) => {
+                    self.session()
+                        .run_helper(&format!("SET \"{}={}\"", name, value))?;
+                }
+                None => {
+                    self.session().run_helper(&format!("SET \"{}=\"", name))?;
+                }
+                _ => {}
+            }
+        }
+        self.invalidate_eval_cache();
+        Ok(())
+    }
+
+    /// Number of lines that can currently be stepped back over
+    pub fn step_back_depth(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Rewind tracked state to before the most recently executed line and
+    /// push it back into the CMD session. Returns the snapshot restored, whose
+    /// `pc` is where execution resumes; `side_effects` means the undone line
+    /// may have changed files or processes that can't be restored.
+    pub fn step_back(&mut self) -> io::Result<Option<StateSnapshot>> {
+        let snapshot = match self.snapshots.pop_back() {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+
+        let before = self.get_visible_variables();
+        self.variables = snapshot.variables.clone();
+        self.variable_origins = snapshot.variable_origins.clone();
+        self.call_stack = snapshot.call_stack.clone();
+        self.root_scopes = snapshot.root_scopes.clone();
+        self.last_exit_code = snapshot.last_exit_code;
+        self.directory_stack = snapshot.directory_stack.clone();
+        let after = self.get_visible_variables();
+        self.sync_session_variables(&before, &after)?;
+        if self.current_dir != snapshot.current_dir {
+            self.current_dir = snapshot.current_dir.clone();
+            let dir = self.current_dir.to_string_lossy().to_string();
+            self.session().run_helper(&format!("cd /d \"{}\"", dir))?;
+        }
+        self.session()
+            .run_helper(&format!("cmd /c exit {}", self.last_exit_code))?;
+        self.invalidate_eval_cache();
+
+        if snapshot.side_effects {
+            eprintln!(
+                "WARNING: Stepped back over line {} which may have side effects that were not undone",
+                snapshot.pc
+            );
+        }
+        eprintln!("Stepped back to logical line {}", snapshot.pc);
+        Ok(Some(snapshot))
+    }
+
     pub fn print_call_stack(&self, logical: &[LogicalLine]) {
         if self.call_stack.is_empty() {
             eprintln!("\n=== Call Stack: <empty - top level> ===");
@@ -104,14 +934,20 @@ impl DebugContext {
                     String::new()
                 };
                 eprintln!(
-                    "  #{}: return to logical line {} (phys line {}){}",
+                    "  #{} {}: return to logical line {} (phys line {}){}",
                     i,
+                    frame.name(),
                     frame.return_pc,
                     line.phys_start + 1,
                     scope_info
                 );
             } else {
-                eprintln!("  #{}: return to logical line {}", i, frame.return_pc);
+                eprintln!(
+                    "  #{} {}: return to logical line {}",
+                    i,
+                    frame.name(),
+                    frame.return_pc
+                );
             }
         }
         eprintln!();
@@ -131,8 +967,98 @@ impl DebugContext {
         eprintln!();
     }
 
+    /// Run a SET /A command and track the variable it assigns. SET /A
+    /// echoes the result, which is where the new value comes from.
+    fn run_set_arithmetic(&mut self, line: &str) -> io::Result<CommandResult> {
+        let result = self.run_command(line)?;
+        self.last_exit_code = result.exit_code;
+        self.track_set_arithmetic(line, &result.stdout);
+        Ok(result)
+    }
+
+    /// Track the variable the SET /A command `line` assigned, from `value`
+    fn track_set_arithmetic(&mut self, line: &str, value: &str) {
+        let key = set_arithmetic_key(line);
+        let val = value.trim().to_string();
+
+        if !key.is_empty() {
+            // Store in local scope if SETLOCAL is active, otherwise global
+            if self.store_variable(&key, Some(val.clone()), VariableOrigin::Script) {
+                eprintln!("SET /A: {}={} (local scope)", key, val);
+            } else {
+                eprintln!("SET /A: {}={}", key, val);
+            }
+        }
+    }
+
+    /// Run a command typed into the debug console exactly as given, tracking
+    /// what it does to variables and ERRORLEVEL like a script line
+    pub fn run_repl_command(&mut self, cmd: &str) -> io::Result<CommandResult> {
+        if let Some(result) = self.run_directory_command(cmd) {
+            return result;
+        }
+        let result = self.run_command(cmd)?;
+        self.record_command(cmd, &result);
+        Ok(result)
+    }
+
+    /// Carry out a CD, CHDIR, PUSHD or POPD typed into the debug console the
+    /// way the script's own are, so the tracked directory follows it. None
+    /// for other commands, and for a bare CD that only prints the directory.
+    pub fn run_directory_command(&mut self, cmd: &str) -> Option<io::Result<CommandResult>> {
+        let cmd = cmd.trim();
+        let upper = cmd.to_uppercase();
+        let done = if let Some(rest) = strip_cd_command(cmd).filter(|rest| !rest.is_empty()) {
+            self.handle_cd(Some(rest))
+        } else if upper == "PUSHD" || upper.starts_with("PUSHD ") {
+            let rest = cmd[5..].trim();
+            self.handle_pushd((!rest.is_empty()).then_some(rest))
+        } else if upper == "POPD" {
+            self.handle_popd()
+        } else {
+            return None;
+        };
+        Some(done.map(|()| CommandResult {
+            exit_code: self.last_exit_code,
+            ..CommandResult::default()
+        }))
+    }
+
+    /// Track what `cmd` did to variables and ERRORLEVEL, once it ran in the
+    /// session with `result`. A SET /A is tracked from the value it echoed
+    /// instead of running again as `track_set_command` would run it.
+    pub fn record_command(&mut self, cmd: &str, result: &CommandResult) {
+        self.last_exit_code = result.exit_code;
+        if is_set_arithmetic(cmd) {
+            self.track_set_arithmetic(cmd, &result.stdout);
+        } else {
+            self.track_set_command(cmd);
+        }
+    }
+
+    /// Track one command of a composite line that already ran as a whole.
+    /// A SET /A among them echoed its value along with the others' output,
+    /// so its variable is read back from the session.
+    pub fn record_command_part(&mut self, part: &str) {
+        if !is_set_arithmetic(part) {
+            self.track_set_command(part);
+            return;
+        }
+        let key = set_arithmetic_key(part);
+        if key.is_empty() {
+            return;
+        }
+        if let Ok(result) = self.run_internal(&format!("echo %{}%", key)) {
+            self.track_set_arithmetic(part, &result.stdout);
+        }
+    }
+
     pub fn track_set_command(&mut self, line: &str) {
         let l = line.trim_start();
+        if l.to_uppercase().starts_with("CALL ") {
+            self.track_call_set_command(&l[5..]);
+            return;
+        }
         if !l.to_uppercase().starts_with("SET ") {
             return;
         }
@@ -141,46 +1067,8 @@ impl DebugContext {
 
         // Handle SET /A (arithmetic)
         if rest.to_uppercase().starts_with("/A") {
-            let expr = rest[2..].trim_start();
-            if let Some(eq_pos) = expr.find('=') {
-                let mut key = expr[..eq_pos].trim().to_string();
-
-                // Handle compound assignment operators (+=, -=, *=, /=, etc.)
-                // Remove the operator suffix to get the variable name
-                if key.ends_with('+')
-                    || key.ends_with('-')
-                    || key.ends_with('*')
-                    || key.ends_with('/')
-                    || key.ends_with('%')
-                    || key.ends_with('&')
-                    || key.ends_with('|')
-                    || key.ends_with('^')
-                {
-                    key.pop();
-                    key = key.trim().to_string();
-                }
-
-                // Execute the SET /A command and capture the result
-                // SET /A echoes the result, so we capture it
-                if let Ok((output, exit_code)) = self.session.run(line) {
-                    self.last_exit_code = exit_code;
-
-                    // The result is the last line of output (the echoed value)
-                    let val = output.trim().to_string();
-
-                    if !key.is_empty() {
-                        // Store in local scope if SETLOCAL is active, otherwise global
-                        if let Some(frame) = self.call_stack.last_mut() {
-                            if frame.has_setlocal {
-                                frame.locals.insert(key.clone(), val.clone());
-                                eprintln!("SET /A: {}={} (local scope)", key, val);
-                                return;
-                            }
-                        }
-                        self.variables.insert(key.clone(), val.clone());
-                        eprintln!("SET /A: {}={}", key, val);
-                    }
-                }
+            if rest.contains('=') {
+                let _ = self.run_set_arithmetic(line);
             }
             return;
         }
@@ -196,19 +1084,15 @@ impl DebugContext {
                 // We'll query the variable value from the session
                 if !key.is_empty() {
                     let query_cmd = format!("echo %{}%", key);
-                    if let Ok((output, _)) = self.session.run(&query_cmd) {
-                        let val = output.trim().to_string();
+                    if let Ok(result) = self.run_internal(&query_cmd) {
+                        let val = result.stdout.trim().to_string();
 
                         // Store in local scope if SETLOCAL is active, otherwise global
-                        if let Some(frame) = self.call_stack.last_mut() {
-                            if frame.has_setlocal {
-                                frame.locals.insert(key.clone(), val.clone());
-                                eprintln!("SET /P: {}={} (local scope)", key, val);
-                                return;
-                            }
+                        if self.store_variable(&key, Some(val.clone()), VariableOrigin::Script) {
+                            eprintln!("SET /P: {}={} (local scope)", key, val);
+                        } else {
+                            eprintln!("SET /P: {}={}", key, val);
                         }
-                        self.variables.insert(key.clone(), val.clone());
-                        eprintln!("SET /P: {}={}", key, val);
                     }
                 }
             }
@@ -233,16 +1117,215 @@ impl DebugContext {
                 && !key.contains('*')
                 && !key.contains('/')
             {
-                // Store in local scope if SETLOCAL is active, otherwise global
-                if let Some(frame) = self.call_stack.last_mut() {
-                    if frame.has_setlocal {
-                        frame.locals.insert(key, val);
-                        return;
-                    }
+                // SET VAR= with no value deletes the variable; otherwise store
+                // in local scope if SETLOCAL is active, otherwise global
+                let value = if val.is_empty() { None } else { Some(val) };
+                self.store_variable(&key, value, VariableOrigin::Script);
+            }
+        }
+    }
+
+    /// Track `CALL SET X=...`, the idiom for one extra round of expansion
+    /// (`call set RESULT=%%%NAME%%%`). The line is expanded once as the batch
+    /// parser would and once more as CALL does before tracking the SET.
+    fn track_call_set_command(&mut self, inner: &str) {
+        let inner = inner.trim_start();
+        let upper = inner.to_uppercase();
+        // SET /A and /P have to run in the session to be tracked; running
+        // them a second time would repeat their side effects
+        if !upper.starts_with("SET ") || upper[4..].trim_start().starts_with('/') {
+            return;
+        }
+        let parsed = self.expand_percents_once(inner);
+        let called = self.expand_percents_once(&parsed);
+        eprintln!("CALL SET: {} -> {}", inner, called);
+        self.track_set_command(&called);
+    }
+
+    /// One percent-expansion pass over `text`: `%%` becomes `%`, `%1`/`%~1`
+    /// and `%0` come from the innermost frame and `%VAR%` from tracked
+    /// variables (empty when undefined). Substring/replace forms are kept.
+    fn expand_percents_once(&self, text: &str) -> String {
+        let args = self.current_args();
+        let mut out = String::new();
+        let mut rest = text;
+
+        while let Some(pos) = rest.find('%') {
+            out.push_str(&rest[..pos]);
+            let after = &rest[pos + 1..];
+
+            if let Some(stripped) = after.strip_prefix('%') {
+                out.push('%');
+                rest = stripped;
+                continue;
+            }
+
+            if let Some((value, remaining)) = self.argument_reference(after, &args) {
+                out.push_str(&value);
+                rest = remaining;
+                continue;
+            }
+
+            match after.find('%') {
+                Some(end) if !after[..end].contains(':') && end > 0 => {
+                    let name = &after[..end];
+                    out.push_str(&self.lookup_variable(name).unwrap_or_default());
+                    rest = &after[end + 1..];
+                }
+                Some(end) => {
+                    // %VAR:~0,3% and friends, or a stray %, stay literal
+                    out.push('%');
+                    out.push_str(&after[..end + 1]);
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    out.push('%');
+                    rest = after;
                 }
-                self.variables.insert(key, val);
             }
         }
+        out.push_str(rest);
+        out
+    }
+
+    /// The code an EXIT or EXIT /B leaves, from the text after the keyword:
+    /// the number once %VAR% references are expanded, or the current
+    /// ERRORLEVEL when none is given
+    pub fn parse_exit_code(&self, text: &str) -> i32 {
+        let expanded = self.expand_percents_once(text.trim());
+        match expanded.split_whitespace().next() {
+            Some(code) => code.parse::<i32>().unwrap_or(0),
+            None => self.last_exit_code,
+        }
+    }
+
+    /// Arguments the script was launched with. `program` becomes %0; values
+    /// with spaces are quoted the way CMD would have received them.
+    pub fn set_script_args(&mut self, program: &str, args: Vec<String>) {
+        self.script_path = quote_argument(program);
+        self.script_args = args.iter().map(|a| quote_argument(a)).collect();
+    }
+
+    /// %1..%n of the top-level script, after any SHIFTs
+    pub fn script_args(&self) -> &[String] {
+        &self.script_args
+    }
+
+    /// %0 of the top-level script
+    pub fn script_path(&self) -> &str {
+        &self.script_path
+    }
+
+    /// %1..%n in effect: the innermost CALL frame's, or the launch arguments
+    /// at top level
+    pub fn current_args(&self) -> Vec<String> {
+        match self.call_stack.last() {
+            Some(frame) => frame.args.clone().unwrap_or_default(),
+            None => self.script_args.clone(),
+        }
+    }
+
+    /// %0 in effect
+    fn current_arg0(&self) -> String {
+        match self.call_stack.last() {
+            Some(frame) => frame.name(),
+            None => self.script_path.clone(),
+        }
+    }
+
+    /// The value of an argument reference (`1`, `~1`, `~dp0`, ...) at the
+    /// start of `after`, the text following a `%`, and what follows it
+    fn argument_reference<'a>(&self, after: &'a str, args: &[String]) -> Option<(String, &'a str)> {
+        let modified = match after.strip_prefix('~') {
+            Some(modified) => modified,
+            None => {
+                let d = after.chars().next()?.to_digit(10)?;
+                let value = match d {
+                    0 => self.current_arg0(),
+                    n => args.get(n as usize - 1).cloned().unwrap_or_default(),
+                };
+                return Some((value, &after[1..]));
+            }
+        };
+
+        let mods_len = modified
+            .find(|c: char| !"fdpnxsatzFDPNXSATZ".contains(c))
+            .unwrap_or(modified.len());
+        let (modifiers, tail) = modified.split_at(mods_len);
+        let d = tail.chars().next()?.to_digit(10)?;
+        let value = match d {
+            // With modifiers %0 is the batch file even inside a :label
+            0 if !modifiers.is_empty() => match self.current_script() {
+                Some(path) => path.to_string_lossy().into_owned(),
+                None => self.script_path.clone(),
+            },
+            0 => self.current_arg0(),
+            n => args.get(n as usize - 1).cloned().unwrap_or_default(),
+        };
+        Some((
+            expand_argument_modifiers(modifiers, &value, &self.current_dir),
+            &tail[1..],
+        ))
+    }
+
+    /// Substitute %0..%9, %~0..%~9 (with modifiers) and %* from the current arguments, which the
+    /// CMD session can't know about. `%%` and `%VAR%` are left for CMD.
+    pub fn expand_arguments(&self, text: &str) -> String {
+        if !text.contains('%') {
+            return text.to_string();
+        }
+        let args = self.current_args();
+        let mut out = String::new();
+        let mut rest = text;
+
+        while let Some(pos) = rest.find('%') {
+            out.push_str(&rest[..pos]);
+            let after = &rest[pos + 1..];
+
+            if let Some(stripped) = after.strip_prefix('%') {
+                out.push_str("%%");
+                rest = stripped;
+                continue;
+            }
+            if let Some(stripped) = after.strip_prefix('*') {
+                out.push_str(&args.join(" "));
+                rest = stripped;
+                continue;
+            }
+
+            if let Some((value, remaining)) = self.argument_reference(after, &args) {
+                out.push_str(&value);
+                rest = remaining;
+                continue;
+            }
+
+            // %VAR% goes through as a whole so its closing % can't start an
+            // argument reference
+            match after.find('%') {
+                Some(end) => {
+                    out.push('%');
+                    out.push_str(&after[..end + 1]);
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    out.push('%');
+                    rest = after;
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// Break at `logical_line` once (run to line), unless a breakpoint is
+    /// already there
+    pub fn add_temporary_breakpoint(&mut self, logical_line: usize) {
+        self.breakpoints.add_temporary(logical_line);
+    }
+
+    /// Forget run-to-line breakpoints that were never reached
+    pub fn clear_temporary_breakpoints(&mut self) {
+        self.breakpoints.clear_temporary();
     }
 
     pub fn add_breakpoint(&mut self, logical_line: usize) {
@@ -257,11 +1340,214 @@ impl DebugContext {
         self.breakpoints.add_with_condition(logical_line, condition);
     }
 
-    #[allow(dead_code)]
     pub fn remove_breakpoint(&mut self, logical_line: usize) {
         self.breakpoints.remove(logical_line);
     }
 
+    /// Enable or disable a breakpoint, keeping its condition and hit count
+    pub fn set_breakpoint_enabled(&mut self, logical_line: usize, enabled: bool) -> bool {
+        self.breakpoints.set_enabled(logical_line, enabled)
+    }
+
+    /// Breakpoint in a CALLed batch file, `source` being its path
+    pub fn add_breakpoint_in(
+        &mut self,
+        source: &Path,
+        logical_line: usize,
+        condition: Option<String>,
+    ) {
+        self.script_breakpoints
+            .entry(source.to_path_buf())
+            .or_insert_with(Breakpoints::new)
+            .add_with_condition(logical_line, condition);
+    }
+
+    /// Breakpoint `spec` on `logical_line` of `source`, None being the
+    /// launched script
+    pub fn add_breakpoint_spec(
+        &mut self,
+        source: Option<&Path>,
+        logical_line: usize,
+        spec: &BreakpointSpec,
+    ) {
+        match source {
+            Some(source) => self
+                .script_breakpoints
+                .entry(source.to_path_buf())
+                .or_insert_with(Breakpoints::new)
+                .add_spec(logical_line, spec),
+            None => self.breakpoints.add_spec(logical_line, spec),
+        }
+    }
+
+    /// Give the breakpoint on `logical_line` of `source` (None being the
+    /// launched script) the id the client knows it by
+    pub fn set_breakpoint_id(&mut self, source: Option<&Path>, logical_line: usize, id: u64) {
+        let breakpoints = match source {
+            Some(source) => self.script_breakpoints.get_mut(source),
+            None => Some(&mut self.breakpoints),
+        };
+        if let Some(bp) = breakpoints.and_then(|b| b.get_mut(logical_line)) {
+            bp.id = Some(id);
+        }
+    }
+
+    /// Replace the launched script's breakpoints
+    pub fn set_breakpoints(&mut self, breakpoints: Breakpoints) {
+        self.breakpoints = breakpoints;
+    }
+
+    /// Every breakpoint as a breakpoints file keeps it, grouped by batch
+    /// file: None for the launched script, whose lines are `pre`, first
+    pub fn breakpoint_specs(
+        &self,
+        pre: &PreprocessResult,
+    ) -> Vec<(Option<PathBuf>, Vec<BreakpointSpec>)> {
+        let mut specs = vec![(None, self.breakpoints.to_spec(pre))];
+        let mut sources: Vec<&PathBuf> = self.script_breakpoints.keys().collect();
+        sources.sort();
+        for source in sources {
+            if let Some(script) = self.scripts.get(source) {
+                specs.push((
+                    Some(source.clone()),
+                    self.script_breakpoints[source].to_spec(&script.pre),
+                ));
+            }
+        }
+        specs
+    }
+
+    pub fn remove_breakpoint_in(&mut self, source: &Path, logical_line: usize) {
+        if let Some(breakpoints) = self.script_breakpoints.get_mut(source) {
+            breakpoints.remove(logical_line);
+        }
+    }
+
+    pub fn get_breakpoint_in(
+        &self,
+        source: &Path,
+        logical_line: usize,
+    ) -> Option<&crate::debugger::breakpoints::Breakpoint> {
+        self.script_breakpoints
+            .get(source)
+            .and_then(|breakpoints| breakpoints.get(logical_line))
+    }
+
+    /// Breakpoints of the batch file the innermost frame runs in
+    fn active_breakpoints(&mut self) -> &mut Breakpoints {
+        match self.current_script().map(Path::to_path_buf) {
+            Some(script) => self
+                .script_breakpoints
+                .entry(script)
+                .or_insert_with(Breakpoints::new),
+            None => &mut self.breakpoints,
+        }
+    }
+
+    /// Batch file the innermost frame runs in, None for the launched script
+    pub fn current_script(&self) -> Option<&Path> {
+        self.call_stack.last().and_then(|f| f.script.as_deref())
+    }
+
+    /// Count a run of logical line `pc`; only the launched script is covered
+    pub fn record_execution(&mut self, pc: usize) {
+        if self.current_script().is_none() {
+            self.coverage.record(pc);
+        }
+    }
+
+    pub fn coverage(&self) -> &Coverage {
+        &self.coverage
+    }
+
+    /// Time a run of logical line `pc` took in the shell; only the launched
+    /// script is profiled
+    pub fn record_timing(&mut self, pc: usize, start: Instant, duration: Duration) {
+        if self.current_script().is_none() {
+            self.profile.record(pc, start, duration);
+        }
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    /// Note that logical line `pc` ran a program rather than a CMD built-in;
+    /// only the launched script's are kept
+    pub fn record_external_command(&mut self, pc: usize, command: &str) {
+        if self.current_script().is_none() {
+            self.external_commands.push((pc, command.to_string()));
+        }
+    }
+
+    /// Programs the launched script ran, in order, with the logical line
+    /// that ran each
+    pub fn external_commands(&self) -> &[(usize, String)] {
+        &self.external_commands
+    }
+
+    /// Warnings for the client's console since the last call, each with the
+    /// script (None for the launched one) and logical line it is about
+    pub fn take_warnings(&mut self) -> Vec<(Option<PathBuf>, usize, String)> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Tell the client about a breakpoint's broken condition, the first time
+    /// it is hit only
+    fn warn_breakpoint(&mut self, pc: usize, text: String) {
+        match self.active_breakpoints().get_mut(pc) {
+            Some(bp) if !bp.warned => bp.warned = true,
+            _ => return,
+        }
+        eprintln!("WARNING: Breakpoint at logical line {}: {}", pc, text);
+        let script = self.current_script().map(Path::to_path_buf);
+        self.warnings.push((script, pc, text));
+    }
+
+    /// Progress to report to the client; the server takes the queued events
+    pub fn progress(&mut self) -> &mut Progress {
+        &mut self.progress
+    }
+
+    /// A CALLed batch file, read and preprocessed the first time it's needed
+    pub fn load_script(&mut self, path: &Path) -> io::Result<Arc<Script>> {
+        if let Some(script) = self.scripts.get(path) {
+            return Ok(script.clone());
+        }
+        let script = Arc::new(Script::load(path)?);
+        eprintln!(
+            "Loaded {} ({} logical lines)",
+            script.path.display(),
+            script.pre.logical.len()
+        );
+        self.scripts.insert(path.to_path_buf(), script.clone());
+        Ok(script)
+    }
+
+    /// Note that the script stepped into a CALLed batch file
+    pub fn script_called(&mut self, script: Arc<Script>) {
+        self.called_scripts.push(script);
+    }
+
+    /// Batch files CALLed since the last call, once per CALL
+    pub fn take_called_scripts(&mut self) -> Vec<Arc<Script>> {
+        std::mem::take(&mut self.called_scripts)
+    }
+
+    /// A CALLed batch file that was already loaded
+    pub fn script(&self, path: &Path) -> Option<Arc<Script>> {
+        self.scripts.get(path).cloned()
+    }
+
+    /// Step into `CALL file.bat` (the default) or run it as one command
+    pub fn set_step_into_called_scripts(&mut self, enabled: bool) {
+        self.step_into_called_scripts = enabled;
+    }
+
+    pub fn step_into_called_scripts(&self) -> bool {
+        self.step_into_called_scripts
+    }
+
     pub fn get_breakpoint(
         &self,
         logical_line: usize,
@@ -269,106 +1555,503 @@ impl DebugContext {
         self.breakpoints.get(logical_line)
     }
 
-    /// Add a data breakpoint on a variable
-    pub fn add_data_breakpoint(&mut self, variable_name: String) {
-        let visible = self.get_visible_variables();
-        let current_value = visible.get(&variable_name).cloned().unwrap_or_default();
-        self.data_breakpoints
-            .insert(variable_name.clone(), current_value);
-        eprintln!("Added data breakpoint on variable: {}", variable_name);
+    /// Add a data breakpoint on a variable
+    pub fn add_data_breakpoint(&mut self, variable_name: String) {
+        self.add_data_breakpoint_with_condition(variable_name, None, false);
+    }
+
+    /// Add a data breakpoint that only fires when `condition` holds (with `$NEW`
+    /// and `$OLD` replaced by the new and old values), or only on deletion
+    pub fn add_data_breakpoint_with_condition(
+        &mut self,
+        variable_name: String,
+        condition: Option<String>,
+        break_on_delete: bool,
+    ) {
+        let visible = self.get_visible_variables();
+        let previous = visible.get(&variable_name).cloned();
+        if let Some(ref cond) = condition {
+            eprintln!(
+                "Added data breakpoint on variable: {} with condition: {}",
+                variable_name, cond
+            );
+        } else if break_on_delete {
+            eprintln!("Added delete data breakpoint on variable: {}", variable_name);
+        } else {
+            eprintln!("Added data breakpoint on variable: {}", variable_name);
+        }
+        self.data_breakpoints.insert(
+            variable_name,
+            DataBreakpoint {
+                previous,
+                condition,
+                break_on_delete,
+                id: None,
+            },
+        );
+    }
+
+    /// Give the data breakpoint on `variable_name` the id the client knows
+    /// it by
+    pub fn set_data_breakpoint_id(&mut self, variable_name: &str, id: u64) {
+        if let Some(dbp) = self.data_breakpoints.get_mut(variable_name) {
+            dbp.id = Some(id);
+        }
+    }
+
+    /// Remove a data breakpoint
+    pub fn remove_data_breakpoint(&mut self, variable_name: &str) {
+        self.data_breakpoints.remove(variable_name);
+        eprintln!("Removed data breakpoint on variable: {}", variable_name);
+    }
+
+    /// Check if any data breakpoints were hit (variable changed)
+    pub fn check_data_breakpoints(&mut self) -> bool {
+        self.data_breakpoint_hit = None;
+        let visible = self.get_visible_variables();
+
+        // Collect changes first, conditions need the session to evaluate
+        let mut changed: Vec<(String, Option<String>, Option<String>, DataBreakpoint)> = self
+            .data_breakpoints
+            .iter()
+            .filter_map(|(var_name, bp)| {
+                let new_value = visible.get(var_name).cloned();
+                if new_value != bp.previous {
+                    Some((var_name.clone(), bp.previous.clone(), new_value, bp.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        changed.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (var_name, old_value, new_value, bp) in changed {
+            let old_str = old_value.clone().unwrap_or_default();
+            let new_str = new_value.clone().unwrap_or_default();
+
+            let hit = if bp.break_on_delete {
+                new_value.is_none()
+            } else if let Some(ref condition) = bp.condition {
+                let substituted = condition.replace("$NEW", &new_str).replace("$OLD", &old_str);
+                match self.evaluate_condition(&substituted) {
+                    Ok(result) => result,
+                    Err(e) => {
+                        eprintln!(
+                            "WARNING: Data breakpoint condition error: {} - {}",
+                            condition, e
+                        );
+                        // On error, stop anyway (safer)
+                        true
+                    }
+                }
+            } else {
+                true
+            };
+
+            if hit {
+                match self.line_where_set(&var_name, old_value.as_deref()) {
+                    Some(line) => eprintln!(
+                        "Data breakpoint hit: {} changed from '{}' (set at logical line {}) to '{}'",
+                        var_name, old_str, line, new_str
+                    ),
+                    None => eprintln!(
+                        "Data breakpoint hit: {} changed from '{}' to '{}'",
+                        var_name, old_str, new_str
+                    ),
+                }
+                self.data_breakpoint_hit = Some((var_name, old_str, new_str));
+                return true;
+            }
+
+            // Not a hit, but remember the value so $OLD stays accurate
+            if let Some(bp) = self.data_breakpoints.get_mut(&var_name) {
+                bp.previous = new_value;
+            }
+        }
+        false
+    }
+
+    /// Update data breakpoint previous values after stopping
+    pub fn update_data_breakpoints(&mut self) {
+        let visible = self.get_visible_variables();
+        for (var_name, bp) in self.data_breakpoints.iter_mut() {
+            bp.previous = visible.get(var_name).cloned();
+        }
+    }
+
+    /// Get all data breakpoints
+    pub fn get_data_breakpoints(&self) -> &HashMap<String, DataBreakpoint> {
+        &self.data_breakpoints
+    }
+
+    /// Pause whenever a line of command output matches `pattern` (a regex)
+    pub fn add_output_breakpoint(&mut self, pattern: &str) -> io::Result<()> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        if !self.output_breakpoints.iter().any(|r| r.as_str() == pattern) {
+            self.output_breakpoints.push(regex);
+            eprintln!("Added output breakpoint: {}", pattern);
+        }
+        Ok(())
+    }
+
+    /// Remove an output breakpoint by its pattern text
+    pub fn remove_output_breakpoint(&mut self, pattern: &str) -> bool {
+        let before = self.output_breakpoints.len();
+        self.output_breakpoints.retain(|r| r.as_str() != pattern);
+        before != self.output_breakpoints.len()
+    }
+
+    pub fn clear_output_breakpoints(&mut self) {
+        self.output_breakpoints.clear();
+    }
+
+    /// Patterns of all output breakpoints, in the order they were added
+    pub fn get_output_breakpoints(&self) -> Vec<&str> {
+        self.output_breakpoints.iter().map(|r| r.as_str()).collect()
+    }
+
+    /// Find the first output line matching an output breakpoint.
+    /// Returns (pattern, matching line).
+    pub fn check_output_breakpoints(&self, output: &str) -> Option<(String, String)> {
+        if self.output_breakpoints.is_empty() {
+            return None;
+        }
+        output.lines().find_map(|line| {
+            self.output_breakpoints
+                .iter()
+                .find(|r| r.is_match(line))
+                .map(|r| (r.as_str().to_string(), line.trim_end().to_string()))
+        })
+    }
+
+    /// Pause before every external (non-builtin) command
+    pub fn set_break_on_external(&mut self, enabled: bool) {
+        self.break_on_external = enabled;
+        eprintln!("Break on external commands: {}", enabled);
+    }
+
+    pub fn break_on_external(&self) -> bool {
+        self.break_on_external
+    }
+
+    /// Replace the external commands that never pause (e.g. `findstr`).
+    /// Names are compared case-insensitively, with or without extension.
+    pub fn set_external_allowlist(&mut self, names: Vec<String>) {
+        self.external_allowlist = names.iter().map(|n| n.to_uppercase()).collect();
+    }
+
+    /// Whether break-on-external mode should pause before `command`
+    pub fn should_break_on_external(&self, command: &str) -> bool {
+        if !self.break_on_external || command.trim().is_empty() || is_builtin_command(command) {
+            return false;
+        }
+        let name = command_name(command).to_uppercase();
+        let file_name = name.rsplit(['\\', '/']).next().unwrap_or(&name);
+        let stem = file_name.split('.').next().unwrap_or(file_name);
+        !self
+            .external_allowlist
+            .iter()
+            .any(|allowed| allowed == file_name || allowed == stem)
+    }
+
+    /// Programs the script STARTed without waiting for them
+    pub fn child_processes(&self) -> ChildProcesses {
+        self.children.clone()
+    }
+
+    /// Environment a STARTed program inherits: the debugger's own, with the
+    /// script's variables on top
+    pub fn start_environment(&self) -> HashMap<String, String> {
+        let mut env: HashMap<String, String> = std::env::vars().collect();
+        env.extend(self.get_visible_variables());
+        env
+    }
+
+    /// Stop when a line runs a command CMD can't find
+    pub fn set_break_on_command_not_found(&mut self, enabled: bool) {
+        self.break_on_command_not_found = enabled;
+    }
+
+    pub fn break_on_command_not_found(&self) -> bool {
+        self.break_on_command_not_found
+    }
+
+    /// The command a misspelled `name` was probably meant to be: the closest
+    /// builtin, or program on PATH (by `where` in the session) starting with
+    /// the same letter, within a few typos
+    pub fn suggest_command(&mut self, name: &str) -> Option<String> {
+        let name = name.trim_matches('"').to_lowercase();
+        let first = name.chars().next()?;
+        let max_distance = name.chars().count().div_ceil(3).max(1);
+
+        let mut candidates: Vec<String> = BUILTIN_COMMANDS
+            .iter()
+            .map(|builtin| builtin.to_lowercase())
+            .collect();
+        if first.is_ascii_alphanumeric() {
+            if let Ok(result) = self.run_internal(&format!("where \"{}*\" 2>nul", first)) {
+                for line in result.stdout.lines() {
+                    let file = line.trim().rsplit(['\\', '/']).next().unwrap_or_default();
+                    if let Some((stem, ext)) = file.rsplit_once('.') {
+                        if ["exe", "bat", "cmd", "com"]
+                            .iter()
+                            .any(|e| ext.eq_ignore_ascii_case(e))
+                        {
+                            candidates.push(stem.to_lowercase());
+                        }
+                    }
+                }
+            }
+        }
+
+        candidates
+            .into_iter()
+            .filter(|candidate| *candidate != name)
+            .map(|candidate| (edit_distance(&name, &candidate), candidate))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, candidate)| candidate)
     }
 
-    /// Remove a data breakpoint
-    pub fn remove_data_breakpoint(&mut self, variable_name: &str) {
-        self.data_breakpoints.remove(variable_name);
-        eprintln!("Removed data breakpoint on variable: {}", variable_name);
+    /// Resolve an external command to the executable CMD would run, using
+    /// `where` in the session
+    pub fn resolve_executable(&mut self, command: &str) -> Option<String> {
+        let name = command_name(command).trim_matches('"').to_string();
+        if name.is_empty() {
+            return None;
+        }
+        // `where` wants a program given with its directory as `dir:name`
+        let pattern = match name.rfind(['\\', '/']) {
+            Some(i) => {
+                let dir = &name[..i];
+                let dir = if dir.is_empty() || dir.ends_with(':') {
+                    &name[..=i]
+                } else {
+                    dir
+                };
+                format!("{}:{}", dir, &name[i + 1..])
+            }
+            None => name,
+        };
+        let result = self
+            .run_internal(&format!("where \"{}\" 2>nul", pattern))
+            .ok()?;
+        if result.exit_code != 0 {
+            return None;
+        }
+        result
+            .stdout
+            .lines()
+            .map(|l| l.trim())
+            .find(|l| !l.is_empty())
+            .map(|l| l.to_string())
     }
 
-    /// Check if any data breakpoints were hit (variable changed)
-    pub fn check_data_breakpoints(&mut self) -> bool {
-        self.data_breakpoint_hit = None;
-        let visible = self.get_visible_variables();
+    /// Evaluate a breakpoint condition. IF-style conditions (`X GTR 5`,
+    /// `"%A%"=="b"`, `DEFINED X`, ...) are evaluated as IF would; anything
+    /// else is evaluated as an expression and is true when non-empty, non-zero
+    /// and not "false".
+    pub fn evaluate_condition(&mut self, condition: &str) -> io::Result<bool> {
+        // Most conditions only reference tracked variables, so avoid the
+        // session roundtrip when we can
+        if let Some(result) = self.evaluate_condition_locally(condition) {
+            return Ok(result);
+        }
 
-        for (var_name, old_value) in &self.data_breakpoints {
-            let new_value = visible.get(var_name).cloned().unwrap_or_default();
-            if &new_value != old_value {
-                eprintln!(
-                    "Data breakpoint hit: {} changed from '{}' to '{}'",
-                    var_name, old_value, new_value
+        if let Some(if_stmt) = parse_if_statement(&format!("IF {} REM", condition.trim())) {
+            return self.evaluate_if_condition(&if_stmt.condition);
+        }
+
+        let result = self.evaluate_expression(condition)?;
+        let result_trimmed = result.trim();
+        Ok(!result_trimmed.is_empty()
+            && result_trimmed != "0"
+            && !result_trimmed.eq_ignore_ascii_case("false"))
+    }
+
+    /// Evaluate a condition using only tracked state. Returns None when the
+    /// condition needs CMD (EXIST, string operations, dynamic or unknown
+    /// variables). Unquoted bare names that match a variable resolve to it,
+    /// so `COUNTER GEQ 50` works like `%COUNTER% GEQ 50`.
+    pub fn evaluate_condition_locally(&self, condition: &str) -> Option<bool> {
+        let condition = condition.trim();
+
+        let if_stmt = match parse_if_statement(&format!("IF {} REM", condition)) {
+            Some(stmt) => stmt,
+            None => {
+                let value = self.resolve_operand_locally(condition)?;
+                let value = value.trim();
+                return Some(
+                    !value.is_empty() && value != "0" && !value.eq_ignore_ascii_case("false"),
                 );
-                self.data_breakpoint_hit =
-                    Some((var_name.clone(), old_value.clone(), new_value.clone()));
-                return true;
             }
-        }
-        false
+        };
+
+        let result = match &if_stmt.condition {
+            IfCondition::ErrorLevel { not, level } => (self.last_exit_code >= *level) != *not,
+            IfCondition::Defined { not, variable } => {
+                self.lookup_variable(variable).is_some() != *not
+            }
+            IfCondition::StringEqual { not, left, right } => {
+                let left = self.resolve_operand_locally(left)?;
+                let right = self.resolve_operand_locally(right)?;
+                left.eq_ignore_ascii_case(&right) != *not
+            }
+            IfCondition::Compare {
+                not,
+                left,
+                op,
+                right,
+            } => {
+                let left = self.resolve_operand_locally(left)?;
+                let right = self.resolve_operand_locally(right)?;
+                compare_values(&left, op, &right) != *not
+            }
+            IfCondition::Exist { .. } => return None,
+        };
+        Some(result)
     }
 
-    /// Update data breakpoint previous values after stopping
-    pub fn update_data_breakpoints(&mut self) {
+    /// Look up a visible variable, case-insensitively like CMD does
+    fn lookup_variable(&self, name: &str) -> Option<String> {
+        if name.eq_ignore_ascii_case("ERRORLEVEL") {
+            return Some(self.last_exit_code.to_string());
+        }
         let visible = self.get_visible_variables();
-        for (var_name, old_value) in self.data_breakpoints.iter_mut() {
-            let new_value = visible.get(var_name).cloned().unwrap_or_default();
-            *old_value = new_value;
+        if let Some(value) = visible.get(name) {
+            return Some(value.clone());
         }
+        visible
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
     }
 
-    /// Get all data breakpoints
-    pub fn get_data_breakpoints(&self) -> &HashMap<String, String> {
-        &self.data_breakpoints
+    /// Expand an IF operand from tracked variables only
+    fn resolve_operand_locally(&self, text: &str) -> Option<String> {
+        let text = text.trim();
+        if text.contains('!') {
+            return None;
+        }
+
+        if !text.contains('%') {
+            let is_identifier = !text.is_empty()
+                && text
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+            if is_identifier {
+                if let Some(value) = self.lookup_variable(text) {
+                    return Some(value);
+                }
+            }
+            return Some(text.to_string());
+        }
+
+        let mut out = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find('%') {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let end = after.find('%')?;
+            let name = &after[..end];
+            // %VAR:~0,5%, %1 and friends need CMD
+            if name.is_empty() || name.contains(':') || name.starts_with(|c: char| c.is_ascii_digit())
+            {
+                return None;
+            }
+            out.push_str(&self.lookup_variable(name)?);
+            rest = &after[end + 1..];
+        }
+        out.push_str(rest);
+        Some(out)
     }
 
     pub fn should_stop_at(&mut self, pc: usize) -> bool {
         match self.mode {
-            RunMode::Continue => {
-                if !self.breakpoints.contains(pc) {
-                    return false;
-                }
+            RunMode::Continue => self.breakpoint_hit(pc),
+            RunMode::StepOver | RunMode::StepInto | RunMode::StepBack => true,
+            // Breakpoints inside the frame being left still stop
+            RunMode::StepOut => {
+                self.call_stack.len() <= self.step_out_target_depth || self.breakpoint_hit(pc)
+            }
+        }
+    }
 
-                // Extract condition before evaluating to avoid borrow checker issues
-                let condition_opt = self.breakpoints.get(pc).and_then(|bp| bp.condition.clone());
+    /// Whether an enabled breakpoint at `pc` whose condition holds is hit,
+    /// counting the hit
+    fn breakpoint_hit(&mut self, pc: usize) -> bool {
+        match self.active_breakpoints().get(pc) {
+            Some(bp) if bp.enabled => {}
+            _ => return false,
+        }
 
-                // Increment hit count
-                if let Some(bp) = self.breakpoints.get_mut(pc) {
-                    bp.hit_count += 1;
-                }
+        // Extract condition before evaluating to avoid borrow checker issues
+        let (condition_opt, condition_error) = self
+            .active_breakpoints()
+            .get(pc)
+            .map(|bp| (bp.condition.clone(), bp.condition_error.clone()))
+            .unwrap_or_default();
 
-                // Check condition if present
-                if let Some(condition) = condition_opt {
-                    // Evaluate condition
-                    match self.evaluate_expression(&condition) {
-                        Ok(result) => {
-                            let result_trimmed = result.trim();
-                            // Consider non-empty, non-zero as true
-                            let is_true = !result_trimmed.is_empty()
-                                && result_trimmed != "0"
-                                && !result_trimmed.eq_ignore_ascii_case("false");
-
-                            if !is_true {
-                                eprintln!(
-                                    "⊘ Breakpoint condition false: {} = '{}'",
-                                    condition, result_trimmed
-                                );
-                                return false;
-                            }
-                            eprintln!(
-                                "Breakpoint condition true: {} = '{}'",
-                                condition, result_trimmed
-                            );
-                        }
-                        Err(e) => {
-                            eprintln!("WARNING: Breakpoint condition error: {} - {}", condition, e);
-                            // On error, stop anyway (safer)
-                            return true;
-                        }
+        // Increment hit count
+        if let Some(bp) = self.active_breakpoints().get_mut(pc) {
+            bp.hit_count += 1;
+        }
+
+        // A condition that doesn't parse can't be trusted to be false
+        if let (Some(condition), Some(error)) = (&condition_opt, condition_error) {
+            self.warn_breakpoint(
+                pc,
+                format!(
+                    "condition '{}' is invalid ({}), stopping every time",
+                    condition, error
+                ),
+            );
+        } else if let Some(condition) = condition_opt {
+            // Evaluate condition
+            match self.evaluate_condition(&condition) {
+                Ok(is_true) => {
+                    if !is_true {
+                        eprintln!("⊘ Breakpoint condition false: {}", condition);
+                        return false;
                     }
+                    eprintln!("Breakpoint condition true: {}", condition);
+                }
+                Err(e) => {
+                    self.warn_breakpoint(
+                        pc,
+                        format!("condition '{}' failed ({}), stopping", condition, e),
+                    );
+                    // On error, stop anyway (safer)
+                    return true;
                 }
-
-                true
             }
-            RunMode::StepOver | RunMode::StepInto => true,
-            RunMode::StepOut => self.call_stack.len() <= self.step_out_target_depth,
         }
+
+        // Run-to-line breakpoints are consumed by their first stop
+        if self
+            .active_breakpoints()
+            .get(pc)
+            .is_some_and(|bp| bp.temporary)
+        {
+            self.active_breakpoints().remove(pc);
+        }
+
+        true
+    }
+
+    /// Step Out: run until the current frame has returned, stopping on the
+    /// line after its CALL. Out of the script itself that is the end, so it
+    /// runs on like Continue.
+    pub fn arm_step_out(&mut self) {
+        if self.call_stack.is_empty() {
+            self.mode = RunMode::Continue;
+            eprintln!("Step Out of the script, continuing");
+            return;
+        }
+        self.mode = RunMode::StepOut;
+        self.step_out_target_depth = self.call_stack.len() - 1;
+        eprintln!("Step Out (target depth: {})", self.step_out_target_depth);
     }
 
     pub fn handle_step_command(&mut self, step_type: &str) {
@@ -385,57 +2068,279 @@ impl DebugContext {
                 self.mode = RunMode::StepInto;
                 eprintln!("Step Into");
             }
-            "stepOut" => {
-                self.mode = RunMode::StepOut;
-                self.step_out_target_depth = self.call_stack.len().saturating_sub(1);
-                eprintln!("Step Out (target depth: {})", self.step_out_target_depth);
+            "stepBack" => {
+                self.mode = RunMode::StepBack;
+                eprintln!("Step Back");
             }
+            "stepOut" => self.arm_step_out(),
             _ => {
                 eprintln!("Unknown step command: {}", step_type);
             }
         }
     }
 
-    pub fn run_command(&mut self, cmd: &str) -> io::Result<(String, i32)> {
-        self.session.run(cmd)
+    /// Run a script command in the session. Anything may have changed
+    /// afterwards, so cached evaluations are dropped.
+    pub fn run_command(&mut self, cmd: &str) -> io::Result<CommandResult> {
+        self.invalidate_eval_cache();
+        self.session().run(cmd)
+    }
+
+    /// Run the parts of a composite line in turn, see `Shell::run_parts`
+    pub fn run_parts(&mut self, parts: &[CommandPart]) -> io::Result<Vec<CommandResult>> {
+        self.invalidate_eval_cache();
+        self.session().run_parts(parts)
+    }
+
+    /// Like `run_command`, but hands stdout to `on_chunk` line by line while
+    /// the command is still running
+    pub fn run_command_streaming(
+        &mut self,
+        cmd: &str,
+        mut on_chunk: impl FnMut(&str),
+    ) -> io::Result<CommandResult> {
+        self.invalidate_eval_cache();
+        self.session().run_streaming(cmd, &mut on_chunk)
+    }
+
+    /// Run a helper command the script didn't ask for (a variable readback,
+    /// FOR expansion, `where` lookup). The script's ERRORLEVEL is restored
+    /// in the session afterwards and `last_exit_code` is never touched.
+    pub fn run_internal(&mut self, cmd: &str) -> io::Result<CommandResult> {
+        let exit_code = self.last_exit_code;
+        run_helper_in(&mut **self.session(), cmd, exit_code)
+    }
+
+    /// Script and helper command counts and timings of the session
+    pub fn session_stats(&self) -> SessionStats {
+        self.record.stats()
+    }
+
+    /// The last `count` commands the session ran and their results, when
+    /// a transcript is recorded
+    pub fn transcript(&self, count: usize) -> Vec<TranscriptEntry> {
+        self.record.transcript(count)
+    }
+
+    /// Check that the session still answers, returning its latency
+    pub fn ping_session(&mut self) -> io::Result<Duration> {
+        self.session().ping()
+    }
+
+    /// `ping_session`, or None while the shell runs a command and pinging
+    /// would wait for it
+    pub fn ping_idle_session(&mut self) -> Option<io::Result<Duration>> {
+        match self.session.try_lock() {
+            Ok(mut shell) => Some(shell.ping()),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner().ping()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    /// Expand `text` with `echo` in the session, reusing the result for the
+    /// rest of the stop
+    fn cached_echo(&mut self, text: &str) -> io::Result<String> {
+        self.cached_echo_command(&format!("echo {}", text.trim()))
+    }
+
+    /// Run a side-effect free query command, caching its trimmed output
+    fn cached_echo_command(&mut self, cmd: &str) -> io::Result<String> {
+        if let Some(result) = self.cached_query(cmd) {
+            return Ok(result);
+        }
+        let result = self.run_internal(cmd)?;
+        Ok(self.record_query(cmd, &result))
+    }
+
+    fn cached_query(&mut self, cmd: &str) -> Option<String> {
+        let result = self.eval_cache.get(cmd).cloned();
+        if result.is_some() {
+            self.eval_cache_hits += 1;
+        }
+        result
+    }
+
+    /// Cache what the query `cmd` printed, returning it trimmed
+    pub fn record_query(&mut self, cmd: &str, result: &CommandResult) -> String {
+        self.eval_cache_misses += 1;
+        let output = ansi::strip(result.stdout.trim());
+        self.eval_cache.insert(cmd.to_string(), output.clone());
+        output
+    }
+
+    /// The answer to `echo text` if it is cached, otherwise the query
+    fn echo_query(&mut self, text: &str) -> Evaluation {
+        let cmd = format!("echo {}", text.trim());
+        match self.cached_query(&cmd) {
+            Some(result) => Evaluation::Value(result),
+            None => Evaluation::Query(cmd),
+        }
+    }
+
+    /// Carry out an evaluation, running its query in the session
+    fn answer(&mut self, evaluation: Evaluation) -> Result<String, BatchDbgError> {
+        match evaluation {
+            Evaluation::Value(value) => Ok(value),
+            Evaluation::Query(cmd) => {
+                let result = self.run_internal(&cmd)?;
+                let output = self.record_query(&cmd, &result);
+                eprintln!("   Result: '{}'", output);
+                Ok(output)
+            }
+        }
+    }
+
+    pub fn invalidate_eval_cache(&mut self) {
+        self.eval_cache.clear();
+    }
+
+    /// (hits, misses) of the evaluation cache since the context was created
+    pub fn eval_cache_stats(&self) -> (usize, usize) {
+        (self.eval_cache_hits, self.eval_cache_misses)
+    }
+
+    /// Resume execution after a stop, waking the executor
+    pub fn request_continue(&mut self) {
+        self.continue_requested = true;
+        self.invalidate_eval_cache();
+        self.resume_signal.notify_all();
+    }
+
+    /// What the executor waits on while stopped, together with the context's
+    /// own lock. `request_continue` signals it; whoever ends the session
+    /// without the context locked should too.
+    pub fn resume_signal(&self) -> Arc<Condvar> {
+        self.resume_signal.clone()
     }
 
     /// Set a variable value directly (used by DAP setVariable request)
     pub fn set_variable(&mut self, name: &str, value: &str) -> io::Result<()> {
-        // Determine if we're in a SETLOCAL scope
-        let in_local_scope = self
-            .call_stack
-            .last()
-            .map(|frame| frame.has_setlocal)
-            .unwrap_or(false);
-
         // Execute SET command in the CMD session
         let set_cmd = format!("SET {}={}", name, value);
-        let (_, exit_code) = self.run_command(&set_cmd)?;
+        let CommandResult { exit_code, .. } = self.run_command(&set_cmd)?;
         self.last_exit_code = exit_code;
 
-        // Update our tracking
-        if in_local_scope {
-            if let Some(frame) = self.call_stack.last_mut() {
-                frame.locals.insert(name.to_string(), value.to_string());
-            }
+        // Update our tracking (local scope if SETLOCAL is active)
+        self.store_variable(name, Some(value.to_string()), VariableOrigin::Injected);
+
+        eprintln!("Variable set: {}={}", name, value);
+        Ok(())
+    }
+
+    /// Set a variable as seen from one stack frame (ids as in
+    /// `get_frame_visible_variables`). The CMD session only holds the
+    /// innermost frame's scope, so a caller frame can only be changed while
+    /// no SETLOCAL opened below it hides its variables.
+    pub fn set_variable_in_frame(
+        &mut self,
+        name: &str,
+        value: &str,
+        frame_id: usize,
+    ) -> io::Result<()> {
+        let hidden = self
+            .call_stack
+            .iter()
+            .skip(frame_id)
+            .any(|frame| frame.has_setlocal);
+        if frame_id < self.call_stack.len() && hidden {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{} can't be changed in this frame while a SETLOCAL in a routine it CALLed is open",
+                    name
+                ),
+            ));
+        }
+        self.set_variable(name, value)
+    }
+
+    /// Queue an answer for the next SET /P or CHOICE prompt without one
+    pub fn provide_input(&mut self, value: &str) {
+        self.pending_input.push_back(value.to_string());
+    }
+
+    pub fn has_input(&self) -> bool {
+        !self.pending_input.is_empty()
+    }
+
+    pub fn take_input(&mut self) -> Option<String> {
+        self.pending_input.pop_front()
+    }
+
+    /// Whether execution is stopped at a prompt waiting for `provide_input`
+    pub fn awaiting_input(&self) -> bool {
+        self.awaiting_input
+    }
+
+    pub fn set_awaiting_input(&mut self, awaiting: bool) {
+        self.awaiting_input = awaiting;
+    }
+
+    pub fn wait_on_pause(&self) -> bool {
+        self.wait_on_pause
+    }
+
+    pub fn set_wait_on_pause(&mut self, wait: bool) {
+        self.wait_on_pause = wait;
+    }
+
+    /// Complete a SET /P prompt with `value` as if it had been typed. Like
+    /// CMD, an empty answer leaves the variable alone and sets ERRORLEVEL 1.
+    pub fn answer_set_prompt(&mut self, name: &str, value: &str) -> io::Result<()> {
+        if value.is_empty() {
+            self.run_command("cmd /c exit 1")?;
+            self.last_exit_code = 1;
+            return Ok(());
+        }
+        let CommandResult { exit_code, .. } =
+            self.run_command(&format!("set \"{}={}\"", name, value))?;
+        self.last_exit_code = exit_code;
+        if self.store_variable(name, Some(value.to_string()), VariableOrigin::Script) {
+            eprintln!("SET /P: {}={} (local scope)", name, value);
         } else {
-            self.variables.insert(name.to_string(), value.to_string());
+            eprintln!("SET /P: {}={}", name, value);
         }
+        Ok(())
+    }
 
-        eprintln!("Variable set: {}={}", name, value);
+    /// Record that the client interrupted the last command: ERRORLEVEL
+    /// becomes what CMD reports after Ctrl+Break
+    pub fn record_interrupt(&mut self) -> io::Result<()> {
+        self.session()
+            .run_helper(&format!("cmd /c exit {}", INTERRUPTED_EXIT_CODE))?;
+        self.last_exit_code = INTERRUPTED_EXIT_CODE;
+        Ok(())
+    }
+
+    /// Complete a CHOICE prompt: ERRORLEVEL becomes the 1-based position of
+    /// `key` in `choices`.
+    pub fn answer_choice(&mut self, choices: &str, key: char) -> io::Result<()> {
+        let level = choices
+            .chars()
+            .position(|c| c.eq_ignore_ascii_case(&key))
+            .map(|i| i as i32 + 1)
+            .unwrap_or(0);
+        self.run_command(&format!("cmd /c exit {}", level))?;
+        self.last_exit_code = level;
         Ok(())
     }
 
     /// Evaluate an expression (used by DAP evaluate request)
-    pub fn evaluate_expression(&mut self, expression: &str) -> io::Result<String> {
+    pub fn evaluate_expression(&mut self, expression: &str) -> Result<String, BatchDbgError> {
+        let evaluation = self.plan_evaluation(expression)?;
+        self.answer(evaluation)
+    }
+
+    /// How to evaluate an expression: from tracked state where possible,
+    /// otherwise with a query the caller runs in the session
+    pub fn plan_evaluation(&mut self, expression: &str) -> Result<Evaluation, BatchDbgError> {
         let expr = expression.trim();
 
         eprintln!("EVAL: Evaluating expression: '{}'", expr);
 
         // Handle special cases
         if expr.eq_ignore_ascii_case("ERRORLEVEL") || expr == "%ERRORLEVEL%" {
-            return Ok(self.last_exit_code.to_string());
+            return Ok(Evaluation::Value(self.last_exit_code.to_string()));
         }
 
         // Detect string operations for logging
@@ -453,7 +2358,7 @@ impl DebugContext {
             if !var_name.contains(':') {
                 let visible = self.get_visible_variables();
                 if let Some(value) = visible.get(var_name) {
-                    return Ok(value.clone());
+                    return Ok(Evaluation::Value(value.clone()));
                 }
             }
             // Variable with string operations or not found, try executing in CMD
@@ -465,10 +2370,25 @@ impl DebugContext {
             // Simple identifier - try looking it up first
             let visible = self.get_visible_variables();
             if let Some(value) = visible.get(expr) {
-                return Ok(value.clone());
+                return Ok(Evaluation::Value(value.clone()));
             }
         }
 
+        // CMD can't answer until the script's command finishes; waiting would
+        // hold the context (and every other request) until then
+        if self.session_busy() {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "The script is running a command, evaluate again once it stops",
+            )
+            .into());
+        }
+
+        // Echoing `x & del file` would delete the file
+        if runs_commands(expr) {
+            return Err(BatchDbgError::EvalUnsafe(expr.to_string()));
+        }
+
         // For complex expressions (including string operations), execute in CMD and capture output
         // Use echo to evaluate the expression
         // This handles:
@@ -477,15 +2397,132 @@ impl DebugContext {
         // - %VAR:old=new% (string replacement)
         // - %VAR:*=new% (replace from start)
         // - Complex expressions with multiple variables
-        let (output, exit_code) = self.run_command(&format!("echo {}", expr))?;
+        Ok(self.echo_query(expr))
+    }
 
-        // Update exit code
-        self.last_exit_code = exit_code;
+    /// Variables as seen from one stack frame: globals overlaid with that
+    /// frame's SETLOCAL scope. Frame ids follow the stack trace: 0 is the
+    /// top-level script and `i + 1` is `call_stack[i]`.
+    pub fn get_frame_visible_variables(&self, frame_id: usize) -> HashMap<String, String> {
+        let mut visible = self.top_level_variables();
+        if frame_id > 0 {
+            if let Some(frame) = self.call_stack.get(frame_id - 1) {
+                if frame.has_setlocal {
+                    overlay_scope(&mut visible, &frame.locals, &frame.local_undefined);
+                }
+            }
+        }
+        visible
+    }
 
-        // Return trimmed output
-        let result = output.trim().to_string();
-        eprintln!("   Result: '{}'", result);
-        Ok(result)
+    /// Evaluate an expression against a specific stack frame (ids as in
+    /// `get_frame_visible_variables`). The innermost frame goes through
+    /// `evaluate_expression`; for caller frames the CMD session holds the
+    /// wrong scope, so variables are expanded from tracked state and the
+    /// session is only used once no variable references remain.
+    pub fn evaluate_expression_in_frame(
+        &mut self,
+        expression: &str,
+        frame_id: usize,
+    ) -> Result<String, BatchDbgError> {
+        let evaluation = self.plan_evaluation_in_frame(expression, frame_id)?;
+        self.answer(evaluation)
+    }
+
+    /// `plan_evaluation` for a specific stack frame
+    pub fn plan_evaluation_in_frame(
+        &mut self,
+        expression: &str,
+        frame_id: usize,
+    ) -> Result<Evaluation, BatchDbgError> {
+        let expr = expression.trim();
+        let args = match frame_id {
+            0 => self.script_args.clone(),
+            n => self
+                .call_stack
+                .get(n - 1)
+                .and_then(|frame| frame.args.clone())
+                .unwrap_or_default(),
+        };
+
+        // %0..%9 and %~1..%~9 refer to the frame's own arguments
+        let mut expr = expr.to_string();
+        for i in (1..=9).rev() {
+            let val = args.get(i - 1).cloned().unwrap_or_default();
+            expr = expr.replace(&format!("%~{}", i), val.trim_matches('"'));
+            expr = expr.replace(&format!("%{}", i), &val);
+        }
+        if frame_id > 0 {
+            if let Some(frame) = self.call_stack.get(frame_id - 1) {
+                expr = expr.replace("%0", &frame.name());
+            }
+        } else {
+            expr = expr.replace("%0", &self.script_path);
+        }
+
+        if frame_id == self.call_stack.len() {
+            return self.plan_evaluation(&expr);
+        }
+
+        eprintln!("EVAL: Evaluating '{}' in frame {}", expr, frame_id);
+        if expr.eq_ignore_ascii_case("ERRORLEVEL") || expr == "%ERRORLEVEL%" {
+            return Ok(Evaluation::Value(self.last_exit_code.to_string()));
+        }
+
+        let visible = self.get_frame_visible_variables(frame_id);
+        let lookup = |name: &str| {
+            visible
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone())
+        };
+
+        // Simple identifier
+        if !expr.contains(['%', '!', ' ', '=', '&', ':']) {
+            if let Some(value) = lookup(&expr) {
+                return Ok(Evaluation::Value(value));
+            }
+        }
+
+        // Expand plain %VAR% references from the frame's variables
+        let mut expanded = String::new();
+        let mut rest = expr.as_str();
+        while let Some(start) = rest.find('%') {
+            expanded.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            match after.find('%') {
+                Some(end) if !after[..end].contains(':') && !after[..end].is_empty() => {
+                    let name = &after[..end];
+                    if name.eq_ignore_ascii_case("ERRORLEVEL") {
+                        expanded.push_str(&self.last_exit_code.to_string());
+                    } else {
+                        expanded.push_str(&lookup(name).unwrap_or_default());
+                    }
+                    rest = &after[end + 1..];
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("Cannot evaluate '{}' outside the current frame", expr),
+                    )
+                    .into());
+                }
+            }
+        }
+        expanded.push_str(rest);
+
+        if expanded.contains('!') {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Cannot evaluate '{}' outside the current frame", expr),
+            )
+            .into());
+        }
+        if runs_commands(&expanded) {
+            return Err(BatchDbgError::EvalUnsafe(expr));
+        }
+
+        Ok(self.echo_query(&expanded))
     }
 
     /// Evaluate an IF condition and return whether it's true
@@ -531,8 +2568,7 @@ impl DebugContext {
 
                 // Use CMD's existence check
                 let check_cmd = format!("if exist \"{}\" (echo 1) else (echo 0)", path_expanded);
-                let (output, _) = self.run_command(&check_cmd)?;
-                let result = output.trim() == "1";
+                let result = self.cached_echo_command(&check_cmd)? == "1";
                 let final_result = if *not { !result } else { result };
                 eprintln!(
                     "IF {}EXIST \"{}\" -> {} (path: \"{}\")",
@@ -567,36 +2603,7 @@ impl DebugContext {
                 let left_expanded = self.expand_variables(left)?;
                 let right_expanded = self.expand_variables(right)?;
 
-                // Try to parse as numbers for numeric comparison
-                let left_num = left_expanded.trim().parse::<i32>();
-                let right_num = right_expanded.trim().parse::<i32>();
-
-                let result = match (left_num, right_num) {
-                    (Ok(l), Ok(r)) => {
-                        // Numeric comparison
-                        match op.to_uppercase().as_str() {
-                            "EQU" => l == r,
-                            "NEQ" => l != r,
-                            "LSS" => l < r,
-                            "LEQ" => l <= r,
-                            "GTR" => l > r,
-                            "GEQ" => l >= r,
-                            _ => false,
-                        }
-                    }
-                    _ => {
-                        // String comparison (case-insensitive)
-                        match op.to_uppercase().as_str() {
-                            "EQU" => left_expanded.eq_ignore_ascii_case(&right_expanded),
-                            "NEQ" => !left_expanded.eq_ignore_ascii_case(&right_expanded),
-                            "LSS" => left_expanded.to_lowercase() < right_expanded.to_lowercase(),
-                            "LEQ" => left_expanded.to_lowercase() <= right_expanded.to_lowercase(),
-                            "GTR" => left_expanded.to_lowercase() > right_expanded.to_lowercase(),
-                            "GEQ" => left_expanded.to_lowercase() >= right_expanded.to_lowercase(),
-                            _ => false,
-                        }
-                    }
-                };
+                let result = compare_values(&left_expanded, op, &right_expanded);
 
                 let final_result = if *not { !result } else { result };
                 eprintln!(
@@ -618,8 +2625,7 @@ impl DebugContext {
     /// Helper to expand variables in a string
     fn expand_variables(&mut self, text: &str) -> io::Result<String> {
         // Use echo to expand variables
-        let (output, _) = self.run_command(&format!("echo {}", text))?;
-        Ok(output.trim().to_string())
+        self.cached_echo(text)
     }
 
     /// Expand a FOR loop into individual iterations
@@ -730,10 +2736,10 @@ impl DebugContext {
                 };
 
                 // Execute the FOR /F to get all values
-                match self.run_command(&for_cmd) {
-                    Ok((output, _)) => {
+                match self.run_internal(&for_cmd) {
+                    Ok(CommandResult { stdout: output, .. }) => {
                         for line in output.lines() {
-                            let value = line.trim().to_string();
+                            let value = ansi::strip(line.trim());
                             if !value.is_empty() {
                                 let expanded_command = command.replace(variable, &value);
                                 iterations.push((expanded_command, variable.clone(), value));
@@ -760,10 +2766,10 @@ impl DebugContext {
                 // Execute FOR /D to get directory names
                 let for_cmd = format!("FOR /D {} IN ({}) DO echo {}", variable, pattern, variable);
 
-                match self.run_command(&for_cmd) {
-                    Ok((output, _)) => {
+                match self.run_internal(&for_cmd) {
+                    Ok(CommandResult { stdout: output, .. }) => {
                         for line in output.lines() {
-                            let value = line.trim().to_string();
+                            let value = ansi::strip(line.trim());
                             if !value.is_empty() {
                                 let expanded_command = command.replace(variable, &value);
                                 iterations.push((expanded_command, variable.clone(), value));
@@ -798,10 +2804,10 @@ impl DebugContext {
                     format!("FOR /R {} IN ({}) DO echo {}", variable, pattern, variable)
                 };
 
-                match self.run_command(&for_cmd) {
-                    Ok((output, _)) => {
+                match self.run_internal(&for_cmd) {
+                    Ok(CommandResult { stdout: output, .. }) => {
                         for line in output.lines() {
-                            let value = line.trim().to_string();
+                            let value = ansi::strip(line.trim());
                             if !value.is_empty() {
                                 let expanded_command = command.replace(variable, &value);
                                 iterations.push((expanded_command, variable.clone(), value));
@@ -822,50 +2828,84 @@ impl DebugContext {
     /// Set a loop variable value (for tracking during FOR loop execution)
     pub fn set_loop_variable(&mut self, name: &str, value: &str) {
         // Loop variables are tracked in the current scope
-        if let Some(frame) = self.call_stack.last_mut() {
-            if frame.has_setlocal {
-                frame.locals.insert(name.to_string(), value.to_string());
-                eprintln!("Loop variable set: {}={} (local scope)", name, value);
-                return;
-            }
+        if self.store_variable(name, Some(value.to_string()), VariableOrigin::LoopVariable) {
+            eprintln!("Loop variable set: {}={} (local scope)", name, value);
+        } else {
+            eprintln!("Loop variable set: {}={}", name, value);
         }
-        self.variables.insert(name.to_string(), value.to_string());
-        eprintln!("Loop variable set: {}={}", name, value);
     }
 
     /// Handle PUSHD command - push current directory onto stack
     pub fn handle_pushd(&mut self, path: Option<&str>) -> io::Result<()> {
-        use std::env;
+        let current_dir_str = self.current_dir.to_string_lossy().to_string();
+
+        // PUSHD without a path just pushes the current directory
+        let new_path = match path.map(|p| p.trim().trim_matches('"')) {
+            Some(p) if !p.is_empty() => p,
+            _ => {
+                self.directory_stack.push(current_dir_str.clone());
+                eprintln!(
+                    "PUSHD: pushed '{}' onto stack (depth: {})",
+                    current_dir_str,
+                    self.directory_stack.len()
+                );
+                return Ok(());
+            }
+        };
 
-        // Get current directory from Rust's process
-        let current_dir = env::current_dir()?;
-        let current_dir_str = current_dir.to_string_lossy().to_string();
+        let target = if is_unc_path(new_path) {
+            // CMD maps a temporary drive letter for UNC paths, so let the session
+            // do the mapping and read back where it landed
+            let CommandResult {
+                stdout: output,
+                exit_code,
+                ..
+            } = self.run_command(&format!("pushd \"{}\" && cd", new_path))?;
+            self.last_exit_code = exit_code;
+            let mapped = output.lines().last().unwrap_or("").trim().to_string();
+            if exit_code != 0 || mapped.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("PUSHD: cannot map network path '{}'", new_path),
+                ));
+            }
+            let drive: String = mapped.chars().take(3).collect();
+            eprintln!("PUSHD: mapped '{}' to drive {}", new_path, drive);
+            self.unc_mappings.push(UncMapping {
+                depth: self.directory_stack.len() + 1,
+                drive,
+                unc_path: new_path.to_string(),
+            });
+            PathBuf::from(mapped)
+        } else {
+            let resolved = resolve_path(&self.current_dir, new_path);
+            if !resolved.is_dir() {
+                self.last_exit_code = 1;
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("PUSHD: directory not found: {}", resolved.display()),
+                ));
+            }
+            let CommandResult { exit_code, .. } =
+                self.run_command(&format!("cd /d \"{}\"", resolved.display()))?;
+            self.last_exit_code = exit_code;
+            resolved
+        };
 
-        // Push onto stack
         self.directory_stack.push(current_dir_str.clone());
         eprintln!(
             "PUSHD: pushed '{}' onto stack (depth: {})",
             current_dir_str,
             self.directory_stack.len()
         );
-
-        // Change to new directory if provided
-        if let Some(new_path) = path {
-            // Change Rust's process directory
-            env::set_current_dir(new_path)?;
-
-            // Also sync CMD session
-            let (_, exit_code) = self.run_command(&format!("cd /d {}", new_path))?;
-            self.last_exit_code = exit_code;
-        }
+        self.current_dir = target;
 
         Ok(())
     }
 
     /// Handle POPD command - pop directory from stack and change to it
     pub fn handle_popd(&mut self) -> io::Result<()> {
-        use std::env;
-
+        let depth = self.directory_stack.len();
         if let Some(dir) = self.directory_stack.pop() {
             eprintln!(
                 "POPD: popped '{}' from stack (depth: {})",
@@ -873,12 +2913,21 @@ impl DebugContext {
                 self.directory_stack.len()
             );
 
-            // Change Rust's process directory
-            env::set_current_dir(&dir)?;
+            // Release the temporary drive if the matching PUSHD mapped one
+            if self.unc_mappings.last().map(|m| m.depth) == Some(depth) {
+                if let Some(mapping) = self.unc_mappings.pop() {
+                    eprintln!(
+                        "POPD: releasing drive {} for '{}'",
+                        mapping.drive, mapping.unc_path
+                    );
+                    self.run_command("popd")?;
+                }
+            }
 
-            // Also sync CMD session
-            let (_, exit_code) = self.run_command(&format!("cd /d {}", dir))?;
+            let CommandResult { exit_code, .. } =
+                self.run_command(&format!("cd /d \"{}\"", dir))?;
             self.last_exit_code = exit_code;
+            self.current_dir = PathBuf::from(dir);
 
             Ok(())
         } else {
@@ -891,13 +2940,68 @@ impl DebugContext {
         }
     }
 
+    /// Handle CD/CHDIR command - change the tracked working directory
+    pub fn handle_cd(&mut self, path: Option<&str>) -> io::Result<()> {
+        let target = path
+            .map(|p| p.trim())
+            .map(|p| match p.get(..2) {
+                Some(switch) if switch.eq_ignore_ascii_case("/D") => p[2..].trim(),
+                _ => p,
+            })
+            .map(|p| p.trim_matches('"'))
+            .unwrap_or("");
+
+        // CD without a path only prints the current directory
+        if target.is_empty() {
+            return Ok(());
+        }
+
+        let resolved = resolve_path(&self.current_dir, target);
+        if !resolved.is_dir() {
+            self.last_exit_code = 1;
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("CD: directory not found: {}", resolved.display()),
+            ));
+        }
+
+        let CommandResult { exit_code, .. } =
+            self.run_command(&format!("cd /d \"{}\"", resolved.display()))?;
+        self.last_exit_code = exit_code;
+        eprintln!("CD: changed directory to '{}'", resolved.display());
+        self.current_dir = resolved;
+
+        Ok(())
+    }
+
+    /// Get the directory the script is currently running in
+    pub fn get_current_dir(&self) -> &Path {
+        &self.current_dir
+    }
+
+    /// Get the drive mappings created by PUSHD on UNC paths
+    pub fn get_unc_mappings(&self) -> &[UncMapping] {
+        &self.unc_mappings
+    }
+
     /// Get the directory stack for display
     pub fn get_directory_stack(&self) -> &[String] {
         &self.directory_stack
     }
 
-    /// Handle SHIFT command - shift parameters in current call frame
+    /// Handle SHIFT command - shift parameters in current call frame, or the
+    /// script's own arguments at top level
     pub fn handle_shift(&mut self, count: usize) {
+        if self.call_stack.is_empty() {
+            let actual_shift = count.min(self.script_args.len());
+            self.script_args.drain(0..actual_shift);
+            eprintln!(
+                "SHIFT: shifted {} script argument(s), {} remaining",
+                actual_shift,
+                self.script_args.len()
+            );
+            return;
+        }
         if let Some(frame) = self.call_stack.last_mut() {
             if let Some(ref mut args) = frame.args {
                 if count > 0 {
@@ -920,8 +3024,6 @@ impl DebugContext {
             } else {
                 eprintln!("WARNING: SHIFT: no parameters to shift");
             }
-        } else {
-            eprintln!("WARNING: SHIFT: not in a subroutine");
         }
     }
 }
@@ -0,0 +1,93 @@
+//! Which lines of the launched script ran and how often, for finding dead
+//! code in old batch files
+
+use crate::parser::{is_statement, PreprocessResult};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Run counts per logical line
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    hits: HashMap<usize, u32>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, logical_line: usize) {
+        *self.hits.entry(logical_line).or_insert(0) += 1;
+    }
+
+    pub fn hits(&self, logical_line: usize) -> u32 {
+        self.hits.get(&logical_line).copied().unwrap_or(0)
+    }
+
+    /// Counts for every physical line holding a statement; labels, comments
+    /// and blank lines can't run and are left out
+    pub fn report(&self, pre: &PreprocessResult) -> CoverageReport {
+        let mut lines = Vec::new();
+        for (i, logical) in pre.logical.iter().enumerate() {
+            if !is_statement(&logical.text) {
+                continue;
+            }
+            let hits = self.hits(i);
+            for phys in logical.phys_start..=logical.phys_end {
+                lines.push(LineHits {
+                    line: phys + 1,
+                    hits,
+                });
+            }
+        }
+        let total = lines.len();
+        let covered = lines.iter().filter(|l| l.hits > 0).count();
+        let percent = if total == 0 {
+            100.0
+        } else {
+            covered as f64 * 100.0 / total as f64
+        };
+        CoverageReport {
+            lines,
+            covered,
+            total,
+            percent,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LineHits {
+    pub line: usize, // 1-based physical line
+    pub hits: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CoverageReport {
+    pub lines: Vec<LineHits>,
+    pub covered: usize, // Lines that ran at least once
+    pub total: usize,   // Lines that could have run
+    pub percent: f64,
+}
+
+impl CoverageReport {
+    /// The report in lcov's tracefile format, for `source`
+    pub fn to_lcov(&self, source: &Path) -> String {
+        let mut out = format!("TN:\nSF:{}\n", source.display());
+        for line in &self.lines {
+            out.push_str(&format!("DA:{},{}\n", line.line, line.hits));
+        }
+        out.push_str(&format!(
+            "LF:{}\nLH:{}\nend_of_record\n",
+            self.total, self.covered
+        ));
+        out
+    }
+
+    pub fn write_lcov(&self, source: &Path, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_lcov(source))
+    }
+}
@@ -0,0 +1,119 @@
+//! The debugger's own trace log, for diagnosing it from inside VS Code where
+//! stderr is hard to get at. Off unless a file is given.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Environment variable naming a log file, for tracing before `launch`
+pub const LOG_ENV_VAR: &str = "BATCH_DEBUGGER_LOG";
+
+/// Size at which the log is moved to `<file>.1` and started afresh
+pub const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+struct LogFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+}
+
+impl LogFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            size,
+        })
+    }
+
+    fn write_line(&mut self, args: fmt::Arguments) -> io::Result<()> {
+        if self.size >= MAX_LOG_SIZE {
+            self.rotate()?;
+        }
+        let line = format!("{}\n", args);
+        self.writer.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let mut old = self.path.clone().into_os_string();
+        old.push(".1");
+        fs::rename(&self.path, &old)?;
+        *self = Self::open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Handle to the trace log. Clones share the file, so a log opened at launch
+/// is used by everything that was handed the handle before. Writes are
+/// buffered and best effort: a failing write closes the log.
+#[derive(Clone, Default)]
+pub struct DebugLog {
+    file: Arc<Mutex<Option<LogFile>>>,
+}
+
+impl DebugLog {
+    /// A log that writes nothing until `open` is called
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A log writing to the file named by BATCH_DEBUGGER_LOG, if it is set
+    pub fn from_env() -> Self {
+        let log = Self::new();
+        if let Some(path) = std::env::var_os(LOG_ENV_VAR).filter(|p| !p.is_empty()) {
+            if let Err(e) = log.open(Path::new(&path)) {
+                eprintln!("WARNING: Cannot open log file {:?}: {}", path, e);
+            }
+        }
+        log
+    }
+
+    /// Write to `path` from now on, creating its directory and appending to
+    /// it if it exists
+    pub fn open(&self, path: &Path) -> io::Result<()> {
+        let file = LogFile::open(path)?;
+        if let Ok(mut current) = self.file.lock() {
+            if let Some(previous) = current.as_mut() {
+                let _ = previous.writer.flush();
+            }
+            *current = Some(file);
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.lock().map(|f| f.is_some()).unwrap_or(false)
+    }
+
+    /// Append one line; takes `format_args!` so nothing is formatted while
+    /// the log is off
+    pub fn write(&self, args: fmt::Arguments) {
+        if let Ok(mut current) = self.file.lock() {
+            if let Some(file) = current.as_mut() {
+                if let Err(e) = file.write_line(args) {
+                    eprintln!("WARNING: Debug log disabled: {}", e);
+                    *current = None;
+                }
+            }
+        }
+    }
+
+    /// Push buffered lines to the file, e.g. before waiting at a stop
+    pub fn flush(&self) {
+        if let Ok(mut current) = self.file.lock() {
+            if let Some(file) = current.as_mut() {
+                let _ = file.writer.flush();
+            }
+        }
+    }
+}
@@ -1,21 +1,73 @@
+pub mod ansi;
 mod breakpoints;
+pub mod codepage;
 mod context;
+mod coverage;
+mod log;
+mod process;
+mod profile;
+mod progress;
+mod script;
 mod session;
+mod shell;
 mod stepping;
+mod summary;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+mod transcript;
 
-pub use breakpoints::Breakpoint;
-pub use context::DebugContext;
-pub use session::CmdSession;
-pub use stepping::RunMode;
+pub use ansi::AnsiMode;
+pub use breakpoints::{Breakpoint, BreakpointSpec, Breakpoints, BreakpointsFile};
+pub use context::{expand_argument_modifiers, run_helper_in, DebugContext, Evaluation};
+pub use coverage::{Coverage, CoverageReport};
+pub use log::DebugLog;
+pub use process::{run_and_wait, ChildProcesses};
+pub use profile::{LineProfile, Profile};
+pub use progress::{Progress, ProgressEvent};
+pub use script::{find_called_script, source_key, Script};
+pub use session::{
+    CmdSession, CommandResult, SessionError, SessionKiller, SessionOptions, SessionRecord,
+    SessionStats, ShellConfig, DEFAULT_OUTPUT_LIMIT, INTERRUPTED_EXIT_CODE,
+};
+pub use shell::{lock_shell, SharedShell, Shell};
+pub use stepping::{RunMode, StepGranularity};
+pub use summary::{RunSummary, TerminatedReason};
+pub use transcript::{Direction, Transcript, TranscriptEntry};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+/// Where a tracked variable's value came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VariableOrigin {
+    Environment,  // Inherited from the environment the script started in
+    Script,       // Set by the script
+    FrameLocal,   // Set by the script inside a SETLOCAL scope
+    Injected,     // Set from the debugger (setVariable)
+    LoopVariable, // FOR loop variable
+}
+
+/// A SETLOCAL at the top level of the script, where there is no Frame to
+/// hold it. They nest, each ENDLOCAL dropping the innermost.
+#[derive(Debug, Clone, Default)]
+pub struct LocalScope {
+    pub locals: HashMap<String, String>,
+    pub origins: HashMap<String, VariableOrigin>,
+    pub undefined: HashSet<String>, // Cleared in the scope, hiding any value from outside it
+}
 
 #[derive(Debug, Clone)]
 pub struct Frame {
     pub return_pc: usize,
     pub args: Option<Vec<String>>,
     pub locals: HashMap<String, String>,
+    pub local_origins: HashMap<String, VariableOrigin>,
+    pub local_undefined: HashSet<String>, // Cleared under SETLOCAL, hiding the caller's value
     pub has_setlocal: bool,
+    pub label: Option<String>,   // Label name the frame was CALLed with
+    pub label_pc: Option<usize>, // Logical line of the label
+    pub current_pc: usize,       // Line this frame is executing (CALL site once it calls out)
+    pub script: Option<PathBuf>, // Batch file the frame runs in, None for the launched one
 }
 
 impl Frame {
@@ -24,7 +76,40 @@ impl Frame {
             return_pc,
             args,
             locals: HashMap::new(),
+            local_origins: HashMap::new(),
+            local_undefined: HashSet::new(),
             has_setlocal: false,
+            label: None,
+            label_pc: None,
+            current_pc: return_pc,
+            script: None,
+        }
+    }
+
+    /// Run the frame in another batch file (a CALLed script, or a label
+    /// inside one)
+    pub fn with_script(mut self, script: Option<PathBuf>) -> Self {
+        self.script = script;
+        self
+    }
+
+    /// Attach the called label and its logical line to the frame
+    pub fn with_label(mut self, label: &str, label_pc: usize) -> Self {
+        self.label = Some(label.to_string());
+        self.label_pc = Some(label_pc);
+        self.current_pc = label_pc;
+        self
+    }
+
+    /// Display name for stack traces, e.g. `:process_file`
+    pub fn name(&self) -> String {
+        match (&self.label, &self.script) {
+            (Some(label), _) => format!(":{}", label),
+            (None, Some(script)) => script
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            (None, None) => "<subroutine>".to_string(),
         }
     }
 }
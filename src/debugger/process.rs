@@ -0,0 +1,197 @@
+//! Programs the script STARTs. They run outside the CMD session, so a
+//! `start /wait` doesn't hold the session (and every query the debugger
+//! makes) for as long as the program runs.
+
+use super::session::kill_tree;
+use super::{CommandResult, SessionError, SessionKiller, ShellConfig};
+use crate::parser::StartCommand;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a waited-for program is checked for having finished
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A program started without /WAIT, as reported to the client
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChildProcess {
+    pub pid: u32,
+    pub command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub running: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+}
+
+struct Spawned {
+    command: String,
+    title: Option<String>,
+    child: Child,
+}
+
+/// The programs a session STARTed without waiting. Clones share the list,
+/// so the server can kill them without the context lock.
+#[derive(Clone, Default)]
+pub struct ChildProcesses {
+    spawned: Arc<Mutex<Vec<Spawned>>>,
+}
+
+impl ChildProcesses {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every program started so far, with whether it is still running
+    pub fn list(&self) -> Vec<ChildProcess> {
+        let mut spawned = match self.spawned.lock() {
+            Ok(s) => s,
+            Err(_) => return Vec::new(),
+        };
+        spawned
+            .iter_mut()
+            .map(|s| {
+                let status = s.child.try_wait().ok().flatten();
+                ChildProcess {
+                    pid: s.child.id(),
+                    command: s.command.clone(),
+                    title: s.title.clone(),
+                    running: status.is_none(),
+                    exit_code: status.and_then(|st| st.code()),
+                }
+            })
+            .collect()
+    }
+
+    /// Kill the programs that are still running, with whatever they started
+    pub fn kill_all(&self) {
+        if let Ok(mut spawned) = self.spawned.lock() {
+            for s in spawned.iter_mut() {
+                if matches!(s.child.try_wait(), Ok(None)) {
+                    eprintln!("Killing started process {} ({})", s.child.id(), s.command);
+                    kill_tree(s.child.id());
+                    let _ = s.child.wait();
+                }
+            }
+        }
+    }
+
+    /// Start `start.command` in its own shell and leave it running
+    pub fn start(
+        &self,
+        shell: &ShellConfig,
+        start: &StartCommand,
+        cwd: &Path,
+        env: &HashMap<String, String>,
+    ) -> io::Result<u32> {
+        let child = start_command(shell, start, cwd, env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let pid = child.id();
+        if let Ok(mut spawned) = self.spawned.lock() {
+            spawned.push(Spawned {
+                command: start.command.clone(),
+                title: start.title.clone(),
+                child,
+            });
+        }
+        Ok(pid)
+    }
+}
+
+/// Run `start.command` in its own shell and wait for it like START /WAIT,
+/// giving up (and killing it) after `timeout` or when `killer` cancels the
+/// session. ERRORLEVEL is the program's exit code.
+pub fn run_and_wait(
+    shell: &ShellConfig,
+    start: &StartCommand,
+    cwd: &Path,
+    env: &HashMap<String, String>,
+    timeout: Duration,
+    killer: &SessionKiller,
+) -> io::Result<CommandResult> {
+    let mut child = start_command(shell, start, cwd, env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().map(read_all);
+    let stderr = child.stderr.take().map(read_all);
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if killer.is_cancelled() || started.elapsed() >= timeout {
+            kill_tree(child.id());
+            let _ = child.wait();
+            if killer.is_cancelled() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "session terminated",
+                ));
+            }
+            return Err(SessionError::Timeout {
+                timeout,
+                partial_output: String::new(),
+            }
+            .into());
+        }
+        thread::sleep(WAIT_POLL_INTERVAL);
+    };
+
+    let collect = |reader: Option<thread::JoinHandle<String>>| {
+        reader.and_then(|r| r.join().ok()).unwrap_or_default()
+    };
+    Ok(CommandResult {
+        stdout: collect(stdout),
+        stderr: collect(stderr),
+        exit_code: status.code().unwrap_or(1),
+        truncated: false,
+    })
+}
+
+/// The shell running `start.command` (`cmd /C command`), or an interactive
+/// shell when START names no command
+fn start_command(
+    shell: &ShellConfig,
+    start: &StartCommand,
+    cwd: &Path,
+    env: &HashMap<String, String>,
+) -> Command {
+    let mut command = Command::new(&shell.path);
+    if !start.command.is_empty() {
+        command.arg("/C");
+        #[cfg(windows)]
+        {
+            // Passed as written; CMD does its own quote handling
+            use std::os::windows::process::CommandExt;
+            command.raw_arg(&start.command);
+        }
+        #[cfg(not(windows))]
+        command.arg(&start.command);
+    }
+    let dir = match &start.directory {
+        Some(dir) => cwd.join(dir),
+        None => cwd.to_path_buf(),
+    };
+    command.current_dir(dir).envs(env);
+    command
+}
+
+fn read_all<R: Read + Send + 'static>(mut reader: R) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        let _ = reader.read_to_end(&mut bytes);
+        String::from_utf8_lossy(&bytes).into_owned()
+    })
+}
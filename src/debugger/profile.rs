@@ -0,0 +1,127 @@
+//! Time spent running each line of the launched script, for finding what
+//! makes a slow script slow
+
+use crate::parser::PreprocessResult;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Runs kept for the trace file; the per-line totals keep counting past it
+const MAX_TRACE_EVENTS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct LineTiming {
+    total: Duration,
+    max: Duration,
+    hits: u32,
+}
+
+/// One run of a line, relative to the start of the profile
+#[derive(Debug, Clone, Copy)]
+struct TraceEvent {
+    pc: usize,
+    start: Duration,
+    duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    started: Instant,
+    lines: HashMap<usize, LineTiming>,
+    events: Vec<TraceEvent>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            lines: HashMap::new(),
+            events: Vec::new(),
+        }
+    }
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a run of logical line `pc` that began at `start` and took
+    /// `duration`
+    pub fn record(&mut self, pc: usize, start: Instant, duration: Duration) {
+        let timing = self.lines.entry(pc).or_default();
+        timing.total += duration;
+        timing.max = timing.max.max(duration);
+        timing.hits += 1;
+
+        if self.events.len() < MAX_TRACE_EVENTS {
+            self.events.push(TraceEvent {
+                pc,
+                start: start.saturating_duration_since(self.started),
+                duration,
+            });
+        }
+    }
+
+    /// The `count` lines with the most time in total, slowest first
+    pub fn slowest(&self, pre: &PreprocessResult, count: usize) -> Vec<LineProfile> {
+        let mut lines: Vec<(&usize, &LineTiming)> = self.lines.iter().collect();
+        lines.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(b.0)));
+        lines
+            .into_iter()
+            .take(count)
+            .filter_map(|(pc, timing)| {
+                let logical = pre.logical.get(*pc)?;
+                Some(LineProfile {
+                    line: logical.phys_start + 1,
+                    text: logical.text.trim().to_string(),
+                    total_ms: timing.total.as_secs_f64() * 1000.0,
+                    max_ms: timing.max.as_secs_f64() * 1000.0,
+                    hits: timing.hits,
+                })
+            })
+            .collect()
+    }
+
+    /// The runs as a Chrome trace (chrome://tracing, Perfetto), one complete
+    /// event per run named after its line
+    pub fn to_chrome_trace(&self, pre: &PreprocessResult) -> Value {
+        let events: Vec<Value> = self
+            .events
+            .iter()
+            .filter_map(|event| {
+                let logical = pre.logical.get(event.pc)?;
+                Some(json!({
+                    "name": logical.text.trim(),
+                    "cat": "line",
+                    "ph": "X",
+                    "ts": event.start.as_micros() as u64,
+                    "dur": event.duration.as_micros() as u64,
+                    "pid": 1,
+                    "tid": 1,
+                    "args": { "line": logical.phys_start + 1 }
+                }))
+            })
+            .collect();
+        json!({ "traceEvents": events, "displayTimeUnit": "ms" })
+    }
+
+    pub fn write_chrome_trace(&self, pre: &PreprocessResult, path: &Path) -> io::Result<()> {
+        let trace = serde_json::to_string(&self.to_chrome_trace(pre))?;
+        fs::write(path, trace)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LineProfile {
+    pub line: usize, // 1-based physical line the statement starts on
+    pub text: String,
+    pub total_ms: f64,
+    pub max_ms: f64,
+    pub hits: u32,
+}
@@ -0,0 +1,68 @@
+//! Progress of work that takes long enough to look stuck, like a FOR loop
+//! over thousands of lines. The executor queues it on the context, so it
+//! reaches the client ahead of the stop or end that follows it.
+
+/// One progress event for the client
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    Start {
+        id: u64,
+        title: String,
+    },
+    Update {
+        id: u64,
+        message: String,
+        percentage: Option<u32>, // None when the amount of work isn't known
+    },
+    End {
+        id: u64,
+    },
+}
+
+/// Progress events waiting to be sent, and the one still running
+#[derive(Debug, Default)]
+pub struct Progress {
+    queued: Vec<ProgressEvent>,
+    active: Option<u64>,
+    next_id: u64,
+}
+
+impl Progress {
+    /// Begin reporting progress, ending any that is still running
+    pub fn start(&mut self, title: String) {
+        self.end();
+        self.next_id += 1;
+        self.active = Some(self.next_id);
+        self.queued.push(ProgressEvent::Start {
+            id: self.next_id,
+            title,
+        });
+    }
+
+    /// Report how far the running progress got; nothing when none runs
+    pub fn update(&mut self, message: String, percentage: Option<u32>) {
+        if let Some(id) = self.active {
+            self.queued.push(ProgressEvent::Update {
+                id,
+                message,
+                percentage,
+            });
+        }
+    }
+
+    /// End the running progress, if any
+    pub fn end(&mut self) {
+        if let Some(id) = self.active.take() {
+            self.queued.push(ProgressEvent::End { id });
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// Events queued since the last call
+    pub fn take(&mut self) -> Vec<ProgressEvent> {
+        std::mem::take(&mut self.queued)
+    }
+}
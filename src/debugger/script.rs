@@ -0,0 +1,79 @@
+use crate::parser::{build_label_map, preprocess_lines, PreprocessResult};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A batch file prepared for stepping: its logical lines and label map
+#[derive(Debug, Clone)]
+pub struct Script {
+    pub path: PathBuf,
+    pub pre: PreprocessResult,
+    pub labels: HashMap<String, usize>,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        Ok(Self {
+            path: source_key(path),
+            pre: preprocess_lines(&physical_lines),
+            labels: build_label_map(&physical_lines),
+        })
+    }
+
+    /// File name for stack traces
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+}
+
+/// The form a source path is compared and stored in, so the client's path
+/// for a file and the one a CALL resolved to agree
+pub fn source_key(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Find the batch file `CALL target` runs: a `.bat`/`.cmd` path (or a bare
+/// name CMD would complete with those extensions) looked up in `dirs`, then
+/// on PATH. None for anything else, e.g. `CALL :label` or `CALL echo`.
+pub fn find_called_script(target: &str, dirs: &[PathBuf]) -> Option<PathBuf> {
+    let target = target.trim().trim_matches('"');
+    if target.is_empty() || target.starts_with(':') {
+        return None;
+    }
+
+    let candidates: Vec<String> = match Path::new(target).extension() {
+        Some(ext) if ext.eq_ignore_ascii_case("bat") || ext.eq_ignore_ascii_case("cmd") => {
+            vec![target.to_string()]
+        }
+        Some(_) => return None,
+        None => vec![format!("{}.bat", target), format!("{}.cmd", target)],
+    };
+
+    let path_dirs = std::env::var_os("PATH")
+        .map(|p| std::env::split_paths(&p).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let absolute = Path::new(target).is_absolute();
+
+    for candidate in &candidates {
+        if absolute {
+            let path = Path::new(candidate);
+            if path.is_file() {
+                return Some(source_key(path));
+            }
+            continue;
+        }
+        for dir in dirs.iter().chain(path_dirs.iter()) {
+            let path = dir.join(candidate);
+            if path.is_file() {
+                return Some(source_key(&path));
+            }
+        }
+    }
+    None
+}
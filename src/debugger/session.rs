@@ -1,45 +1,562 @@
-use std::io::{self, BufRead, BufReader, Write};
-use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
-use std::time::{Duration, Instant};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::{ansi, codepage, AnsiMode, Shell, Transcript, TranscriptEntry};
 
 const SENTINEL: &str = "__CMD_DONE__";
 
+/// How long a shell whose pipes closed gets to finish exiting
+const EXIT_GRACE: Duration = Duration::from_millis(500);
+
+/// How long `ping` waits for an answer
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a single command may run before `run` gives up on it
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most output kept per stream of a single command, in bytes
+pub const DEFAULT_OUTPUT_LIMIT: usize = 4 * 1024 * 1024;
+
+/// ERRORLEVEL of a command that was interrupted, as CMD reports it after
+/// Ctrl+Break (STATUS_CONTROL_C_EXIT)
+pub const INTERRUPTED_EXIT_CODE: i32 = -1073741510;
+
+/// PROMPT the session runs with, so a customized one (colors, git status
+/// helpers) can't end up in the output
+const SAFE_PROMPT: &str = "$P$G";
+
+/// Variables kept with `clear_env`; without them CMD can't find programs or
+/// its temp directory
+const ESSENTIAL_ENV: [&str; 8] = [
+    "SYSTEMROOT",
+    "SYSTEMDRIVE",
+    "WINDIR",
+    "COMSPEC",
+    "PATH",
+    "PATHEXT",
+    "TEMP",
+    "TMP",
+];
+
+/// Keeps cmd.exe from opening a console window of its own
+#[cfg(windows)]
+const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+/// Session failures callers can react to, carried inside an `io::Error`
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionError {
+    /// The command didn't finish in time; the session must be `restart`ed
+    Timeout {
+        timeout: Duration,
+        partial_output: String,
+    },
+    /// cmd.exe exited (e.g. the script ran `exit` without /B or something
+    /// crashed it); the session must be `restart`ed
+    SessionDied { exit_code: i32 },
+}
+
+impl SessionError {
+    /// The SessionError inside `err`, if it came from the session
+    pub fn from_io(err: &io::Error) -> Option<&SessionError> {
+        err.get_ref().and_then(|e| e.downcast_ref::<SessionError>())
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Timeout { timeout, .. } => {
+                write!(f, "command timed out after {} seconds", timeout.as_secs())
+            }
+            SessionError::SessionDied { exit_code } => {
+                write!(f, "CMD exited with code {}", exit_code)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl From<SessionError> for io::Error {
+    fn from(e: SessionError) -> Self {
+        let kind = match e {
+            SessionError::Timeout { .. } => io::ErrorKind::TimedOut,
+            SessionError::SessionDied { .. } => io::ErrorKind::BrokenPipe,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// Shell executable the session runs, with its command line arguments
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShellConfig {
+    pub path: PathBuf,
+    pub args: Vec<String>,
+}
+
+impl ShellConfig {
+    /// Arguments the default shell is started with
+    pub const DEFAULT_ARGS: [&'static str; 2] = ["/V:ON", "/Q"];
+}
+
+impl Default for ShellConfig {
+    /// %COMSPEC% (cmd from PATH if unset) with delayed expansion enabled
+    fn default() -> Self {
+        Self {
+            path: std::env::var_os("COMSPEC")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("cmd")),
+            args: Self::DEFAULT_ARGS.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+}
+
+/// How the cmd.exe child is started
+#[derive(Debug, Clone, Default)]
+pub struct SessionOptions {
+    /// Working directory for the session, the adapter's own when None
+    pub cwd: Option<PathBuf>,
+    /// Shell to start; its arguments replace the defaults entirely
+    pub shell: ShellConfig,
+    /// Whether escape sequences are removed from command output
+    pub ansi: AnsiMode,
+    /// Start with /D so the AutoRun commands in the registry don't run
+    pub skip_autorun: bool,
+    /// Start from `ESSENTIAL_ENV` only instead of the adapter's environment
+    pub clear_env: bool,
+    /// Variables set for the session on top of the inherited ones
+    pub env: Vec<(String, String)>,
+}
+
+impl SessionOptions {
+    /// The command the session is spawned with: no console window, a known
+    /// PROMPT, plus whatever the options ask for
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.shell.path);
+        command.args(&self.shell.args);
+        if self.skip_autorun && !self.shell.args.iter().any(|a| a.eq_ignore_ascii_case("/D")) {
+            command.arg("/D");
+        }
+        if self.clear_env {
+            command.env_clear();
+            for (key, value) in std::env::vars() {
+                if ESSENTIAL_ENV.contains(&key.to_uppercase().as_str()) {
+                    command.env(key, value);
+                }
+            }
+        }
+        command.env("PROMPT", SAFE_PROMPT);
+        for (key, value) in &self.env {
+            command.env(key, value);
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::CommandExt;
+            command.creation_flags(CREATE_NO_WINDOW);
+        }
+        command
+    }
+}
+
+/// Counters for how busy the session has been
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionStats {
+    pub script_commands: usize, // Commands the script asked for
+    pub helper_commands: usize, // Queries made by the debugger itself
+    pub total_time_ms: u64,     // Wall time spent waiting on commands
+    pub slowest_command: Option<String>,
+    pub slowest_time_ms: u64,
+}
+
+impl SessionStats {
+    /// Count one command that took `elapsed`
+    pub fn record(&mut self, cmd: &str, elapsed: Duration, helper: bool) {
+        if helper {
+            self.helper_commands += 1;
+        } else {
+            self.script_commands += 1;
+        }
+        let ms = elapsed.as_millis() as u64;
+        self.total_time_ms += ms;
+        if self.slowest_command.is_none() || ms > self.slowest_time_ms {
+            self.slowest_command = Some(cmd.to_string());
+            self.slowest_time_ms = ms;
+        }
+    }
+}
+
+/// A session's stats and transcript, kept apart from the session so they can
+/// be read while it runs a command
+#[derive(Clone, Default)]
+pub struct SessionRecord {
+    stats: Arc<Mutex<SessionStats>>,
+    transcript: Arc<Mutex<Option<Transcript>>>,
+}
+
+impl SessionRecord {
+    pub fn stats(&self) -> SessionStats {
+        lock_record(&self.stats).clone()
+    }
+
+    /// Count one command that took `elapsed`
+    pub fn count(&self, cmd: &str, elapsed: Duration, helper: bool) {
+        lock_record(&self.stats).record(cmd, elapsed, helper);
+    }
+
+    /// The last `count` recorded interactions, oldest first
+    pub fn transcript(&self, count: usize) -> Vec<TranscriptEntry> {
+        lock_record(&self.transcript)
+            .as_ref()
+            .map(|t| t.recent(count))
+            .unwrap_or_default()
+    }
+
+    fn set_transcript(&self, transcript: Transcript) {
+        *lock_record(&self.transcript) = Some(transcript);
+    }
+
+    /// Add `entry` to the transcript, if one is being recorded
+    fn transcribe(&self, entry: TranscriptEntry) {
+        if let Some(transcript) = lock_record(&self.transcript).as_mut() {
+            transcript.record(entry);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(transcript) = lock_record(&self.transcript).as_mut() {
+            transcript.flush();
+        }
+    }
+}
+
+/// A panic while recording leaves nothing half-done worth refusing to read
+fn lock_record<T>(value: &Mutex<T>) -> MutexGuard<'_, T> {
+    value.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Output of one command, with the two streams kept apart
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommandResult {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+    /// Output went over the limit and its middle was cut out
+    pub truncated: bool,
+}
+
+/// Collects one stream of a command's output, keeping at most `limit` bytes:
+/// the first and last halves, with a marker in place of what was dropped
+struct BoundedOutput {
+    limit: usize,
+    head: String,
+    tail: VecDeque<String>,
+    tail_len: usize,
+    dropped: usize,
+}
+
+impl BoundedOutput {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            head: String::new(),
+            tail: VecDeque::new(),
+            tail_len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Add a line. Returns true if it went into the head, i.e. it is final
+    /// and can be passed on right away.
+    fn push(&mut self, line: &str) -> bool {
+        let head_limit = self.limit / 2;
+        if self.tail.is_empty() && self.head.len() + line.len() <= head_limit {
+            self.head.push_str(line);
+            return true;
+        }
+
+        let tail_limit = self.limit - head_limit;
+        self.tail.push_back(line.to_string());
+        self.tail_len += line.len();
+        while self.tail_len > tail_limit && self.tail.len() > 1 {
+            if let Some(old) = self.tail.pop_front() {
+                self.tail_len -= old.len();
+                self.dropped += old.len();
+            }
+        }
+        // A single line can still be longer than the whole tail
+        if self.tail_len > tail_limit {
+            if let Some(only) = self.tail.front_mut() {
+                let mut cut = self.tail_len - tail_limit;
+                while !only.is_char_boundary(cut) {
+                    cut += 1;
+                }
+                only.drain(..cut);
+                self.tail_len -= cut;
+                self.dropped += cut;
+            }
+        }
+        false
+    }
+
+    fn is_truncated(&self) -> bool {
+        self.dropped > 0
+    }
+
+    /// Everything after the head: the truncation marker and the tail
+    fn rest(&self) -> String {
+        let mut rest = String::new();
+        if self.is_truncated() {
+            rest.push_str(&format!("... [{} bytes truncated] ...\r\n", self.dropped));
+        }
+        for line in &self.tail {
+            rest.push_str(line);
+        }
+        rest
+    }
+
+    fn contents(&self) -> String {
+        format!("{}{}", self.head, self.rest())
+    }
+}
+
+/// State shared with `SessionKiller`s, which outlive restarts of the child
+#[derive(Debug, Default)]
+struct SessionControl {
+    pid: AtomicU32,
+    cancelled: AtomicBool,
+    interrupted: AtomicBool, // Set by `SessionKiller::interrupt`
+    code_page: AtomicU32,    // Code page output is decoded with
+}
+
+/// Terminates a session from another thread, without access to the session
+/// itself (which may be busy running a command)
+#[derive(Debug, Clone)]
+pub struct SessionKiller {
+    control: Arc<SessionControl>,
+}
+
+impl SessionKiller {
+    /// Mark the session cancelled and kill cmd.exe along with everything it
+    /// started. A command blocked in `run` then fails instead of waiting.
+    pub fn kill(&self) {
+        self.control.cancelled.store(true, Ordering::SeqCst);
+        kill_tree(self.control.pid.load(Ordering::SeqCst));
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.control.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Kill the program cmd.exe is running, with everything it started, but
+    /// keep cmd.exe itself, much like Ctrl+Break. The command in `run` then
+    /// finishes with the output printed so far.
+    pub fn interrupt(&self) {
+        self.control.interrupted.store(true, Ordering::SeqCst);
+        kill_children(self.control.pid.load(Ordering::SeqCst));
+    }
+
+    /// Whether `interrupt` was called since the last `take_interrupted`
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn is_interrupted(&self) -> bool {
+        self.control.interrupted.load(Ordering::SeqCst)
+    }
+
+    /// Whether `interrupt` was called since the last call, clearing it
+    pub fn take_interrupted(&self) -> bool {
+        self.control.interrupted.swap(false, Ordering::SeqCst)
+    }
+
+    /// A killer not tied to any process, for shells without one
+    #[cfg(any(test, feature = "test-support"))]
+    pub(crate) fn detached() -> Self {
+        Self {
+            control: Arc::new(SessionControl::default()),
+        }
+    }
+}
+
+/// Kill a process and its descendants. cmd.exe doesn't take its children
+/// down with it, so a plain kill would leave e.g. a running ping behind.
+pub(crate) fn kill_tree(pid: u32) {
+    #[cfg(windows)]
+    {
+        let _ = Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &pid.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+    #[cfg(not(windows))]
+    {
+        // pid 0 would be our own process group
+        if pid == 0 {
+            return;
+        }
+        let _ = Command::new("kill")
+            .args(["-KILL", &pid.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+/// Kill the processes `pid` started, with their descendants, leaving `pid`
+/// itself running
+fn kill_children(pid: u32) {
+    if pid == 0 {
+        return;
+    }
+    #[cfg(windows)]
+    {
+        // conhost belongs to cmd.exe's console, not to the command
+        let query = format!(
+            "Get-CimInstance Win32_Process -Filter \"ParentProcessId={} AND Name<>'conhost.exe'\" | ForEach-Object {{ $_.ProcessId }}",
+            pid
+        );
+        let children = Command::new("powershell")
+            .args(["-NoProfile", "-NonInteractive", "-Command", &query])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output();
+        if let Ok(out) = children {
+            for child in String::from_utf8_lossy(&out.stdout).lines() {
+                if let Ok(child) = child.trim().parse::<u32>() {
+                    kill_tree(child);
+                }
+            }
+        }
+    }
+    #[cfg(not(windows))]
+    {
+        let _ = Command::new("pkill")
+            .args(["-KILL", "-P", &pid.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
 pub struct CmdSession {
-    _child: Child,
+    child: Child,
+    nonce: String, // Makes this session's output markers unguessable
+    options: SessionOptions,
+    control: Arc<SessionControl>,
     stdin: ChildStdin,
-    stdout: BufReader<ChildStdout>,
+    lines: Receiver<String>, // stdout lines, read on a separate thread so waits can time out
+    err_lines: Receiver<String>, // stderr lines
+    commands_run: usize,
+    timeout: Duration,
+    output_limit: usize,
+    record: SessionRecord, // Transcript set by `record_to`
+}
+
+/// Read `stream` line by line on a background thread. Blocking reads would
+/// otherwise leave `run` stuck forever on a command that never finishes.
+fn spawn_reader<R: Read + Send + 'static>(
+    stream: R,
+    control: Arc<SessionControl>,
+) -> Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    let code_page = control.code_page.load(Ordering::SeqCst);
+                    if tx.send(codepage::decode(&buf, code_page)).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Per-session random part of the output markers
+fn session_nonce(pid: u32) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    format!("{:x}", nanos ^ ((pid as u64) << 32))
 }
 
 impl CmdSession {
     pub fn start() -> io::Result<Self> {
-        let mut child = Command::new("cmd")
-            .args(["/V:ON", "/Q"])
+        Self::start_with(SessionOptions::default())
+    }
+
+    pub fn start_with(options: SessionOptions) -> io::Result<Self> {
+        Self::spawn(options, Arc::new(SessionControl::default()))
+    }
+
+    fn spawn(options: SessionOptions, control: Arc<SessionControl>) -> io::Result<Self> {
+        let mut command = options.command();
+        command
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .spawn()?;
+            .stderr(Stdio::piped());
+        if let Some(cwd) = &options.cwd {
+            if !cwd.is_dir() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("working directory '{}' does not exist", cwd.display()),
+                ));
+            }
+            command.current_dir(cwd);
+        }
+        let mut child = command.spawn().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "cannot start shell '{}': {}",
+                    options.shell.path.display(),
+                    e
+                ),
+            )
+        })?;
         let stdin = child.stdin.take().expect("no stdin");
         let stdout = child.stdout.take().expect("no stdout");
+        let stderr = child.stderr.take().expect("no stderr");
+        control.pid.store(child.id(), Ordering::SeqCst);
+        control.code_page.store(codepage::UTF8, Ordering::SeqCst);
         let mut session = Self {
-            _child: child,
+            nonce: session_nonce(child.id()),
+            child,
+            options,
+            control: control.clone(),
             stdin,
-            stdout: BufReader::new(stdout),
+            lines: spawn_reader(stdout, control.clone()),
+            err_lines: spawn_reader(stderr, control),
+            commands_run: 0,
+            timeout: DEFAULT_COMMAND_TIMEOUT,
+            output_limit: DEFAULT_OUTPUT_LIMIT,
+            record: SessionRecord::default(),
         };
         session.stdin.write_all(b"@echo off\r\n")?;
         session.stdin.flush()?;
         session.stdin.write_all(b"echo INITIALIZED\r\n")?;
         session.stdin.flush()?;
 
-        let mut line = String::new();
         let timeout = Duration::from_secs(2);
         let start = Instant::now();
 
-        loop {
-            if start.elapsed() > timeout {
-                break;
-            }
-            line.clear();
-            match session.stdout.read_line(&mut line) {
-                Ok(_) => {
+        while let Some(remaining) = timeout.checked_sub(start.elapsed()) {
+            match session.lines.recv_timeout(remaining) {
+                Ok(line) => {
                     if line.contains("INITIALIZED") {
                         break;
                     }
@@ -48,8 +565,138 @@ impl CmdSession {
             }
         }
 
+        // Output arrives in the console code page, which depends on the system
+        if let Ok(result) = session.run_helper("chcp") {
+            if let Some(code_page) = codepage::parse_chcp(&result.stdout) {
+                session.control.code_page.store(code_page, Ordering::SeqCst);
+            }
+        }
+
         Ok(session)
     }
+
+    /// Code page the session's output is decoded with
+    pub fn code_page(&self) -> u32 {
+        self.control.code_page.load(Ordering::SeqCst)
+    }
+
+    /// Switch the console code page (`chcp`), e.g. to 65001 for UTF-8
+    pub fn set_code_page(&mut self, code_page: u32) -> io::Result<()> {
+        let result = self.run_helper(&format!("chcp {} >nul", code_page))?;
+        if result.exit_code != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("code page {} is not available", code_page),
+            ));
+        }
+        self.control.code_page.store(code_page, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Directory the session was started in
+    pub fn working_dir(&self) -> PathBuf {
+        let current = std::env::current_dir().unwrap_or_default();
+        match &self.options.cwd {
+            Some(cwd) => current.join(cwd),
+            None => current,
+        }
+    }
+
+    /// Shell executable and arguments the session was started with
+    pub fn shell(&self) -> &ShellConfig {
+        &self.options.shell
+    }
+
+    /// Process id of the cmd.exe child
+    pub fn process_id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Handle for terminating the session from another thread
+    pub fn killer(&self) -> SessionKiller {
+        SessionKiller {
+            control: self.control.clone(),
+        }
+    }
+
+    /// Whether the session was shut down; the executor stops when it is
+    pub fn is_cancelled(&self) -> bool {
+        self.control.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Terminate cmd.exe and every process it launched
+    pub fn shutdown(&mut self) {
+        self.control.cancelled.store(true, Ordering::SeqCst);
+        self.kill_child();
+    }
+
+    fn kill_child(&mut self) {
+        self.record.flush();
+        if let Ok(None) = self.child.try_wait() {
+            kill_tree(self.child.id());
+            let _ = self.child.kill();
+        }
+        let _ = self.child.wait();
+    }
+
+    /// Timeout applied by `run`
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Append every command and its result to the JSONL file at `path`
+    pub fn record_to(&mut self, path: &Path) -> io::Result<()> {
+        self.record.set_transcript(Transcript::open(path)?);
+        Ok(())
+    }
+
+    /// The last `count` recorded interactions, oldest first
+    pub fn transcript(&self, count: usize) -> Vec<TranscriptEntry> {
+        self.record.transcript(count)
+    }
+
+    /// Bytes of stdout (and of stderr) kept per command
+    pub fn output_limit(&self) -> usize {
+        self.output_limit
+    }
+
+    pub fn set_output_limit(&mut self, limit: usize) {
+        self.output_limit = limit;
+    }
+
+    /// Replace a wedged or dead cmd.exe (after a timeout or `exit`) with a
+    /// fresh one, restoring `variables` and the working directory so the
+    /// script can go on
+    pub fn restart(&mut self, variables: &HashMap<String, String>, cwd: &Path) -> io::Result<()> {
+        eprintln!("Restarting CMD session");
+        self.kill_child();
+
+        // Killers handed out earlier must reach the new child, so it shares
+        // this session's control
+        let code_page = self.code_page();
+        let mut fresh = Self::spawn(self.options.clone(), self.control.clone())?;
+        fresh.timeout = self.timeout;
+        fresh.output_limit = self.output_limit;
+        fresh.record = self.record.clone();
+        fresh.commands_run = self.commands_run;
+        *self = fresh;
+        if self.code_page() != code_page {
+            self.set_code_page(code_page)?;
+        }
+
+        let mut names: Vec<&String> = variables.keys().collect();
+        names.sort();
+        for name in names {
+            self.run_helper(&format!("SET \"{}={}\"", name, variables[name]))?;
+        }
+        self.run_helper(&format!("cd /d \"{}\"", cwd.display()))?;
+        Ok(())
+    }
+
     fn needs_continuation(cmd: &str) -> bool {
         let mut paren_count = 0;
         let mut in_quotes = false;
@@ -80,28 +727,152 @@ impl CmdSession {
         paren_count > 0
     }
 
-    pub fn run_batch_block(&mut self, lines: &[String]) -> io::Result<(String, i32)> {
-        let temp_batch = "__temp_block__.bat";
+    pub fn run_batch_block(&mut self, lines: &[String]) -> io::Result<CommandResult> {
+        let temp_batch = self.temp_batch_path("block");
         let mut body = String::from("@echo off\r\n");
         for l in lines {
             body.push_str(l);
             body.push_str("\r\n");
         }
-        std::fs::write(temp_batch, body).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        let (out, code) = self.run(&format!("call {}", temp_batch))?;
-        let _ = self.run(&format!("del {} >nul 2>&1", temp_batch));
+        std::fs::write(&temp_batch, codepage::encode(&body, self.code_page()))
+            .map_err(io::Error::other)?;
+        let result = self.run(&format!("call \"{}\"", temp_batch.display()));
+        let _ = std::fs::remove_file(&temp_batch);
+
+        result
+    }
+
+    /// A batch file of its own for this session and command, in the temp
+    /// directory: the session may have changed directory since it started,
+    /// and a file left behind by another session must never run instead
+    fn temp_batch_path(&self, kind: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "batch-debugger-{}-{}-{}.bat",
+            kind, self.nonce, self.commands_run
+        ))
+    }
+
+    /// Number of commands sent to cmd.exe so far
+    pub fn command_count(&self) -> usize {
+        self.commands_run
+    }
+
+    pub fn run(&mut self, cmd: &str) -> io::Result<CommandResult> {
+        self.run_with_timeout(cmd, self.timeout)
+    }
+
+    /// Run `cmd`, failing with `SessionError::Timeout` if it takes longer
+    /// than `timeout`
+    pub fn run_with_timeout(&mut self, cmd: &str, timeout: Duration) -> io::Result<CommandResult> {
+        self.run_inner(cmd, timeout, false, &mut |_| {})
+    }
+
+    /// Run a command the debugger needs for itself rather than the script;
+    /// it is counted separately in `stats`
+    pub fn run_helper(&mut self, cmd: &str) -> io::Result<CommandResult> {
+        let timeout = self.timeout;
+        self.run_inner(cmd, timeout, true, &mut |_| {})
+    }
+
+    /// Check that cmd.exe still answers, returning how long it took
+    pub fn ping(&mut self) -> io::Result<Duration> {
+        let start = Instant::now();
+        let result = self.run_inner("echo __alive__", PING_TIMEOUT, true, &mut |_| {})?;
+        if result.stdout.trim() != "__alive__" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected answer to health check",
+            ));
+        }
+        Ok(start.elapsed())
+    }
+
+    /// Commands run and time spent so far
+    pub fn stats(&self) -> SessionStats {
+        self.record.stats()
+    }
+
+    /// Run `cmd`, passing each line of stdout to `on_chunk` as soon as CMD
+    /// prints it. The result carries the same output. Once output passes
+    /// half the output limit, the rest is held back and passed on (cut down
+    /// to the limit) when the command finishes.
+    pub fn run_streaming(
+        &mut self,
+        cmd: &str,
+        mut on_chunk: impl FnMut(&str),
+    ) -> io::Result<CommandResult> {
+        let timeout = self.timeout;
+        self.run_inner(cmd, timeout, false, &mut on_chunk)
+    }
+
+    /// Write to cmd.exe's stdin, reporting a shell that has exited as such
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self.stdin.write_all(bytes).and_then(|_| self.stdin.flush()) {
+            Ok(()) => Ok(()),
+            Err(e) => match self.exit_status(EXIT_GRACE) {
+                Some(exit_code) => Err(SessionError::SessionDied { exit_code }.into()),
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Exit code of cmd.exe if it is no longer running, waiting up to
+    /// `grace` for it to finish exiting
+    fn exit_status(&mut self, grace: Duration) -> Option<i32> {
+        let deadline = Instant::now() + grace;
+        loop {
+            match self.child.try_wait() {
+                Ok(Some(status)) => return Some(status.code().unwrap_or(-1)),
+                Ok(None) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(20))
+                }
+                _ => return None,
+            }
+        }
+    }
 
-        Ok((out, code))
+    /// Error for output that ended before the command finished
+    fn closed_error(&mut self) -> io::Error {
+        match self.exit_status(EXIT_GRACE) {
+            Some(exit_code) => SessionError::SessionDied { exit_code }.into(),
+            None => io::Error::new(io::ErrorKind::BrokenPipe, "CMD session output closed"),
+        }
+    }
+
+    fn run_inner(
+        &mut self,
+        cmd: &str,
+        timeout: Duration,
+        helper: bool,
+        on_chunk: &mut dyn FnMut(&str),
+    ) -> io::Result<CommandResult> {
+        self.record.transcribe(TranscriptEntry::sent(cmd));
+        let start = Instant::now();
+        let result = self.execute(cmd, timeout, on_chunk);
+        self.record.count(cmd, start.elapsed(), helper);
+        self.record.transcribe(match &result {
+            Ok(r) => TranscriptEntry::received(&r.stdout, &r.stderr, r.exit_code),
+            Err(e) => TranscriptEntry::failed(e),
+        });
+        result
     }
 
-    pub fn run(&mut self, cmd: &str) -> io::Result<(String, i32)> {
+    fn execute(
+        &mut self,
+        cmd: &str,
+        timeout: Duration,
+        on_chunk: &mut dyn FnMut(&str),
+    ) -> io::Result<CommandResult> {
+        self.commands_run += 1;
+        if let Some(exit_code) = self.exit_status(Duration::ZERO) {
+            return Err(SessionError::SessionDied { exit_code }.into());
+        }
         if cmd.trim().eq_ignore_ascii_case("@echo off")
             || cmd.trim().eq_ignore_ascii_case("echo off")
         {
-            self.stdin.write_all(cmd.as_bytes())?;
-            self.stdin.write_all(b"\r\n")?;
-            self.stdin.flush()?;
-            return Ok((String::new(), 0));
+            self.send(&codepage::encode(cmd, self.code_page()))?;
+            self.send(b"\r\n")?;
+            return Ok(CommandResult::default());
         }
 
         let debug_this = cmd.contains("set /a") || cmd.contains("COUNTER") || cmd.contains("if ");
@@ -111,85 +882,227 @@ impl CmdSession {
         }
         let is_multiline = Self::needs_continuation(cmd);
 
+        // Markers are unique to this session and command, so output that
+        // happens to contain one (or a late line from an earlier command)
+        // can't be mistaken for them
+        let tag = format!("{}_{}_{}", SENTINEL, self.nonce, self.commands_run);
+        let begin = format!("{}_BEGIN", tag);
+        let err_begin = format!("{}_ERR_BEGIN", tag);
+        let err_end = format!("{}_ERR_END", tag);
+        self.send(format!("echo {}\r\necho {} 1>&2\r\n", begin, err_begin).as_bytes())?;
+
         if is_multiline {
             eprintln!("DEBUG: Detected multi-line command");
-            let temp_batch = "__temp_cmd__.bat";
-            std::fs::write(temp_batch, format!("@echo off\r\n{}\r\n", cmd))
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            let temp_batch = self.temp_batch_path("cmd");
+            let body = format!("@echo off\r\n{}\r\n", cmd);
+            std::fs::write(&temp_batch, codepage::encode(&body, self.code_page()))
+                .map_err(io::Error::other)?;
 
-            self.stdin
-                .write_all(format!("call {}\r\n", temp_batch).as_bytes())?;
-            self.stdin.flush()?;
+            let call = format!("call \"{}\"\r\n", temp_batch.display());
+            self.send(&codepage::encode(&call, self.code_page()))?;
 
             std::thread::sleep(Duration::from_millis(200));
-            self.stdin
-                .write_all(format!("del {} >nul 2>&1\r\n", temp_batch).as_bytes())?;
-            self.stdin.flush()?;
+            let del = format!("del \"{}\" >nul 2>&1\r\n", temp_batch.display());
+            self.send(&codepage::encode(&del, self.code_page()))?;
         } else {
-            self.stdin.write_all(cmd.as_bytes())?;
-            self.stdin.write_all(b"\r\n")?;
-            self.stdin.flush()?;
+            self.send(&codepage::encode(cmd, self.code_page()))?;
+            self.send(b"\r\n")?;
         }
         std::thread::sleep(Duration::from_millis(100));
-        self.stdin.write_all(b"echo.\r\n")?; // Force a blank line first
-        let sentinel_cmd = format!("echo {}_%errorlevel%_END\r\n", SENTINEL);
-        self.stdin.write_all(sentinel_cmd.as_bytes())?;
-        self.stdin.flush()?;
-
-        let mut output = String::new();
-        let mut exit_code = 0;
-        let timeout = Duration::from_secs(5);
+        // Make sure output without a trailing newline doesn't run into the
+        // end marker
+        self.send(b"echo.\r\n")?;
+        let sentinel_cmd = format!("echo {}_%errorlevel%_END\r\n", tag);
+        self.send(sentinel_cmd.as_bytes())?;
+        // stderr has no ordering with stdout, so it gets its own end marker
+        self.send(format!("echo {} 1>&2\r\n", err_end).as_bytes())?;
+
+        let mut output = BoundedOutput::new(self.output_limit);
+        let exit_code;
         let start = Instant::now();
-        let mut found_blank = false;
-        let mut collecting = true;
+        let mut started = false;
+        // A blank line is only output once something follows it; the last
+        // one before the end marker comes from the `echo.` above
+        let mut held_blank: Option<String> = None;
+        let end_prefix = format!("{}_", tag);
 
         loop {
-            if start.elapsed() > timeout {
-                eprintln!("WARNING: Command timed out after 5 seconds");
-                eprintln!("  Command was: {}", cmd);
-                eprintln!("  Output collected so far: '{}'", output.trim());
-                return Ok((output, 1));
-            }
-
-            let mut line = String::new();
-            match self.stdout.read_line(&mut line) {
-                Ok(0) => {
-                    std::thread::sleep(Duration::from_millis(50));
-                    continue;
+            let received = match timeout.checked_sub(start.elapsed()) {
+                Some(remaining) => self.lines.recv_timeout(remaining),
+                None => Err(RecvTimeoutError::Timeout),
+            };
+            match received {
+                Err(RecvTimeoutError::Timeout) => {
+                    eprintln!(
+                        "WARNING: Command timed out after {} seconds",
+                        timeout.as_secs()
+                    );
+                    eprintln!("  Command was: {}", cmd);
+                    eprintln!("  Output collected so far: '{}'", output.contents().trim());
+                    return Err(SessionError::Timeout {
+                        timeout,
+                        partial_output: output.contents(),
+                    }
+                    .into());
                 }
-                Ok(_) => {
-                    let trimmed = line.trim();
+                Err(RecvTimeoutError::Disconnected) => return Err(self.closed_error()),
+                Ok(line) => {
+                    let line = match self.options.ansi {
+                        AnsiMode::Strip => ansi::strip(&line),
+                        AnsiMode::Passthrough => line,
+                    };
+                    let content = line.trim_end_matches(['\r', '\n']);
 
                     if debug_this {
-                        eprintln!("DEBUG: Read line: '{}'", trimmed);
+                        eprintln!("DEBUG: Read line: '{}'", content);
                     }
-                    if trimmed.starts_with(SENTINEL) && trimmed.ends_with("_END") {
-                        let prefix_len = SENTINEL.len() + 1;
-                        let suffix_len = 4;
-                        if trimmed.len() > prefix_len + suffix_len {
-                            let code_str = &trimmed[prefix_len..trimmed.len() - suffix_len];
-                            if let Ok(code) = code_str.parse::<i32>() {
-                                exit_code = code;
-                            }
-                        }
+                    // Anything before our start marker belongs to an earlier command
+                    if !started {
+                        started = content == begin;
+                        continue;
+                    }
+                    let code = content
+                        .strip_prefix(end_prefix.as_str())
+                        .and_then(|rest| rest.strip_suffix("_END"))
+                        .and_then(|code| code.parse::<i32>().ok());
+                    if let Some(code) = code {
+                        exit_code = code;
                         break;
                     }
-                    if trimmed.is_empty() && !found_blank {
-                        found_blank = true;
-                        collecting = false;
+                    if let Some(blank) = held_blank.take() {
+                        if output.push(&blank) {
+                            on_chunk(&blank);
+                        }
+                    }
+                    if content.trim().is_empty() {
+                        held_blank = Some(line);
+                    } else if output.push(&line) {
+                        on_chunk(&line);
+                    }
+                }
+            }
+        }
+
+        let rest = output.rest();
+        if !rest.is_empty() {
+            on_chunk(&rest);
+        }
+
+        let mut stderr = BoundedOutput::new(self.output_limit);
+        let mut started = false;
+        loop {
+            let received = match timeout.checked_sub(start.elapsed()) {
+                Some(remaining) => self.err_lines.recv_timeout(remaining),
+                None => Err(RecvTimeoutError::Timeout),
+            };
+            match received {
+                Ok(line) => {
+                    let content = line.trim_end_matches(['\r', '\n']).trim_end();
+                    if !started {
+                        started = content == err_begin;
                         continue;
                     }
-                    if collecting && !trimmed.is_empty() {
-                        output.push_str(&line);
+                    if content == err_end {
+                        break;
                     }
+                    match self.options.ansi {
+                        AnsiMode::Strip => stderr.push(&ansi::strip(&line)),
+                        AnsiMode::Passthrough => stderr.push(&line),
+                    };
                 }
-                Err(e) => {
-                    eprintln!("DEBUG: Read error: {}", e);
-                    return Err(e);
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(SessionError::Timeout {
+                        timeout,
+                        partial_output: output.contents(),
+                    }
+                    .into());
                 }
+                Err(RecvTimeoutError::Disconnected) => return Err(self.closed_error()),
             }
         }
 
-        Ok((output, exit_code))
+        Ok(CommandResult {
+            stdout: output.contents(),
+            stderr: stderr.contents(),
+            exit_code,
+            truncated: output.is_truncated() || stderr.is_truncated(),
+        })
+    }
+}
+
+impl Drop for CmdSession {
+    fn drop(&mut self) {
+        // Don't leak cmd.exe (or what it started) when a session goes away
+        self.kill_child();
+    }
+}
+
+impl Shell for CmdSession {
+    fn run(&mut self, cmd: &str) -> io::Result<CommandResult> {
+        CmdSession::run(self, cmd)
+    }
+
+    fn run_streaming(
+        &mut self,
+        cmd: &str,
+        on_chunk: &mut dyn FnMut(&str),
+    ) -> io::Result<CommandResult> {
+        CmdSession::run_streaming(self, cmd, on_chunk)
+    }
+
+    fn run_batch_block(&mut self, lines: &[String]) -> io::Result<CommandResult> {
+        CmdSession::run_batch_block(self, lines)
+    }
+
+    fn shutdown(&mut self) {
+        CmdSession::shutdown(self)
+    }
+
+    fn restart(&mut self, variables: &HashMap<String, String>, cwd: &Path) -> io::Result<()> {
+        CmdSession::restart(self, variables, cwd)
+    }
+
+    fn killer(&self) -> SessionKiller {
+        CmdSession::killer(self)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        CmdSession::is_cancelled(self)
+    }
+
+    fn timeout(&self) -> Duration {
+        CmdSession::timeout(self)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        CmdSession::set_timeout(self, timeout)
+    }
+
+    fn output_limit(&self) -> usize {
+        CmdSession::output_limit(self)
+    }
+
+    fn working_dir(&self) -> PathBuf {
+        CmdSession::working_dir(self)
+    }
+
+    fn shell_config(&self) -> ShellConfig {
+        self.options.shell.clone()
+    }
+
+    fn command_count(&self) -> usize {
+        CmdSession::command_count(self)
+    }
+
+    fn run_helper(&mut self, cmd: &str) -> io::Result<CommandResult> {
+        CmdSession::run_helper(self, cmd)
+    }
+
+    fn ping(&mut self) -> io::Result<Duration> {
+        CmdSession::ping(self)
+    }
+
+    fn record(&self) -> SessionRecord {
+        self.record.clone()
     }
 }
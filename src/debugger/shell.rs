@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use super::{CommandResult, SessionKiller, SessionRecord, ShellConfig};
+use crate::parser::{part_runs, CommandPart};
+
+/// What DebugContext needs from the shell running the script. CmdSession is
+/// the real one; `test_support::MockShell` stands in for it where there is no
+/// cmd.exe.
+pub trait Shell: Send {
+    fn run(&mut self, cmd: &str) -> io::Result<CommandResult>;
+
+    /// Run `cmd`, passing each line of stdout to `on_chunk` as it is printed
+    fn run_streaming(
+        &mut self,
+        cmd: &str,
+        on_chunk: &mut dyn FnMut(&str),
+    ) -> io::Result<CommandResult>;
+
+    /// Run the parts of a composite line (`a & b`, `a && b`, `a || b`) one
+    /// by one, skipping those their operator rules out like CMD would. Gives
+    /// one result per part that ran, so each keeps its own exit code.
+    fn run_parts(&mut self, parts: &[CommandPart]) -> io::Result<Vec<CommandResult>> {
+        let mut results: Vec<CommandResult> = Vec::new();
+        let mut previous: Option<&CommandPart> = None;
+        for part in parts {
+            let last_exit = results.last().map(|last| last.exit_code);
+            let runs = part_runs(previous.and_then(|p| p.op), last_exit);
+            previous = Some(part);
+            if runs && !part.text.trim().is_empty() {
+                results.push(self.run(&part.text)?);
+            }
+        }
+        Ok(results)
+    }
+
+    /// Run a multi-line block (IF/FOR with parentheses) as a unit
+    fn run_batch_block(&mut self, lines: &[String]) -> io::Result<CommandResult>;
+
+    /// Terminate the shell and everything it started
+    fn shutdown(&mut self);
+
+    /// Replace a wedged shell with a fresh one holding `variables` and `cwd`
+    fn restart(&mut self, variables: &HashMap<String, String>, cwd: &Path) -> io::Result<()>;
+
+    /// Handle for terminating the shell from another thread
+    fn killer(&self) -> SessionKiller;
+
+    fn is_cancelled(&self) -> bool;
+
+    fn timeout(&self) -> Duration;
+
+    fn set_timeout(&mut self, timeout: Duration);
+
+    fn output_limit(&self) -> usize;
+
+    /// Directory the shell was started in
+    fn working_dir(&self) -> PathBuf;
+
+    /// Executable and arguments the shell was started with
+    fn shell_config(&self) -> ShellConfig;
+
+    /// Number of commands run so far
+    fn command_count(&self) -> usize;
+
+    /// Run a query the debugger makes for itself, not for the script
+    fn run_helper(&mut self, cmd: &str) -> io::Result<CommandResult> {
+        self.run(cmd)
+    }
+
+    /// Check that the shell still answers, returning how long it took
+    fn ping(&mut self) -> io::Result<Duration> {
+        let start = Instant::now();
+        self.run_helper("echo __alive__")?;
+        Ok(start.elapsed())
+    }
+
+    /// Handle on the commands run, time spent and transcript so far,
+    /// readable while the shell is busy
+    fn record(&self) -> SessionRecord {
+        SessionRecord::default()
+    }
+}
+
+/// A shell behind a lock of its own, so a long command can run while the
+/// DebugContext stays available to the DAP server
+pub type SharedShell = Arc<Mutex<Box<dyn Shell>>>;
+
+/// Lock a shared shell. A panic while running a command doesn't make the
+/// shell unusable, so a poisoned lock is taken over.
+pub fn lock_shell(shell: &SharedShell) -> MutexGuard<'_, Box<dyn Shell>> {
+    shell.lock().unwrap_or_else(|e| e.into_inner())
+}
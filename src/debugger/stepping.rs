@@ -1,7 +1,72 @@
+use super::{Frame, LocalScope, VariableOrigin};
+use crate::parser::{command_name, is_builtin_command, parse_redirections};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RunMode {
     Continue,
     StepOver,
     StepInto,
     StepOut,
+    StepBack,
+}
+
+/// How far a step moves
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum StepGranularity {
+    #[default]
+    Line, // A whole logical line
+    Statement, // One of the `&`, `&&` or `||` separated commands of a line
+}
+
+/// Tracked state before a logical line executed, used to step back
+#[derive(Debug, Clone)]
+pub struct StateSnapshot {
+    pub pc: usize,
+    pub variables: HashMap<String, String>,
+    pub variable_origins: HashMap<String, VariableOrigin>,
+    pub call_stack: Vec<Frame>,
+    pub root_scopes: Vec<LocalScope>,
+    pub last_exit_code: i32,
+    pub current_dir: PathBuf,
+    pub directory_stack: Vec<String>,
+    pub side_effects: bool, // The line may have changed things outside tracked state
+}
+
+/// Whether a line can change things stepping back cannot restore: files,
+/// other processes, or output written to a file
+pub fn has_external_side_effects(line: &str) -> bool {
+    let cmd = parse_redirections(line);
+    let writes_file = cmd.redirections.iter().any(|r| {
+        matches!(r.operator.as_str(), ">" | ">>" | "2>")
+            && !r.target.trim().eq_ignore_ascii_case("nul")
+    });
+    if writes_file {
+        return true;
+    }
+    let base = cmd.base_command.trim();
+    if base.is_empty() {
+        return false;
+    }
+    if !is_builtin_command(base) {
+        return true;
+    }
+    matches!(
+        command_name(base).to_uppercase().as_str(),
+        "COPY"
+            | "DEL"
+            | "ERASE"
+            | "MOVE"
+            | "MD"
+            | "MKDIR"
+            | "RD"
+            | "RMDIR"
+            | "REN"
+            | "RENAME"
+            | "MKLINK"
+            | "START"
+            | "ASSOC"
+            | "FTYPE"
+    )
 }
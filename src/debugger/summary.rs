@@ -0,0 +1,68 @@
+//! How a run of the script ended, as reported by the executor and sent to
+//! the client with the terminated event.
+
+use crate::error::BatchDbgError;
+
+/// Why the executor stopped running the script
+#[derive(Debug, Clone, PartialEq)]
+pub enum TerminatedReason {
+    /// Ran off the end, or left with EXIT /B or GOTO :EOF
+    Completed,
+    /// EXIT ended the script
+    Exit,
+    /// CALL or GOTO of a label that doesn't exist, on 1-based line `line`
+    UnknownLabel { name: String, line: usize },
+    /// The client terminated the session
+    Cancelled,
+    /// The client stopped taking events
+    Disconnected,
+    /// The executor couldn't go on (what went wrong)
+    Failed(String),
+}
+
+impl TerminatedReason {
+    /// Name used in the terminated event
+    pub fn name(&self) -> &'static str {
+        match self {
+            TerminatedReason::Completed => "completed",
+            TerminatedReason::Exit => "exit",
+            TerminatedReason::UnknownLabel { .. } => "unknownLabel",
+            TerminatedReason::Cancelled => "cancelled",
+            TerminatedReason::Disconnected => "disconnected",
+            TerminatedReason::Failed(_) => "error",
+        }
+    }
+
+    /// Text explaining the reason, for the ones that carry any
+    pub fn detail(&self) -> Option<String> {
+        match self {
+            TerminatedReason::UnknownLabel { name, .. } => Some(format!(
+                "The system cannot find the batch label specified - {}",
+                name
+            )),
+            TerminatedReason::Failed(message) => Some(message.clone()),
+            _ => None,
+        }
+    }
+
+    /// The error the run ended with, for the reasons that are the script's
+    /// fault
+    pub fn error(&self) -> Option<BatchDbgError> {
+        match self {
+            TerminatedReason::UnknownLabel { name, line } => Some(BatchDbgError::UnknownLabel {
+                name: name.clone(),
+                line: *line,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of one run of the script
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunSummary {
+    pub exit_code: i32,         // ERRORLEVEL when the script ended
+    pub lines_executed: usize,  // Lines run; a FOR line counts once however often it loops
+    pub max_stack_depth: usize, // Deepest CALL nesting reached
+    pub terminated_reason: TerminatedReason,
+}
@@ -0,0 +1,221 @@
+//! Stand-ins for the CMD session, so DebugContext logic can be tested
+//! without cmd.exe
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::session::DEFAULT_COMMAND_TIMEOUT;
+use super::{
+    CommandResult, SessionError, SessionKiller, SessionRecord, Shell, ShellConfig,
+    DEFAULT_OUTPUT_LIMIT,
+};
+
+/// A shell that answers from a script instead of running anything. The first
+/// response whose pattern occurs in the command (ignoring case) is returned;
+/// commands without one succeed with no output.
+pub struct MockShell {
+    responses: Vec<(String, CommandResult)>,
+    delays: Vec<(String, Duration)>,
+    commands: Arc<Mutex<Vec<String>>>,
+    record: SessionRecord,
+    killer: SessionKiller,
+    timeout: Duration,
+    output_limit: usize,
+    working_dir: PathBuf,
+}
+
+impl MockShell {
+    pub fn new() -> Self {
+        Self {
+            responses: Vec::new(),
+            delays: Vec::new(),
+            commands: Arc::new(Mutex::new(Vec::new())),
+            record: SessionRecord::default(),
+            killer: SessionKiller::detached(),
+            timeout: DEFAULT_COMMAND_TIMEOUT,
+            output_limit: DEFAULT_OUTPUT_LIMIT,
+            working_dir: std::env::current_dir().unwrap_or_default(),
+        }
+    }
+
+    /// Answer commands containing `pattern` with `stdout` and `exit_code`
+    pub fn respond(mut self, pattern: &str, stdout: &str, exit_code: i32) -> Self {
+        self.responses.push((
+            pattern.to_lowercase(),
+            CommandResult {
+                stdout: stdout.to_string(),
+                exit_code,
+                ..CommandResult::default()
+            },
+        ));
+        self
+    }
+
+    /// Answer commands containing `pattern` with `stderr` and `exit_code`,
+    /// like a failing program would
+    pub fn fail(mut self, pattern: &str, stderr: &str, exit_code: i32) -> Self {
+        self.responses.push((
+            pattern.to_lowercase(),
+            CommandResult {
+                stderr: stderr.to_string(),
+                exit_code,
+                ..CommandResult::default()
+            },
+        ));
+        self
+    }
+
+    /// Take `duration` to run commands containing `pattern`, like a slow
+    /// program would. Output comes first; an interrupt cuts the wait short
+    /// and the command fails with exit code 1, and running past the command
+    /// timeout fails the way CMD's does.
+    pub fn delay(mut self, pattern: &str, duration: Duration) -> Self {
+        self.delays.push((pattern.to_lowercase(), duration));
+        self
+    }
+
+    /// Start in `dir` instead of the current directory
+    pub fn with_working_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.working_dir = dir.into();
+        self
+    }
+
+    /// Commands run so far, shared so they can be inspected after the shell
+    /// was handed to a DebugContext
+    pub fn commands(&self) -> Arc<Mutex<Vec<String>>> {
+        self.commands.clone()
+    }
+}
+
+impl Default for MockShell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockShell {
+    /// The scripted response to `cmd`
+    fn answer(&mut self, cmd: &str, on_chunk: &mut dyn FnMut(&str)) -> io::Result<CommandResult> {
+        if self.killer.is_cancelled() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "CMD session output closed",
+            ));
+        }
+        self.commands.lock().unwrap().push(cmd.to_string());
+        let lower = cmd.to_lowercase();
+        let mut result = self
+            .responses
+            .iter()
+            .find(|(pattern, _)| lower.contains(pattern.as_str()))
+            .map(|(_, result)| result.clone())
+            .unwrap_or_default();
+        for line in result.stdout.split_inclusive('\n') {
+            on_chunk(line);
+        }
+        if let Some((_, duration)) = self
+            .delays
+            .iter()
+            .find(|(pattern, _)| lower.contains(pattern.as_str()))
+        {
+            let started = Instant::now();
+            while started.elapsed() < *duration {
+                if self.killer.is_interrupted() {
+                    result.exit_code = 1;
+                    break;
+                }
+                if started.elapsed() >= self.timeout {
+                    return Err(SessionError::Timeout {
+                        timeout: self.timeout,
+                        partial_output: String::new(),
+                    }
+                    .into());
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl Shell for MockShell {
+    fn run(&mut self, cmd: &str) -> io::Result<CommandResult> {
+        self.run_streaming(cmd, &mut |_| {})
+    }
+
+    fn run_streaming(
+        &mut self,
+        cmd: &str,
+        on_chunk: &mut dyn FnMut(&str),
+    ) -> io::Result<CommandResult> {
+        let result = self.answer(cmd, on_chunk);
+        self.record.count(cmd, Duration::ZERO, false);
+        result
+    }
+
+    fn run_helper(&mut self, cmd: &str) -> io::Result<CommandResult> {
+        let result = self.answer(cmd, &mut |_| {});
+        self.record.count(cmd, Duration::ZERO, true);
+        result
+    }
+
+    fn record(&self) -> SessionRecord {
+        self.record.clone()
+    }
+
+    fn run_batch_block(&mut self, lines: &[String]) -> io::Result<CommandResult> {
+        self.run(&lines.join("\r\n"))
+    }
+
+    fn shutdown(&mut self) {
+        self.killer.kill();
+    }
+
+    fn restart(&mut self, variables: &HashMap<String, String>, cwd: &Path) -> io::Result<()> {
+        let mut names: Vec<&String> = variables.keys().collect();
+        names.sort();
+        for name in names {
+            self.run(&format!("SET \"{}={}\"", name, variables[name]))?;
+        }
+        self.run(&format!("cd /d \"{}\"", cwd.display()))?;
+        Ok(())
+    }
+
+    fn killer(&self) -> SessionKiller {
+        self.killer.clone()
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.killer.is_cancelled()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn output_limit(&self) -> usize {
+        self.output_limit
+    }
+
+    fn working_dir(&self) -> PathBuf {
+        self.working_dir.clone()
+    }
+
+    fn shell_config(&self) -> ShellConfig {
+        ShellConfig {
+            path: PathBuf::from("mock"),
+            args: Vec::new(),
+        }
+    }
+
+    fn command_count(&self) -> usize {
+        self.commands.lock().unwrap().len()
+    }
+}
@@ -0,0 +1,123 @@
+//! Record of everything exchanged with cmd.exe, for diagnosing the debugger
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Entries kept in memory for `batch/dumpTranscript`
+const RECENT_ENTRIES: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,     // Command written to cmd.exe
+    Received, // What came back for it
+}
+
+/// One interaction, written as a JSON line
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TranscriptEntry {
+    pub timestamp_ms: u64, // Milliseconds since the Unix epoch
+    pub direction: Direction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl TranscriptEntry {
+    fn new(direction: Direction) -> Self {
+        Self {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            direction,
+            command: None,
+            output: None,
+            stderr: None,
+            exit_code: None,
+            error: None,
+        }
+    }
+
+    pub fn sent(command: &str) -> Self {
+        Self {
+            command: Some(command.to_string()),
+            ..Self::new(Direction::Sent)
+        }
+    }
+
+    pub fn received(output: &str, stderr: &str, exit_code: i32) -> Self {
+        Self {
+            output: Some(output.to_string()),
+            stderr: Some(stderr.to_string()).filter(|s| !s.is_empty()),
+            exit_code: Some(exit_code),
+            ..Self::new(Direction::Received)
+        }
+    }
+
+    pub fn failed(error: &io::Error) -> Self {
+        Self {
+            error: Some(error.to_string()),
+            ..Self::new(Direction::Received)
+        }
+    }
+}
+
+/// Appends entries to a JSONL file and keeps the latest ones in memory.
+/// Recording is best effort: a failing write disables the file, never the
+/// command that was being recorded.
+pub struct Transcript {
+    writer: Option<BufWriter<File>>,
+    recent: VecDeque<TranscriptEntry>,
+}
+
+impl Transcript {
+    /// Record to `path`, appending if it exists
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: Some(BufWriter::new(file)),
+            recent: VecDeque::new(),
+        })
+    }
+
+    pub fn record(&mut self, entry: TranscriptEntry) {
+        if let Some(writer) = &mut self.writer {
+            let written = serde_json::to_string(&entry)
+                .map_err(io::Error::from)
+                .and_then(|line| writeln!(writer, "{}", line));
+            if let Err(e) = written {
+                eprintln!("WARNING: Session transcript disabled: {}", e);
+                self.writer = None;
+            }
+        }
+        if self.recent.len() == RECENT_ENTRIES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(entry);
+    }
+
+    /// The last `count` entries, oldest first
+    pub fn recent(&self, count: usize) -> Vec<TranscriptEntry> {
+        let skip = self.recent.len().saturating_sub(count);
+        self.recent.iter().skip(skip).cloned().collect()
+    }
+
+    pub fn flush(&mut self) {
+        if let Some(writer) = &mut self.writer {
+            let _ = writer.flush();
+        }
+    }
+}
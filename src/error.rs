@@ -0,0 +1,154 @@
+//! What can go wrong while debugging a script, told apart so callers (and
+//! DAP clients, through the error id) can react to each: a dead session
+//! needs a restart, a timeout may only need a retry, an unknown label is
+//! the script's own fault.
+
+use crate::debugger::SessionError;
+use serde_json::{json, Value};
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum BatchDbgError {
+    /// Talking to the CMD session failed
+    SessionIo(io::Error),
+    /// A command didn't finish in time; the session must be restarted
+    SessionTimeout {
+        timeout: Duration,
+        partial_output: String,
+    },
+    /// cmd.exe exited under the debugger; the session must be restarted
+    SessionDied { exit_code: i32 },
+    /// Text the debugger couldn't make sense of, on 1-based line `line`
+    ParseError { line: usize, message: String },
+    /// CALL or GOTO of a label the script doesn't define, on 1-based line
+    /// `line` of the file doing it
+    UnknownLabel { name: String, line: usize },
+    /// An expression that would run commands of its own if evaluated
+    EvalUnsafe(String),
+    /// A thread panicked while holding the debug context
+    LockPoisoned,
+}
+
+impl BatchDbgError {
+    /// The id of the error in a DAP error response. 1 and 2 are taken by
+    /// failures that aren't a BatchDbgError (a read-only variable, a failed
+    /// pre-launch command).
+    pub fn dap_id(&self) -> u64 {
+        match self {
+            BatchDbgError::SessionIo(_) => 3,
+            BatchDbgError::SessionTimeout { .. } => 4,
+            BatchDbgError::SessionDied { .. } => 5,
+            BatchDbgError::ParseError { .. } => 6,
+            BatchDbgError::UnknownLabel { .. } => 7,
+            BatchDbgError::EvalUnsafe(_) => 8,
+            BatchDbgError::LockPoisoned => 9,
+        }
+    }
+
+    /// Body of the DAP response failing a request with this error
+    pub fn dap_body(&self) -> Value {
+        json!({
+            "error": {
+                "id": self.dap_id(),
+                "format": self.to_string()
+            }
+        })
+    }
+}
+
+impl fmt::Display for BatchDbgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchDbgError::SessionIo(e) => write!(f, "{}", e),
+            BatchDbgError::SessionTimeout { timeout, .. } => {
+                write!(f, "command timed out after {} seconds", timeout.as_secs())
+            }
+            BatchDbgError::SessionDied { exit_code } => {
+                write!(f, "CMD exited with code {}", exit_code)
+            }
+            BatchDbgError::ParseError { line, message } => {
+                write!(f, "line {}: {}", line, message)
+            }
+            BatchDbgError::UnknownLabel { name, line } => write!(
+                f,
+                "line {}: the system cannot find the batch label specified - {}",
+                line, name
+            ),
+            BatchDbgError::EvalUnsafe(expression) => write!(
+                f,
+                "'{}' would run a command; only expressions without &, |, < or > are evaluated",
+                expression
+            ),
+            BatchDbgError::LockPoisoned => write!(f, "debug context lock poisoned"),
+        }
+    }
+}
+
+impl std::error::Error for BatchDbgError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BatchDbgError::SessionIo(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<SessionError> for BatchDbgError {
+    fn from(e: SessionError) -> Self {
+        match e {
+            SessionError::Timeout {
+                timeout,
+                partial_output,
+            } => BatchDbgError::SessionTimeout {
+                timeout,
+                partial_output,
+            },
+            SessionError::SessionDied { exit_code } => BatchDbgError::SessionDied { exit_code },
+        }
+    }
+}
+
+/// The session layer still speaks io::Error; a SessionError or BatchDbgError
+/// carried inside one comes back out as itself
+impl From<io::Error> for BatchDbgError {
+    fn from(e: io::Error) -> Self {
+        let ours = e
+            .get_ref()
+            .is_some_and(|inner| inner.is::<BatchDbgError>() || inner.is::<SessionError>());
+        if !ours {
+            return BatchDbgError::SessionIo(e);
+        }
+        let kind = e.kind();
+        match e
+            .into_inner()
+            .map(|inner| inner.downcast::<BatchDbgError>())
+        {
+            Some(Ok(err)) => *err,
+            Some(Err(inner)) => match inner.downcast::<SessionError>() {
+                Ok(session) => (*session).into(),
+                Err(inner) => BatchDbgError::SessionIo(io::Error::new(kind, inner)),
+            },
+            None => BatchDbgError::SessionIo(io::Error::from(kind)),
+        }
+    }
+}
+
+/// For the callers still returning io::Result; `From<io::Error>` gets the
+/// BatchDbgError back
+impl From<BatchDbgError> for io::Error {
+    fn from(e: BatchDbgError) -> Self {
+        let kind = match e {
+            BatchDbgError::SessionIo(inner) => return inner,
+            BatchDbgError::SessionTimeout { .. } => io::ErrorKind::TimedOut,
+            BatchDbgError::SessionDied { .. } => io::ErrorKind::BrokenPipe,
+            BatchDbgError::ParseError { .. } | BatchDbgError::UnknownLabel { .. } => {
+                io::ErrorKind::InvalidData
+            }
+            BatchDbgError::EvalUnsafe(_) => io::ErrorKind::InvalidInput,
+            BatchDbgError::LockPoisoned => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
+    }
+}
@@ -1,182 +1,957 @@
-use crate::debugger::{leave_context, DebugContext, Frame, RunMode};
+use crate::debugger::{
+    find_called_script, leave_context, lock_shell, run_and_wait, CommandResult, DebugContext,
+    DebugLog, Frame, RunMode, RunSummary, SessionError, StepGranularity, TerminatedReason,
+};
+use crate::error::BatchDbgError;
 use crate::parser::{
-    normalize_whitespace, parse_for_statement, parse_if_statement, parse_redirections,
-    PreprocessResult,
+    block_end, command_name, exit_command, find_label, goto_target, group_body,
+    is_builtin_command, join_block, normalize_whitespace, parse_delay, parse_for_statement,
+    parse_if_statement, parse_interactive_prompt, parse_redirections, parse_start_command,
+    part_runs, split_batch_arguments, split_composite_command, strip_cd_command, CommandPart,
+    InteractivePrompt, LogicalLine, PreprocessResult,
 };
 use std::collections::HashMap;
-use std::io::{self, Write};
-use std::sync::mpsc::Sender;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-
-/// Check if a command is a CMD built-in command
-fn is_builtin_command(cmd: &str) -> bool {
-    let cmd_upper = cmd.to_uppercase();
-
-    // Extract just the command name (before first space or special char)
-    let cmd_name = cmd_upper.split_whitespace().next().unwrap_or(&cmd_upper);
-
-    // List of CMD built-in commands
-    matches!(
-        cmd_name,
-        "ASSOC"
-            | "BREAK"
-            | "CALL"
-            | "CD"
-            | "CHDIR"
-            | "CLS"
-            | "COLOR"
-            | "COPY"
-            | "DATE"
-            | "DEL"
-            | "DIR"
-            | "ECHO"
-            | "ENDLOCAL"
-            | "ERASE"
-            | "EXIT"
-            | "FOR"
-            | "FTYPE"
-            | "GOTO"
-            | "IF"
-            | "MD"
-            | "MKDIR"
-            | "MKLINK"
-            | "MOVE"
-            | "PATH"
-            | "PAUSE"
-            | "POPD"
-            | "PROMPT"
-            | "PUSHD"
-            | "RD"
-            | "REM"
-            | "REN"
-            | "RENAME"
-            | "RMDIR"
-            | "SET"
-            | "SETLOCAL"
-            | "SHIFT"
-            | "START"
-            | "TIME"
-            | "TITLE"
-            | "TYPE"
-            | "VER"
-            | "VERIFY"
-            | "VOL"
-    )
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a stopped executor checks whether the session was terminated
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// FOR loops with more iterations than this report their progress
+const PROGRESS_MIN_ITERATIONS: usize = 50;
+/// Iterations between progress updates, which come at most once per
+/// PROGRESS_INTERVAL
+const PROGRESS_STEP: usize = 50;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The batch file a `CALL` runs, `rest` being what follows the keyword.
+/// Relative paths are tried against the current directory, then the directory
+/// of the file making the call.
+fn called_script_path(ctx: &DebugContext, rest: &str) -> Option<PathBuf> {
+    let rest = rest.trim_start();
+    let target = match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next().unwrap_or(""),
+        None => rest.split_whitespace().next().unwrap_or(""),
+    };
+
+    let caller = match ctx.current_script() {
+        Some(script) => Some(script.to_path_buf()),
+        None => Some(PathBuf::from(ctx.script_path().trim_matches('"')))
+            .filter(|p| !p.as_os_str().is_empty()),
+    };
+    let mut dirs = vec![ctx.get_current_dir().to_path_buf()];
+    if let Some(dir) = caller.as_deref().and_then(Path::parent) {
+        dirs.push(dir.to_path_buf());
+    }
+    find_called_script(target, &dirs)
+}
+
+/// Where a CALL, GOTO or EXIT /B sends execution
+enum Transfer {
+    /// Carry on at this line of the script the innermost frame runs in
+    Jump(usize),
+    /// The script has finished, or can't go on
+    Finish(TerminatedReason),
+}
+
+/// FOR iterations still to run while a CALL from the loop body is away
+struct PendingLoop {
+    pc: usize,
+    depth: usize,
+    iterations: Vec<(String, String, String)>,
+    done: usize,
+}
+
+/// Whether `line` is a CALL, GOTO or EXIT, which transfer_control carries out
+fn is_control_transfer(line: &str) -> bool {
+    let name = command_name(line).to_uppercase();
+    matches!(name.as_str(), "CALL" | "GOTO" | "EXIT") || name.starts_with("GOTO:")
+}
+
+/// Carry out `line` if it is a CALL of a label or batch file, a GOTO or an
+/// EXIT. Used for whole lines as well as IF branches and FOR bodies, so
+/// control flow is the same wherever it appears. Labels are looked up from
+/// `pc`, the line being run, and a CALL returns to `return_pc`. None for
+/// every other line, CALLs of commands included.
+fn transfer_control(
+    ctx: &mut DebugContext,
+    line: &str,
+    pc: usize,
+    return_pc: usize,
+    pre: &PreprocessResult,
+    labels_phys: &HashMap<String, usize>,
+    stderr_tx: &Sender<ScriptOutput>,
+) -> Option<Transfer> {
+    let upper = line.to_uppercase();
+
+    if upper.starts_with("CALL ") {
+        let rest = line[5..].trim();
+        let mut words = split_batch_arguments(rest).into_iter();
+        let first = words.next().unwrap_or_default();
+        let label_key = first.trim_start_matches(':').to_lowercase();
+        let args: Vec<String> = words.collect();
+
+        if let Some(logical_target) = labels_phys
+            .contains_key(&label_key)
+            .then(|| find_label(pre, &label_key, pc))
+            .flatten()
+        {
+            let label = first.trim_start_matches(':');
+            let script = ctx.current_script().map(Path::to_path_buf);
+            ctx.call_stack.push(
+                Frame::new(return_pc, Some(args))
+                    .with_script(script)
+                    .with_label(label, logical_target),
+            );
+            return Some(Transfer::Jump(logical_target));
+        }
+        if first.starts_with(':') {
+            eprintln!("ERROR: CALL to unknown label: {}", label_key);
+            return Some(Transfer::Finish(TerminatedReason::UnknownLabel {
+                name: label_key,
+                line: pre.logical.get(pc).map_or(1, |l| l.phys_start + 1),
+            }));
+        }
+        // CALL of another batch file: step through it in a frame of its own
+        // unless it should run as a single command
+        if ctx.step_into_called_scripts() {
+            if let Some(path) = called_script_path(ctx, rest) {
+                match ctx.load_script(&path) {
+                    Ok(script) => {
+                        eprintln!("CALL: stepping into {}", script.path.display());
+                        ctx.script_called(script);
+                        ctx.call_stack
+                            .push(Frame::new(return_pc, Some(args)).with_script(Some(path)));
+                        return Some(Transfer::Jump(0));
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "WARNING: Cannot read {}, running it whole: {}",
+                            path.display(),
+                            e
+                        );
+                    }
+                }
+            }
+        }
+        // CALL of a command (CALL SET, CALL ECHO, ...): run it like any
+        // other line, track_set_command handles the extra expansion
+        return None;
+    }
+    if let Some((leaves_call, rest)) = exit_command(line) {
+        if leaves_call {
+            ctx.last_exit_code = ctx.parse_exit_code(rest);
+            return Some(match leave_context(&mut ctx.call_stack) {
+                Some(next_pc) => Transfer::Jump(next_pc),
+                None => Transfer::Finish(TerminatedReason::Completed),
+            });
+        }
+        // Plain EXIT ends the whole script, however deep in CALLs. Sending it
+        // to the session would only kill cmd.exe.
+        ctx.last_exit_code = ctx.parse_exit_code(rest);
+        eprintln!("EXIT: script exited with code {}", ctx.last_exit_code);
+        ctx.call_stack.clear();
+        ctx.session_mut().shutdown();
+        return Some(Transfer::Finish(TerminatedReason::Exit));
+    }
+    if let Some(label_key) = goto_target(line) {
+        if label_key == "eof" {
+            return Some(match leave_context(&mut ctx.call_stack) {
+                Some(next_pc) => Transfer::Jump(next_pc),
+                None => Transfer::Finish(TerminatedReason::Completed),
+            });
+        }
+        // The label map knows which labels exist; which copy of one is taken
+        // depends on where the GOTO is
+        if labels_phys.contains_key(&label_key) {
+            if let Some(target) = find_label(pre, &label_key, pc) {
+                return Some(Transfer::Jump(target));
+            }
+        }
+        // CMD reports the missing label and ends the current CALL (or the
+        // script), with ERRORLEVEL 1
+        eprintln!("ERROR: GOTO to unknown label: {}", label_key);
+        let _ = stderr_tx.send(
+            ScriptOutput::at(
+                format!(
+                    "The system cannot find the batch label specified - {}\r\n",
+                    label_key
+                ),
+                pc,
+                ctx.current_script(),
+            )
+            .with_kind(OutputKind::ScriptStderr),
+        );
+        ctx.last_exit_code = 1;
+        return Some(match leave_context(&mut ctx.call_stack) {
+            Some(next_pc) => Transfer::Jump(next_pc),
+            None => Transfer::Finish(TerminatedReason::UnknownLabel {
+                name: label_key,
+                line: pre.logical.get(pc).map_or(1, |l| l.phys_start + 1),
+            }),
+        });
+    }
+    None
+}
+
+/// Evaluate an IF line's condition once and pick the branch to run in its
+/// place: Some(None) when no branch runs, None when `line` is not an IF that
+/// can be handled here (open blocks, /I), which then goes to CMD whole
+fn take_if_branch(
+    ctx: &mut DebugContext,
+    line: &str,
+    output_tx: &Sender<ScriptOutput>,
+) -> Option<Option<String>> {
+    if !line.to_uppercase().starts_with("IF ") {
+        return None;
+    }
+    let if_stmt = parse_if_statement(line)?;
+    let condition_result = match ctx.evaluate_if_condition(&if_stmt.condition) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("WARNING: Failed to evaluate IF condition: {}", e);
+            return None;
+        }
+    };
+    let (note, branch) = if condition_result {
+        (
+            "IF: Condition is TRUE -> executing THEN branch",
+            Some(if_stmt.then_command),
+        )
+    } else if if_stmt.else_command.is_some() {
+        (
+            "IF: Condition is FALSE -> executing ELSE branch",
+            if_stmt.else_command,
+        )
+    } else {
+        ("IF: Condition is FALSE -> skipping THEN branch", None)
+    };
+    eprintln!("{}", note);
+    if ctx.verbose_console() {
+        if let Err(e) = output_tx.send(format!("{}\r\n", note).into()) {
+            eprintln!("ERROR: Failed to send output: {}", e);
+        }
+    }
+    Some(branch.filter(|command| !command.trim().is_empty()))
+}
+
+/// Track the SETs of every command on a line that ran with `result`. The
+/// line may chain them with `&`, `&&` or `||` and group them in parentheses
+/// (a joined ( block).
+fn track_set_commands(ctx: &mut DebugContext, line: &str, result: &CommandResult) {
+    let parts = split_composite_command(line);
+    for part in &parts {
+        let mut text = part.text.trim_start_matches(['(', ' ', '\t']).trim_end();
+        // The ) closing the group, not one belonging to the value
+        if text.matches(')').count() > text.matches('(').count() {
+            text = text.strip_suffix(')').unwrap_or(text).trim_end();
+        }
+        if parts.len() == 1 {
+            ctx.record_command(text, result);
+        } else {
+            ctx.record_command_part(text);
+        }
+    }
+}
+
+/// Commands the executor carries out itself instead of passing to CMD;
+/// statement stepping leaves lines with one of them before the last part
+/// whole
+const EXECUTOR_COMMANDS: &[&str] = &[
+    "CALL", "GOTO", "EXIT", "IF", "FOR", "SETLOCAL", "ENDLOCAL", "PUSHD", "POPD", "CD", "CHDIR",
+    "SHIFT", "START",
+];
+
+/// The commands of `line` a statement step stops between, when it chains
+/// two or more with `&`, `&&` or `||`. None for lines with groups, and for
+/// those that need the executor before the last command.
+fn statement_parts(line: &str) -> Option<Vec<CommandPart>> {
+    let parts = split_composite_command(line);
+    if parts.len() < 2
+        || parts
+            .iter()
+            .any(|p| p.text.is_empty() || p.text.contains('('))
+    {
+        return None;
+    }
+    let handled = parts[..parts.len() - 1].iter().any(|p| {
+        let name = command_name(&p.text).to_uppercase();
+        EXECUTOR_COMMANDS.contains(&name.as_str())
+    });
+    (!handled).then_some(parts)
+}
+
+/// 1-based column of each command of `raw` (the line as written), or
+/// nothing when it doesn't split into `count` of them
+fn statement_columns(raw: &str, count: usize) -> Vec<usize> {
+    let parts = split_composite_command(raw);
+    if parts.len() != count {
+        return Vec::new();
+    }
+    let mut columns = Vec::new();
+    let mut from = 0;
+    for part in parts {
+        match raw[from..].find(part.text.as_str()) {
+            Some(offset) => {
+                columns.push(raw[..from + offset].chars().count() + 1);
+                from += offset + part.text.len();
+            }
+            None => return Vec::new(),
+        }
+    }
+    columns
+}
+
+/// Whether the step the client asked for stops between the commands of a
+/// line, `step_depth` being where a step over ends
+fn stops_between_statements(ctx: &DebugContext, step_depth: Option<usize>) -> bool {
+    ctx.step_granularity() == StepGranularity::Statement
+        && match ctx.mode() {
+            RunMode::StepInto => true,
+            RunMode::StepOver => step_depth.is_none_or(|depth| ctx.call_stack.len() <= depth),
+            _ => false,
+        }
+}
+
+/// The command CMD couldn't find when `line` ran, if that is why it failed:
+/// ERRORLEVEL 9009 or CMD's "is not recognized" message, which names it
+fn missing_command(line: &str, result: &CommandResult) -> Option<String> {
+    const NOT_RECOGNIZED: &str = "' is not recognized as an internal or external command";
+    if result.exit_code != 9009 && !result.stderr.contains(NOT_RECOGNIZED) {
+        return None;
+    }
+    let named = result.stderr.lines().find_map(|l| {
+        let rest = l.trim().strip_prefix('\'')?;
+        rest.find(NOT_RECOGNIZED).map(|end| rest[..end].to_string())
+    });
+    Some(named.unwrap_or_else(|| command_name(line).trim_matches('"').to_string()))
+}
+
+/// Send the stdout and stderr of the command on line `pc` of `script` to
+/// the client on their own channels
+fn forward_output(
+    result: &CommandResult,
+    pc: usize,
+    script: Option<&Path>,
+    output_tx: &Sender<ScriptOutput>,
+    stderr_tx: &Sender<ScriptOutput>,
+) {
+    if !result.stdout.trim().is_empty() {
+        if let Err(e) = output_tx.send(ScriptOutput::at(result.stdout.clone(), pc, script)) {
+            eprintln!("ERROR: Failed to send output: {}", e);
+        }
+    }
+    if !result.stderr.trim().is_empty() {
+        let stderr = ScriptOutput::at(result.stderr.clone(), pc, script);
+        if let Err(e) = stderr_tx.send(stderr.with_kind(OutputKind::ScriptStderr)) {
+            eprintln!("ERROR: Failed to send output: {}", e);
+        }
+    }
+}
+
+/// Stand in for the user at a prompt the session can't show: SET /P and
+/// CHOICE get the input provided by the client (or CHOICE's default)
+fn answer_prompt(
+    ctx: &mut DebugContext,
+    prompt: &InteractivePrompt,
+    output_tx: &Sender<ScriptOutput>,
+) -> io::Result<()> {
+    match prompt {
+        // Either skipped or already waited for a continue
+        InteractivePrompt::Pause => {}
+        InteractivePrompt::SetPrompt { variable, prompt } => {
+            let value = ctx.take_input().unwrap_or_default();
+            let _ = output_tx.send(
+                ScriptOutput::from(format!("{}{}\r\n", prompt, value))
+                    .with_kind(OutputKind::ScriptStdout),
+            );
+            ctx.answer_set_prompt(variable, &value)?;
+        }
+        InteractivePrompt::Choice { choices, default } => {
+            let key = ctx
+                .take_input()
+                .and_then(|input| input.trim().chars().next())
+                .or(*default)
+                .or_else(|| choices.chars().next())
+                .unwrap_or('Y');
+            let _ = output_tx.send(format!("CHOICE [{}]: answered {}\r\n", choices, key).into());
+            ctx.answer_choice(choices, key)?;
+        }
+    }
+    Ok(())
+}
+
+/// Block after a stop until the client resumes. Returns the step depth for
+/// the requested mode, or None when execution should be abandoned.
+fn wait_for_resume(
+    ctx_arc: &Arc<Mutex<DebugContext>>,
+    pc: usize,
+    log: &DebugLog,
+) -> Option<Option<usize>> {
+    let mut ctx = match ctx_arc.lock() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("ERROR: Failed to lock context: {}", e);
+            log.write(format_args!("ERROR: Failed to lock context: {}", e));
+            return None;
+        }
+    };
+    ctx.current_line = Some(pc);
+    let signal = ctx.resume_signal();
+    log.write(format_args!("  Waiting at line {}", pc));
+    log.flush();
+
+    // However long the user stays at the stop. request_continue wakes this
+    // up at once; the timeout only bounds how long a terminate, which
+    // doesn't take the context lock, can go unnoticed.
+    loop {
+        if ctx.is_cancelled() {
+            eprintln!("Session terminated while stopped");
+            return None;
+        }
+
+        if ctx.continue_requested {
+            eprintln!("Continue requested, mode: {:?}", ctx.mode());
+            log.write(format_args!("Continue requested, mode: {:?}", ctx.mode()));
+            return Some(match ctx.mode() {
+                RunMode::StepOver => Some(ctx.call_stack.len()),
+                RunMode::Continue | RunMode::StepInto | RunMode::StepOut | RunMode::StepBack => {
+                    None
+                }
+            });
+        }
+
+        ctx = match signal.wait_timeout(ctx, CANCEL_CHECK_INTERVAL) {
+            Ok((guard, _)) => guard,
+            Err(e) => {
+                eprintln!("ERROR: Failed to lock context during wait: {}", e);
+                return None;
+            }
+        };
+    }
+}
+
+/// Who printed a piece of output: the script, or the debugger explaining
+/// what it does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    ScriptStdout,
+    ScriptStderr,
+    /// Notes on what the debugger did (FOR banners, IF branches, redirections)
+    DebuggerInfo,
+    /// Something went wrong that the user should see
+    DebuggerWarning,
+}
+
+impl OutputKind {
+    /// Category of the DAP output event
+    pub fn category(self) -> &'static str {
+        match self {
+            OutputKind::ScriptStdout => "stdout",
+            OutputKind::ScriptStderr => "stderr",
+            OutputKind::DebuggerInfo => "console",
+            OutputKind::DebuggerWarning => "important",
+        }
+    }
+}
+
+/// Text for the client's console, with the line of the script that
+/// printed it. Notes of the debugger's own (FOR banners, warnings) have no
+/// line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptOutput {
+    pub text: String,
+    pub pc: Option<usize>,       // Logical line whose command printed the text
+    pub script: Option<PathBuf>, // CALLed batch file the line is in; None for the launched one
+    pub kind: OutputKind,
+}
+
+impl ScriptOutput {
+    /// Output of the command on logical line `pc` of `script` (None for the
+    /// launched program)
+    pub fn at(text: impl Into<String>, pc: usize, script: Option<&Path>) -> Self {
+        Self {
+            text: text.into(),
+            pc: Some(pc),
+            script: script.map(Path::to_path_buf),
+            kind: OutputKind::ScriptStdout,
+        }
+    }
+
+    /// A warning of the debugger's own
+    pub fn warning(text: impl Into<String>) -> Self {
+        Self::from(text.into()).with_kind(OutputKind::DebuggerWarning)
+    }
+
+    pub fn with_kind(mut self, kind: OutputKind) -> Self {
+        self.kind = kind;
+        self
+    }
+}
+
+/// A note of the debugger's own
+impl From<String> for ScriptOutput {
+    fn from(text: String) -> Self {
+        Self {
+            text,
+            pc: None,
+            script: None,
+            kind: OutputKind::DebuggerInfo,
+        }
+    }
 }
 
+impl From<&str> for ScriptOutput {
+    fn from(text: &str) -> Self {
+        Self::from(text.to_string())
+    }
+}
+
+/// Counted while the script runs, for its RunSummary
+#[derive(Default)]
+struct RunStats {
+    lines_executed: usize,
+    max_stack_depth: usize,
+}
+
+impl RunStats {
+    fn count_line(&mut self, ctx: &DebugContext) {
+        self.lines_executed += 1;
+        self.max_stack_depth = self.max_stack_depth.max(ctx.call_stack.len());
+    }
+}
+
+/// A stop as the executor reports it, or the end of the run
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopInfo {
+    pub reason: String,                 // "breakpoint", "step", ... or "terminated"
+    pub pc: usize,                      // Logical line it stopped on
+    pub hit_breakpoint_id: Option<u64>, // Id of the breakpoint that stopped it
+    pub description: Option<String>,    // What stopped it, for people
+}
+
+impl StopInfo {
+    pub fn new(reason: &str, pc: usize) -> Self {
+        Self {
+            reason: reason.to_string(),
+            pc,
+            hit_breakpoint_id: None,
+            description: None,
+        }
+    }
+
+    /// The stop for `reason` on `pc`, whose 1-based physical line is `line`:
+    /// a breakpoint stop names the breakpoint, a data breakpoint stop also
+    /// the variable with its old and new value
+    fn describe(ctx: &DebugContext, reason: &str, pc: usize, line: usize) -> Self {
+        let mut stop = Self::new(reason, pc);
+        if reason == "breakpoint" {
+            let breakpoint = match ctx.current_script() {
+                Some(path) => ctx.get_breakpoint_in(path, pc),
+                None => ctx.get_breakpoint(pc),
+            };
+            if let Some(bp) = breakpoint.filter(|bp| !bp.temporary) {
+                stop.hit_breakpoint_id = bp.id;
+                stop.description = Some(match &bp.condition {
+                    Some(condition) => {
+                        format!("Paused on breakpoint at line {} ({})", line, condition)
+                    }
+                    None => format!("Paused on breakpoint at line {}", line),
+                });
+            }
+        }
+        if reason == "data breakpoint" {
+            if let Some((name, old, new)) = &ctx.data_breakpoint_hit {
+                stop.hit_breakpoint_id = ctx.get_data_breakpoints().get(name).and_then(|d| d.id);
+                stop.description = Some(format!("{} changed from '{}' to '{}'", name, old, new));
+            }
+        }
+        stop
+    }
+}
+
+/// Carry on after the command on `ll` failed with `e`: a command that timed
+/// out or a CMD that exited leaves a fresh session behind, and Ok(true)
+/// means the line hung and stops where it is. Anything else ends the run
+/// with the reason returned.
+fn recover_from_session_error(
+    ctx: &mut DebugContext,
+    e: &io::Error,
+    ll: &LogicalLine,
+    pc: usize,
+    source: Option<&Path>,
+    output_tx: &Sender<ScriptOutput>,
+) -> Result<bool, TerminatedReason> {
+    eprintln!("ERROR: Command execution error: {}", e);
+    if ctx.is_cancelled() {
+        return Err(TerminatedReason::Cancelled);
+    }
+    let hung = match SessionError::from_io(e) {
+        Some(SessionError::Timeout { partial_output, .. }) => {
+            if !partial_output.trim().is_empty() {
+                let _ = output_tx.send(ScriptOutput::at(partial_output.clone(), pc, source));
+            }
+            let _ = output_tx.send(ScriptOutput::warning(format!(
+                "Line {} {}, restarting the CMD session\r\n",
+                ll.phys_start + 1,
+                e
+            )));
+            true
+        }
+        Some(SessionError::SessionDied { exit_code }) => {
+            // Something ended CMD (an EXIT inside a compound line); carry on
+            // with the next line in a fresh shell
+            let _ = output_tx.send(ScriptOutput::warning(format!(
+                "WARNING: CMD exited with code {} at line {}, restarting the CMD session\r\n",
+                exit_code,
+                ll.phys_start + 1
+            )));
+            ctx.last_exit_code = *exit_code;
+            false
+        }
+        _ => return Err(TerminatedReason::Failed(e.to_string())),
+    };
+    if let Err(e) = ctx.recover_session() {
+        eprintln!("ERROR: Failed to restart CMD session: {}", e);
+        return Err(TerminatedReason::Failed(format!(
+            "Failed to restart CMD session: {}",
+            e
+        )));
+    }
+    Ok(hung)
+}
+
+/// Run the script for the DAP server, stopping wherever the client asks to.
+/// However the run ends, the summary is kept on the context and a
+/// terminated event is sent.
 pub fn run_debugger_dap(
     ctx_arc: Arc<Mutex<DebugContext>>,
     pre: &PreprocessResult,
     labels_phys: &HashMap<String, usize>,
-    event_tx: Sender<(String, usize)>,
-    output_tx: Sender<String>,
-) -> io::Result<()> {
-    let mut log = std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("C:\\temp\\batch-debugger-vscode.log")
-        .ok();
-
-    if let Some(ref mut f) = log {
-        writeln!(f, "run_debugger_dap: ENTRY").ok();
-        writeln!(f, "  Logical lines: {}", pre.logical.len()).ok();
-        f.flush().ok();
+    event_tx: Sender<StopInfo>,
+    output_tx: Sender<ScriptOutput>,
+    stderr_tx: Sender<ScriptOutput>,
+) -> Result<RunSummary, BatchDbgError> {
+    let mut stats = RunStats::default();
+    let ended = execute(
+        &ctx_arc,
+        pre,
+        labels_phys,
+        &event_tx,
+        &output_tx,
+        &stderr_tx,
+        &mut stats,
+    );
+
+    eprintln!("DAP: Script execution completed");
+    let mut summary = RunSummary {
+        exit_code: 0,
+        lines_executed: stats.lines_executed,
+        max_stack_depth: stats.max_stack_depth,
+        terminated_reason: match &ended {
+            Ok(reason) => reason.clone(),
+            Err(e) => TerminatedReason::Failed(e.to_string()),
+        },
+    };
+    if let Ok(mut ctx) = ctx_arc.lock() {
+        let log = ctx.log();
+        log.write(format_args!(
+            "DAP: Script execution completed ({})",
+            summary.terminated_reason.name()
+        ));
+        log.flush();
+        // A run-to-line target that was never reached must not fire in a later run
+        ctx.clear_temporary_breakpoints();
+        // Nor may the progress of a loop the run broke out of go on
+        ctx.progress().end();
+        // CMD ends the SETLOCALs a script leaves open when it finishes
+        if !ctx.is_cancelled() {
+            ctx.end_script_scopes();
+        }
+        summary.exit_code = ctx.last_exit_code;
+        ctx.set_run_summary(summary.clone());
     }
+    let _ = event_tx.send(StopInfo::new("terminated", 0));
+
+    ended.map(|_| summary)
+}
+
+/// A script running under `run_debugger_dap` on a thread of its own. It
+/// stops and resumes through its context; what it reports arrives here.
+pub struct RunningScript {
+    pub events: Receiver<StopInfo>, // Each stop, then "terminated"
+    pub output: Receiver<ScriptOutput>,
+    pub stderr: Receiver<ScriptOutput>,
+    pub thread: thread::JoinHandle<()>,
+}
+
+/// Start running the script in `ctx_arc` on a thread of its own
+pub fn spawn_debugger_dap(
+    ctx_arc: Arc<Mutex<DebugContext>>,
+    pre: &PreprocessResult,
+    labels_phys: &HashMap<String, usize>,
+) -> RunningScript {
+    let (event_tx, events) = channel();
+    let (output_tx, output) = channel();
+    let (stderr_tx, stderr) = channel();
+    let log = match ctx_arc.lock() {
+        Ok(ctx) => ctx.log(),
+        Err(_) => DebugLog::new(),
+    };
+    let pre = pre.clone();
+    let labels_phys = labels_phys.clone();
+    let thread = thread::spawn(move || {
+        log.write(format_args!("🧵 Execution thread STARTED"));
+        eprintln!("🧵 Execution thread started");
+        match run_debugger_dap(ctx_arc, &pre, &labels_phys, event_tx, output_tx, stderr_tx) {
+            Ok(_) => {
+                eprintln!("✅ Execution completed successfully");
+                log.write(format_args!("✅ Execution completed successfully"));
+            }
+            Err(e) => {
+                eprintln!("ERROR: Execution error: {}", e);
+                log.write(format_args!("ERROR: Execution error: {}", e));
+            }
+        }
+        log.write(format_args!("🧵 Execution thread EXITING"));
+        eprintln!("🧵 Execution thread exiting");
+    });
+    RunningScript {
+        events,
+        output,
+        stderr,
+        thread,
+    }
+}
+
+fn execute(
+    ctx_arc: &Arc<Mutex<DebugContext>>,
+    pre: &PreprocessResult,
+    labels_phys: &HashMap<String, usize>,
+    event_tx: &Sender<StopInfo>,
+    output_tx: &Sender<ScriptOutput>,
+    stderr_tx: &Sender<ScriptOutput>,
+    stats: &mut RunStats,
+) -> Result<TerminatedReason, BatchDbgError> {
+    let log = match ctx_arc.lock() {
+        Ok(ctx) => ctx.log(),
+        Err(_) => DebugLog::new(),
+    };
+
+    log.write(format_args!("run_debugger_dap: ENTRY"));
+    log.write(format_args!("  Logical lines: {}", pre.logical.len()));
 
     let mut pc: usize = 0;
     let mut step_depth: Option<usize> = None;
+    let mut pending_loops: Vec<PendingLoop> = Vec::new();
+    // Lines of the ( block the previous line opened, which ran with it
+    let mut block_lines: Option<(usize, usize)> = None;
+    // The next stop is where a goto request sent the script
+    let mut jumped = false;
 
-    'run: loop {
-        if let Some(ref mut f) = log {
-            writeln!(f, "Main loop: pc={}", pc).ok();
-            f.flush().ok();
+    let ended = 'run: loop {
+        log.write(format_args!("Main loop: pc={}", pc));
+        // Lines come from the batch file the innermost frame runs in
+        let (cancelled, script) = match ctx_arc.lock() {
+            Ok(ctx) => (
+                ctx.is_cancelled(),
+                ctx.current_script().and_then(|path| ctx.script(path)),
+            ),
+            Err(_) => (true, None),
+        };
+        if cancelled {
+            eprintln!("DAP: Session terminated, stopping execution");
+            break 'run TerminatedReason::Cancelled;
         }
-        while pc >= pre.logical.len() {
-            if let Some(ref mut f) = log {
-                writeln!(f, "EOF reached, unwinding").ok();
-                f.flush().ok();
+        // Where output of this line is attributed to
+        let source = script.as_ref().map(|s| s.path.as_path());
+        let (pre, labels_phys) = match &script {
+            Some(script) => (&script.pre, &script.labels),
+            None => (pre, labels_phys),
+        };
+        if let Some((first, end)) = block_lines.take() {
+            if pc == first {
+                pc = end + 1;
+                continue;
             }
+        }
+        if pc >= pre.logical.len() {
+            log.write(format_args!("EOF reached, unwinding"));
 
             let mut ctx = match ctx_arc.lock() {
                 Ok(c) => c,
                 Err(e) => {
                     eprintln!("ERROR: Failed to lock context: {}", e);
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "ERROR: Failed to lock context: {}", e).ok();
-                        f.flush().ok();
-                    }
-                    break 'run;
+                    log.write(format_args!("ERROR: Failed to lock context: {}", e));
+                    return Err(BatchDbgError::LockPoisoned);
                 }
             };
+            // The caller may be in another file
             match leave_context(&mut ctx.call_stack) {
                 Some(next_pc) => pc = next_pc,
-                None => break 'run,
+                None => break 'run TerminatedReason::Completed,
             }
+            continue;
         }
 
         let ll = &pre.logical[pc];
         let raw = ll.text.as_str();
-        let line = normalize_whitespace(raw.trim());
+        // A multi-line ( block is read by CMD as a whole, so it runs as one
+        // command and stepping treats it as one line
+        let line = match block_end(pre, pc) {
+            Some(end) => {
+                block_lines = Some((pc + 1, end));
+                normalize_whitespace(&join_block(pre, pc, end))
+            }
+            None => normalize_whitespace(raw.trim()),
+        };
         let line_upper = line.to_uppercase();
 
-        if let Some(ref mut f) = log {
-            writeln!(f, "Processing line {}: '{}'", pc, raw).ok();
-            f.flush().ok();
-        }
+        log.write(format_args!("Processing line {}: '{}'", pc, raw));
         if line.trim().starts_with(':') {
-            if let Some(ref mut f) = log {
-                writeln!(f, "  Skipping label line").ok();
-                f.flush().ok();
-            }
+            log.write(format_args!("  Skipping label line"));
             pc += 1;
             continue;
         }
         if line_upper.starts_with("REM ") || line.trim().starts_with("::") {
-            if let Some(ref mut f) = log {
-                writeln!(f, "  Skipping comment line").ok();
-                f.flush().ok();
-            }
+            log.write(format_args!("  Skipping comment line"));
             pc += 1;
             continue;
         }
-        let should_stop = {
-            if let Some(ref mut f) = log {
-                writeln!(f, "  Checking if should stop...").ok();
-                f.flush().ok();
+        // %0..%9 and %* belong to the simulated call frames and the launch
+        // arguments, the CMD session has no values for them
+        let line = match ctx_arc.lock() {
+            Ok(ctx) => ctx.expand_arguments(&line),
+            Err(_) => line,
+        };
+        let line_upper = line.to_uppercase();
+        // Step back: restore the state from before the previous line and stop
+        // there instead of executing this one
+        {
+            let mut ctx = match ctx_arc.lock() {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("ERROR: Failed to lock context: {}", e);
+                    return Err(BatchDbgError::LockPoisoned);
+                }
+            };
+            // A goto request moved the script on; it stops where it landed
+            if let Some(target) = ctx.take_pending_jump() {
+                log.write(format_args!("  Jumping from line {} to {}", pc, target));
+                pc = target;
+                jumped = true;
+                continue;
             }
+            if ctx.mode() == RunMode::StepBack {
+                ctx.set_mode(RunMode::StepInto);
+                match ctx.step_back() {
+                    Ok(Some(snapshot)) => {
+                        if snapshot.side_effects {
+                            let _ = output_tx.send(ScriptOutput::warning(format!(
+                                "WARNING: Stepped back over line {}, its effects outside the script's variables were not undone\r\n",
+                                pre.logical[snapshot.pc].phys_start + 1
+                            )));
+                        }
+                        pc = snapshot.pc;
+                    }
+                    Ok(None) => {
+                        let _ = output_tx.send("Nothing to step back over\r\n".to_string().into());
+                    }
+                    Err(e) => {
+                        eprintln!("ERROR: Step back failed: {}", e);
+                        break 'run TerminatedReason::Failed(format!("Step back failed: {}", e));
+                    }
+                }
+                continue;
+            }
+        }
+        let (should_stop, external_stop, iterations) = {
+            log.write(format_args!("  Checking if should stop..."));
 
             let mut ctx = match ctx_arc.lock() {
                 Ok(c) => c,
                 Err(e) => {
                     eprintln!("ERROR: Failed to lock context: {}", e);
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "ERROR: Failed to lock context: {}", e).ok();
-                        f.flush().ok();
-                    }
-                    break 'run;
+                    log.write(format_args!("ERROR: Failed to lock context: {}", e));
+                    return Err(BatchDbgError::LockPoisoned);
                 }
             };
 
-            let stop = match ctx.mode() {
-                RunMode::Continue => ctx.should_stop_at(pc),
-                RunMode::StepInto => true,
-                RunMode::StepOver => {
-                    if let Some(target_depth) = step_depth {
-                        ctx.call_stack.len() <= target_depth
-                    } else {
-                        true
+            ctx.track_pc(pc);
+
+            // FOR loops are expanded up front and stop per iteration instead
+            // of once on the line. A loop whose body CALLed out picks up where
+            // it left off when the CALL returns to it.
+            let depth = ctx.call_stack.len();
+            pending_loops.retain(|l| l.depth <= depth);
+            let resumed = match pending_loops.last() {
+                Some(l) if l.pc == pc && l.depth == depth => pending_loops.pop(),
+                _ => None,
+            };
+            let iterations = if let Some(pending) = resumed {
+                Some((pending.iterations, pending.done))
+            } else if line_upper.starts_with("FOR ") {
+                parse_for_statement(&line).and_then(|for_stmt| {
+                    match ctx.expand_for_loop(&for_stmt.loop_type) {
+                        Ok(iterations) if !iterations.is_empty() => Some((iterations, 0)),
+                        Ok(_) => None,
+                        Err(e) => {
+                            eprintln!("ERROR: FOR loop expansion error: {}", e);
+                            let _ = output_tx.send(ScriptOutput::warning(format!(
+                                "ERROR: FOR loop expansion error: {}\r\n",
+                                e
+                            )));
+                            None
+                        }
                     }
-                }
-                RunMode::StepOut => ctx.should_stop_at(pc),
+                })
+            } else {
+                None
             };
 
-            if let Some(ref mut f) = log {
-                writeln!(f, "  Should stop: {}, mode: {:?}", stop, ctx.mode()).ok();
-                f.flush().ok();
+            let stop = iterations.is_none()
+                && match ctx.mode() {
+                    RunMode::Continue => ctx.should_stop_at(pc),
+                    RunMode::StepInto | RunMode::StepBack => true,
+                    RunMode::StepOver => {
+                        if let Some(target_depth) = step_depth {
+                            ctx.call_stack.len() <= target_depth
+                        } else {
+                            true
+                        }
+                    }
+                    RunMode::StepOut => ctx.should_stop_at(pc),
+                };
+
+            log.write(format_args!(
+                "  Should stop: {}, mode: {:?}",
+                stop,
+                ctx.mode()
+            ));
+
+            // Break-on-external pauses before programs the script launches
+            let base_cmd = parse_redirections(&line).base_command;
+            let external = !stop && ctx.should_break_on_external(&base_cmd);
+            if external {
+                let name = command_name(&base_cmd);
+                let resolved = ctx
+                    .resolve_executable(&base_cmd)
+                    .unwrap_or_else(|| format!("{} (not found on PATH)", name));
+                let args = base_cmd.trim_start().trim_start_matches('@')[name.len()..].trim();
+                let _ = output_tx.send(
+                    format!(
+                        "External command: {}\r\n  Arguments: {}\r\n",
+                        resolved, args
+                    )
+                    .into(),
+                );
             }
 
-            stop
+            (stop || external, external, iterations)
         };
         if should_stop {
             eprintln!(
@@ -186,231 +961,482 @@ pub fn run_debugger_dap(
                 raw
             );
 
-            if let Some(ref mut f) = log {
-                writeln!(
-                    f,
-                    "STOPPED at line {} (phys {}): {}",
-                    pc,
-                    ll.phys_start + 1,
-                    raw
-                )
-                .ok();
-                f.flush().ok();
-            }
-            let stop_reason = {
-                let ctx = match ctx_arc.lock() {
+            log.write(format_args!(
+                "STOPPED at line {} (phys {}): {}",
+                pc,
+                ll.phys_start + 1,
+                raw
+            ));
+            let (stop_reason, stop) = {
+                let mut ctx = match ctx_arc.lock() {
                     Ok(c) => c,
                     Err(e) => {
                         eprintln!("ERROR: Failed to lock context: {}", e);
-                        break 'run;
+                        return Err(BatchDbgError::LockPoisoned);
                     }
                 };
 
-                match ctx.mode() {
+                ctx.mark_stop();
+                let stop_reason = match ctx.mode() {
+                    _ if std::mem::take(&mut jumped) => "goto",
+                    _ if external_stop => "external command",
                     RunMode::Continue => "breakpoint",
-                    RunMode::StepInto | RunMode::StepOver | RunMode::StepOut => "step",
-                }
+                    RunMode::StepInto
+                    | RunMode::StepOver
+                    | RunMode::StepOut
+                    | RunMode::StepBack => "step",
+                };
+                let stop = StopInfo::describe(&ctx, stop_reason, pc, ll.phys_start + 1);
+                (stop_reason, stop)
             };
-            if let Err(e) = event_tx.send((stop_reason.to_string(), pc)) {
+            if let Err(e) = event_tx.send(stop) {
                 eprintln!("ERROR: Failed to send stopped event: {}", e);
-                if let Some(ref mut f) = log {
-                    writeln!(f, "ERROR: Failed to send stopped event: {}", e).ok();
-                    f.flush().ok();
-                }
-                break 'run;
+                log.write(format_args!("ERROR: Failed to send stopped event: {}", e));
+                break 'run TerminatedReason::Disconnected;
             }
 
             eprintln!("Sent stopped event: {}", stop_reason);
-            if let Some(ref mut f) = log {
-                writeln!(f, "Sent stopped event: {}", stop_reason).ok();
-                f.flush().ok();
+            log.write(format_args!("Sent stopped event: {}", stop_reason));
+            match wait_for_resume(ctx_arc, pc, &log) {
+                Some(depth) => step_depth = depth,
+                None => break 'run TerminatedReason::Cancelled,
             }
+            // Rewind or jump before this line runs; the check at the top of
+            // the loop restores the snapshot or moves pc
+            if ctx_arc
+                .lock()
+                .map(|c| c.mode() == RunMode::StepBack || c.has_pending_jump())
+                .unwrap_or(false)
             {
-                let mut ctx = match ctx_arc.lock() {
-                    Ok(c) => c,
-                    Err(e) => {
-                        eprintln!("ERROR: Failed to lock context: {}", e);
-                        if let Some(ref mut f) = log {
-                            writeln!(f, "ERROR: Failed to lock context: {}", e).ok();
-                            f.flush().ok();
-                        }
-                        break 'run;
+                continue;
+            }
+        }
+        // With ECHO on, CMD shows each command before running it. A FOR
+        // line picked up again after a CALL from its body was shown already.
+        if !matches!(iterations, Some((_, done)) if done > 0) {
+            if let Ok(mut ctx) = ctx_arc.lock() {
+                if let Some(echoed) = ctx.echo_line(&line) {
+                    let _ = output_tx.send(ScriptOutput::at(echoed, pc, source));
+                }
+            }
+        }
+        // Nothing is attached to the session's stdin, so prompts would block
+        // it forever. Stop for input where it's needed, then answer them.
+        if let Some(prompt) = parse_interactive_prompt(&line) {
+            let stop_reason = match ctx_arc.lock() {
+                Ok(mut ctx) => match prompt {
+                    InteractivePrompt::Pause if ctx.wait_on_pause() => Some("pause"),
+                    InteractivePrompt::SetPrompt { .. } if !ctx.has_input() => {
+                        ctx.set_awaiting_input(true);
+                        Some("input required")
                     }
+                    _ => None,
+                },
+                Err(e) => {
+                    eprintln!("ERROR: Failed to lock context: {}", e);
+                    return Err(BatchDbgError::LockPoisoned);
+                }
+            };
+            if prompt == InteractivePrompt::Pause {
+                let note = if stop_reason.is_some() {
+                    "PAUSE: continue to resume\r\n"
+                } else {
+                    "PAUSE skipped (no console input)\r\n"
                 };
-                ctx.continue_requested = false;
-                ctx.current_line = Some(pc);
-
-                if let Some(ref mut f) = log {
-                    writeln!(
-                        f,
-                        "  Reset continue_requested to false, set current_line to {}",
-                        pc
-                    )
-                    .ok();
-                    f.flush().ok();
+                let _ = output_tx.send(note.to_string().into());
+            }
+            if let Some(reason) = stop_reason {
+                if let Ok(mut ctx) = ctx_arc.lock() {
+                    ctx.mark_stop();
+                }
+                if event_tx.send(StopInfo::new(reason, pc)).is_err() {
+                    break 'run TerminatedReason::Disconnected;
+                }
+                match wait_for_resume(ctx_arc, pc, &log) {
+                    Some(depth) => step_depth = depth,
+                    None => break 'run TerminatedReason::Cancelled,
+                }
+            }
+            let mut ctx = match ctx_arc.lock() {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("ERROR: Failed to lock context: {}", e);
+                    return Err(BatchDbgError::LockPoisoned);
+                }
+            };
+            ctx.set_awaiting_input(false);
+            ctx.record_snapshot(pc, &line);
+            ctx.record_execution(pc);
+            stats.count_line(&ctx);
+            if let Err(e) = answer_prompt(&mut ctx, &prompt, output_tx) {
+                eprintln!("ERROR: Failed to answer prompt: {}", e);
+                break 'run TerminatedReason::Failed(format!("Failed to answer prompt: {}", e));
+            }
+            pc += 1;
+            continue;
+        }
+
+        // FOR loops run one iteration at a time with the context unlocked in
+        // between, so every iteration can stop (or be paused) like a line of
+        // its own. Step Over from an iteration runs the rest of the loop.
+        if let Some((iterations, done)) = iterations {
+            if let Ok(mut ctx) = ctx_arc.lock() {
+                ctx.record_snapshot(pc, &line);
+                if done == 0 {
+                    ctx.record_execution(pc);
+                    stats.count_line(&ctx);
                 }
             }
-            let mut wait_count = 0;
-            if let Some(ref mut f) = log {
-                writeln!(f, "  Entering wait loop...").ok();
-                f.flush().ok();
+            let total = done + iterations.len();
+            let verbose = ctx_arc.lock().map(|c| c.verbose_console()).unwrap_or(false);
+            if done == 0 && verbose {
+                let _ = output_tx.send(format!("FOR: Loop: {} iterations\r\n", total).into());
             }
+            let mut run_through = false;
+            let mut last_progress: Option<Instant> = None;
 
-            loop {
-                std::thread::sleep(Duration::from_millis(50));
-                wait_count += 1;
+            for (i, (command, var_name, var_value)) in iterations.iter().enumerate() {
+                let idx = done + i;
+                let stop_reason = {
+                    let mut ctx = match ctx_arc.lock() {
+                        Ok(c) => c,
+                        Err(e) => {
+                            eprintln!("ERROR: Failed to lock context: {}", e);
+                            return Err(BatchDbgError::LockPoisoned);
+                        }
+                    };
+                    if ctx.is_cancelled() {
+                        break 'run TerminatedReason::Cancelled;
+                    }
+                    ctx.set_loop_variable(var_name, var_value);
 
-                if wait_count % 20 == 0 {
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "  Still waiting... ({} iterations)", wait_count).ok();
-                        f.flush().ok();
+                    let reason = match ctx.mode() {
+                        RunMode::StepInto | RunMode::StepBack => Some("step"),
+                        RunMode::StepOver => {
+                            let depth_ok = step_depth.is_none_or(|d| ctx.call_stack.len() <= d);
+                            (!run_through && depth_ok).then_some("step")
+                        }
+                        RunMode::Continue => ctx.should_stop_at(pc).then_some("breakpoint"),
+                        RunMode::StepOut => ctx.should_stop_at(pc).then_some("step"),
+                    };
+                    if reason.is_some() {
+                        // A spinner must not go on over the paused session
+                        ctx.progress().end();
+                        ctx.mark_stop();
+                        ctx.set_stop_text(Some(format!(
+                            "FOR iteration {} of {}: {}={}",
+                            idx + 1,
+                            total,
+                            var_name,
+                            var_value
+                        )));
                     }
-                }
-                if wait_count > 6000 {
-                    eprintln!("Timeout waiting for step command");
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "Timeout waiting for step command").ok();
-                        f.flush().ok();
+                    if reason.is_none() && total > PROGRESS_MIN_ITERATIONS {
+                        if !ctx.progress().is_active() {
+                            ctx.progress().start(line.trim().to_string());
+                            last_progress = None;
+                        } else if idx % PROGRESS_STEP == 0
+                            && last_progress.is_none_or(|t| t.elapsed() >= PROGRESS_INTERVAL)
+                        {
+                            ctx.progress().update(
+                                format!("Iteration {} of {}", idx, total),
+                                Some((idx * 100 / total) as u32),
+                            );
+                            last_progress = Some(Instant::now());
+                        }
+                    }
+                    reason
+                };
+                if let Some(reason) = stop_reason {
+                    if event_tx.send(StopInfo::new(reason, pc)).is_err() {
+                        break 'run TerminatedReason::Disconnected;
+                    }
+                    match wait_for_resume(ctx_arc, pc, &log) {
+                        Some(depth) => step_depth = depth,
+                        None => break 'run TerminatedReason::Cancelled,
+                    }
+                    match ctx_arc.lock().map(|c| c.mode()) {
+                        // Rewinds to before the FOR line
+                        Ok(RunMode::StepBack) => continue 'run,
+                        Ok(mode) => run_through = mode == RunMode::StepOver,
+                        Err(_) => return Err(BatchDbgError::LockPoisoned),
                     }
-                    break 'run;
                 }
 
-                let ctx = match ctx_arc.lock() {
+                let mut ctx = match ctx_arc.lock() {
                     Ok(c) => c,
                     Err(e) => {
-                        eprintln!("ERROR: Failed to lock context during wait: {}", e);
-                        if let Some(ref mut f) = log {
-                            writeln!(f, "ERROR: Failed to lock context during wait: {}", e).ok();
-                            f.flush().ok();
-                        }
-                        break 'run;
+                        eprintln!("ERROR: Failed to lock context: {}", e);
+                        return Err(BatchDbgError::LockPoisoned);
                     }
                 };
+                eprintln!("  Iteration {}: {}={}", idx + 1, var_name, var_value);
+                if verbose {
+                    let _ = output_tx
+                        .send(format!("  [{}] {}={}\r\n", idx + 1, var_name, var_value).into());
+                }
+                if let Some(echoed) = ctx.echo_line(command) {
+                    let _ = output_tx.send(ScriptOutput::at(echoed, pc, source));
+                }
 
-                if ctx.continue_requested {
-                    eprintln!("Continue requested, mode: {:?}", ctx.mode());
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "Continue requested, mode: {:?}", ctx.mode()).ok();
-                        f.flush().ok();
-                    }
-                    match ctx.mode() {
-                        RunMode::Continue => {
-                            step_depth = None;
-                        }
-                        RunMode::StepOver => {
-                            step_depth = Some(ctx.call_stack.len());
+                // GOTO and EXIT /B leave the loop; a CALL comes back to the
+                // FOR line for the iterations after this one
+                let command = match take_if_branch(&mut ctx, command, output_tx) {
+                    Some(Some(branch)) => branch,
+                    Some(None) => continue,
+                    None => command.clone(),
+                };
+                let depth = ctx.call_stack.len();
+                match transfer_control(&mut ctx, &command, pc, pc, pre, labels_phys, stderr_tx) {
+                    Some(Transfer::Jump(next_pc)) => {
+                        if ctx.call_stack.len() > depth {
+                            pending_loops.push(PendingLoop {
+                                pc,
+                                depth,
+                                iterations: iterations[i + 1..].to_vec(),
+                                done: idx + 1,
+                            });
                         }
-                        RunMode::StepInto => {
-                            step_depth = None;
+                        ctx.progress().end();
+                        pc = next_pc;
+                        continue 'run;
+                    }
+                    Some(Transfer::Finish(reason)) => break 'run reason,
+                    None => {}
+                }
+                let shell = ctx.shared_session();
+                drop(ctx);
+                let started = Instant::now();
+                let ran = lock_shell(&shell).run(&command);
+                let elapsed = started.elapsed();
+                let mut ctx = match ctx_arc.lock() {
+                    Ok(c) => c,
+                    Err(e) => {
+                        eprintln!("ERROR: Failed to lock context: {}", e);
+                        return Err(BatchDbgError::LockPoisoned);
+                    }
+                };
+                ctx.record_timing(pc, started, elapsed);
+                match ran {
+                    Ok(result) => {
+                        forward_output(&result, pc, source, output_tx, stderr_tx);
+                        ctx.last_exit_code = result.exit_code;
+                        track_set_commands(&mut ctx, &command, &result);
+                    }
+                    Err(e) => {
+                        eprintln!("ERROR: Command execution error in FOR loop: {}", e);
+                        if ctx.is_cancelled() {
+                            break 'run TerminatedReason::Cancelled;
                         }
-                        RunMode::StepOut => {
-                            step_depth = None;
+                        if SessionError::from_io(&e).is_some() {
+                            if let Err(e) = ctx.recover_session() {
+                                eprintln!("ERROR: Failed to restart CMD session: {}", e);
+                                break 'run TerminatedReason::Failed(format!(
+                                    "Failed to restart CMD session: {}",
+                                    e
+                                ));
+                            }
                         }
+                        let _ = output_tx.send(ScriptOutput::warning(format!(
+                            "ERROR: Error in iteration {}: {}\r\n",
+                            idx + 1,
+                            e
+                        )));
+                        // Carry on with the next iteration
+                        continue;
                     }
-                    break;
                 }
-            }
 
-            if let Some(ref mut f) = log {
-                writeln!(f, "  Exited wait loop, continuing execution").ok();
-                f.flush().ok();
+                if ctx.check_data_breakpoints() {
+                    if let Some((name, old, new)) = ctx.data_breakpoint_hit.clone() {
+                        let _ = output_tx.send(
+                            format!(
+                            "Data breakpoint: {} changed from '{}' to '{}' in FOR iteration {}\r\n",
+                            name,
+                            old,
+                            new,
+                            idx + 1
+                        )
+                            .into(),
+                        );
+                    }
+                    ctx.update_data_breakpoints();
+                    ctx.progress().end();
+                    ctx.mark_stop();
+                    let stop = StopInfo::describe(&ctx, "data breakpoint", pc, ll.phys_start + 1);
+                    drop(ctx);
+                    if event_tx.send(stop).is_err() {
+                        break 'run TerminatedReason::Disconnected;
+                    }
+                    match wait_for_resume(ctx_arc, pc, &log) {
+                        Some(depth) => step_depth = depth,
+                        None => break 'run TerminatedReason::Cancelled,
+                    }
+                    match ctx_arc.lock().map(|c| c.mode()) {
+                        Ok(RunMode::StepBack) => continue 'run,
+                        Ok(mode) => run_through = mode == RunMode::StepOver,
+                        Err(_) => return Err(BatchDbgError::LockPoisoned),
+                    }
+                }
             }
+            if let Ok(mut ctx) = ctx_arc.lock() {
+                ctx.progress().end();
+            }
+            pc += 1;
+            continue;
         }
+
+        let mut output_hit: Option<(String, String)> = None;
+        let mut data_hit = false;
+        let mut not_found: Option<String> = None;
+        let mut timed_out = false;
+        let mut interrupted = false;
         {
-            if let Some(ref mut f) = log {
-                writeln!(f, "  Executing line: '{}'", line).ok();
-                f.flush().ok();
-            }
+            log.write(format_args!("  Executing line: '{}'", line));
 
             let mut ctx = match ctx_arc.lock() {
                 Ok(c) => c,
                 Err(e) => {
                     eprintln!("ERROR: Failed to lock context for execution: {}", e);
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "ERROR: Failed to lock context for execution: {}", e).ok();
-                        f.flush().ok();
+                    log.write(format_args!(
+                        "ERROR: Failed to lock context for execution: {}",
+                        e
+                    ));
+                    return Err(BatchDbgError::LockPoisoned);
+                }
+            };
+            ctx.record_snapshot(pc, &line);
+            // IF runs here rather than in CMD, and its branch is then handled
+            // like a line of its own so GOTO, CALL and EXIT /B in it work
+            let line = match take_if_branch(&mut ctx, &line, output_tx) {
+                Some(Some(branch)) => branch,
+                // Nothing runs, ERRORLEVEL stays as it was
+                Some(None) => {
+                    pc += 1;
+                    continue;
+                }
+                None => line,
+            };
+            ctx.record_execution(pc);
+            stats.count_line(&ctx);
+            // Statement steps stop between the commands of `a & b & c`. All
+            // but the last run here; the last goes on like a line of its own.
+            let line = match statement_parts(&line)
+                .filter(|_| stops_between_statements(&ctx, step_depth))
+            {
+                None => line,
+                Some(parts) => {
+                    let columns = statement_columns(raw, parts.len());
+                    let mut last_exit: Option<i32> = None;
+                    for (i, part) in parts[..parts.len() - 1].iter().enumerate() {
+                        let op = i.checked_sub(1).and_then(|p| parts[p].op);
+                        if !part_runs(op, last_exit) {
+                            continue;
+                        }
+                        log.write(format_args!("  Statement {}: '{}'", i + 1, part.text));
+                        // Only the shell is locked while it runs, as for a whole line
+                        ctx.invalidate_eval_cache();
+                        let shell = ctx.shared_session();
+                        drop(ctx);
+                        let streamed = lock_shell(&shell).run_streaming(&part.text, &mut |chunk| {
+                            let _ = output_tx.send(ScriptOutput::at(chunk, pc, source));
+                        });
+                        ctx = match ctx_arc.lock() {
+                            Ok(c) => c,
+                            Err(_) => return Err(BatchDbgError::LockPoisoned),
+                        };
+                        let result = match streamed {
+                            Ok(result) => result,
+                            Err(e) => {
+                                // The rest of the line is given up, like a
+                                // whole line that failed
+                                log.write(format_args!("ERROR: Command execution error: {}", e));
+                                match recover_from_session_error(&mut ctx, &e, ll, pc, source, output_tx) {
+                                    Ok(true) => {
+                                        ctx.mark_stop();
+                                        drop(ctx);
+                                        if event_tx.send(StopInfo::new("timeout", pc)).is_err() {
+                                            break 'run TerminatedReason::Disconnected;
+                                        }
+                                        match wait_for_resume(ctx_arc, pc, &log) {
+                                            Some(depth) => step_depth = depth,
+                                            None => break 'run TerminatedReason::Cancelled,
+                                        }
+                                    }
+                                    Ok(false) => {}
+                                    Err(reason) => break 'run reason,
+                                }
+                                pc += 1;
+                                continue 'run;
+                            }
+                        };
+                        track_set_commands(&mut ctx, &part.text, &result);
+                        if !result.stderr.trim().is_empty() {
+                            let _ = stderr_tx.send(
+                                ScriptOutput::at(result.stderr, pc, source)
+                                    .with_kind(OutputKind::ScriptStderr),
+                            );
+                        }
+                        ctx.last_exit_code = result.exit_code;
+                        last_exit = Some(result.exit_code);
+                        if !stops_between_statements(&ctx, step_depth) {
+                            continue;
+                        }
+                        ctx.mark_stop();
+                        ctx.set_statement_column(columns.get(i + 1).copied());
+                        drop(ctx);
+                        if event_tx.send(StopInfo::new("step", pc)).is_err() {
+                            break 'run TerminatedReason::Disconnected;
+                        }
+                        match wait_for_resume(ctx_arc, pc, &log) {
+                            Some(depth) => step_depth = depth,
+                            None => break 'run TerminatedReason::Cancelled,
+                        }
+                        ctx = match ctx_arc.lock() {
+                            Ok(c) => c,
+                            Err(_) => return Err(BatchDbgError::LockPoisoned),
+                        };
                     }
-                    break 'run;
+                    let last = &parts[parts.len() - 1];
+                    if !part_runs(parts[parts.len() - 2].op, last_exit) {
+                        pc += 1;
+                        continue;
+                    }
+                    last.text.clone()
                 }
             };
+            // A CALL in a multi-line block returns to the line after it;
+            // the block's other lines already ran with it
+            let return_pc = block_end(pre, pc).map_or(pc + 1, |end| end + 1);
+            let line = match group_body(&line) {
+                Some(body) if is_control_transfer(body) => body.to_string(),
+                _ => line,
+            };
+            let line_upper = line.to_uppercase();
+            match transfer_control(&mut ctx, &line, pc, return_pc, pre, labels_phys, stderr_tx) {
+                Some(Transfer::Jump(next_pc)) => {
+                    pc = next_pc;
+                    continue;
+                }
+                Some(Transfer::Finish(reason)) => break 'run reason,
+                None => {}
+            }
             if line_upper.starts_with("SETLOCAL") {
                 ctx.handle_setlocal();
-                let (out, code) = ctx.run_command(&line)?;
-                if !out.trim().is_empty() {
-                    if let Err(e) = output_tx.send(out.clone()) {
-                        eprintln!("ERROR: Failed to send output: {}", e);
-                    }
-                }
-                ctx.last_exit_code = code;
+                let result = ctx.run_command(&line)?;
+                forward_output(&result, pc, source, output_tx, stderr_tx);
+                ctx.last_exit_code = result.exit_code;
                 pc += 1;
                 continue;
             }
             if line_upper.starts_with("ENDLOCAL") {
                 ctx.handle_endlocal();
-                let (out, code) = ctx.run_command(&line)?;
-                if !out.trim().is_empty() {
-                    if let Err(e) = output_tx.send(out.clone()) {
-                        eprintln!("ERROR: Failed to send output: {}", e);
-                    }
-                }
-                ctx.last_exit_code = code;
+                let result = ctx.run_command(&line)?;
+                forward_output(&result, pc, source, output_tx, stderr_tx);
+                ctx.last_exit_code = result.exit_code;
                 pc += 1;
                 continue;
             }
-            if line_upper.starts_with("CALL ") {
-                let rest = &line[5..].trim();
-                let mut lexer = shlex::Shlex::new(rest);
-                let first = lexer.next().unwrap_or_default();
-                let label_key = first.trim_start_matches(':').to_lowercase();
-                let args: Vec<String> = lexer.collect();
-
-                if let Some(&phys_target) = labels_phys.get(&label_key) {
-                    let logical_target = pre.phys_to_logical[phys_target];
-                    ctx.call_stack.push(Frame::new(pc + 1, Some(args)));
-                    pc = logical_target;
-                } else {
-                    eprintln!("ERROR: CALL to unknown label: {}", label_key);
-                    break 'run;
-                }
-                continue;
-            }
-            if line_upper.starts_with("EXIT /B") {
-                let rest = &line[7..].trim();
-                let code: i32 = rest.parse::<i32>().unwrap_or(0);
-                ctx.last_exit_code = code;
-
-                match leave_context(&mut ctx.call_stack) {
-                    Some(next_pc) => pc = next_pc,
-                    None => break 'run,
-                }
-                continue;
-            }
-            if line_upper.starts_with("GOTO ") {
-                let rest = &line[5..].trim();
-                let label_key = rest
-                    .trim_start_matches(':')
-                    .split_whitespace()
-                    .next()
-                    .unwrap_or("")
-                    .to_lowercase();
-
-                if label_key == "eof" {
-                    match leave_context(&mut ctx.call_stack) {
-                        Some(next_pc) => pc = next_pc,
-                        None => break 'run,
-                    }
-                    continue;
-                }
-
-                if let Some(&phys_target) = labels_phys.get(&label_key) {
-                    let logical_target = pre.phys_to_logical[phys_target];
-                    pc = logical_target;
-                } else {
-                    eprintln!("ERROR: GOTO to unknown label: {}", label_key);
-                    break 'run;
-                }
-                continue;
-            }
             if line_upper.starts_with("PUSHD") {
                 let rest = line[5..].trim();
                 let path = if rest.is_empty() { None } else { Some(rest) };
@@ -427,6 +1453,18 @@ pub fn run_debugger_dap(
                 pc += 1;
                 continue;
             }
+            if let Some(rest) = strip_cd_command(&line) {
+                if rest.is_empty() {
+                    // Bare CD prints the current directory, let CMD handle it
+                    let result = ctx.run_command(&line)?;
+                    forward_output(&result, pc, source, output_tx, stderr_tx);
+                    ctx.last_exit_code = result.exit_code;
+                } else if let Err(e) = ctx.handle_cd(Some(rest)) {
+                    eprintln!("ERROR: CD error: {}", e);
+                }
+                pc += 1;
+                continue;
+            }
             if line_upper.starts_with("SHIFT") {
                 let rest = line[5..].trim();
                 let count = if rest.is_empty() {
@@ -441,116 +1479,92 @@ pub fn run_debugger_dap(
                 pc += 1;
                 continue;
             }
-            // Check if this is a FOR loop and expand it for stepping
-            if line_upper.starts_with("FOR ") {
-                if let Some(for_stmt) = parse_for_statement(&line) {
-                    eprintln!("FOR: Loop detected, expanding iterations...");
-
-                    match ctx.expand_for_loop(&for_stmt.loop_type) {
-                        Ok(iterations) => {
-                            eprintln!("FOR: Loop expanded into {} iterations", iterations.len());
-
-                            if let Err(e) = output_tx
-                                .send(format!("FOR: Loop: {} iterations\r\n", iterations.len()))
-                            {
-                                eprintln!("ERROR: Failed to send output: {}", e);
-                            }
-
-                            // Execute each iteration
-                            for (idx, (command, var_name, var_value)) in
-                                iterations.iter().enumerate()
-                            {
-                                eprintln!("  Iteration {}: {}={}", idx + 1, var_name, var_value);
-
-                                // Update loop variable
-                                ctx.set_loop_variable(var_name, var_value);
-
-                                // Send iteration info to debug console
-                                if let Err(e) = output_tx.send(format!(
-                                    "  [{}] {}={}\r\n",
-                                    idx + 1,
-                                    var_name,
-                                    var_value
-                                )) {
-                                    eprintln!("ERROR: Failed to send output: {}", e);
-                                }
-
-                                // Track SET commands in the iteration
-                                ctx.track_set_command(command);
-
-                                // Execute the command
-                                match ctx.run_command(command) {
-                                    Ok((out, code)) => {
-                                        if !out.trim().is_empty() {
-                                            if let Err(e) = output_tx.send(out.clone()) {
-                                                eprintln!("ERROR: Failed to send output: {}", e);
-                                            }
-                                        }
-                                        ctx.last_exit_code = code;
-                                    }
-                                    Err(e) => {
-                                        eprintln!(
-                                            "ERROR: Command execution error in FOR loop: {}",
-                                            e
-                                        );
-                                        if let Err(e) = output_tx.send(format!(
-                                            "ERROR: Error in iteration {}: {}\r\n",
-                                            idx + 1,
-                                            e
-                                        )) {
-                                            eprintln!("ERROR: Failed to send error output: {}", e);
-                                        }
-                                        // Continue to next iteration instead of breaking
-                                    }
-                                }
-                            }
-
-                            // Skip the FOR loop line itself and continue
-                            pc += 1;
-                            continue;
+            if let Some(delay) = parse_delay(&line).filter(|_| ctx.fast_forward_delays()) {
+                eprintln!("DELAY: skipping {}s wait: {}", delay.seconds, line);
+                let _ = output_tx.send(format!("(skipped {}s delay)\r\n", delay.seconds).into());
+                ctx.last_exit_code = delay.exit_code;
+                pc += 1;
+                continue;
+            }
+            // START runs its program outside the CMD session: a detached
+            // program is tracked, and /WAIT doesn't hold the session while it
+            // waits, so the client can still inspect the script
+            if let Some(start) = parse_start_command(&line)
+                .filter(|_| parse_redirections(&line).redirections.is_empty())
+            {
+                let shell = ctx.shell_config();
+                let cwd = ctx.get_current_dir().to_path_buf();
+                let env = ctx.start_environment();
+                if !start.wait {
+                    match ctx.child_processes().start(&shell, &start, &cwd, &env) {
+                        Ok(pid) => {
+                            eprintln!("START: '{}' is running as process {}", start.command, pid);
+                            ctx.last_exit_code = 0;
                         }
                         Err(e) => {
-                            eprintln!("ERROR: FOR loop expansion error: {}", e);
-                            if let Err(e) = output_tx
-                                .send(format!("ERROR: FOR loop expansion error: {}\r\n", e))
-                            {
-                                eprintln!("ERROR: Failed to send error output: {}", e);
-                            }
+                            eprintln!("ERROR: START failed: {}", e);
+                            let _ = stderr_tx.send(
+                                ScriptOutput::at(
+                                    format!("Cannot start '{}': {}\r\n", start.command, e),
+                                    pc,
+                                    source,
+                                )
+                                .with_kind(OutputKind::ScriptStderr),
+                            );
+                            ctx.last_exit_code = 1;
                         }
                     }
+                    pc += 1;
+                    continue;
                 }
-            }
 
-            // Check if this is an IF statement and pre-evaluate the condition
-            if line_upper.starts_with("IF ") {
-                if let Some(if_stmt) = parse_if_statement(&line) {
-                    match ctx.evaluate_if_condition(&if_stmt.condition) {
-                        Ok(condition_result) => {
-                            if condition_result {
-                                eprintln!("IF: Condition is TRUE -> will execute THEN branch");
-                                if let Err(e) = output_tx.send(
-                                    "IF: Condition is TRUE -> executing THEN branch\r\n"
-                                        .to_string(),
-                                ) {
-                                    eprintln!("ERROR: Failed to send output: {}", e);
-                                }
-                            } else {
-                                eprintln!("IF: Condition is FALSE -> will skip THEN branch");
-                                if let Err(e) = output_tx.send(
-                                    "IF: Condition is FALSE -> skipping THEN branch\r\n"
-                                        .to_string(),
-                                ) {
-                                    eprintln!("ERROR: Failed to send output: {}", e);
-                                }
-                            }
+                let timeout = ctx.command_timeout();
+                let killer = ctx.session_killer();
+                drop(ctx);
+                let started = Instant::now();
+                let waited = run_and_wait(&shell, &start, &cwd, &env, timeout, &killer);
+                let elapsed = started.elapsed();
+                let mut ctx = match ctx_arc.lock() {
+                    Ok(c) => c,
+                    Err(e) => {
+                        eprintln!("ERROR: Failed to lock context after START /WAIT: {}", e);
+                        return Err(BatchDbgError::LockPoisoned);
+                    }
+                };
+                ctx.record_timing(pc, started, elapsed);
+                match waited {
+                    Ok(result) => {
+                        forward_output(&result, pc, source, output_tx, stderr_tx);
+                        ctx.last_exit_code = result.exit_code;
+                    }
+                    Err(e) => {
+                        eprintln!("ERROR: START /WAIT error: {}", e);
+                        if ctx.is_cancelled() {
+                            break 'run TerminatedReason::Cancelled;
                         }
-                        Err(e) => {
-                            eprintln!("WARNING: Failed to evaluate IF condition: {}", e);
+                        let _ = output_tx.send(ScriptOutput::warning(format!(
+                            "Line {} {}\r\n",
+                            ll.phys_start + 1,
+                            e
+                        )));
+                        ctx.last_exit_code = 1;
+                        // A program that hung stops the script like any line that times out
+                        if let Some(SessionError::Timeout { .. }) = SessionError::from_io(&e) {
+                            ctx.mark_stop();
+                            drop(ctx);
+                            if event_tx.send(StopInfo::new("timeout", pc)).is_err() {
+                                break 'run TerminatedReason::Disconnected;
+                            }
+                            match wait_for_resume(ctx_arc, pc, &log) {
+                                Some(depth) => step_depth = depth,
+                                None => break 'run TerminatedReason::Cancelled,
+                            }
                         }
                     }
                 }
+                pc += 1;
+                continue;
             }
-
             // Parse and display redirections
             let cmd_with_redirections = parse_redirections(&line);
 
@@ -559,57 +1573,65 @@ pub fn run_debugger_dap(
             let is_builtin = is_builtin_command(base_cmd);
             let cmd_type = if is_builtin { "built-in" } else { "external" };
 
-            if !cmd_with_redirections.redirections.is_empty() {
+            if !cmd_with_redirections.redirections.is_empty() && ctx.verbose_console() {
                 eprintln!("Executing {} command: {}", cmd_type, line);
                 for redir in &cmd_with_redirections.redirections {
                     match redir.operator.as_str() {
                         ">" => {
                             eprintln!("  |-- Output redirected to: {} (overwrite)", redir.target);
-                            if let Err(e) = output_tx.send(format!(
-                                "  |-- Output redirected to: {} (overwrite)\r\n",
-                                redir.target
-                            )) {
+                            if let Err(e) = output_tx.send(
+                                format!(
+                                    "  |-- Output redirected to: {} (overwrite)\r\n",
+                                    redir.target
+                                )
+                                .into(),
+                            ) {
                                 eprintln!("ERROR: Failed to send output: {}", e);
                             }
                         }
                         ">>" => {
                             eprintln!("  |-- Output redirected to: {} (append)", redir.target);
-                            if let Err(e) = output_tx.send(format!(
-                                "  |-- Output redirected to: {} (append)\r\n",
-                                redir.target
-                            )) {
+                            if let Err(e) = output_tx.send(
+                                format!(
+                                    "  |-- Output redirected to: {} (append)\r\n",
+                                    redir.target
+                                )
+                                .into(),
+                            ) {
                                 eprintln!("ERROR: Failed to send output: {}", e);
                             }
                         }
                         "<" => {
                             eprintln!("  |-- Input redirected from: {}", redir.target);
-                            if let Err(e) = output_tx
-                                .send(format!("  |-- Input redirected from: {}\r\n", redir.target))
-                            {
+                            if let Err(e) = output_tx.send(
+                                format!("  |-- Input redirected from: {}\r\n", redir.target).into(),
+                            ) {
                                 eprintln!("ERROR: Failed to send output: {}", e);
                             }
                         }
                         "2>" => {
                             eprintln!("  |-- Error output redirected to: {}", redir.target);
-                            if let Err(e) = output_tx.send(format!(
-                                "  |-- Error output redirected to: {}\r\n",
-                                redir.target
-                            )) {
+                            if let Err(e) = output_tx.send(
+                                format!("  |-- Error output redirected to: {}\r\n", redir.target)
+                                    .into(),
+                            ) {
                                 eprintln!("ERROR: Failed to send output: {}", e);
                             }
                         }
                         "2>&1" => {
                             eprintln!("  |-- Error output redirected to stdout");
-                            if let Err(e) = output_tx
-                                .send("  |-- Error output redirected to stdout\r\n".to_string())
-                            {
+                            if let Err(e) = output_tx.send(
+                                "  |-- Error output redirected to stdout\r\n"
+                                    .to_string()
+                                    .into(),
+                            ) {
                                 eprintln!("ERROR: Failed to send output: {}", e);
                             }
                         }
                         "|" => {
                             eprintln!("  |-- Piped to: {}", redir.target);
-                            if let Err(e) =
-                                output_tx.send(format!("  |-- Piped to: {}\r\n", redir.target))
+                            if let Err(e) = output_tx
+                                .send(format!("  |-- Piped to: {}\r\n", redir.target).into())
                             {
                                 eprintln!("ERROR: Failed to send output: {}", e);
                             }
@@ -621,68 +1643,206 @@ pub fn run_debugger_dap(
                 eprintln!("Executing {} command: {}", cmd_type, line);
             }
 
-            ctx.track_set_command(&line);
-
-            if let Some(ref mut f) = log {
-                writeln!(f, "  About to run_command: '{}'", line).ok();
-                f.flush().ok();
+            if !is_builtin {
+                ctx.record_external_command(pc, base_cmd);
             }
 
-            match ctx.run_command(&line) {
-                Ok((out, code)) => {
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "  Command executed, exit code: {}", code).ok();
-                        f.flush().ok();
-                    }
+            log.write(format_args!("  About to run_command: '{}'", line));
 
-                    if !out.trim().is_empty() {
-                        if let Err(e) = output_tx.send(out.clone()) {
+            // Only the shell is locked while the command runs, so the server
+            // can answer requests (variables, evaluate, pause) meanwhile.
+            // Stdout goes to the client as it is printed, so long-running
+            // commands don't look frozen.
+            let shell = ctx.shared_session();
+            // A pause from before the command started has nothing to interrupt
+            let killer = ctx.session_killer();
+            killer.take_interrupted();
+            drop(ctx);
+            let started = Instant::now();
+            let streamed = lock_shell(&shell).run_streaming(&line, &mut |chunk| {
+                if let Err(e) = output_tx.send(ScriptOutput::at(chunk, pc, source)) {
+                    eprintln!("ERROR: Failed to send output: {}", e);
+                }
+            });
+            let elapsed = started.elapsed();
+            let mut ctx = match ctx_arc.lock() {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("ERROR: Failed to lock context after execution: {}", e);
+                    return Err(BatchDbgError::LockPoisoned);
+                }
+            };
+            ctx.record_timing(pc, started, elapsed);
+            match streamed {
+                Ok(result) => {
+                    log.write(format_args!(
+                        "  Command executed, exit code: {}",
+                        result.exit_code
+                    ));
+
+                    if !result.stderr.trim().is_empty() {
+                        if let Err(e) = stderr_tx.send(
+                            ScriptOutput::at(result.stderr.clone(), pc, source)
+                                .with_kind(OutputKind::ScriptStderr),
+                        ) {
                             eprintln!("ERROR: Failed to send output: {}", e);
-                            if let Some(ref mut f) = log {
-                                writeln!(f, "ERROR: Failed to send output: {}", e).ok();
-                                f.flush().ok();
-                            }
                         }
                     }
-                    ctx.last_exit_code = code;
+                    if result.truncated {
+                        let _ = output_tx.send(ScriptOutput::warning(format!(
+                            "WARNING: Output of line {} was over {} bytes, only its start and end are shown\r\n",
+                            ll.phys_start + 1,
+                            ctx.session_mut().output_limit()
+                        )));
+                    }
+                    ctx.last_exit_code = result.exit_code;
+                    track_set_commands(&mut ctx, &line, &result);
+                    output_hit = ctx.check_output_breakpoints(&result.stdout);
+
+                    // Pause killed the command; it ends like Ctrl+Break ended it
+                    if killer.take_interrupted() {
+                        let _ = output_tx.send(
+                            format!("Line {} interrupted by pause\r\n", ll.phys_start + 1).into(),
+                        );
+                        if let Err(e) = ctx.record_interrupt() {
+                            eprintln!("ERROR: Failed to set ERRORLEVEL: {}", e);
+                        }
+                        interrupted = true;
+                    }
+
+                    if let Some(name) = missing_command(&line, &result).filter(|_| !is_builtin) {
+                        let hint = match ctx.suggest_command(&name) {
+                            Some(suggestion) => format!(" Did you mean '{}'?", suggestion),
+                            None => String::new(),
+                        };
+                        let _ = output_tx.send(ScriptOutput::warning(format!(
+                            "WARNING: Line {}: '{}' is not a command or a program on PATH (ERRORLEVEL {}).{}\r\n",
+                            ll.phys_start + 1,
+                            name,
+                            result.exit_code,
+                            hint
+                        )));
+                        if ctx.break_on_command_not_found() {
+                            not_found = Some(name);
+                        }
+                    }
 
                     // Check for data breakpoint hits after command execution
                     if ctx.check_data_breakpoints() {
                         eprintln!("BREAK: Data breakpoint triggered, pausing execution");
-                        if let Some(ref mut f) = log {
-                            writeln!(f, "BREAK: Data breakpoint triggered").ok();
-                            f.flush().ok();
+                        if let Some((name, old, new)) = ctx.data_breakpoint_hit.clone() {
+                            let old_value = if old.is_empty() { None } else { Some(old.as_str()) };
+                            let set_at = ctx
+                                .line_where_set(&name, old_value)
+                                .and_then(|l| pre.logical.get(l))
+                                .map(|l| format!(" (set at line {})", l.phys_start + 1))
+                                .unwrap_or_default();
+                            let _ = output_tx.send(
+                                format!(
+                                    "Data breakpoint: {} changed from '{}'{} to '{}'\r\n",
+                                    name, old, set_at, new
+                                )
+                                .into(),
+                            );
                         }
-                        // Send stopped event
-                        let _ = event_tx.send(("stopped".to_string(), pc));
-                        // Update data breakpoint values for next iteration
+                        log.write(format_args!("BREAK: Data breakpoint triggered"));
                         ctx.update_data_breakpoints();
-                        // Wait for continue
-                        ctx.continue_requested = false;
-                        ctx.set_mode(crate::debugger::RunMode::Continue);
-                        // Continue to next iteration
+                        data_hit = true;
                     }
                 }
                 Err(e) => {
-                    eprintln!("ERROR: Command execution error: {}", e);
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "ERROR: Command execution error: {}", e).ok();
-                        f.flush().ok();
+                    log.write(format_args!("ERROR: Command execution error: {}", e));
+                    match recover_from_session_error(&mut ctx, &e, ll, pc, source, output_tx) {
+                        Ok(hung) => timed_out = hung,
+                        Err(reason) => break 'run reason,
                     }
-                    break 'run;
                 }
             }
         }
 
-        pc += 1;
-    }
+        // A line that timed out stops where it hung; resuming moves past it
+        if timed_out {
+            if let Ok(mut ctx) = ctx_arc.lock() {
+                ctx.mark_stop();
+            }
+            if event_tx.send(StopInfo::new("timeout", pc)).is_err() {
+                break 'run TerminatedReason::Disconnected;
+            }
+            match wait_for_resume(ctx_arc, pc, &log) {
+                Some(depth) => step_depth = depth,
+                None => break 'run TerminatedReason::Cancelled,
+            }
+        }
 
-    eprintln!("DAP: Script execution completed");
-    if let Some(ref mut f) = log {
-        writeln!(f, "DAP: Script execution completed").ok();
-        f.flush().ok();
-    }
-    let _ = event_tx.send(("terminated".to_string(), 0));
+        // An interrupted command stops on its own line, as the pause asked
+        if interrupted {
+            if let Ok(mut ctx) = ctx_arc.lock() {
+                ctx.mark_stop();
+                ctx.set_stop_text(Some(format!(
+                    "Paused: the command on line {} was interrupted",
+                    ll.phys_start + 1
+                )));
+            }
+            if event_tx.send(StopInfo::new("pause", pc)).is_err() {
+                break 'run TerminatedReason::Disconnected;
+            }
+            match wait_for_resume(ctx_arc, pc, &log) {
+                Some(depth) => step_depth = depth,
+                None => break 'run TerminatedReason::Cancelled,
+            }
+        }
 
-    Ok(())
+        // Data breakpoints pause on the line that changed the variable
+        if data_hit {
+            let stop = match ctx_arc.lock() {
+                Ok(mut ctx) => {
+                    ctx.mark_stop();
+                    StopInfo::describe(&ctx, "data breakpoint", pc, ll.phys_start + 1)
+                }
+                Err(_) => StopInfo::new("data breakpoint", pc),
+            };
+            if event_tx.send(stop).is_err() {
+                break 'run TerminatedReason::Disconnected;
+            }
+            match wait_for_resume(ctx_arc, pc, &log) {
+                Some(depth) => step_depth = depth,
+                None => break 'run TerminatedReason::Cancelled,
+            }
+        }
+
+        // The "command not found" exception filter pauses after the line
+        if let Some(name) = not_found {
+            if let Ok(mut ctx) = ctx_arc.lock() {
+                ctx.mark_stop();
+                ctx.set_stop_text(Some(format!("Command not found: {}", name)));
+            }
+            if event_tx.send(StopInfo::new("exception", pc)).is_err() {
+                break 'run TerminatedReason::Disconnected;
+            }
+            match wait_for_resume(ctx_arc, pc, &log) {
+                Some(depth) => step_depth = depth,
+                None => break 'run TerminatedReason::Cancelled,
+            }
+        }
+
+        // Output breakpoints pause on the line that printed the match
+        if let Some((pattern, matched)) = output_hit {
+            eprintln!("BREAK: Output matched '{}': {}", pattern, matched);
+            let _ = output_tx
+                .send(format!("Output breakpoint '{}' matched: {}\r\n", pattern, matched).into());
+            if let Ok(mut ctx) = ctx_arc.lock() {
+                ctx.mark_stop();
+            }
+            if event_tx.send(StopInfo::new("output breakpoint", pc)).is_err() {
+                break 'run TerminatedReason::Disconnected;
+            }
+            match wait_for_resume(ctx_arc, pc, &log) {
+                Some(depth) => step_depth = depth,
+                None => break 'run TerminatedReason::Cancelled,
+            }
+        }
+
+        pc += 1;
+    };
+    Ok(ended)
 }
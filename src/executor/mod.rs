@@ -1,5 +1,7 @@
 mod dap_runner;
 mod runner;
 
+#[cfg(any(test, feature = "test-support"))]
 pub use dap_runner::run_debugger_dap;
+pub use dap_runner::{spawn_debugger_dap, OutputKind, RunningScript, ScriptOutput, StopInfo};
 pub use runner::run_debugger;
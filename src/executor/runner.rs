@@ -1,6 +1,7 @@
-use crate::debugger::{leave_context, DebugContext, Frame, RunMode};
+use crate::debugger::{leave_context, CommandResult, DebugContext, Frame, RunMode};
 use crate::parser::{
-    is_comment, normalize_whitespace, split_composite_command, CommandOp, PreprocessResult,
+    exit_command, find_label, goto_target, is_comment, normalize_whitespace, part_runs,
+    split_batch_arguments, split_composite_command, PreprocessResult,
 };
 use std::collections::HashMap;
 use std::io::{self, Write};
@@ -34,16 +35,15 @@ fn paren_delta(line: &str) -> i32 {
     delta
 }
 
-fn expand_positional_args(mut text: String, args: &[String]) -> String {
-    for i in (1..=9).rev() {
-        let idx = i - 1;
-        let val = args.get(idx).cloned().unwrap_or_default();
-        let unquoted = val.trim_matches('"').to_string();
-
-        text = text.replace(&format!("%~{}", i), &unquoted);
-        text = text.replace(&format!("%{}", i), &val);
+// Interactive mode: stdout is the script's own
+#[allow(clippy::print_stdout)]
+fn print_output(result: &CommandResult) {
+    if !result.stdout.trim().is_empty() {
+        print!("{}", result.stdout);
+    }
+    if !result.stderr.trim().is_empty() {
+        eprint!("{}", result.stderr);
     }
-    text
 }
 
 pub fn run_debugger(
@@ -80,29 +80,26 @@ pub fn run_debugger(
         }
         if line_upper.starts_with("SETLOCAL") {
             ctx.handle_setlocal();
-            let (out, code) = ctx.run_command(&line)?;
-            if !out.trim().is_empty() {
-                print!("{}", out);
-            }
-            ctx.last_exit_code = code;
+            let result = ctx.run_command(&line)?;
+            print_output(&result);
+            ctx.last_exit_code = result.exit_code;
             pc += 1;
             continue;
         }
         if line_upper.starts_with("ENDLOCAL") {
             ctx.handle_endlocal();
-            let (out, code) = ctx.run_command(&line)?;
-            if !out.trim().is_empty() {
-                print!("{}", out);
-            }
-            ctx.last_exit_code = code;
+            let result = ctx.run_command(&line)?;
+            print_output(&result);
+            ctx.last_exit_code = result.exit_code;
             pc += 1;
             continue;
         }
         let is_block_start = (line_upper.starts_with("IF ") || line_upper.starts_with("FOR "))
             && paren_delta(raw) > 0;
+        ctx.track_pc(pc);
         let should_stop = match ctx.mode() {
             RunMode::Continue => ctx.should_stop_at(pc),
-            RunMode::StepInto => true,
+            RunMode::StepInto | RunMode::StepBack => true,
             RunMode::StepOver => {
                 if let Some(target_depth) = step_depth {
                     ctx.call_stack.len() <= target_depth
@@ -184,30 +181,36 @@ pub fn run_debugger(
         }
         if line_upper.starts_with("CALL ") {
             let rest = &line[5..].trim();
-            let mut lexer = shlex::Shlex::new(rest);
-            let first = lexer.next().unwrap_or_default();
+            let mut words = split_batch_arguments(rest).into_iter();
+            let first = words.next().unwrap_or_default();
             let label_key = first.trim_start_matches(':').to_lowercase();
-            let args: Vec<String> = lexer.collect();
+            let args: Vec<String> = words.collect();
 
-            if let Some(&phys_target) = labels_phys.get(&label_key) {
-                let logical_target = pre.phys_to_logical[phys_target];
-
-                ctx.call_stack.push(Frame::new(pc + 1, Some(args)));
+            if let Some(logical_target) = labels_phys
+                .contains_key(&label_key)
+                .then(|| find_label(pre, &label_key, pc))
+                .flatten()
+            {
+                let label = first.trim_start_matches(':');
+                ctx.call_stack
+                    .push(Frame::new(pc + 1, Some(args)).with_label(label, logical_target));
 
                 eprintln!(
                     "\nCALL to :{} (jumping to logical line {})",
                     label_key, logical_target
                 );
                 pc = logical_target;
-            } else {
+                continue;
+            }
+            if first.starts_with(':') {
                 eprintln!("ERROR: CALL to unknown label: {}", label_key);
                 break 'run;
             }
-            continue;
+            // CALL of a command (CALL SET, CALL ECHO, ...): run it like any
+            // other line, track_set_command handles the extra expansion
         }
-        if line_upper.starts_with("EXIT /B") {
-            let rest = &line[7..].trim();
-            let code: i32 = rest.parse::<i32>().unwrap_or(0);
+        if let Some((true, rest)) = exit_command(&line) {
+            let code = ctx.parse_exit_code(rest);
             ctx.last_exit_code = code;
 
             eprintln!("\nEXIT /B {} (returning from subroutine)", code);
@@ -220,8 +223,33 @@ pub fn run_debugger(
             }
             continue;
         }
-        if line_upper == "GOTO :EOF" {
-            eprintln!("\nGOTO :EOF (returning from subroutine)");
+        if let Some(label_key) = goto_target(&line) {
+            let target = if label_key == "eof" {
+                eprintln!("\nGOTO :EOF (returning from subroutine)");
+                None
+            } else if labels_phys.contains_key(&label_key) {
+                find_label(pre, &label_key, pc)
+            } else {
+                None
+            };
+            match target {
+                Some(logical_target) => {
+                    eprintln!(
+                        "\nGOTO :{} (jumping to logical line {})",
+                        label_key, logical_target
+                    );
+                    pc = logical_target;
+                    continue;
+                }
+                None if label_key != "eof" => {
+                    eprintln!(
+                        "The system cannot find the batch label specified - {}",
+                        label_key
+                    );
+                    ctx.last_exit_code = 1;
+                }
+                None => {}
+            }
 
             match leave_context(&mut ctx.call_stack) {
                 Some(next_pc) => {
@@ -231,28 +259,6 @@ pub fn run_debugger(
             }
             continue;
         }
-        if line_upper.starts_with("GOTO ") {
-            let rest = &line[5..].trim();
-            let label_key = rest
-                .trim_start_matches(':')
-                .split_whitespace()
-                .next()
-                .unwrap_or("")
-                .to_lowercase();
-
-            if let Some(&phys_target) = labels_phys.get(&label_key) {
-                let logical_target = pre.phys_to_logical[phys_target];
-                eprintln!(
-                    "\nGOTO :{} (jumping to logical line {})",
-                    label_key, logical_target
-                );
-                pc = logical_target;
-            } else {
-                eprintln!("ERROR: GOTO to unknown label: {}", label_key);
-                break 'run;
-            }
-            continue;
-        }
         if is_block_start {
             let mut block_lines = vec![raw.to_string()];
             let mut block_pc = pc + 1;
@@ -266,20 +272,14 @@ pub fn run_debugger(
                 balance += paren_delta(&b.text);
                 block_pc += 1;
             }
-            if let Some(frame) = ctx.call_stack.last() {
-                if let Some(a) = &frame.args {
-                    for l in &mut block_lines {
-                        *l = expand_positional_args(l.clone(), a);
-                    }
-                }
+            for l in &mut block_lines {
+                *l = ctx.expand_arguments(l);
             }
 
-            let (out, code) = ctx.session_mut().run_batch_block(&block_lines)?;
-            if !out.trim().is_empty() {
-                print!("{}", out);
-            }
-            ctx.last_exit_code = code;
-            eprintln!("    |-- block exit code: {}", code);
+            let result = ctx.session_mut().run_batch_block(&block_lines)?;
+            print_output(&result);
+            ctx.last_exit_code = result.exit_code;
+            eprintln!("    |-- block exit code: {}", result.exit_code);
 
             pc = block_pc;
             continue;
@@ -295,51 +295,44 @@ pub fn run_debugger(
             eprintln!("    {}", raw);
         }
 
-        let parts = split_composite_command(&line);
+        let parts = split_composite_command(&ctx.expand_arguments(&line));
 
-        for (i, part) in parts.iter().enumerate() {
-            if part.text.trim().is_empty() {
-                continue;
-            }
+        if parts.len() == 1 {
+            let exec_text = &parts[0].text;
+            let result = ctx.run_command(exec_text)?;
+            print_output(&result);
 
-            let should_execute = match (i, ctx.last_exit_code) {
-                (0, _) => true,
-                (_, code) => {
-                    let prev_op = parts[i - 1].op;
-                    match prev_op {
-                        Some(CommandOp::Unconditional) => true,
-                        Some(CommandOp::And) => code == 0,
-                        Some(CommandOp::Or) => code != 0,
-                        None => true,
-                    }
-                }
-            };
-
-            if should_execute {
-                let mut exec_text = part.text.clone();
-                if let Some(frame) = ctx.call_stack.last() {
-                    if let Some(a) = &frame.args {
-                        exec_text = expand_positional_args(exec_text, a);
-                    }
+            ctx.record_command(exec_text, &result);
+            if !should_stop {
+                eprintln!("    |-- exit code: {}", result.exit_code);
+            }
+        } else {
+            // Each part runs on its own so it keeps its own exit code; pair
+            // the results up with the parts that ran using the same rules
+            let mut results = ctx.run_parts(&parts)?.into_iter();
+            let mut last_code: Option<i32> = None;
+
+            for (i, part) in parts.iter().enumerate() {
+                if part.text.trim().is_empty() {
+                    continue;
                 }
 
-                if parts.len() > 1 {
-                    eprintln!("    |-- Part {}: {}", i + 1, exec_text);
+                let prev_op = i.checked_sub(1).and_then(|p| parts[p].op);
+                if !part_runs(prev_op, last_code) {
+                    eprintln!("    |-- Part {} skipped (condition failed)", i + 1);
+                    continue;
                 }
 
-                ctx.track_set_command(&exec_text);
-
-                let (out, code) = ctx.run_command(&exec_text)?;
-                if !out.trim().is_empty() {
-                    print!("{}", out);
-                }
+                if let Some(result) = results.next() {
+                    eprintln!("    |-- Part {}: {}", i + 1, part.text);
+                    print_output(&result);
 
-                ctx.last_exit_code = code;
-                if !should_stop {
-                    eprintln!("    |-- exit code: {}", code);
+                    ctx.record_command(&part.text, &result);
+                    last_code = Some(result.exit_code);
+                    if !should_stop {
+                        eprintln!("    |-- exit code: {}", result.exit_code);
+                    }
                 }
-            } else {
-                eprintln!("    |-- Part {} skipped (condition failed)", i + 1);
             }
         }
 
@@ -347,6 +340,7 @@ pub fn run_debugger(
     }
 
     eprintln!("\nScript execution completed");
+    ctx.end_script_scopes();
     ctx.print_call_stack(&pre.logical);
     ctx.print_variables();
 
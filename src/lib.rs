@@ -1,4 +1,10 @@
+// stdout carries the DAP stream; a stray print there breaks the session
+#![deny(clippy::print_stdout)]
+
+pub mod api;
+pub mod cli;
 pub mod dap;
 pub mod debugger;
+pub mod error;
 pub mod executor;
 pub mod parser;
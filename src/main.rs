@@ -1,56 +1,181 @@
+// stdout carries the DAP stream; a stray print there breaks the session
+#![deny(clippy::print_stdout)]
+
+mod api;
+mod cli;
 mod dap;
 mod debugger;
+mod error;
 mod executor;
 mod parser;
 
+use debugger::DebugLog;
 use std::fs;
 use std::io::{self, Write};
+use std::path::Path;
 
 fn main() -> io::Result<()> {
-    // Log to file
-    let mut log = fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("C:\\temp\\batch-debugger-vscode.log")
-        .ok();
-
-    if let Some(ref mut f) = log {
-        writeln!(
-            f,
-            "\n=== DEBUGGER STARTED at {:?} ===",
-            std::time::SystemTime::now()
-        )
-        .ok();
-    }
+    // Trace log, only written when BATCH_DEBUGGER_LOG names a file
+    let log = DebugLog::from_env();
+
+    log.write(format_args!(
+        "\n=== DEBUGGER STARTED at {:?} ===",
+        std::time::SystemTime::now()
+    ));
 
     let args: Vec<String> = std::env::args().collect();
 
-    if let Some(ref mut f) = log {
-        writeln!(f, "Args: {:?}", args).ok();
-    }
+    log.write(format_args!("Args: {:?}", args));
 
     let dap_mode = args
         .iter()
         .any(|arg| arg == "--dap" || arg == "--debug-adapter");
 
-    if dap_mode {
-        if let Some(ref mut f) = log {
-            writeln!(f, "Starting DAP mode").ok();
+    // `--port N` serves DAP over TCP instead of stdio, on `--host` (localhost
+    // by default); `--program script.bat` adds running it for clients to attach
+    let option = |name: &str| {
+        args.iter()
+            .position(|arg| arg == name)
+            .and_then(|i| args.get(i + 1))
+            .cloned()
+    };
+    let port = match option("--port").map(|p| p.parse::<u16>()) {
+        Some(Ok(port)) => Some(port),
+        Some(Err(e)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid --port: {}", e),
+            ))
+        }
+        None => None,
+    };
+
+    if let Some(port) = port {
+        let host = option("--host").unwrap_or_else(|| "127.0.0.1".to_string());
+        // With `--program` the script runs at once and clients attach to it
+        if let Some(program) = option("--program") {
+            log.write(format_args!("Starting DAP service mode"));
+            eprintln!("Starting DAP service for {} on port {}...", program, port);
+            dap::run_dap_service(
+                &host,
+                port,
+                &program,
+                option("--breakpoints-file").as_deref(),
+                log.clone(),
+            )?;
+        } else {
+            log.write(format_args!("Starting DAP socket mode"));
+            eprintln!("Starting in DAP mode on port {}...", port);
+            dap::run_dap_socket(&host, port, log.clone())?;
         }
+    } else if let Some(script) = option("--dump-ast") {
+        dump_ast(&script)?;
+    } else if let Some(script) = option("--callgraph") {
+        let graph = api::call_graph_of(&script)?;
+        let title = Path::new(&script)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or(script);
+        write!(io::stdout().lock(), "{}", graph.to_dot(&title))?;
+    } else if let Some(script) = option("--lint") {
+        let exit_code = run_lint(&script)?;
+        log.write(format_args!("=== DEBUGGER EXITING ==="));
+        log.flush();
+        std::process::exit(exit_code);
+    } else if let Some(script) = option("--run") {
+        log.write(format_args!("Starting headless run"));
+        let exit_code = run_headless(&script, option("--report").as_deref())?;
+        log.write(format_args!("=== DEBUGGER EXITING ==="));
+        log.flush();
+        // Exit with the script's code so CI sees it fail
+        std::process::exit(exit_code);
+    } else if let Some(script) = option("--cli") {
+        log.write(format_args!("Starting CLI mode"));
+        let mut session = api::DebugSession::load(&script)?;
+        if let Some(file) = option("--breakpoints-file") {
+            for unbound in session.use_breakpoints_file(&file)? {
+                eprintln!(
+                    "WARNING: Saved breakpoint at line {} not set: no statement at or after it",
+                    unbound.line
+                );
+            }
+        }
+        cli::run_repl(&mut session, io::stdin().lock(), io::stdout().lock())?;
+    } else if dap_mode {
+        log.write(format_args!("Starting DAP mode"));
         eprintln!("Starting in DAP mode...");
-        dap::run_dap_mode()?;
+        dap::run_dap_mode(log.clone())?;
     } else {
         eprintln!("Starting in interactive mode...");
         run_interactive_mode()?;
     }
 
-    if let Some(ref mut f) = log {
-        writeln!(f, "=== DEBUGGER EXITING ===").ok();
-    }
+    log.write(format_args!("=== DEBUGGER EXITING ==="));
+    log.flush();
 
     Ok(())
 }
 
+/// Print what the parser makes of `script` as JSON
+#[cfg(feature = "serde")]
+fn dump_ast(script: &str) -> io::Result<()> {
+    let contents = fs::read_to_string(script)?;
+    let physical_lines: Vec<&str> = contents.lines().collect();
+    let dump = parser::dump_ast(&physical_lines);
+    let json = serde_json::to_string_pretty(&dump).map_err(io::Error::other)?;
+    writeln!(io::stdout().lock(), "{}", json)
+}
+
+#[cfg(not(feature = "serde"))]
+fn dump_ast(_script: &str) -> io::Result<()> {
+    Err(io::Error::other(
+        "--dump-ast needs the debugger built with the serde feature",
+    ))
+}
+
+/// Print what a read of `script` finds wrong, one `file:line:` per
+/// finding. Gives 1 when any of it is an error, for CI.
+fn run_lint(script: &str) -> io::Result<i32> {
+    let diagnostics = api::lint(script)?;
+    let mut stdout = io::stdout().lock();
+    for diagnostic in &diagnostics {
+        writeln!(stdout, "{}:{}", script, diagnostic)?;
+    }
+    let failed = diagnostics
+        .iter()
+        .any(|d| d.severity == parser::Severity::Error);
+    Ok(i32::from(failed))
+}
+
+/// Run `script` to the end without stopping, its output going to stdout and
+/// stderr, and write the report of the run to `report` as JSON. Gives the
+/// script's exit code.
+fn run_headless(script: &str, report: Option<&str>) -> io::Result<i32> {
+    let mut session = api::DebugSession::load(script)?;
+    let mut stdout = io::stdout().lock();
+    let mut stderr = io::stderr().lock();
+    loop {
+        let stop = session.run_until_stop()?;
+        for printed in session.take_output() {
+            match printed.kind {
+                executor::OutputKind::ScriptStdout => stdout.write_all(printed.text.as_bytes())?,
+                _ => stderr.write_all(printed.text.as_bytes())?,
+            }
+        }
+        if stop.is_none() {
+            break;
+        }
+    }
+    stdout.flush()?;
+
+    let run = session.report()?;
+    if let Some(report) = report {
+        let json = serde_json::to_string_pretty(&run).map_err(io::Error::other)?;
+        fs::write(report, json)?;
+    }
+    Ok(run.exit_code)
+}
+
 fn run_interactive_mode() -> io::Result<()> {
     let contents = fs::read_to_string("test.bat").expect("Could not read test.bat");
     let physical_lines: Vec<&str> = contents.lines().collect();
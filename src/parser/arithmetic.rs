@@ -0,0 +1,254 @@
+//! Checking SET /A expressions without running them. CMD evaluates them
+//! itself; all the debugger needs to know is whether it will accept one.
+
+/// Operators of SET /A, longest first so `<<=` isn't read as `<<` and `=`
+const OPERATORS: [&str; 25] = [
+    "<<=", ">>=", "<<", ">>", "*=", "/=", "%=", "+=", "-=", "&=", "^=", "|=", "=", "+", "-", "*",
+    "/", "%", "&", "|", "^", "!", "~", "(", ")",
+];
+
+const ASSIGNMENTS: [&str; 11] = [
+    "=", "*=", "/=", "%=", "+=", "-=", "&=", "^=", "|=", "<<=", ">>=",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number,
+    Name(String),
+    Op(&'static str),
+    Comma,
+}
+
+/// Why CMD would refuse the SET /A expression `expression` (the text after
+/// `/A`), or None when it evaluates. Variable references expand to a
+/// number and `%%` is the modulo operator, as in a batch file.
+pub fn arithmetic_error(expression: &str) -> Option<String> {
+    let tokens = match tokenize(&expand(expression)) {
+        Ok(tokens) => tokens,
+        Err(message) => return Some(message),
+    };
+    if tokens.is_empty() {
+        return Some("The expression is empty".to_string());
+    }
+    let mut parser = Parser { tokens, pos: 0 };
+    if let Err(message) = parser.list() {
+        return Some(message);
+    }
+    match parser.peek() {
+        None => None,
+        Some(Token::Op(")")) => Some("Unbalanced parenthesis".to_string()),
+        Some(Token::Op(op)) if ASSIGNMENTS.contains(op) => {
+            Some(format!("Only a variable can be assigned with {}", op))
+        }
+        Some(_) => Some("Missing operator".to_string()),
+    }
+}
+
+/// Names the SET /A expression `expression` assigns, as written
+pub fn arithmetic_assignments(expression: &str) -> Vec<String> {
+    let tokens = tokenize(&expand(expression)).unwrap_or_default();
+    tokens
+        .windows(2)
+        .filter_map(|pair| match (&pair[0], &pair[1]) {
+            (Token::Name(name), Token::Op(op)) if ASSIGNMENTS.contains(op) => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The expression as SET /A sees it: quotes gone, ^ escapes outside quotes
+/// applied, `%%` as `%` and each %VAR%, !VAR! or FOR variable as a number
+fn expand(expression: &str) -> String {
+    let mut unquoted = String::new();
+    let mut in_quotes = false;
+    let mut chars = expression.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '^' if !in_quotes => {
+                if let Some(escaped) = chars.next() {
+                    unquoted.push(escaped);
+                }
+            }
+            _ => unquoted.push(c),
+        }
+    }
+
+    let mut expanded = String::new();
+    let mut rest = unquoted.as_str();
+    while let Some(start) = rest.find(['%', '!']) {
+        expanded.push_str(&rest[..start]);
+        let marker = &rest[start..start + 1];
+        let after = &rest[start + 1..];
+        if marker == "%" && after.starts_with('%') {
+            // %%n is a FOR variable, %% alone the modulo operator
+            match after[1..].chars().next() {
+                Some(c) if c.is_ascii_alphabetic() || c == '~' => {
+                    expanded.push('1');
+                    let variable = after[1..].trim_start_matches('~');
+                    let end = variable
+                        .char_indices()
+                        .nth(1)
+                        .map_or(variable.len(), |(i, _)| i);
+                    rest = &variable[end..];
+                }
+                _ => {
+                    expanded.push('%');
+                    rest = &after[1..];
+                }
+            }
+            continue;
+        }
+        match after.find(marker) {
+            Some(end) if end > 0 && !after[..end].contains(char::is_whitespace) => {
+                expanded.push('1');
+                rest = &after[end + 1..];
+            }
+            _ => {
+                expanded.push_str(marker);
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c == ',' {
+            tokens.push(Token::Comma);
+            rest = &rest[1..];
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else if "<>".contains(c) {
+            return Err(format!("Unexpected {}", c));
+        } else {
+            let end = rest
+                .find(|c: char| {
+                    c.is_whitespace() || c == ',' || OPERATORS.iter().any(|op| op.starts_with(c))
+                })
+                .unwrap_or(rest.len());
+            let end = end.max(c.len_utf8());
+            let word = &rest[..end];
+            if c.is_ascii_digit() {
+                check_number(word)?;
+                tokens.push(Token::Number);
+            } else {
+                tokens.push(Token::Name(word.to_string()));
+            }
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Numbers are decimal (17), hexadecimal (0x11) or octal (021)
+fn check_number(word: &str) -> Result<(), String> {
+    let valid = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        Some(hex) => !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None if word.starts_with('0') => word.chars().all(|c| ('0'..='7').contains(&c)),
+        None => word.chars().all(|c| c.is_ascii_digit()),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid number {}: numbers are decimal (17), hexadecimal (0x11) or octal (021)",
+            word
+        ))
+    }
+}
+
+/// Recursive descent over the tokens, lowest precedence first
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next_is(&self, ops: &[&str]) -> bool {
+        matches!(self.peek(), Some(Token::Op(op)) if ops.contains(op))
+    }
+
+    /// Expressions separated by commas
+    fn list(&mut self) -> Result<(), String> {
+        self.assignment()?;
+        while self.peek() == Some(&Token::Comma) {
+            self.pos += 1;
+            self.assignment()?;
+        }
+        Ok(())
+    }
+
+    fn assignment(&mut self) -> Result<(), String> {
+        let assigns = matches!(
+            (self.peek(), self.tokens.get(self.pos + 1)),
+            (Some(Token::Name(_)), Some(Token::Op(op))) if ASSIGNMENTS.contains(op)
+        );
+        if assigns {
+            self.pos += 2;
+            return self.assignment();
+        }
+        self.binary(0)
+    }
+
+    /// Binary operators, from | (level 0) down to * / % (level 5)
+    fn binary(&mut self, level: usize) -> Result<(), String> {
+        const LEVELS: [&[&str]; 6] = [
+            &["|"],
+            &["^"],
+            &["&"],
+            &["<<", ">>"],
+            &["+", "-"],
+            &["*", "/", "%"],
+        ];
+        let operand = |parser: &mut Self| {
+            if level + 1 < LEVELS.len() {
+                parser.binary(level + 1)
+            } else {
+                parser.unary()
+            }
+        };
+        operand(self)?;
+        while self.next_is(LEVELS[level]) {
+            self.pos += 1;
+            operand(self)?;
+        }
+        Ok(())
+    }
+
+    fn unary(&mut self) -> Result<(), String> {
+        if self.next_is(&["!", "~", "-", "+"]) {
+            self.pos += 1;
+            return self.unary();
+        }
+        match self.peek() {
+            Some(Token::Number) | Some(Token::Name(_)) => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(Token::Op("(")) => {
+                self.pos += 1;
+                self.list()?;
+                if !self.next_is(&[")"]) {
+                    return Err("Unbalanced parenthesis".to_string());
+                }
+                self.pos += 1;
+                Ok(())
+            }
+            Some(Token::Op(op)) if ASSIGNMENTS.contains(op) => {
+                Err(format!("Only a variable can be assigned with {}", op))
+            }
+            _ => Err("Missing operand".to_string()),
+        }
+    }
+}
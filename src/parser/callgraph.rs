@@ -0,0 +1,215 @@
+//! Which parts of a script jump to which: the code before the first label
+//! and each label's lines are the nodes, CALL, GOTO and running on into the
+//! next label the edges. For finding one's way around a long script.
+
+use super::commands::commands_named;
+use super::labels::{find_label, label_name};
+use super::{is_statement, PreprocessResult};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub enum NodeKind {
+    Main,   // The lines before the first label
+    Label,  // A :label and the lines up to the next one
+    Eof,    // GOTO :EOF, the end of the script or CALLed label
+    Script, // Another batch file the script CALLs
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LabelNode {
+    pub name: String, // "main", ":label" (lowercased), ":eof" or the CALLed file
+    pub kind: NodeKind,
+    pub line: Option<usize>, // 1-based physical line it starts on, for main and labels
+    pub unreachable: bool,   // No edge leads to it
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub enum EdgeKind {
+    Call,
+    Goto,
+    FallThrough, // The last line of a label doesn't jump, so the next label runs
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+    pub kind: EdgeKind,
+    pub line: usize, // 1-based physical line of the CALL or GOTO, or the last line falling through
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CallGraph {
+    pub nodes: Vec<LabelNode>,
+    pub edges: Vec<Edge>,
+}
+
+/// The call graph of `pre`. Targets built from variables, and labels the
+/// script doesn't define, have no edge.
+pub fn call_graph(pre: &PreprocessResult) -> CallGraph {
+    let mut graph = CallGraph::default();
+    graph.nodes.push(LabelNode {
+        name: "main".to_string(),
+        kind: NodeKind::Main,
+        line: Some(1),
+        unreachable: false,
+    });
+    let mut current = "main".to_string();
+    let mut last_statement = None; // Of the current node: logical line
+    for (i, logical) in pre.logical.iter().enumerate() {
+        let text = logical.text.trim_start().trim_start_matches('@');
+        if let Some(name) = label_name(text).filter(|name| !name.starts_with(':')) {
+            let name = format!(":{}", name);
+            if graph.nodes.iter().any(|node| node.name == name) {
+                continue; // Defined again; GOTO finds the first from above
+            }
+            if let Some(last) = last_statement.filter(|&last| falls_through(pre, last)) {
+                graph.edges.push(Edge {
+                    from: current.clone(),
+                    to: name.clone(),
+                    kind: EdgeKind::FallThrough,
+                    line: pre.logical[last].phys_start + 1,
+                });
+            }
+            graph.nodes.push(LabelNode {
+                name: name.clone(),
+                kind: NodeKind::Label,
+                line: Some(logical.phys_start + 1),
+                unreachable: false,
+            });
+            current = name;
+            last_statement = None;
+            continue;
+        }
+        if !is_statement(text) {
+            continue;
+        }
+        last_statement = Some(i);
+        let targets = commands_named(text, "goto")
+            .into_iter()
+            .filter_map(|rest| label_target(pre, rest, i))
+            .map(|target| (EdgeKind::Goto, target))
+            .chain(
+                commands_named(text, "call")
+                    .into_iter()
+                    .filter_map(|rest| call_target(pre, rest, i))
+                    .map(|target| (EdgeKind::Call, target)),
+            );
+        for (kind, (to, to_kind)) in targets.collect::<Vec<_>>() {
+            if !graph.nodes.iter().any(|node| node.name == to) && to_kind != NodeKind::Label {
+                graph.nodes.push(LabelNode {
+                    name: to.clone(),
+                    kind: to_kind,
+                    line: None,
+                    unreachable: false,
+                });
+            }
+            graph.edges.push(Edge {
+                from: current.clone(),
+                to,
+                kind,
+                line: logical.phys_start + 1,
+            });
+        }
+    }
+
+    for node in &mut graph.nodes {
+        node.unreachable = node.kind == NodeKind::Label
+            && !graph
+                .edges
+                .iter()
+                .any(|edge| edge.to == node.name && edge.from != node.name);
+    }
+    graph
+}
+
+/// Node a GOTO with `rest` after it jumps to
+fn label_target(pre: &PreprocessResult, rest: &str, from: usize) -> Option<(String, NodeKind)> {
+    let label = rest.trim_start().trim_start_matches(':');
+    let label = label.split_whitespace().next()?.to_lowercase();
+    if label == "eof" {
+        return Some((":eof".to_string(), NodeKind::Eof));
+    }
+    if label.contains(['%', '!']) {
+        return None;
+    }
+    find_label(pre, &label, from).map(|_| (format!(":{}", label), NodeKind::Label))
+}
+
+/// Node a CALL with `rest` after it runs: a label, or a .bat or .cmd file
+fn call_target(pre: &PreprocessResult, rest: &str, from: usize) -> Option<(String, NodeKind)> {
+    let rest = rest.trim_start();
+    if rest.starts_with(':') {
+        return label_target(pre, rest, from);
+    }
+    let file = match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next()?,
+        None => rest.split_whitespace().next()?,
+    };
+    let lower = file.to_ascii_lowercase();
+    if !(lower.ends_with(".bat") || lower.ends_with(".cmd")) || file.contains(['%', '!']) {
+        return None;
+    }
+    Some((file.to_string(), NodeKind::Script))
+}
+
+/// Whether the script runs on past logical line `last`, the last statement
+/// before a label: anything but an unconditional GOTO or EXIT does
+fn falls_through(pre: &PreprocessResult, last: usize) -> bool {
+    let text = pre.logical[last].text.trim_start().trim_start_matches('@');
+    let first = text
+        .split(|c: char| c.is_whitespace() || c == ':' || c == '/')
+        .next()
+        .unwrap_or("");
+    !(first.eq_ignore_ascii_case("goto") || first.eq_ignore_ascii_case("exit"))
+}
+
+impl CallGraph {
+    /// The graph in Graphviz DOT, unreachable labels greyed out
+    pub fn to_dot(&self, title: &str) -> String {
+        let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let quote = |s: &str| format!("\"{}\"", escape(s));
+        let mut dot = format!("digraph {} {{\n", quote(title));
+        for node in &self.nodes {
+            let label = match node.line {
+                Some(line) => format!("{}\\nline {}", escape(&node.name), line),
+                None => escape(&node.name),
+            };
+            let mut attributes = format!("label=\"{}\"", label);
+            match node.kind {
+                NodeKind::Eof => attributes.push_str(", shape=doublecircle"),
+                NodeKind::Script => attributes.push_str(", shape=box"),
+                _ => {}
+            }
+            if node.unreachable {
+                attributes.push_str(", style=dashed, color=gray, fontcolor=gray");
+            }
+            let _ = writeln!(dot, "  {} [{}];", quote(&node.name), attributes);
+        }
+        for edge in &self.edges {
+            let style = match edge.kind {
+                EdgeKind::Call => "label=\"call\"",
+                EdgeKind::Goto => "label=\"goto\"",
+                EdgeKind::FallThrough => "style=dotted",
+            };
+            let _ = writeln!(
+                dot,
+                "  {} -> {} [{}];",
+                quote(&edge.from),
+                quote(&edge.to),
+                style
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
@@ -1,3 +1,6 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 /// Represents a command operator for composite commands
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CommandOp {
@@ -18,6 +21,36 @@ pub fn normalize_whitespace(line: &str) -> String {
     line.split_whitespace().collect::<Vec<_>>().join(" ")
 }
 
+/// Whether a command joined to the previous one by `op` runs, given the
+/// exit code of the last command that did: `&&` needs it to have succeeded,
+/// `||` to have failed
+pub fn part_runs(op: Option<CommandOp>, last_exit: Option<i32>) -> bool {
+    match (op, last_exit) {
+        (Some(CommandOp::And), Some(code)) => code == 0,
+        (Some(CommandOp::Or), Some(code)) => code != 0,
+        _ => true,
+    }
+}
+
+/// If the line is a CD/CHDIR command, return its argument text
+pub fn strip_cd_command(line: &str) -> Option<&str> {
+    let upper = line.to_uppercase();
+    for name in ["CHDIR", "CD"] {
+        if upper.starts_with(name) {
+            let rest = &line[name.len()..];
+            // CD.. and CD\ are valid without a separating space
+            if rest.is_empty()
+                || rest.starts_with(' ')
+                || rest.starts_with('.')
+                || rest.starts_with('\\')
+            {
+                return Some(rest.trim());
+            }
+        }
+    }
+    None
+}
+
 /// Split a command line by composite operators (&, &&, ||)
 pub fn split_composite_command(line: &str) -> Vec<CommandPart> {
     let mut parts = Vec::new();
@@ -95,8 +128,110 @@ pub fn is_comment(line: &str) -> bool {
         || trimmed.to_uppercase().starts_with("REM\t")
 }
 
+/// The text after each `name` command on `line`, also after IF, FOR ... DO,
+/// ( and &. `name` is lowercase and must be a word of its own, followed by
+/// whitespace or a `:` as in `goto:eof`.
+pub fn commands_named<'a>(line: &'a str, name: &str) -> Vec<&'a str> {
+    let mut commands = Vec::new();
+    let lower = line.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(found) = lower[from..].find(name) {
+        let start = from + found;
+        from = start + name.len();
+        let before = lower[..start].chars().next_back();
+        let starts_word = before.is_none_or(|c| c.is_whitespace() || "(&|@".contains(c));
+        let after = &line[from..];
+        if starts_word && after.starts_with([' ', '\t', ':']) {
+            commands.push(after);
+        }
+    }
+    commands
+}
+
+/// Check if line is something CMD runs, not a label, comment or blank line
+pub fn is_statement(line: &str) -> bool {
+    !is_comment(line) && !line.trim().starts_with(':')
+}
+
+/// Split CALL arguments the way CMD fills %1..%9: spaces, tabs, commas,
+/// semicolons and `=` separate arguments outside quotes, and quotes are
+/// kept so `%~1` can remove them. Outside quotes `^` makes the next
+/// character literal (`a^ b` is one argument); backslashes are never
+/// escapes, so `"C:\dir\"` stays as written.
+pub fn split_batch_arguments(text: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.chars();
+
+    while let Some(ch) = chars.next() {
+        if ch == '"' {
+            in_quotes = !in_quotes;
+            current.push(ch);
+        } else if ch == '^' && !in_quotes {
+            if let Some(next) = chars.next() {
+                current.push(next);
+            }
+        } else if !in_quotes && matches!(ch, ' ' | '\t' | ',' | ';' | '=') {
+            if !current.is_empty() {
+                args.push(std::mem::take(&mut current));
+            }
+        } else {
+            current.push(ch);
+        }
+    }
+    if !current.is_empty() {
+        args.push(current);
+    }
+    args
+}
+
+/// Extract the command name from a command line: the first word without a
+/// leading `@`. Built-ins may be glued to their arguments (`echo.`, `dir/s`,
+/// `echo(`), so those end at the special character; other names keep it
+/// (`cl.exe`, `C:/tools/app`).
+pub fn command_name(cmd: &str) -> &str {
+    let cmd = cmd.trim_start().trim_start_matches('@');
+    // A quoted program path may hold spaces: `"C:\Program Files\x.exe" /q`
+    if let Some(quoted) = cmd.strip_prefix('"') {
+        return match quoted.find('"') {
+            Some(close) => &cmd[..close + 2],
+            None => cmd,
+        };
+    }
+    let word_end = cmd.find(char::is_whitespace).unwrap_or(cmd.len());
+    let word = &cmd[..word_end];
+
+    for (i, c) in word.char_indices() {
+        if matches!(c, '.' | '(' | '/' | ',' | ';' | '=') && is_builtin_name(&word[..i]) {
+            return &word[..i];
+        }
+    }
+    word
+}
+
+/// Check if a command is a CMD built-in command
+pub fn is_builtin_command(cmd: &str) -> bool {
+    is_builtin_name(command_name(cmd))
+}
+
+/// CMD's built-in commands
+pub const BUILTIN_COMMANDS: &[&str] = &[
+    "ASSOC", "BREAK", "CALL", "CD", "CHDIR", "CLS", "COLOR", "COPY", "DATE", "DEL", "DIR", "ECHO",
+    "ENDLOCAL", "ERASE", "EXIT", "FOR", "FTYPE", "GOTO", "IF", "MD", "MKDIR", "MKLINK", "MOVE",
+    "PATH", "PAUSE", "POPD", "PROMPT", "PUSHD", "RD", "REM", "REN", "RENAME", "RMDIR", "SET",
+    "SETLOCAL", "SHIFT", "START", "TIME", "TITLE", "TYPE", "VER", "VERIFY", "VOL",
+];
+
+fn is_builtin_name(name: &str) -> bool {
+    BUILTIN_COMMANDS
+        .iter()
+        .any(|builtin| builtin.eq_ignore_ascii_case(name))
+}
+
 /// Represents a redirection operator and its target
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Redirection {
     pub operator: String, // ">", ">>", "<", "2>", "2>&1", "|"
     pub target: String,   // filename or empty for pipes
@@ -104,6 +239,7 @@ pub struct Redirection {
 
 /// Represents a command with its redirections
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct CommandWithRedirections {
     pub base_command: String,
     pub redirections: Vec<Redirection>,
@@ -312,8 +448,208 @@ pub fn parse_redirections(line: &str) -> CommandWithRedirections {
     }
 }
 
+/// A command that would wait for keyboard input, which the debugger's CMD
+/// session doesn't have
+#[derive(Debug, Clone, PartialEq)]
+pub enum InteractivePrompt {
+    /// PAUSE
+    Pause,
+    /// SET /P variable=prompt
+    SetPrompt { variable: String, prompt: String },
+    /// CHOICE with the keys it offers (/C, YN by default) and its /D default
+    Choice {
+        choices: String,
+        default: Option<char>,
+    },
+}
+
+/// Classify a command that would block on keyboard input. Commands reading
+/// from a file or pipe (`set /p X=<file`) don't block and aren't reported.
+pub fn parse_interactive_prompt(line: &str) -> Option<InteractivePrompt> {
+    let parsed = parse_redirections(line);
+    if parsed.redirections.iter().any(|r| r.operator == "<") {
+        return None;
+    }
+    let base = parsed.base_command.trim_start_matches('@').trim();
+    let upper = base.to_uppercase();
+
+    if upper == "PAUSE" {
+        return Some(InteractivePrompt::Pause);
+    }
+
+    if upper.starts_with("SET ") && upper[4..].trim_start().starts_with("/P") {
+        let rest = base[4..].trim_start()[2..].trim();
+        let rest = rest
+            .strip_prefix('"')
+            .map(|r| r.strip_suffix('"').unwrap_or(r))
+            .unwrap_or(rest);
+        let (variable, prompt) = rest.split_once('=')?;
+        return Some(InteractivePrompt::SetPrompt {
+            variable: variable.trim().to_string(),
+            prompt: prompt.to_string(),
+        });
+    }
+
+    let name = command_name(base).to_uppercase();
+    if name == "CHOICE" || name == "CHOICE.EXE" {
+        let mut choices = "YN".to_string();
+        let mut default = None;
+        let mut tokens = base.split_whitespace().skip(1);
+        while let Some(token) = tokens.next() {
+            if !token.starts_with('/') || token.len() < 2 {
+                continue;
+            }
+            let flag = token[1..2].to_uppercase();
+            // Values may follow the switch directly, after a colon, or as the next word
+            let mut value = token[2..].trim_start_matches(':').to_string();
+            if value.is_empty() && matches!(flag.as_str(), "C" | "D" | "T" | "M") {
+                value = tokens.next().unwrap_or("").to_string();
+            }
+            match flag.as_str() {
+                "C" if !token[1..].to_uppercase().starts_with("CS") => choices = value,
+                "D" => default = value.chars().next(),
+                _ => {}
+            }
+        }
+        return Some(InteractivePrompt::Choice { choices, default });
+    }
+
+    None
+}
+
+/// A START command line, split into the switches the debugger acts on and
+/// the command it launches
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StartCommand {
+    pub title: Option<String>,     // First quoted argument, CMD's window title
+    pub directory: Option<String>, // /D path
+    pub wait: bool,                // /WAIT
+    pub background: bool,          // /B, no new window
+    pub command: String,           // Program and its arguments, as written
+}
+
+/// The next argument of a command line, with its quotes, and the rest
+fn take_argument(text: &str) -> (&str, &str) {
+    let end = if let Some(quoted) = text.strip_prefix('"') {
+        quoted.find('"').map(|i| i + 2).unwrap_or(text.len())
+    } else {
+        text.find(char::is_whitespace).unwrap_or(text.len())
+    };
+    (&text[..end], text[end..].trim_start())
+}
+
+/// Parse a START line. The first quoted argument is always the title, so
+/// `start "C:\app.exe"` starts a titled shell rather than the program.
+pub fn parse_start_command(line: &str) -> Option<StartCommand> {
+    let base = line.trim().trim_start_matches('@').trim_start();
+    if !command_name(base).eq_ignore_ascii_case("START") {
+        return None;
+    }
+    let mut rest = base[5..].trim_start();
+    let mut start = StartCommand::default();
+
+    loop {
+        if rest.starts_with('"') && start.title.is_none() {
+            let (title, after) = take_argument(rest);
+            start.title = Some(title.trim_matches('"').to_string());
+            rest = after;
+        } else if rest.starts_with('/') {
+            let (switch, after) = take_argument(rest);
+            rest = after;
+            let name = switch[1..].to_uppercase();
+            match name.as_str() {
+                "WAIT" => start.wait = true,
+                "B" => start.background = true,
+                // Switches whose value is the next argument
+                "D" | "NODE" | "AFFINITY" => {
+                    let (value, after) = take_argument(rest);
+                    if name == "D" {
+                        start.directory = Some(value.trim_matches('"').to_string());
+                    }
+                    rest = after;
+                }
+                _ if name.starts_with('D') => {
+                    start.directory = Some(switch[2..].trim_matches('"').to_string());
+                }
+                // /MIN, /MAX, priorities and the like only affect the window
+                _ => {}
+            }
+        } else {
+            break;
+        }
+    }
+    start.command = rest.trim_end().to_string();
+    Some(start)
+}
+
+/// A line that only waits: TIMEOUT, WAITFOR with a timeout, or the
+/// `ping -n N localhost >nul` sleep idiom
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Delay {
+    pub seconds: u64,
+    pub exit_code: i32, // ERRORLEVEL once the wait has run its course
+}
+
+/// Recognize a line that does nothing but wait. Pings of other hosts, or
+/// whose output is kept, are real connectivity checks and not delays.
+pub fn parse_delay(line: &str) -> Option<Delay> {
+    let parsed = parse_redirections(line.trim().trim_start_matches('@'));
+    let to_nul = |r: &Redirection| r.target.eq_ignore_ascii_case("nul");
+    let discards_output = parsed
+        .redirections
+        .iter()
+        .any(|r| matches!(r.operator.as_str(), ">" | ">>") && to_nul(r))
+        && parsed.redirections.iter().all(|r| {
+            r.operator == "2>&1" || (matches!(r.operator.as_str(), ">" | ">>" | "2>") && to_nul(r))
+        });
+    if parsed.base_command.contains(['&', '|']) {
+        return None;
+    }
+    let words: Vec<String> = parsed
+        .base_command
+        .split_whitespace()
+        .map(str::to_uppercase)
+        .collect();
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+
+    match words.as_slice() {
+        // TIMEOUT [/T] N [/NOBREAK]; -1 waits for a key, which is no delay
+        ["TIMEOUT", rest @ ..] => {
+            let rest: Vec<&str> = rest.iter().copied().filter(|w| *w != "/NOBREAK").collect();
+            let seconds = match rest.as_slice() {
+                ["/T", n] | [n] => n.parse().ok()?,
+                _ => return None,
+            };
+            Some(Delay {
+                seconds,
+                exit_code: 0,
+            })
+        }
+        // Nothing signals a waiting script under the debugger, so WAITFOR
+        // runs out its time and fails
+        ["WAITFOR", rest @ ..] if !rest.contains(&"/SI") => {
+            let at = rest.iter().position(|w| *w == "/T")?;
+            let seconds = rest.get(at + 1)?.parse().ok()?;
+            Some(Delay {
+                seconds,
+                exit_code: 1,
+            })
+        }
+        // N echo requests a second apart take N-1 seconds
+        ["PING", "-N", n, "LOCALHOST" | "127.0.0.1"] if discards_output => {
+            let count: u64 = n.parse().ok()?;
+            Some(Delay {
+                seconds: count.saturating_sub(1),
+                exit_code: 0,
+            })
+        }
+        _ => None,
+    }
+}
+
 /// Represents different types of IF conditions
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum IfCondition {
     /// IF [NOT] ERRORLEVEL number
     ErrorLevel { not: bool, level: i32 },
@@ -338,6 +674,7 @@ pub enum IfCondition {
 
 /// Represents an IF statement with its condition and branches
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct IfStatement {
     pub condition: IfCondition,
     pub then_command: String,
@@ -346,6 +683,138 @@ pub struct IfStatement {
 
 /// Parse an IF statement and extract its condition and branches
 pub fn parse_if_statement(line: &str) -> Option<IfStatement> {
+    let mut stmt = parse_if_condition(line)?;
+    let (then_command, else_command) = split_if_branches(&stmt.then_command)?;
+    stmt.then_command = then_command;
+    stmt.else_command = else_command;
+    Some(stmt)
+}
+
+/// What is wrong with a breakpoint condition, None when it parses. A
+/// condition is one IF would take (`X GEQ 5`, `"%A%"=="b"`, `DEFINED X`, ...)
+/// or a single expression. Variables that don't exist yet are fine.
+pub fn condition_error(condition: &str) -> Option<String> {
+    let condition = condition.trim();
+    if condition.is_empty() {
+        return Some("The condition is empty".to_string());
+    }
+    if !condition.matches('"').count().is_multiple_of(2) {
+        return Some("Unbalanced quotes".to_string());
+    }
+    if !condition
+        .replace("%%", "")
+        .matches('%')
+        .count()
+        .is_multiple_of(2)
+    {
+        return Some("A % has no closing %".to_string());
+    }
+    match parse_if_condition(&format!("IF {} REM", condition)) {
+        Some(stmt) if stmt.then_command.eq_ignore_ascii_case("REM") => match &stmt.condition {
+            IfCondition::Compare { left, right, .. }
+            | IfCondition::StringEqual { left, right, .. }
+                if left.is_empty() || right.is_empty() =>
+            {
+                Some("A comparison needs a value on both sides".to_string())
+            }
+            _ => None,
+        },
+        Some(stmt) => {
+            let extra = stmt.then_command.trim_end_matches("REM").trim_end();
+            Some(format!("Unexpected text after the condition: {}", extra))
+        }
+        // One word (outside quotes) is an expression
+        None if !has_unquoted_whitespace(condition) => None,
+        None => Some(
+            "Not a condition: compare with EQU, NEQ, LSS, LEQ, GTR, GEQ or ==, \
+             or test DEFINED, EXIST or ERRORLEVEL"
+                .to_string(),
+        ),
+    }
+}
+
+fn has_unquoted_whitespace(text: &str) -> bool {
+    let mut in_quotes = false;
+    text.chars().any(|c| {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        }
+        !in_quotes && c.is_whitespace()
+    })
+}
+
+/// Split the text after an IF condition into its THEN and ELSE commands.
+/// Parenthesized branches lose their parentheses; ELSE only counts after a
+/// parenthesized THEN, as in CMD. None when a block is left open for later
+/// lines.
+fn split_if_branches(command: &str) -> Option<(String, Option<String>)> {
+    let command = command.trim();
+    if !command.starts_with('(') {
+        return Some((command.to_string(), None));
+    }
+    let close = matching_paren(command)?;
+    let then_command = command[1..close].trim().to_string();
+    let rest = command[close + 1..].trim_start();
+
+    let is_else = rest
+        .get(..4)
+        .is_some_and(|word| word.eq_ignore_ascii_case("ELSE"))
+        && rest[4..].starts_with([' ', '\t', '(']);
+    if !is_else {
+        return Some((then_command, None));
+    }
+    let else_text = rest[4..].trim();
+    let else_command = if else_text.starts_with('(') {
+        let close = matching_paren(else_text)?;
+        else_text[1..close].trim().to_string()
+    } else {
+        else_text.to_string()
+    };
+    Some((then_command, Some(else_command)))
+}
+
+/// The commands inside `text` when it is one ( group and nothing else, as
+/// a multi-line block joined to a line is
+pub fn group_body(text: &str) -> Option<&str> {
+    let text = text.trim();
+    if !text.starts_with('(') {
+        return None;
+    }
+    let close = matching_paren(text)?;
+    (close == text.len() - 1).then(|| text[1..close].trim())
+}
+
+/// Byte index of the `)` closing the `(` that `text` starts with, skipping
+/// quoted and ^-escaped parentheses
+fn matching_paren(text: &str) -> Option<usize> {
+    let mut depth = 0i32;
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for (i, ch) in text.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match ch {
+            '^' => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '(' if !in_quotes => depth += 1,
+            ')' if !in_quotes => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parse the condition of an IF statement; everything after it ends up in
+/// `then_command`
+fn parse_if_condition(line: &str) -> Option<IfStatement> {
     let trimmed = line.trim();
     let upper = trimmed.to_uppercase();
 
@@ -485,6 +954,7 @@ fn find_command_start(text: &str) -> Option<usize> {
 
 /// Represents different types of FOR loop variants
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ForLoopType {
     /// FOR %%i IN (item1 item2 item3) DO command
     Basic {
@@ -524,6 +994,7 @@ pub enum ForLoopType {
 
 /// Represents the source for FOR /F parsing
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ForFileSource {
     File(String),    // File path
     Command(String), // Command in single quotes
@@ -532,10 +1003,37 @@ pub enum ForFileSource {
 
 /// Represents a parsed FOR loop statement
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ForStatement {
     pub loop_type: ForLoopType,
 }
 
+/// What a line of a script parses to, for tools that want its structure
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "lowercase"))]
+pub enum Statement {
+    If(IfStatement),
+    For(ForStatement),
+    Command(CommandWithRedirections),
+}
+
+/// Parse a logical line into the statement it holds. None for labels,
+/// comments and blank lines, which hold none.
+pub fn parse_statement(line: &str) -> Option<Statement> {
+    if !is_statement(line) {
+        return None;
+    }
+    let line = line.trim().trim_start_matches('@');
+    if let Some(stmt) = parse_if_statement(line) {
+        return Some(Statement::If(stmt));
+    }
+    if let Some(stmt) = parse_for_statement(line) {
+        return Some(Statement::For(stmt));
+    }
+    Some(Statement::Command(parse_redirections(line)))
+}
+
 /// Parse a FOR loop statement
 pub fn parse_for_statement(line: &str) -> Option<ForStatement> {
     let trimmed = line.trim();
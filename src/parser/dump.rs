@@ -0,0 +1,23 @@
+//! `--dump-ast script.bat`: what the parser makes of a script, as JSON
+
+use super::{build_label_map, parse_statement, preprocess_lines};
+use serde_json::{json, Value};
+
+/// The logical lines of a script, each with the statement it parses to
+/// (null for labels, comments and blank lines), and its labels
+pub fn dump_ast(physical: &[&str]) -> Value {
+    let pre = preprocess_lines(physical);
+    let lines: Vec<Value> = pre
+        .logical
+        .iter()
+        .map(|line| {
+            let mut entry = json!(line);
+            entry["statement"] = json!(parse_statement(&line.text));
+            entry
+        })
+        .collect();
+    json!({
+        "lines": lines,
+        "labels": build_label_map(physical),
+    })
+}
@@ -1,15 +1,135 @@
+use super::PreprocessResult;
 use std::collections::HashMap;
 
+/// The label a `:label` line defines, lowercased
+pub fn label_name(line: &str) -> Option<String> {
+    let t = line.trim();
+    if t.starts_with(':') && t.len() > 1 {
+        let label_text = &t[1..];
+        let label_name = label_text.split_whitespace().next().unwrap_or(label_text);
+        Some(label_name.trim().to_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Labels of a script, lowercased, with the 0-based physical line of the
+/// last definition of each
+pub type LabelMap = HashMap<String, usize>;
+
 /// Scan labels (case-insensitive)
-pub fn build_label_map(lines: &[&str]) -> HashMap<String, usize> {
+pub fn build_label_map(lines: &[&str]) -> LabelMap {
     let mut map = HashMap::new();
     for (i, line) in lines.iter().enumerate() {
-        let t = line.trim();
-        if t.starts_with(':') && t.len() > 1 {
-            let label_text = &t[1..];
-            let label_name = label_text.split_whitespace().next().unwrap_or(label_text);
-            map.insert(label_name.trim().to_lowercase(), i);
+        if let Some(name) = label_name(line) {
+            map.insert(name, i);
         }
     }
     map
 }
+
+/// The label a GOTO line jumps to, lowercased and without its colon:
+/// `goto done`, `GOTO :done` and `goto:eof` all work
+pub fn goto_target(line: &str) -> Option<String> {
+    let t = line.trim_start();
+    if !t
+        .get(..4)
+        .is_some_and(|verb| verb.eq_ignore_ascii_case("GOTO"))
+    {
+        return None;
+    }
+    let rest = &t[4..];
+    if !rest.starts_with([' ', '\t', ':']) {
+        return None;
+    }
+    let label = rest.trim_start().trim_start_matches(':');
+    Some(label.split_whitespace().next().unwrap_or("").to_lowercase())
+}
+
+/// An EXIT line as whether it is EXIT /B and the exit code text after it:
+/// `exit 2`, `EXIT /B 1` and `exit/b` all work
+pub fn exit_command(line: &str) -> Option<(bool, &str)> {
+    let t = line.trim_start();
+    if !t.get(..4).is_some_and(|verb| verb.eq_ignore_ascii_case("EXIT")) {
+        return None;
+    }
+    let rest = &t[4..];
+    if !rest.is_empty() && !rest.starts_with([' ', '\t', '/']) {
+        return None;
+    }
+    let rest = rest.trim_start();
+    match rest.get(..2) {
+        Some(switch) if switch.eq_ignore_ascii_case("/B") => Some((true, &rest[2..])),
+        _ => Some((false, rest)),
+    }
+}
+
+/// The logical line `label` is defined on as seen from logical line `from`.
+/// Like CMD, the search starts below `from` and wraps around to the top, so
+/// a label defined twice resolves to the next one down.
+pub fn find_label(pre: &PreprocessResult, label: &str, from: usize) -> Option<usize> {
+    let len = pre.logical.len();
+    let start = (from + 1).min(len);
+    (start..len)
+        .chain(0..start)
+        .find(|&i| label_name(&pre.logical[i].text).is_some_and(|name| name == label))
+}
+
+/// The label a `CALL :label` line calls, lowercased
+pub fn call_target(line: &str) -> Option<String> {
+    let t = line.trim_start();
+    if !t
+        .get(..4)
+        .is_some_and(|verb| verb.eq_ignore_ascii_case("CALL"))
+    {
+        return None;
+    }
+    let rest = t[4..].trim_start();
+    if !t[4..].starts_with([' ', '\t']) || !rest.starts_with(':') {
+        return None;
+    }
+    let label = rest.trim_start_matches(':');
+    Some(label.split_whitespace().next().unwrap_or("").to_lowercase())
+}
+
+/// Jumps to labels the script doesn't define, and labels defined twice, as
+/// (logical line, message). Targets built from variables are left alone;
+/// they can only be checked when the line runs.
+pub fn label_warnings(pre: &PreprocessResult) -> Vec<(usize, String)> {
+    let mut warnings = Vec::new();
+    let mut defined: HashMap<String, usize> = HashMap::new();
+    for (i, logical) in pre.logical.iter().enumerate() {
+        let text = &logical.text;
+        if let Some(name) = label_name(text) {
+            match defined.get(&name) {
+                Some(&first) => warnings.push((
+                    i,
+                    format!(
+                        "Label :{} is defined again; line {} defines it first",
+                        name,
+                        pre.logical[first].phys_start + 1
+                    ),
+                )),
+                None => {
+                    defined.insert(name, i);
+                }
+            }
+            continue;
+        }
+        let (verb, target) = match (goto_target(text), call_target(text)) {
+            (Some(target), _) => ("GOTO", target),
+            (None, Some(target)) => ("CALL", target),
+            (None, None) => continue,
+        };
+        if target.is_empty() || target == "eof" || target.contains(['%', '!']) {
+            continue;
+        }
+        if find_label(pre, &target, i).is_none() {
+            warnings.push((
+                i,
+                format!("{} :{} jumps to a label that doesn't exist", verb, target),
+            ));
+        }
+    }
+    warnings
+}
@@ -0,0 +1,392 @@
+//! What a read of a script finds wrong without running it, for `--lint`
+//! and the console at launch. Each rule looks at the logical lines only;
+//! nothing is expanded or run.
+
+use super::arithmetic::{arithmetic_assignments, arithmetic_error};
+use super::commands::commands_named;
+use super::labels::{call_target, find_label, goto_target, label_name};
+use super::{is_comment, is_statement, PreprocessResult};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Variables CMD provides without the script setting them: the dynamic
+/// ones and those every Windows environment has
+const KNOWN_VARIABLES: &[&str] = &[
+    "CD",
+    "DATE",
+    "TIME",
+    "RANDOM",
+    "ERRORLEVEL",
+    "CMDEXTVERSION",
+    "CMDCMDLINE",
+    "HIGHESTNUMANODENUMBER",
+    "__CD__",
+    "__APPDIR__",
+    "ALLUSERSPROFILE",
+    "APPDATA",
+    "COMMONPROGRAMFILES",
+    "COMMONPROGRAMFILES(X86)",
+    "COMPUTERNAME",
+    "COMSPEC",
+    "HOMEDRIVE",
+    "HOMEPATH",
+    "LOCALAPPDATA",
+    "LOGONSERVER",
+    "NUMBER_OF_PROCESSORS",
+    "OS",
+    "PATH",
+    "PATHEXT",
+    "PROCESSOR_ARCHITECTURE",
+    "PROCESSOR_IDENTIFIER",
+    "PROCESSOR_LEVEL",
+    "PROCESSOR_REVISION",
+    "PROGRAMDATA",
+    "PROGRAMFILES",
+    "PROGRAMFILES(X86)",
+    "PROGRAMW6432",
+    "PROMPT",
+    "PUBLIC",
+    "SYSTEMDRIVE",
+    "SYSTEMROOT",
+    "TEMP",
+    "TMP",
+    "USERDOMAIN",
+    "USERNAME",
+    "USERPROFILE",
+    "WINDIR",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,   // CMD will fail on the line
+    Warning, // The line runs, likely not as meant
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub line: usize, // 1-based physical line
+    pub severity: Severity,
+    pub code: &'static str, // "unknown-label", "duplicate-label", ...
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}[{}]: {}",
+            self.line, self.severity, self.code, self.message
+        )
+    }
+}
+
+/// Everything the rules find in `pre`, in line order
+pub fn lint_script(pre: &PreprocessResult) -> Vec<Diagnostic> {
+    let mut found = Vec::new();
+    label_rules(pre, &mut found);
+    block_comments(pre, &mut found);
+    undefined_variables(pre, &mut found);
+    arithmetic(pre, &mut found);
+    stray_else(pre, &mut found);
+
+    let mut diagnostics: Vec<Diagnostic> = found
+        .into_iter()
+        .map(|(logical, severity, code, message)| Diagnostic {
+            line: pre.logical[logical].phys_start + 1,
+            severity,
+            code,
+            message,
+        })
+        .collect();
+    diagnostics.sort_by_key(|d| d.line);
+    diagnostics
+}
+
+/// What a rule found: logical line, severity, code and message
+type Found = Vec<(usize, Severity, &'static str, String)>;
+
+/// Jumps to labels the script doesn't define, with the nearest one it
+/// does, and labels defined twice. `::` comments aren't labels.
+fn label_rules(pre: &PreprocessResult, found: &mut Found) {
+    let labels: Vec<String> = pre
+        .logical
+        .iter()
+        .filter_map(|l| label_name(&l.text))
+        .filter(|name| !name.starts_with(':'))
+        .collect();
+    let mut defined: HashMap<String, usize> = HashMap::new();
+    for (i, logical) in pre.logical.iter().enumerate() {
+        let text = logical.text.trim_start().trim_start_matches('@');
+        if let Some(name) = label_name(text).filter(|name| !name.starts_with(':')) {
+            match defined.get(&name) {
+                Some(&first) => found.push((
+                    i,
+                    Severity::Warning,
+                    "duplicate-label",
+                    format!(
+                        "Label :{} is defined again; line {} defines it first, and GOTO \
+                         from above this line only reaches that one",
+                        name,
+                        pre.logical[first].phys_start + 1
+                    ),
+                )),
+                None => {
+                    defined.insert(name, i);
+                }
+            }
+            continue;
+        }
+        let (verb, target) = match (goto_target(text), call_target(text)) {
+            (Some(target), _) => ("GOTO", target),
+            (None, Some(target)) => ("CALL", target),
+            (None, None) => continue,
+        };
+        if target.is_empty() || target == "eof" || target.contains(['%', '!']) {
+            continue;
+        }
+        if find_label(pre, &target, i).is_some() {
+            continue;
+        }
+        let mut message = format!("{} :{} jumps to a label that doesn't exist", verb, target);
+        if let Some(nearest) = closest(&target, &labels) {
+            message.push_str(&format!("; did you mean :{}?", nearest));
+        }
+        found.push((i, Severity::Error, "unknown-label", message));
+    }
+}
+
+/// The label closest to `name` by edit distance, if one is close enough
+/// to be a typo of it
+fn closest<'a>(name: &str, labels: &'a [String]) -> Option<&'a str> {
+    let limit = name.chars().count().div_ceil(3);
+    labels
+        .iter()
+        .map(|label| (edit_distance(name, label), label))
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, label)| label.as_str())
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// `::` comments inside a ( block: CMD reads them as labels, and one on the
+/// block's last line breaks it
+fn block_comments(pre: &PreprocessResult, found: &mut Found) {
+    for (i, logical) in pre.logical.iter().enumerate() {
+        if logical.group_depth > 0 && logical.text.trim_start().starts_with("::") {
+            found.push((
+                i,
+                Severity::Warning,
+                "comment-in-block",
+                ":: inside a ( block is read as a label; use REM".to_string(),
+            ));
+        }
+    }
+}
+
+/// %VAR% references to variables the script never sets and Windows doesn't
+/// provide, at the first reference of each
+fn undefined_variables(pre: &PreprocessResult, found: &mut Found) {
+    let set: HashSet<String> = pre
+        .logical
+        .iter()
+        .flat_map(|l| assigned_names(&l.text))
+        .map(|name| name.to_uppercase())
+        .collect();
+    let mut reported = HashSet::new();
+    for (i, logical) in pre.logical.iter().enumerate() {
+        if !is_statement(&logical.text) {
+            continue;
+        }
+        for name in referenced_names(&logical.text) {
+            let upper = name.to_uppercase();
+            if set.contains(&upper)
+                || KNOWN_VARIABLES.contains(&upper.as_str())
+                || !reported.insert(upper)
+            {
+                continue;
+            }
+            found.push((
+                i,
+                Severity::Warning,
+                "undefined-variable",
+                format!(
+                    "%{}% is never set in the script and isn't a Windows variable",
+                    name
+                ),
+            ));
+        }
+    }
+}
+
+/// Names SET commands on `text` assign, with SET /A's and SET /P's
+fn assigned_names(text: &str) -> Vec<String> {
+    set_commands(text)
+        .into_iter()
+        .flat_map(|(switch, rest)| match switch {
+            Some('A') => arithmetic_assignments(rest),
+            _ => {
+                let rest = rest.trim_start_matches('"');
+                match rest.split_once('=') {
+                    Some((name, _)) if !name.trim().is_empty() => vec![name.trim().to_string()],
+                    _ => Vec::new(),
+                }
+            }
+        })
+        .collect()
+}
+
+/// Each SET on `text`: its /A or /P switch (uppercase) and the text after
+/// the switch
+fn set_commands(text: &str) -> Vec<(Option<char>, &str)> {
+    commands_named(text, "set")
+        .into_iter()
+        .map(|after| {
+            let after = after.trim_start();
+            match after.strip_prefix('/').and_then(|s| s.chars().next()) {
+                Some(switch) => (
+                    Some(switch.to_ascii_uppercase()),
+                    after[1 + switch.len_utf8()..].trim_start(),
+                ),
+                None => (None, after),
+            }
+        })
+        .collect()
+}
+
+/// Names of the %VAR% references in `text`; %%, %1, %~dp0 and %* aren't
+/// variables, and a substring or replacement names the variable before `:`
+fn referenced_names(text: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('%') {
+        let after = &rest[start + 1..];
+        match after.chars().next() {
+            Some('%') => {
+                rest = &after[1..];
+                continue;
+            }
+            Some(c) if c.is_ascii_digit() || c == '~' || c == '*' => {
+                rest = after;
+                continue;
+            }
+            _ => {}
+        }
+        match after.find('%') {
+            Some(end) => {
+                let name = after[..end].split(':').next().unwrap_or("");
+                if name.is_empty() || name.contains(char::is_whitespace) {
+                    rest = &after[end..];
+                } else {
+                    names.push(name.to_string());
+                    rest = &after[end + 1..];
+                }
+            }
+            None => break,
+        }
+    }
+    names
+}
+
+/// SET /A expressions CMD would refuse
+fn arithmetic(pre: &PreprocessResult, found: &mut Found) {
+    for (i, logical) in pre.logical.iter().enumerate() {
+        if is_comment(&logical.text) {
+            continue;
+        }
+        for (switch, expression) in set_commands(&logical.text) {
+            if switch != Some('A') {
+                continue;
+            }
+            // The expression runs to the next command on the line
+            let expression = unquoted_end(expression);
+            if let Some(message) = arithmetic_error(expression) {
+                found.push((
+                    i,
+                    Severity::Error,
+                    "set-arithmetic",
+                    format!("SET /A {}: {}", expression.trim(), message),
+                ));
+            }
+        }
+    }
+}
+
+/// `text` up to the first unquoted, unescaped & or | ending the command,
+/// or a ) closing the block it is in
+fn unquoted_end(text: &str) -> &str {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '^' if !in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '(' if !in_quotes => depth += 1,
+            ')' if !in_quotes && depth > 0 => depth -= 1,
+            '&' | '|' | ')' if !in_quotes => return &text[..i],
+            _ => {}
+        }
+    }
+    text
+}
+
+/// ELSE that doesn't follow the `)` of an IF's block on the same line
+fn stray_else(pre: &PreprocessResult, found: &mut Found) {
+    let mut blocks: Vec<bool> = Vec::new(); // Open ( blocks, true for an IF's
+    for (i, logical) in pre.logical.iter().enumerate() {
+        if !is_statement(&logical.text) {
+            continue;
+        }
+        let mut text = logical.text.trim_start().trim_start_matches('@');
+        let mut closed = None;
+        while let Some(rest) = text.strip_prefix(')') {
+            closed = Some(blocks.pop().unwrap_or(false));
+            text = rest.trim_start();
+        }
+        if let Some(rest) = strip_word(text, "ELSE") {
+            let message = match closed {
+                None => Some("ELSE must be on the line with the ) closing the IF's block"),
+                Some(false) => Some("ELSE follows the ) of a block that isn't an IF's"),
+                Some(true) => None,
+            };
+            if let Some(message) = message {
+                found.push((i, Severity::Error, "else-without-if", message.to_string()));
+            }
+            text = rest.trim_start();
+        }
+        if logical.opens_block {
+            blocks.push(strip_word(text, "IF").is_some());
+        }
+    }
+}
+
+/// `text` after its first word when that is `word`, in any case
+fn strip_word<'a>(text: &'a str, word: &str) -> Option<&'a str> {
+    let head = text.get(..word.len())?;
+    let rest = &text[word.len()..];
+    (head.eq_ignore_ascii_case(word) && (rest.is_empty() || rest.starts_with([' ', '\t', '('])))
+        .then_some(rest)
+}
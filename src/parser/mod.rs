@@ -1,13 +1,27 @@
+mod arithmetic;
+mod callgraph;
 mod commands;
+#[cfg(feature = "serde")]
+mod dump;
 mod labels;
+mod lint;
 mod preprocessor;
 mod types;
 
+pub use callgraph::{call_graph, CallGraph, EdgeKind, NodeKind};
 pub use commands::{
-    is_comment, normalize_whitespace, parse_for_statement, parse_if_statement, parse_redirections,
-    split_composite_command, CommandOp, CommandWithRedirections, ForFileSource, ForLoopType,
-    ForStatement, IfCondition, IfStatement, Redirection,
+    command_name, condition_error, group_body, is_builtin_command, is_comment, is_statement,
+    normalize_whitespace, parse_delay, parse_for_statement, parse_if_statement,
+    parse_interactive_prompt, parse_redirections, parse_start_command, parse_statement, part_runs,
+    split_batch_arguments, split_composite_command, strip_cd_command, CommandOp, CommandPart,
+    CommandWithRedirections, Delay, ForFileSource, ForLoopType, ForStatement, IfCondition,
+    IfStatement, InteractivePrompt, Redirection, StartCommand, Statement, BUILTIN_COMMANDS,
+};
+#[cfg(feature = "serde")]
+pub use dump::dump_ast;
+pub use labels::{build_label_map, exit_command, find_label, goto_target, LabelMap};
+pub use lint::{lint_script, Diagnostic, Severity};
+pub use preprocessor::{
+    block_end, block_start, breakpoint_line, join_block, preprocess_lines, script_warnings,
 };
-pub use labels::build_label_map;
-pub use preprocessor::preprocess_lines;
 pub use types::{LogicalLine, PreprocessResult};
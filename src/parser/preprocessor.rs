@@ -1,3 +1,5 @@
+use super::commands::is_statement;
+use super::labels::label_warnings;
 use super::types::{JoinedLine, LogicalLine, PreprocessResult};
 
 /// Join physical lines that are continued with a trailing caret `^`.
@@ -82,6 +84,7 @@ pub fn annotate_blocks(joined: Vec<JoinedLine>) -> Vec<LogicalLine> {
 
         let mut chars = j.text.chars().peekable();
         let mut escaped = false;
+        let mut lowest = depth;
 
         while let Some(ch) = chars.next() {
             if escaped {
@@ -102,6 +105,7 @@ pub fn annotate_blocks(joined: Vec<JoinedLine>) -> Vec<LogicalLine> {
                     if depth > 0 {
                         depth -= 1;
                     }
+                    lowest = lowest.min(depth);
                     let _ = group_id_stack.pop();
                 }
                 _ => {}
@@ -114,6 +118,8 @@ pub fn annotate_blocks(joined: Vec<JoinedLine>) -> Vec<LogicalLine> {
             phys_end: j.phys_end,
             group_id: current_group,
             group_depth: line_depth,
+            opens_block: depth > lowest,
+            closes_block: lowest < line_depth as i32,
         });
     }
 
@@ -137,3 +143,77 @@ pub fn preprocess_lines(physical: &[&str]) -> PreprocessResult {
         phys_to_logical,
     }
 }
+
+/// The last line of the ( block that logical line `start` opens, which CMD
+/// reads and runs together with it. None when `start` opens no block or the
+/// block is never closed.
+pub fn block_end(pre: &PreprocessResult, start: usize) -> Option<usize> {
+    let opener = pre.logical.get(start).filter(|l| l.opens_block)?;
+    (start + 1..pre.logical.len()).find(|&i| {
+        let depth_after = pre.logical.get(i + 1).map_or(0, |next| next.group_depth);
+        pre.logical[i].closes_block && depth_after <= opener.group_depth
+    })
+}
+
+/// What a read of the script finds wrong before it runs, as (logical line,
+/// message) in line order: ( blocks never closed and jumps to labels that
+/// aren't there
+pub fn script_warnings(pre: &PreprocessResult) -> Vec<(usize, String)> {
+    let mut warnings: Vec<(usize, String)> = (0..pre.logical.len())
+        .filter(|&i| is_statement(&pre.logical[i].text))
+        .filter(|&i| pre.logical[i].opens_block && pre.logical[i].group_depth == 0)
+        .filter(|&i| block_end(pre, i).is_none())
+        .map(|i| {
+            (
+                i,
+                "( is never closed; CMD reads to the end of the file for its )".to_string(),
+            )
+        })
+        .collect();
+    warnings.extend(label_warnings(pre));
+    warnings.sort_by_key(|(line, _)| *line);
+    warnings
+}
+
+/// The line opening the outermost ( block that logical line `line` is
+/// inside of, if it is in one
+pub fn block_start(pre: &PreprocessResult, line: usize) -> Option<usize> {
+    if pre.logical.get(line)?.group_depth == 0 {
+        return None;
+    }
+    let start = (0..line).rev().find(|&i| pre.logical[i].group_depth == 0)?;
+    block_end(pre, start)
+        .filter(|&end| end >= line)
+        .map(|_| start)
+}
+
+/// Text of the block from `start` to `end` as one line: lines are joined
+/// with `&` except next to a parenthesis, so `if x (`, `a`, `b`, `)` becomes
+/// `if x ( a & b )`
+pub fn join_block(pre: &PreprocessResult, start: usize, end: usize) -> String {
+    let mut joined = String::new();
+    for line in &pre.logical[start..=end] {
+        let text = line.text.trim();
+        if !is_statement(text) {
+            continue;
+        }
+        if !joined.is_empty() {
+            let separator = if joined.ends_with('(') || text.starts_with(')') {
+                " "
+            } else {
+                " & "
+            };
+            joined.push_str(separator);
+        }
+        joined.push_str(text);
+    }
+    joined
+}
+
+/// The logical line a breakpoint on physical line `phys` binds to: the line
+/// itself, or the next one that runs when it is a label, comment or blank
+/// line, which the debugger never stops on. None past the last statement.
+pub fn breakpoint_line(pre: &PreprocessResult, phys: usize) -> Option<usize> {
+    let start = *pre.phys_to_logical.get(phys)?;
+    (start..pre.logical.len()).find(|&i| is_statement(&pre.logical[i].text))
+}
@@ -1,3 +1,6 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 /// One physical->logical joined line (before block annotation).
 #[derive(Debug, Clone)]
 pub struct JoinedLine {
@@ -8,16 +11,20 @@ pub struct JoinedLine {
 
 /// Final normalized line with block metadata for the debugger.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LogicalLine {
     pub text: String,
     pub phys_start: usize,
     pub phys_end: usize,
     pub group_id: Option<u32>,
     pub group_depth: u16,
+    pub opens_block: bool,  // Ends inside a ( block it started
+    pub closes_block: bool, // Has the ) of a block an earlier line started
 }
 
 /// Output of preprocessing: logical lines + mapping back to physical indices.
 #[derive(Debug, Clone)] // <-- ADD Clone here
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PreprocessResult {
     pub logical: Vec<LogicalLine>,
     pub phys_to_logical: Vec<usize>,
@@ -0,0 +1,7 @@
+@echo off
+echo Line 1
+echo Line 2
+echo Line 3
+echo Line 4
+echo Line 5
+exit /b 0
@@ -0,0 +1,10 @@
+@echo off
+echo Line 1
+echo Line 2
+call :sub
+echo Line 4
+exit /b 0
+
+:sub
+echo In subroutine
+exit /b 0
@@ -0,0 +1,9 @@
+@echo off
+echo Before call
+call :subroutine
+echo After call
+exit /b 0
+
+:subroutine
+echo In sub
+exit /b 0
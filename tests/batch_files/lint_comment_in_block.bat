@@ -0,0 +1,6 @@
+@echo off
+:: a comment outside any block is fine
+if exist build (
+    :: remove the old build
+    rmdir /s /q build
+)
@@ -0,0 +1,6 @@
+@echo off
+goto next
+:next
+echo first
+:NEXT
+echo second
@@ -0,0 +1,17 @@
+@echo off
+if exist a.txt (
+    echo a
+) else (
+    echo no a
+)
+if exist b.txt (
+    echo b
+)
+else (
+    echo no b
+)
+for %%f in (*.log) do (
+    echo %%f
+) else (
+    echo none
+)
@@ -0,0 +1,9 @@
+@echo off
+set /a A=1
+set /a B=(A+2)*3, C=B %% 4
+for %%n in (1 2) do set /a A+=%%n
+set /a "D=A<<2 | 0x1F"
+set /a E=(A+1
+set /a F=08
+set /a G=A 3
+set /a 5=A
@@ -0,0 +1,8 @@
+@echo off
+set NAME=world
+set /a COUNT=1, TOTAL+=2
+echo Hello %NAME% from %USERNAME% in %CD%
+echo %COUNT% of %TOTAL% in %~dp0 for %1 at 100%%
+echo %OUTDIR%
+echo %OUTDIR:~0,3% %VERSION:.=_%
+for %%f in (*.txt) do echo %%f
@@ -0,0 +1,10 @@
+@echo off
+goto :biuld
+:build
+echo building
+call :pakage
+call :cleanup
+goto :eof
+:package
+echo packaging
+goto :eof
@@ -0,0 +1,5 @@
+@echo off
+set SCRIPT=x.py
+findstr /? >nul
+python x.py --verbose
+echo done
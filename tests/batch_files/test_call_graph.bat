@@ -0,0 +1,12 @@
+@echo off
+goto :first
+:first
+echo first
+goto :second
+:second
+echo second
+call helper.bat
+goto :eof
+:orphan
+echo never reached
+goto :first
@@ -0,0 +1,10 @@
+@echo off
+set TARGET=found
+set NAME=TARGET
+call set RESULT=%%%NAME%%%
+call :sub
+exit /b 0
+
+:sub
+echo in sub
+exit /b 0
@@ -0,0 +1,6 @@
+@echo off
+:start
+if exist out.txt (echo found) else echo missing
+for /f "tokens=1" %%a in ('dir /b') do echo %%a
+type input.txt > out.txt 2>&1
+rem done
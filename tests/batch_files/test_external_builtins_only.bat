@@ -0,0 +1,5 @@
+@echo off
+set A=1
+if "%A%"=="1" echo one
+echo.
+dir >nul
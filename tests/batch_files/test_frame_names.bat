@@ -0,0 +1,11 @@
+@echo off
+call :outer
+exit /b 0
+
+:outer
+call :inner
+exit /b 0
+
+:inner
+echo In inner
+exit /b 0
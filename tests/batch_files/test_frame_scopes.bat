@@ -0,0 +1,15 @@
+@echo off
+call :outer first
+exit /b 0
+
+:outer
+setlocal
+set OUTER_VAR=1
+call :inner second third
+exit /b 0
+
+:inner
+setlocal
+set INNER_VAR=2
+echo In inner
+exit /b 0
@@ -0,0 +1,8 @@
+@echo off
+echo Starting
+set STEP=1
+echo Copying files
+set STEP=2
+echo Still fine
+echo ERROR: disk full
+echo Done
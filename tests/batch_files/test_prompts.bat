@@ -0,0 +1,6 @@
+@echo off
+pause
+set /p NAME=Enter name: 
+choice /c ABC /n
+set PICKED=%errorlevel%
+echo Hello %NAME%
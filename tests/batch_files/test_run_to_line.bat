@@ -0,0 +1,9 @@
+@echo off
+call :sub first
+call :sub second
+exit /b 0
+
+:sub
+echo entering %1
+echo middle of sub
+exit /b 0
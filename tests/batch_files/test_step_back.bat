@@ -0,0 +1,5 @@
+@echo off
+set FIRST=1
+set SECOND=2
+set /a THIRD=10/0
+echo done
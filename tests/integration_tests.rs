@@ -12,6 +12,68 @@ fn cleanup_test_batch(path: &str) {
     let _ = fs::remove_file(path);
 }
 
+// Helper to run a script on the DAP executor thread until it reports a stop
+fn start_dap_executor(
+    ctx: batch_debugger::debugger::DebugContext,
+    pre: &batch_debugger::parser::PreprocessResult,
+    labels: &std::collections::HashMap<String, usize>,
+) -> (
+    std::sync::Arc<std::sync::Mutex<batch_debugger::debugger::DebugContext>>,
+    std::sync::mpsc::Receiver<batch_debugger::executor::StopInfo>,
+    std::thread::JoinHandle<()>,
+) {
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+
+    let ctx_arc = Arc::new(Mutex::new(ctx));
+    let (event_tx, event_rx) = channel();
+    let (output_tx, _output_rx) = channel();
+    let (stderr_tx, _stderr_rx) = channel();
+
+    let exec_ctx = ctx_arc.clone();
+    let exec_pre = pre.clone();
+    let exec_labels = labels.clone();
+    let handle = std::thread::spawn(move || {
+        let _ = batch_debugger::executor::run_debugger_dap(
+            exec_ctx,
+            &exec_pre,
+            &exec_labels,
+            event_tx,
+            output_tx,
+            stderr_tx,
+        );
+    });
+
+    (ctx_arc, event_rx, handle)
+}
+
+// Helper to wait until the executor has parked at `pc`
+fn wait_for_dap_stop(
+    ctx_arc: &std::sync::Arc<std::sync::Mutex<batch_debugger::debugger::DebugContext>>,
+    pc: usize,
+) {
+    loop {
+        {
+            let ctx = ctx_arc.lock().unwrap();
+            if ctx.current_line == Some(pc) && !ctx.continue_requested {
+                return;
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+// Helper to wait until the executor has parked at `pc`, then resume it
+fn resume_dap_executor(
+    ctx_arc: &std::sync::Arc<std::sync::Mutex<batch_debugger::debugger::DebugContext>>,
+    pc: usize,
+) {
+    wait_for_dap_stop(ctx_arc, pc);
+    let mut ctx = ctx_arc.lock().unwrap();
+    ctx.set_mode(batch_debugger::debugger::RunMode::Continue);
+    ctx.request_continue();
+}
+
 #[cfg(test)]
 mod debugger_tests {
     use super::*;
@@ -111,11 +173,10 @@ exit /b 0
 
     #[test]
     fn test_breakpoint_management() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Add breakpoints
         ctx.add_breakpoint(5);
@@ -133,12 +194,11 @@ exit /b 0
 
     #[test]
     fn test_run_modes() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
         use batch_debugger::debugger::RunMode;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Test mode switching
         ctx.set_mode(RunMode::Continue);
@@ -156,11 +216,12 @@ exit /b 0
 
     #[test]
     fn test_variable_tracking() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        // SET /A runs in the shell, which echoes the result
+        let shell = MockShell::new().respond("SET /A COUNTER=1", "1", 0);
+        let mut ctx = DebugContext::new(shell);
 
         // Track simple SET commands
         ctx.track_set_command("SET NAME=Alice");
@@ -206,14 +267,152 @@ exit /b 0
         assert_eq!(call_stack.len(), 1, "Should have 1 frame left");
     }
 
+    #[test]
+    #[cfg(windows)]
+    fn test_stack_trace_frame_names() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::Duration;
+
+        let content = r#"@echo off
+call :outer
+exit /b 0
+
+:outer
+call :inner
+exit /b 0
+
+:inner
+echo In inner
+exit /b 0
+"#;
+
+        let path = create_test_batch(content, "frame_names");
+        let contents = fs::read_to_string(&path).expect("Could not read test file");
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let inner_echo = pre.phys_to_logical[9];
+        ctx.add_breakpoint(inner_echo);
+
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let StopInfo { reason, pc, .. } = events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should stop at the breakpoint");
+        assert_eq!(reason, "breakpoint");
+        assert_eq!(pc, inner_echo);
+
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+        server.set_program(&path, pre.clone());
+
+        let frames = server.collect_stack_frames();
+        let names: Vec<&str> = frames.iter().map(|f| f["name"].as_str().unwrap()).collect();
+        let lines: Vec<u64> = frames.iter().map(|f| f["line"].as_u64().unwrap()).collect();
+        assert_eq!(names, vec![":inner", ":outer", "main"]);
+        assert_eq!(lines, vec![10, 6, 2], "Callers should point at their CALL lines");
+
+        resume_dap_executor(&ctx_arc, inner_echo);
+        handle.join().expect("Executor thread panicked");
+
+        cleanup_test_batch(&path);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_scopes_and_variables_per_frame() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::time::Duration;
+
+        let content = r#"@echo off
+call :outer first
+exit /b 0
+
+:outer
+setlocal
+set OUTER_VAR=1
+call :inner second third
+exit /b 0
+
+:inner
+setlocal
+set INNER_VAR=2
+echo In inner
+exit /b 0
+"#;
+
+        let path = create_test_batch(content, "frame_scopes");
+        let contents = fs::read_to_string(&path).expect("Could not read test file");
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let inner_echo = pre.phys_to_logical[13];
+        ctx.add_breakpoint(inner_echo);
+
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should stop at the breakpoint");
+
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+        server.set_program(&path, pre.clone());
+
+        let scope_ref = |scopes: &[serde_json::Value], name: &str| {
+            scopes
+                .iter()
+                .find(|s| s["name"] == name)
+                .and_then(|s| s["variablesReference"].as_u64())
+                .expect("Scope should exist")
+        };
+        let names = |vars: &[serde_json::Value]| -> Vec<String> {
+            vars.iter()
+                .map(|v| v["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        // Frame 1 (innermost, :inner) is call_stack[1]
+        let inner_scopes = server.collect_scopes(Some(2));
+        let inner_locals = server.collect_variables(scope_ref(&inner_scopes, "Local"));
+        assert_eq!(names(&inner_locals), vec!["INNER_VAR"]);
+        let inner_args = server.collect_variables(scope_ref(&inner_scopes, "Arguments"));
+        assert_eq!(names(&inner_args), vec!["%0", "%1", "%2"]);
+        assert_eq!(inner_args[1]["value"], "second");
+
+        // Frame 0 (:outer) is call_stack[0]
+        let outer_scopes = server.collect_scopes(Some(1));
+        let outer_locals = server.collect_variables(scope_ref(&outer_scopes, "Local"));
+        assert_eq!(names(&outer_locals), vec!["OUTER_VAR"]);
+        let outer_args = server.collect_variables(scope_ref(&outer_scopes, "Arguments"));
+        assert_eq!(outer_args[1]["value"], "first");
+
+        // The top-level script only has shared scopes
+        let main_scopes = server.collect_scopes(Some(0));
+        assert!(main_scopes.iter().all(|s| s["name"] != "Local"));
+
+        resume_dap_executor(&ctx_arc, inner_echo);
+        handle.join().expect("Executor thread panicked");
+
+        cleanup_test_batch(&path);
+    }
+
     #[test]
     fn test_setlocal_scope() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
         use batch_debugger::debugger::Frame;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set global variable
         ctx.track_set_command("SET GLOBAL=value1");
@@ -242,38 +441,237 @@ exit /b 0
     }
 
     #[test]
+    fn test_top_level_setlocal_scope() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+
+        let content = "@echo off\r\nset KEEP=1\r\nsetlocal\r\nset TEMPVAR=1\r\nset KEEP=2\r\nendlocal\r\nif defined TEMPVAR (echo leaked) else (echo scoped)\r\nsetlocal\r\nset OPEN=1\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new();
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let (event_tx, _events) = channel();
+        let (output_tx, _output) = channel();
+        let (stderr_tx, _stderr) = channel();
+        batch_debugger::executor::run_debugger_dap(
+            ctx_arc.clone(),
+            &pre,
+            &labels,
+            event_tx,
+            output_tx,
+            stderr_tx,
+        )
+        .expect("Executor failed");
+
+        let commands = commands.lock().unwrap().clone();
+        assert!(
+            commands.iter().any(|c| c == "echo scoped"),
+            "{:?}",
+            commands
+        );
+        assert!(!commands.iter().any(|c| c == "echo leaked"));
+        // CMD ignores SETLOCAL in the session, so ENDLOCAL puts values back
+        assert!(commands.iter().any(|c| c == "SET \"TEMPVAR=\""));
+        assert!(commands.iter().any(|c| c == "SET \"KEEP=1\""));
+
+        // The SETLOCAL left open is ended with the script
+        let ctx = ctx_arc.lock().unwrap();
+        assert_eq!(ctx.root_scope_depth(), 0);
+        let visible = ctx.get_visible_variables();
+        assert_eq!(visible.get("KEEP").map(String::as_str), Some("1"));
+        assert!(!visible.contains_key("OPEN"));
+    }
+
+    #[test]
+    #[cfg(windows)]
     fn test_cmd_session_basic_command() {
         use batch_debugger::debugger::CmdSession;
 
         let mut session = CmdSession::start().expect("Failed to start CMD session");
 
         // Test basic echo command
-        let (output, code) = session
+        let result = session
             .run("echo Hello World")
             .expect("Failed to run command");
         assert!(
-            output.contains("Hello World"),
+            result.stdout.contains("Hello World"),
             "Output should contain 'Hello World'"
         );
-        assert_eq!(code, 0, "Exit code should be 0");
+        assert_eq!(result.exit_code, 0, "Exit code should be 0");
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_cmd_session_separates_stderr() {
+        use batch_debugger::debugger::CmdSession;
+
+        let mut session = CmdSession::start().expect("Failed to start CMD session");
+
+        let result = session
+            .run("dir /b \\nonexistent_batch_debugger_dir")
+            .expect("Failed to run command");
+        assert!(
+            result.stdout.trim().is_empty(),
+            "Errors should not reach stdout"
+        );
+        assert!(
+            !result.stderr.trim().is_empty(),
+            "dir should report an error"
+        );
+        assert_ne!(result.exit_code, 0);
+
+        let result = session.run("echo hi").expect("Failed to run command");
+        assert_eq!(result.stdout.trim(), "hi");
+        assert!(result.stderr.is_empty());
+        assert_eq!(result.exit_code, 0);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_cmd_session_streams_output() {
+        use batch_debugger::debugger::CmdSession;
+        use std::time::{Duration, Instant};
+
+        let mut session = CmdSession::start().expect("Failed to start CMD session");
+
+        let mut chunks: Vec<(Instant, String)> = Vec::new();
+        let result = session
+            .run_streaming(
+                "echo first & ping -n 3 127.0.0.1 >nul & echo second",
+                |chunk| chunks.push((Instant::now(), chunk.to_string())),
+            )
+            .expect("Failed to run command");
+
+        assert_eq!(chunks.len(), 2, "Each echo should arrive as its own chunk");
+        assert_eq!(chunks[0].1.trim(), "first");
+        assert_eq!(chunks[1].1.trim(), "second");
+        assert!(
+            chunks[1].0 - chunks[0].0 >= Duration::from_secs(1),
+            "The first line should arrive before the ping finishes"
+        );
+        assert!(chunks.iter().all(|(_, c)| !c.contains("__CMD_DONE__")));
+        assert!(result.stdout.contains("first") && result.stdout.contains("second"));
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_sentinel_collisions_keep_output_paired() {
+        use batch_debugger::debugger::CmdSession;
+
+        let mut session = CmdSession::start().expect("Failed to start CMD session");
+
+        let cases = [
+            ("echo __CMD_DONE___0_END", "__CMD_DONE___0_END", 0),
+            ("echo __CMD_DONE__ & cmd /c exit 3", "__CMD_DONE__", 3),
+            (
+                "echo __CMD_DONE___1_5_END& echo.& echo after",
+                "__CMD_DONE___1_5_END\n\nafter",
+                0,
+            ),
+            ("echo __CMD_DONE___1_ERR_END 1>&2 & cmd /c exit 4", "", 4),
+            ("echo done", "done", 0),
+        ];
+        for (cmd, expected, code) in cases {
+            let result = session.run(cmd).expect("Failed to run command");
+            assert_eq!(
+                result.stdout.replace('\r', "").trim_end(),
+                expected,
+                "{}",
+                cmd
+            );
+            assert_eq!(result.exit_code, code, "{}", cmd);
+        }
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_huge_output_is_truncated() {
+        use batch_debugger::debugger::{CmdSession, DEFAULT_OUTPUT_LIMIT};
+        use std::time::Duration;
+
+        let mut session = CmdSession::start().expect("Failed to start CMD session");
+        session.set_timeout(Duration::from_secs(300));
+
+        let file = "__huge_output_test.txt";
+        let line = "x".repeat(90);
+        session
+            .run(&format!(
+                "for /L %i in (1,1,220000) do @echo %i {} >> {}",
+                line, file
+            ))
+            .expect("Failed to write the file");
+
+        let mut streamed = 0;
+        let result = session
+            .run_streaming(&format!("type {}", file), |chunk| streamed += chunk.len())
+            .expect("Failed to type the file");
+        let _ = session.run(&format!("del {}", file));
+
+        assert!(result.truncated);
+        assert_eq!(result.exit_code, 0);
+        assert!(result.stdout.len() <= DEFAULT_OUTPUT_LIMIT + 100);
+        assert!(result.stdout.contains(" bytes truncated] ..."));
+        assert!(result.stdout.starts_with("1 x"));
+        assert!(result
+            .stdout
+            .trim_end()
+            .ends_with(&format!("220000 {}", line)));
+        assert_eq!(streamed, result.stdout.len());
+
+        let small = session.run("echo small").expect("Failed to run command");
+        assert!(!small.truncated);
+        assert_eq!(small.stdout.trim(), "small");
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_code_page_decoding() {
+        use batch_debugger::debugger::codepage;
+        use batch_debugger::debugger::CmdSession;
+
+        assert_eq!(codepage::decode(b"Caf\x82", 850), "Café");
+        assert_eq!(codepage::decode(b"Caf\x82", 437), "Café");
+        assert_eq!(codepage::decode(b"Caf\xe9", 1252), "Café");
+        assert_eq!(codepage::encode("Café", 850), b"Caf\x82");
+        assert_eq!(codepage::parse_chcp("Active code page: 850."), Some(850));
+
+        let mut session = CmdSession::start().expect("Failed to start CMD session");
+        session
+            .set_code_page(850)
+            .expect("Failed to switch code page");
+        session
+            .run("set CAFE=Café")
+            .expect("Failed to set variable");
+        let result = session.run("echo %CAFE%").expect("Failed to echo");
+        assert_eq!(result.stdout.trim(), "Café");
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_cmd_session_set_command() {
         use batch_debugger::debugger::CmdSession;
 
         let mut session = CmdSession::start().expect("Failed to start CMD session");
 
         // Set a variable
-        let (_, code) = session
+        let code = session
             .run("set TESTVAR=TestValue")
-            .expect("Failed to set variable");
+            .expect("Failed to set variable")
+            .exit_code;
         assert_eq!(code, 0, "SET command should succeed");
 
         // Echo the variable
-        let (output, _) = session
+        let output = session
             .run("echo %TESTVAR%")
-            .expect("Failed to echo variable");
+            .expect("Failed to echo variable")
+            .stdout;
         assert!(
             output.contains("TestValue"),
             "Should echo the variable value"
@@ -318,6 +716,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_errorlevel_tracking() {
         use batch_debugger::debugger::CmdSession;
         use batch_debugger::debugger::DebugContext;
@@ -329,9 +728,10 @@ exit /b 0
         assert_eq!(ctx.last_exit_code, 0, "Initial ERRORLEVEL should be 0");
 
         // Run a successful command
-        let (_, code) = ctx
+        let code = ctx
             .run_command("echo Success")
-            .expect("Failed to run command");
+            .expect("Failed to run command")
+            .exit_code;
         ctx.last_exit_code = code;
         assert_eq!(
             ctx.last_exit_code, 0,
@@ -339,9 +739,10 @@ exit /b 0
         );
 
         // Run a command that fails
-        let (_, code) = ctx
+        let code = ctx
             .run_command("findstr \"NONEXISTENT\" nonexistent_file.txt 2>nul")
-            .expect("Failed to run command");
+            .expect("Failed to run command")
+            .exit_code;
         ctx.last_exit_code = code;
         assert_ne!(
             ctx.last_exit_code, 0,
@@ -349,28 +750,32 @@ exit /b 0
         );
 
         // Test explicit exit code
-        let (_, code) = ctx
+        let code = ctx
             .run_command("cmd /c exit /b 5")
-            .expect("Failed to run command");
+            .expect("Failed to run command")
+            .exit_code;
         ctx.last_exit_code = code;
         assert_eq!(ctx.last_exit_code, 5, "ERRORLEVEL should be 5");
 
         // Another explicit exit code
-        let (_, code) = ctx
+        let code = ctx
             .run_command("cmd /c exit /b 42")
-            .expect("Failed to run command");
+            .expect("Failed to run command")
+            .exit_code;
         ctx.last_exit_code = code;
         assert_eq!(ctx.last_exit_code, 42, "ERRORLEVEL should be 42");
 
         // Run a command that explicitly returns 0
-        let (_, code) = ctx
+        let code = ctx
             .run_command("cmd /c exit /b 0")
-            .expect("Failed to run command");
+            .expect("Failed to run command")
+            .exit_code;
         ctx.last_exit_code = code;
         assert_eq!(ctx.last_exit_code, 0, "ERRORLEVEL should be back to 0");
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_set_variable() {
         use batch_debugger::debugger::CmdSession;
         use batch_debugger::debugger::DebugContext;
@@ -390,9 +795,10 @@ exit /b 0
         );
 
         // Verify it was set in the CMD session
-        let (output, _) = ctx
+        let output = ctx
             .run_command("echo %TEST_VAR%")
-            .expect("Failed to echo variable");
+            .expect("Failed to echo variable")
+            .stdout;
         assert!(
             output.contains("TestValue"),
             "Variable should be set in CMD session"
@@ -412,9 +818,10 @@ exit /b 0
         ctx.set_variable("SPACE_VAR", "Value With Spaces")
             .expect("Failed to set variable with spaces");
 
-        let (output, _) = ctx
+        let output = ctx
             .run_command("echo %SPACE_VAR%")
-            .expect("Failed to echo variable");
+            .expect("Failed to echo variable")
+            .stdout;
         assert!(
             output.contains("Value With Spaces"),
             "Variable with spaces should work"
@@ -423,12 +830,11 @@ exit /b 0
 
     #[test]
     fn test_set_variable_with_setlocal() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
         use batch_debugger::debugger::Frame;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set a global variable
         ctx.set_variable("GLOBAL_VAR", "GlobalValue")
@@ -473,11 +879,10 @@ exit /b 0
 
     #[test]
     fn test_set_variable_special_characters() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Test with equals sign in value
         ctx.set_variable("VAR_WITH_EQUALS", "key=value")
@@ -502,6 +907,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_set_variable_persistence() {
         use batch_debugger::debugger::CmdSession;
         use batch_debugger::debugger::DebugContext;
@@ -527,9 +933,10 @@ exit /b 0
         assert_eq!(ctx.variables.get("VAR3"), Some(&"Value3".to_string()));
 
         // Verify they're still set in CMD session
-        let (output, _) = ctx
+        let output = ctx
             .run_command("echo %VAR1% %VAR2% %VAR3%")
-            .expect("Failed to echo variables");
+            .expect("Failed to echo variables")
+            .stdout;
         assert!(output.contains("Value1"));
         assert!(output.contains("Value2"));
         assert!(output.contains("Value3"));
@@ -537,11 +944,10 @@ exit /b 0
 
     #[test]
     fn test_evaluate_expression_simple_variables() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set some variables
         ctx.set_variable("NAME", "Alice")
@@ -569,11 +975,10 @@ exit /b 0
 
     #[test]
     fn test_evaluate_expression_errorlevel() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set exit code
         ctx.last_exit_code = 42;
@@ -599,6 +1004,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_evaluate_expression_complex() {
         use batch_debugger::debugger::CmdSession;
         use batch_debugger::debugger::DebugContext;
@@ -630,12 +1036,11 @@ exit /b 0
 
     #[test]
     fn test_evaluate_expression_with_setlocal() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
         use batch_debugger::debugger::Frame;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set global variable
         ctx.set_variable("GLOBAL", "GlobalValue")
@@ -669,6 +1074,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_evaluate_expression_literals() {
         use batch_debugger::debugger::CmdSession;
         use batch_debugger::debugger::DebugContext;
@@ -691,11 +1097,10 @@ exit /b 0
 
     #[test]
     fn test_evaluate_expression_empty_and_whitespace() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Evaluate with leading/trailing whitespace
         ctx.set_variable("VAR", "Value").expect("Failed to set VAR");
@@ -714,12 +1119,11 @@ exit /b 0
 
     #[test]
     fn test_conditional_breakpoint_true() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
         use batch_debugger::debugger::RunMode;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set a variable
         ctx.set_variable("COUNTER", "5")
@@ -737,12 +1141,11 @@ exit /b 0
 
     #[test]
     fn test_conditional_breakpoint_false() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
         use batch_debugger::debugger::RunMode;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set a variable to 0
         ctx.set_variable("COUNTER", "0")
@@ -760,12 +1163,11 @@ exit /b 0
 
     #[test]
     fn test_conditional_breakpoint_expression() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
         use batch_debugger::debugger::RunMode;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set variables
         ctx.set_variable("NAME", "Alice")
@@ -783,12 +1185,11 @@ exit /b 0
 
     #[test]
     fn test_conditional_breakpoint_hit_count() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
         use batch_debugger::debugger::RunMode;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Add unconditional breakpoint
         ctx.add_breakpoint_with_condition(10, None);
@@ -810,80 +1211,6005 @@ exit /b 0
     }
 
     #[test]
-    fn test_unconditional_breakpoint_still_works() {
-        use batch_debugger::debugger::CmdSession;
-        use batch_debugger::debugger::DebugContext;
-        use batch_debugger::debugger::RunMode;
+    #[cfg(windows)]
+    fn test_conditional_breakpoint_evaluated_locally() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
 
         let session = CmdSession::start().expect("Failed to start CMD session");
         let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        ctx.add_breakpoint_with_condition(10, Some("I GEQ 990".to_string()));
+
+        let mut stops = 0;
+        for i in 0..1000 {
+            ctx.track_set_command(&format!("SET I={}", i));
+            let before = ctx.session().command_count();
+            if ctx.should_stop_at(10) {
+                stops += 1;
+            } else {
+                assert_eq!(
+                    ctx.session().command_count(),
+                    before,
+                    "False evaluation at I={} should not hit the session",
+                    i
+                );
+            }
+        }
+        assert_eq!(stops, 10, "Should stop for I=990..999");
+    }
 
-        // Add unconditional breakpoint (no condition)
-        ctx.add_breakpoint(10);
+    #[test]
+    #[cfg(windows)]
+    fn test_output_breakpoint_stops_on_matching_line() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::Duration;
 
-        // Set mode to Continue
+        let content = r#"@echo off
+echo Starting
+set STEP=1
+echo Copying files
+set STEP=2
+echo Still fine
+echo ERROR: disk full
+echo Done
+"#;
+
+        let path = create_test_batch(content, "output_breakpoint");
+        let contents = fs::read_to_string(&path).expect("Could not read test file");
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
         ctx.set_mode(RunMode::Continue);
+        ctx.add_output_breakpoint("ERROR:.*")
+            .expect("Pattern should compile");
+        assert!(ctx.add_output_breakpoint("(unclosed").is_err());
+        assert_eq!(ctx.get_output_breakpoints(), vec!["ERROR:.*"]);
 
-        // Should stop unconditionally
-        assert!(
-            ctx.should_stop_at(10),
-            "Should stop at unconditional breakpoint"
-        );
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let StopInfo { reason, pc, .. } = events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should stop on the matching output");
+        assert_eq!(reason, "output breakpoint");
+        assert_eq!(pre.logical[pc].phys_start + 1, 7);
+
+        resume_dap_executor(&ctx_arc, pc);
+        handle.join().expect("Executor thread panicked");
+
+        cleanup_test_batch(&path);
     }
 
     #[test]
-    fn test_set_a_simple_arithmetic() {
-        use batch_debugger::debugger::CmdSession;
-        use batch_debugger::debugger::DebugContext;
+    #[cfg(windows)]
+    fn test_break_on_external_command() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::Duration;
+
+        let content = r#"@echo off
+set SCRIPT=x.py
+findstr /? >nul
+python x.py --verbose
+echo done
+"#;
+
+        let path = create_test_batch(content, "break_on_external");
+        let contents = fs::read_to_string(&path).expect("Could not read test file");
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
 
         let session = CmdSession::start().expect("Failed to start CMD session");
         let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        ctx.set_break_on_external(true);
+        assert!(ctx.should_break_on_external("python x.py"));
+        assert!(!ctx.should_break_on_external("findstr foo bar.txt"));
+        assert!(!ctx.should_break_on_external("echo hello"));
+
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let StopInfo { reason, pc, .. } = events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should stop before the external command");
+        assert_eq!(reason, "external command");
+        assert_eq!(pre.logical[pc].phys_start + 1, 4);
+
+        resume_dap_executor(&ctx_arc, pc);
+        let StopInfo { reason, .. } = events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should finish");
+        assert_eq!(reason, "terminated");
+        handle.join().expect("Executor thread panicked");
 
-        // Test simple addition
-        ctx.track_set_command("SET /A RESULT=10+20");
-
-        let vars = ctx.get_visible_variables();
-        assert_eq!(vars.get("RESULT"), Some(&"30".to_string()));
+        cleanup_test_batch(&path);
     }
 
     #[test]
-    fn test_set_a_multiplication() {
-        use batch_debugger::debugger::CmdSession;
-        use batch_debugger::debugger::DebugContext;
+    #[cfg(windows)]
+    fn test_break_on_external_ignores_builtins() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::Duration;
+
+        let content = r#"@echo off
+set A=1
+if "%A%"=="1" echo one
+echo.
+dir >nul
+"#;
+
+        let path = create_test_batch(content, "external_builtins_only");
+        let contents = fs::read_to_string(&path).expect("Could not read test file");
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
 
         let session = CmdSession::start().expect("Failed to start CMD session");
         let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        ctx.set_break_on_external(true);
 
-        // Test multiplication
-        ctx.track_set_command("SET /A COUNTER=5");
-        ctx.track_set_command("SET /A RESULT=COUNTER*2");
+        let (_ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let StopInfo { reason, .. } = events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should finish");
+        assert_eq!(reason, "terminated", "Built-ins should never pause");
+        handle.join().expect("Executor thread panicked");
 
-        let vars = ctx.get_visible_variables();
-        assert_eq!(vars.get("COUNTER"), Some(&"5".to_string()));
-        assert_eq!(vars.get("RESULT"), Some(&"10".to_string()));
+        cleanup_test_batch(&path);
     }
 
     #[test]
-    fn test_set_a_complex_expression() {
-        use batch_debugger::debugger::CmdSession;
-        use batch_debugger::debugger::DebugContext;
+    #[cfg(windows)]
+    fn test_command_timeout_and_recover() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, SessionError};
+        use std::time::{Duration, Instant};
 
         let session = CmdSession::start().expect("Failed to start CMD session");
         let mut ctx = DebugContext::new(session);
+        ctx.set_command_timeout(Duration::from_secs(2));
+        ctx.track_set_command("SET KEPT=value");
+        ctx.run_command("SET KEPT=value").unwrap();
+
+        let started = Instant::now();
+        let err = ctx
+            .run_command("timeout /t 300")
+            .expect_err("Command should time out");
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(matches!(
+            SessionError::from_io(&err),
+            Some(SessionError::Timeout { .. })
+        ));
 
-        // Test complex expression with precedence
-        ctx.track_set_command("SET /A BASE=5");
-        ctx.track_set_command("SET /A RESULT=BASE*2+3");
+        ctx.recover_session().expect("Session should restart");
+        let result = ctx.run_command("echo ok").unwrap();
+        assert_eq!(result.stdout.trim(), "ok");
+        assert_eq!(result.exit_code, 0);
 
-        let vars = ctx.get_visible_variables();
-        assert_eq!(vars.get("BASE"), Some(&"5".to_string()));
-        assert_eq!(vars.get("RESULT"), Some(&"13".to_string())); // 5*2=10, 10+3=13
+        // Tracked variables are replayed into the new session
+        let out = ctx.run_command("echo %KEPT%").unwrap().stdout;
+        assert_eq!(out.trim(), "value");
     }
 
     #[test]
-    fn test_set_a_compound_assignment() {
+    #[cfg(windows)]
+    fn test_shutdown_kills_process_tree() {
         use batch_debugger::debugger::CmdSession;
-        use batch_debugger::debugger::DebugContext;
+        use std::process::Command;
+
+        fn powershell(script: &str) -> String {
+            let out = Command::new("powershell")
+                .args(["-NoProfile", "-Command", script])
+                .output()
+                .expect("Failed to run powershell");
+            String::from_utf8_lossy(&out.stdout).trim().to_string()
+        }
+
+        let mut session = CmdSession::start().expect("Failed to start CMD session");
+        session
+            .run("start /b ping -n 60 127.0.0.1 >nul")
+            .expect("Failed to start ping");
+
+        let ping_pid = powershell(&format!(
+            "(Get-CimInstance Win32_Process -Filter \"ParentProcessId={} and Name='PING.EXE'\").ProcessId",
+            session.process_id()
+        ));
+        assert!(
+            !ping_pid.is_empty(),
+            "ping should be running under the session"
+        );
+
+        session.shutdown();
+        assert!(session.is_cancelled());
+        assert!(session.run("echo after").is_err());
+
+        let remaining = powershell(&format!(
+            "Get-Process -Id {} -ErrorAction SilentlyContinue | Select-Object -ExpandProperty Id",
+            ping_pid
+        ));
+        assert!(
+            remaining.is_empty(),
+            "ping should be killed with the session"
+        );
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_session_working_directory() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, SessionOptions};
+
+        let path = create_test_batch("@echo off\r\n", "cwd_probe");
+
+        let session = CmdSession::start_with(SessionOptions {
+            cwd: Some("tests/batch_files".into()),
+            ..SessionOptions::default()
+        })
+        .expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        assert!(ctx.get_current_dir().ends_with("tests/batch_files"));
+
+        let result = ctx
+            .run_command("if exist test_cwd_probe.bat (echo found) else (echo missing)")
+            .expect("Failed to run command");
+        assert_eq!(result.stdout.trim(), "found");
+
+        let missing = CmdSession::start_with(SessionOptions {
+            cwd: Some("tests/no_such_directory".into()),
+            ..SessionOptions::default()
+        });
+        assert!(missing.is_err());
+
+        cleanup_test_batch(&path);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_shell_arguments() {
+        use batch_debugger::debugger::{CmdSession, SessionOptions, ShellConfig};
+
+        let mut session = CmdSession::start_with(SessionOptions {
+            shell: ShellConfig {
+                args: vec!["/V:ON".to_string()],
+                ..ShellConfig::default()
+            },
+            ..SessionOptions::default()
+        })
+        .expect("Failed to start CMD session");
+        assert_eq!(session.shell().args, vec!["/V:ON".to_string()]);
+
+        session
+            .run("set X=expanded")
+            .expect("Failed to run command");
+        let result = session.run("echo !X!").expect("Failed to run command");
+        assert_eq!(result.stdout.trim(), "expanded");
+    }
+
+    #[test]
+    fn test_shell_bogus_path_fails() {
+        use batch_debugger::debugger::{CmdSession, SessionOptions, ShellConfig};
+
+        let result = CmdSession::start_with(SessionOptions {
+            shell: ShellConfig {
+                path: "no_such_dir/no_such_shell.exe".into(),
+                args: Vec::new(),
+            },
+            ..SessionOptions::default()
+        });
+        match result {
+            Ok(_) => panic!("Starting a missing shell should fail"),
+            Err(e) => assert!(e.to_string().contains("no_such_shell.exe")),
+        }
+    }
+
+    #[test]
+    fn test_session_command_options() {
+        use batch_debugger::debugger::SessionOptions;
+        use std::ffi::OsStr;
+
+        let options = SessionOptions {
+            skip_autorun: true,
+            clear_env: true,
+            env: vec![("BUILD_MODE".to_string(), "release".to_string())],
+            ..SessionOptions::default()
+        };
+        let command = options.command();
+        let args: Vec<&OsStr> = command.get_args().collect();
+        assert!(args.contains(&OsStr::new("/D")));
+        assert!(args.contains(&OsStr::new("/V:ON")));
+
+        let envs: Vec<(String, Option<String>)> = command
+            .get_envs()
+            .map(|(k, v)| {
+                (
+                    k.to_string_lossy().to_uppercase(),
+                    v.map(|v| v.to_string_lossy().into_owned()),
+                )
+            })
+            .collect();
+        assert!(envs.contains(&("PROMPT".to_string(), Some("$P$G".to_string()))));
+        assert!(envs.contains(&("BUILD_MODE".to_string(), Some("release".to_string()))));
+        assert!(envs.iter().all(|(k, _)| {
+            [
+                "PROMPT",
+                "BUILD_MODE",
+                "PATH",
+                "PATHEXT",
+                "COMSPEC",
+                "TEMP",
+                "TMP",
+            ]
+            .contains(&k.as_str())
+                || k.starts_with("SYSTEM")
+                || k == "WINDIR"
+        }));
+
+        // Without the options the adapter's environment is inherited untouched
+        let command = SessionOptions::default().command();
+        assert!(!command.get_args().any(|a| a == "/D"));
+        assert_eq!(command.get_envs().count(), 1);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_session_clear_env() {
+        use batch_debugger::debugger::{CmdSession, SessionOptions};
+
+        std::env::set_var("BATCH_DEBUGGER_HOST_VAR", "leaked");
+        let mut session = CmdSession::start_with(SessionOptions {
+            skip_autorun: true,
+            clear_env: true,
+            env: vec![("FROM_LAUNCH".to_string(), "given".to_string())],
+            ..SessionOptions::default()
+        })
+        .expect("Failed to start CMD session");
+
+        let result = session
+            .run("echo [%BATCH_DEBUGGER_HOST_VAR%] [%FROM_LAUNCH%]")
+            .expect("Failed to run command");
+        assert_eq!(result.stdout.trim(), "[%BATCH_DEBUGGER_HOST_VAR%] [given]");
+        let result = session.run("where cmd").expect("Failed to run command");
+        assert_eq!(result.exit_code, 0);
+    }
+
+    #[test]
+    fn test_transcript_jsonl_format() {
+        use batch_debugger::debugger::{Transcript, TranscriptEntry};
+
+        let path = "tests/batch_files/test_transcript_format.jsonl";
+        let _ = fs::remove_file(path);
+        {
+            let mut transcript =
+                Transcript::open(std::path::Path::new(path)).expect("Failed to open transcript");
+            transcript.record(TranscriptEntry::sent("echo hi"));
+            transcript.record(TranscriptEntry::received("hi\r\n", "", 0));
+            transcript.record(TranscriptEntry::sent("type nope"));
+            transcript.record(TranscriptEntry::received("", "not found\r\n", 1));
+            assert_eq!(transcript.recent(10).len(), 4);
+            assert_eq!(transcript.recent(1)[0].exit_code, Some(1));
+        }
+
+        let lines: Vec<serde_json::Value> = fs::read_to_string(path)
+            .expect("Transcript should exist")
+            .lines()
+            .map(|l| serde_json::from_str(l).expect("Each line should be JSON"))
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["direction"], "sent");
+        assert_eq!(lines[0]["command"], "echo hi");
+        assert!(lines[1].get("stderr").is_none());
+        assert_eq!(lines[3]["stderr"], "not found\r\n");
+        assert_eq!(lines[3]["exitCode"], 1);
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_session_transcript() {
+        use batch_debugger::debugger::{CmdSession, Direction};
+
+        let path = "tests/batch_files/test_transcript.jsonl";
+        let _ = fs::remove_file(path);
+
+        let mut session = CmdSession::start().expect("Failed to start CMD session");
+        session
+            .record_to(std::path::Path::new(path))
+            .expect("Failed to open transcript");
+        session.run("echo one").expect("Failed to run command");
+        session.run("set X=2").expect("Failed to run command");
+        session.run("cmd /c exit 3").expect("Failed to run command");
+
+        let recent = session.transcript(2);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].command.as_deref(), Some("cmd /c exit 3"));
+        assert_eq!(recent[1].direction, Direction::Received);
+        drop(session);
+
+        let entries: Vec<serde_json::Value> = fs::read_to_string(path)
+            .expect("Transcript should exist")
+            .lines()
+            .map(|l| serde_json::from_str(l).expect("Each line should be JSON"))
+            .collect();
+        assert_eq!(entries.len(), 6);
+        let commands: Vec<&str> = entries
+            .iter()
+            .filter(|e| e["direction"] == "sent")
+            .map(|e| e["command"].as_str().unwrap())
+            .collect();
+        assert_eq!(commands, vec!["echo one", "set X=2", "cmd /c exit 3"]);
+        assert_eq!(entries[1]["direction"], "received");
+        assert_eq!(entries[1]["output"].as_str().unwrap().trim(), "one");
+        assert_eq!(entries[5]["exitCode"], 3);
+        assert!(entries[0]["timestampMs"].as_u64().unwrap() > 0);
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_pre_launch_commands() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        let mut server = DapServer::new();
+
+        let setup = vec!["set BOOTSTRAPPED=yes".to_string()];
+        assert!(server
+            .run_pre_launch_commands(&mut ctx, &setup, true)
+            .is_ok());
+        let result = ctx.run_command("echo %BOOTSTRAPPED%").unwrap();
+        assert_eq!(result.stdout.trim(), "yes");
+
+        let failing = vec!["cmd /c exit 3".to_string(), "set AFTER=1".to_string()];
+        let err = server
+            .run_pre_launch_commands(&mut ctx, &failing, true)
+            .expect_err("A failing command should abort launch");
+        assert!(err.contains("exited with code 3"));
+        let result = ctx.run_command("echo [%AFTER%]").unwrap();
+        assert_eq!(result.stdout.trim(), "[%AFTER%]");
+
+        // Without failOnPreLaunchError the failure is only reported
+        assert!(server
+            .run_pre_launch_commands(&mut ctx, &failing, false)
+            .is_ok());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_evaluation_cache_per_stop() {
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.run_command("SET BIG=abcdefghij")
+            .expect("Failed to set BIG");
+
+        // BIG isn't tracked, so evaluating it needs the session
+        let before = ctx.session().command_count();
+        for _ in 0..3 {
+            assert_eq!(ctx.evaluate_expression("%BIG%").unwrap(), "abcdefghij");
+        }
+        assert_eq!(ctx.session().command_count(), before + 1);
+        assert_eq!(ctx.eval_cache_stats(), (2, 1));
+
+        // Stepping runs a command, so the cache is cold again
+        ctx.run_command("SET BIG=xyz").expect("Failed to set BIG");
+        assert_eq!(ctx.evaluate_expression("%BIG%").unwrap(), "xyz");
+        assert_eq!(ctx.eval_cache_stats(), (2, 2));
+
+        ctx.request_continue();
+        ctx.evaluate_expression("%BIG%").unwrap();
+        assert_eq!(ctx.eval_cache_stats(), (2, 3));
+    }
+
+    #[test]
+    fn test_call_set_indirection_tracking() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, Frame};
+
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.track_set_command("SET TARGET=found");
+        ctx.track_set_command("SET NAME=TARGET");
+
+        ctx.track_set_command("call set RESULT=%%%NAME%%%");
+        assert_eq!(ctx.variables.get("RESULT"), Some(&"found".to_string()));
+
+        // %~1 comes from the innermost frame's arguments, unquoted
+        ctx.call_stack
+            .push(Frame::new(0, Some(vec!["\"a b\"".to_string()])).with_label("sub", 0));
+        ctx.track_set_command("call set \"ARG=%~1\"");
+        assert_eq!(ctx.variables.get("ARG"), Some(&"a b".to_string()));
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_call_set_runs_and_call_label_unchanged() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::time::Duration;
+
+        let content = r#"@echo off
+set TARGET=found
+set NAME=TARGET
+call set RESULT=%%%NAME%%%
+call :sub
+exit /b 0
+
+:sub
+echo in sub
+exit /b 0
+"#;
+
+        let path = create_test_batch(content, "call_set_trick");
+        let contents = fs::read_to_string(&path).expect("Could not read test file");
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let echo_in_sub = pre.phys_to_logical[8];
+        ctx.add_breakpoint(echo_in_sub);
+
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should stop inside :sub");
+        {
+            let ctx = ctx_arc.lock().unwrap();
+            assert_eq!(ctx.call_stack.len(), 1);
+            assert_eq!(ctx.call_stack[0].name(), ":sub");
+            assert_eq!(ctx.variables.get("RESULT"), Some(&"found".to_string()));
+        }
+
+        resume_dap_executor(&ctx_arc, echo_in_sub);
+        handle.join().expect("Executor thread panicked");
+
+        cleanup_test_batch(&path);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_run_to_line_stops_once() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::Duration;
+
+        let content = r#"@echo off
+call :sub first
+call :sub second
+exit /b 0
+
+:sub
+echo entering %1
+echo middle of sub
+exit /b 0
+"#;
+
+        let path = create_test_batch(content, "run_to_line");
+        let contents = fs::read_to_string(&path).expect("Could not read test file");
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::StepInto);
+
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let StopInfo { pc: entry_pc, .. } = events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should stop on entry");
+        wait_for_dap_stop(&ctx_arc, entry_pc);
+
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+        server.set_program(&path, pre.clone());
+        server.handle_run_to_line(
+            1,
+            "batch/runToLine".to_string(),
+            Some(serde_json::json!({"line": 8})),
+        );
+
+        let StopInfo { reason, pc, .. } = events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should stop at the target line");
+        assert_eq!(reason, "breakpoint");
+        assert_eq!(pre.logical[pc].phys_start + 1, 8);
+        assert_eq!(
+            ctx_arc.lock().unwrap().call_stack[0].args,
+            Some(vec!["first".to_string()])
+        );
+
+        // The second call passes the line without stopping again
+        resume_dap_executor(&ctx_arc, pc);
+        let StopInfo { reason, .. } = events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should finish");
+        assert_eq!(reason, "terminated");
+        handle.join().expect("Executor thread panicked");
+
+        cleanup_test_batch(&path);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_step_back_restores_variables() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::Duration;
+
+        let content = r#"@echo off
+set FIRST=1
+set SECOND=2
+set /a THIRD=10/0
+echo done
+"#;
+
+        let path = create_test_batch(content, "step_back");
+        let contents = fs::read_to_string(&path).expect("Could not read test file");
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::StepInto);
+
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+
+        let value_of = |server: &DapServer, name: &str| {
+            server
+                .collect_variables(2)
+                .iter()
+                .find(|v| v["name"] == name)
+                .map(|v| v["value"].as_str().unwrap().to_string())
+        };
+
+        // Step forward over the three SETs
+        let StopInfo { mut pc, .. } = events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should stop on entry");
+        for _ in 0..4 {
+            wait_for_dap_stop(&ctx_arc, pc);
+            server.handle_step_in(1, "stepIn".to_string(), None);
+            pc = events
+                .recv_timeout(Duration::from_secs(10))
+                .expect("Executor should stop after stepping")
+                .pc;
+        }
+        assert_eq!(pre.logical[pc].phys_start + 1, 5);
+        assert_eq!(value_of(&server, "SECOND"), Some("2".to_string()));
+        assert_ne!(ctx_arc.lock().unwrap().last_exit_code, 0);
+
+        // Undo the failing SET /A, then SECOND
+        for _ in 0..2 {
+            wait_for_dap_stop(&ctx_arc, pc);
+            server.handle_step_back(2, "stepBack".to_string());
+            pc = events
+                .recv_timeout(Duration::from_secs(10))
+                .expect("Executor should stop after stepping back")
+                .pc;
+        }
+        assert_eq!(pre.logical[pc].phys_start + 1, 3);
+        assert_eq!(value_of(&server, "FIRST"), Some("1".to_string()));
+        assert_eq!(value_of(&server, "SECOND"), None);
+        assert_eq!(value_of(&server, "ERRORLEVEL"), Some("0".to_string()));
+        assert_eq!(ctx_arc.lock().unwrap().step_back_depth(), 2);
+
+        // The session was re-synced, so running forward again sees the rewound state
+        wait_for_dap_stop(&ctx_arc, pc);
+        let out = ctx_arc
+            .lock()
+            .unwrap()
+            .run_command("echo [%SECOND%]")
+            .unwrap()
+            .stdout;
+        assert_eq!(out.trim(), "[%SECOND%]");
+
+        resume_dap_executor(&ctx_arc, pc);
+        let StopInfo { reason, .. } = events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should finish");
+        assert_eq!(reason, "terminated");
+        handle.join().expect("Executor thread panicked");
+
+        cleanup_test_batch(&path);
+    }
+
+    #[test]
+    fn test_interactive_prompt_detection() {
+        use batch_debugger::parser::{parse_interactive_prompt, InteractivePrompt};
+
+        assert_eq!(
+            parse_interactive_prompt("pause"),
+            Some(InteractivePrompt::Pause)
+        );
+        assert_eq!(
+            parse_interactive_prompt("@PAUSE"),
+            Some(InteractivePrompt::Pause)
+        );
+        assert_eq!(
+            parse_interactive_prompt("set /p NAME=Enter name: "),
+            Some(InteractivePrompt::SetPrompt {
+                variable: "NAME".to_string(),
+                prompt: "Enter name:".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_interactive_prompt("set /p \"NAME=Name? \""),
+            Some(InteractivePrompt::SetPrompt {
+                variable: "NAME".to_string(),
+                prompt: "Name? ".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_interactive_prompt("choice /c ABC /d B /t 5"),
+            Some(InteractivePrompt::Choice {
+                choices: "ABC".to_string(),
+                default: Some('B'),
+            })
+        );
+        assert_eq!(
+            parse_interactive_prompt("choice"),
+            Some(InteractivePrompt::Choice {
+                choices: "YN".to_string(),
+                default: None,
+            })
+        );
+
+        // Input from a file doesn't block
+        assert_eq!(parse_interactive_prompt("set /p NAME=<name.txt"), None);
+        assert_eq!(parse_interactive_prompt("set NAME=value"), None);
+        assert_eq!(parse_interactive_prompt("echo pause"), None);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_prompts_answered_under_debugger() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::Duration;
+
+        let content = r#"@echo off
+pause
+set /p NAME=Enter name: 
+choice /c ABC /n
+set PICKED=%errorlevel%
+echo Hello %NAME%
+"#;
+
+        let path = create_test_batch(content, "prompts");
+        let contents = fs::read_to_string(&path).expect("Could not read test file");
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+
+        // PAUSE is skipped, SET /P stops for input
+        let StopInfo { reason, pc, .. } = events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should stop at SET /P");
+        assert_eq!(reason, "input required");
+        assert_eq!(pre.logical[pc].phys_start + 1, 3);
+        wait_for_dap_stop(&ctx_arc, pc);
+        assert!(ctx_arc.lock().unwrap().awaiting_input());
+
+        // Answers are used in order: the name, then the CHOICE key
+        ctx_arc.lock().unwrap().provide_input("Bob");
+        assert!(server.provide_input("C"));
+
+        let StopInfo { reason, .. } = events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should finish");
+        assert_eq!(reason, "terminated");
+        handle.join().expect("Executor thread panicked");
+
+        let ctx = ctx_arc.lock().unwrap();
+        assert_eq!(ctx.variables.get("NAME"), Some(&"Bob".to_string()));
+        assert_eq!(ctx.variables.get("PICKED"), Some(&"3".to_string()));
+
+        cleanup_test_batch(&path);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_bare_exit_restarts_session() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+
+        let content = "@echo off\r\nset A=kept\r\nexit\r\necho after exit %A%\r\n";
+        let path = create_test_batch(content, "bare_exit");
+        let contents = fs::read_to_string(&path).expect("Could not read test file");
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let (event_tx, events) = channel();
+        let (output_tx, output) = channel();
+        let (stderr_tx, _stderr) = channel();
+
+        batch_debugger::executor::run_debugger_dap(
+            ctx_arc, &pre, &labels, event_tx, output_tx, stderr_tx,
+        )
+        .expect("Executor failed");
+
+        let output: String = output.try_iter().map(|o| o.text).collect();
+        assert!(output.contains("WARNING: CMD exited"), "{}", output);
+        assert!(output.contains("after exit kept"), "{}", output);
+        let reasons: Vec<String> = events.try_iter().map(|stop| stop.reason).collect();
+        assert_eq!(reasons, vec!["terminated".to_string()]);
+
+        cleanup_test_batch(&path);
+    }
+
+    #[test]
+    fn test_script_launch_arguments() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+
+        let content = "@echo off\r\nif \"%1\"==\"release\" (echo release build) else (echo debug build)\r\nshift\r\necho arch=%1 all=%*\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new().respond("echo \"release\"", "\"release\"", 0);
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_script_args(
+            "C:\\My Scripts\\build.bat",
+            vec!["release".into(), "x64".into()],
+        );
+        ctx.set_mode(RunMode::Continue);
+        assert_eq!(
+            ctx.expand_arguments("%0 %~0"),
+            "\"C:\\My Scripts\\build.bat\" C:\\My Scripts\\build.bat"
+        );
+        assert_eq!(ctx.expand_arguments("%%1 %PATH%%2"), "%%1 %PATH%x64");
+
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let (event_tx, _events) = channel();
+        let (output_tx, _output) = channel();
+        let (stderr_tx, _stderr) = channel();
+        batch_debugger::executor::run_debugger_dap(
+            ctx_arc.clone(),
+            &pre,
+            &labels,
+            event_tx,
+            output_tx,
+            stderr_tx,
+        )
+        .expect("Executor failed");
+
+        let commands = commands.lock().unwrap();
+        assert!(commands.iter().any(|c| c == "echo release build"));
+        assert!(!commands.iter().any(|c| c == "echo debug build"));
+        assert!(commands.iter().any(|c| c == "echo arch=x64 all=x64"));
+        assert_eq!(ctx_arc.lock().unwrap().script_args(), ["x64".to_string()]);
+    }
+
+    #[test]
+    fn test_control_flow_in_for_bodies_and_if_branches() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+
+        let content = "@echo off\r\nfor %%i in (a b c d) do if \"%%i\"==\"b\" (goto found) else (echo item %%i)\r\necho not reached\r\n:found\r\nfor %%n in (1 2 3) do call :show %%n\r\ncall :check\r\nif errorlevel 2 (echo got two) else (echo wrong code)\r\ngoto :eof\r\n:show\r\necho show %1\r\nexit /b 0\r\n:check\r\nif 1==1 exit /b 2\r\necho still checking\r\nexit /b 0\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        // FOR items and IF operands are expanded with echo
+        let mut shell =
+            MockShell::new()
+                .respond("echo \"a\"", "\"a\"", 0)
+                .respond("echo \"b\"", "\"b\"", 0);
+        for item in ["a", "b", "c", "d", "1", "2", "3"] {
+            shell = shell.respond(&format!("echo {}", item), item, 0);
+        }
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let (event_tx, _events) = channel();
+        let (output_tx, _output) = channel();
+        let (stderr_tx, _stderr) = channel();
+        batch_debugger::executor::run_debugger_dap(
+            ctx_arc.clone(),
+            &pre,
+            &labels,
+            event_tx,
+            output_tx,
+            stderr_tx,
+        )
+        .expect("Executor failed");
+
+        let commands: Vec<String> = commands
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| c.split_whitespace().count() > 2)
+            .cloned()
+            .collect();
+        assert_eq!(
+            commands,
+            [
+                "echo item a",
+                "echo show 1",
+                "echo show 2",
+                "echo show 3",
+                "echo got two"
+            ]
+        );
+        let ctx = ctx_arc.lock().unwrap();
+        assert!(ctx.call_stack.is_empty());
+    }
+
+    #[test]
+    fn test_step_latency() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::{Duration, Instant};
+
+        let content: String = (0..100).map(|i| format!("echo line {}\r\n", i)).collect();
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new();
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::StepInto);
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+
+        // Resume as soon as each stop is reported, like a client holding F11
+        let start = Instant::now();
+        let mut steps = 0;
+        loop {
+            let StopInfo { reason, .. } = events
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Executor stopped reporting");
+            if reason == "terminated" {
+                break;
+            }
+            steps += 1;
+            let mut ctx = ctx_arc.lock().unwrap();
+            ctx.set_mode(RunMode::StepInto);
+            ctx.request_continue();
+        }
+        // Polling every 50ms took at least 5 seconds for this
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(steps, 100);
+
+        handle.join().expect("Executor thread panicked");
+        assert_eq!(commands.lock().unwrap().len(), 100);
+    }
+
+    #[test]
+    fn test_context_unlocked_while_command_runs() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use std::time::{Duration, Instant};
+
+        let content = "@echo off\r\nset NAME=value\r\nslow-build --all\r\necho done\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new().delay("slow-build", Duration::from_secs(2));
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+        let (ctx_arc, _events, handle) = start_dap_executor(ctx, &pre, &labels);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !commands
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|c| c == "slow-build --all")
+        {
+            assert!(Instant::now() < deadline, "Slow command never started");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let start = Instant::now();
+        {
+            let mut ctx = ctx_arc.lock().unwrap();
+            assert!(ctx.session_busy());
+            assert_eq!(ctx.evaluate_expression("%NAME%").unwrap(), "value");
+            let busy = ctx.evaluate_expression("%NAME:~0,2%").unwrap_err();
+            assert_eq!(
+                std::io::Error::from(busy).kind(),
+                std::io::ErrorKind::WouldBlock
+            );
+        }
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        handle.join().expect("Executor thread panicked");
+        assert_eq!(commands.lock().unwrap().last().unwrap(), "echo done");
+        assert!(!ctx_arc.lock().unwrap().session_busy());
+    }
+
+    #[test]
+    fn test_breakpoints_slide_past_labels_and_blank_lines() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use batch_debugger::parser::breakpoint_line;
+        use std::time::Duration;
+
+        let content =
+            "@echo off\r\ngoto main\r\n:main\r\nrem setup\r\necho first\r\n\r\necho second\r\n\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        // The label (3) and the comment under it bind to `echo first` (5),
+        // the blank line (6) to `echo second` (7); nothing runs after 7
+        assert_eq!(breakpoint_line(&pre, 2), Some(4));
+        assert_eq!(breakpoint_line(&pre, 5), Some(6));
+        assert_eq!(breakpoint_line(&pre, 7), None);
+        assert_eq!(breakpoint_line(&pre, 20), None);
+
+        let shell = MockShell::new();
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::StepInto);
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let StopInfo { pc: entry_pc, .. } = events
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Executor should stop on entry");
+        wait_for_dap_stop(&ctx_arc, entry_pc);
+
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+        server.set_program("slide.bat", pre.clone());
+        server.handle_set_breakpoints(
+            1,
+            "setBreakpoints".to_string(),
+            Some(serde_json::json!({
+                "source": {"path": "slide.bat"},
+                "breakpoints": [{"line": 3}, {"line": 6}, {"line": 8}]
+            })),
+        );
+        resume_dap_executor(&ctx_arc, entry_pc);
+
+        for expected in ["echo first", "echo second"] {
+            let StopInfo { reason, pc, .. } = events
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Breakpoint never hit");
+            assert_eq!(reason, "breakpoint");
+            assert_eq!(pre.logical[pc].text, expected);
+            wait_for_dap_stop(&ctx_arc, pc);
+            resume_dap_executor(&ctx_arc, pc);
+        }
+        let StopInfo { reason, .. } = events
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Executor should finish");
+        assert_eq!(reason, "terminated");
+        handle.join().expect("Executor thread panicked");
+        let ran: Vec<String> = commands
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| !c.is_empty())
+            .cloned()
+            .collect();
+        assert_eq!(ran, ["@echo off", "echo first", "echo second"]);
+    }
+
+    #[test]
+    fn test_data_breakpoint_pauses_with_details() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::Duration;
+
+        let content = "@echo off\r\nset COUNT=1\r\nset COUNT=2\r\necho done\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new();
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::StepInto);
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let StopInfo { pc: entry_pc, .. } = events
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Executor should stop on entry");
+        wait_for_dap_stop(&ctx_arc, entry_pc);
+
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+        server.handle_set_data_breakpoints(
+            1,
+            "setDataBreakpoints".to_string(),
+            Some(serde_json::json!({"breakpoints": [{"dataId": "COUNT"}]})),
+        );
+        resume_dap_executor(&ctx_arc, entry_pc);
+
+        for (line, old, new) in [("set COUNT=1", "", "1"), ("set COUNT=2", "1", "2")] {
+            let stop = events
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Data breakpoint never hit");
+            assert_eq!(stop.reason, "data breakpoint");
+            assert_eq!(pre.logical[stop.pc].text, line);
+
+            let body = server.stopped_body(&stop);
+            assert_eq!(
+                body["description"],
+                format!("COUNT changed from '{}' to '{}'", old, new)
+            );
+            assert_eq!(body["hitBreakpointIds"], serde_json::json!([1]));
+
+            // The stop holds until the client continues
+            std::thread::sleep(Duration::from_millis(200));
+            assert!(!commands.lock().unwrap().iter().any(|c| c == "echo done"));
+            resume_dap_executor(&ctx_arc, stop.pc);
+        }
+
+        let StopInfo { reason, .. } = events
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Executor should finish");
+        assert_eq!(reason, "terminated");
+        handle.join().expect("Executor thread panicked");
+        assert_eq!(commands.lock().unwrap().last().unwrap(), "echo done");
+    }
+
+    #[test]
+    fn test_step_out_of_nested_calls() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::Duration;
+
+        let content = "@echo off\r\ncall :outer\r\necho after outer\r\nexit /b 0\r\n\
+                       :outer\r\ncall :inner\r\necho after inner\r\nexit /b 0\r\n\
+                       :inner\r\necho in inner\r\necho still inner\r\nexit /b 0\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.set_mode(RunMode::StepInto);
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+
+        // Step in down to the innermost subroutine
+        let mut pc = loop {
+            let StopInfo { pc, .. } = events
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Executor stopped reporting");
+            wait_for_dap_stop(&ctx_arc, pc);
+            if pre.logical[pc].text == "echo in inner" {
+                break pc;
+            }
+            server.handle_step_in(1, "stepIn".to_string(), None);
+        };
+        assert_eq!(ctx_arc.lock().unwrap().call_stack.len(), 2);
+
+        for (line, depth) in [("echo after inner", 1), ("echo after outer", 0)] {
+            wait_for_dap_stop(&ctx_arc, pc);
+            server.handle_step_out(2, "stepOut".to_string());
+            let StopInfo { reason, pc: next, .. } = events
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Step Out never stopped");
+            assert_eq!(reason, "step");
+            assert_eq!(pre.logical[next].text, line);
+            assert_eq!(ctx_arc.lock().unwrap().call_stack.len(), depth);
+            pc = next;
+        }
+
+        // Out of the script itself runs to the end
+        wait_for_dap_stop(&ctx_arc, pc);
+        server.handle_step_out(3, "stepOut".to_string());
+        let StopInfo { reason, .. } = events
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Executor should finish");
+        assert_eq!(reason, "terminated");
+        handle.join().expect("Executor thread panicked");
+    }
+
+    #[test]
+    fn test_echo_state_controls_command_echo() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use std::path::PathBuf;
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+
+        let run = |content: &str, verbose: bool| -> String {
+            let physical_lines: Vec<&str> = content.lines().collect();
+            let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+            let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+            let shell = MockShell::new()
+                .with_working_dir(PathBuf::from("/work"))
+                .respond("echo hello", "hello\r\n", 0)
+                .respond("echo bye", "bye\r\n", 0)
+                .respond("echo x", "x", 0);
+            let mut ctx = DebugContext::new(shell);
+            ctx.set_mode(RunMode::Continue);
+            ctx.set_verbose_console(verbose);
+            let ctx_arc = Arc::new(Mutex::new(ctx));
+            let (event_tx, _events) = channel();
+            let (output_tx, output) = channel();
+            let (stderr_tx, _stderr) = channel();
+            batch_debugger::executor::run_debugger_dap(
+                ctx_arc, &pre, &labels, event_tx, output_tx, stderr_tx,
+            )
+            .expect("Executor failed");
+            output.try_iter().map(|o| o.text).collect()
+        };
+
+        let quiet = "@echo off\r\necho hello\r\nif 1==1 echo bye\r\n";
+        assert_eq!(run(quiet, false), "hello\r\nbye\r\n");
+        assert!(run(quiet, true).contains("IF: Condition is TRUE"));
+
+        // Echo is on until turned off; `@` hides a single line
+        let echoing = "echo hello\r\n@echo bye\r\necho off\r\necho hello\r\n";
+        let dir = PathBuf::from("/work").display().to_string();
+        assert_eq!(
+            run(echoing, false),
+            format!(
+                "{0}>echo hello\r\nhello\r\nbye\r\n{0}>echo off\r\nhello\r\n",
+                dir
+            )
+        );
+
+        // FOR shows itself and then each iteration's command
+        let looping = "for %%i in (x) do echo hello\r\n";
+        assert_eq!(
+            run(looping, false),
+            format!(
+                "{0}>for %%i in (x) do echo hello\r\n{0}>echo hello\r\nhello\r\n",
+                dir
+            )
+        );
+    }
+
+    #[test]
+    fn test_coverage_reports_dead_lines() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use std::path::Path;
+
+        let content = "@echo off\r\nset A=1\r\nif 1==2 echo no\r\ngoto end\r\n\
+                       :unused\r\necho never\r\nset B=2\r\n:end\r\necho done\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        // IF operands are expanded by the shell
+        let shell = MockShell::new()
+            .respond("echo 1", "1", 0)
+            .respond("echo 2", "2", 0);
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+        let (ctx_arc, _events, handle) = start_dap_executor(ctx, &pre, &labels);
+        handle.join().expect("Executor thread panicked");
+
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc);
+        server.set_program("coverage.bat", pre);
+        let report = server.coverage_report().expect("No coverage report");
+
+        // Labels are left out; the false IF and the :unused block never ran
+        let hits: Vec<(usize, u32)> = report.lines.iter().map(|l| (l.line, l.hits)).collect();
+        assert_eq!(
+            hits,
+            vec![(1, 1), (2, 1), (3, 0), (4, 1), (6, 0), (7, 0), (9, 1)]
+        );
+        assert_eq!((report.covered, report.total), (4, 7));
+        assert!((report.percent - 400.0 / 7.0).abs() < 1e-9);
+
+        let lcov = report.to_lcov(Path::new("coverage.bat"));
+        assert!(lcov.starts_with("TN:\nSF:coverage.bat\nDA:1,1\n"));
+        assert!(lcov.contains("DA:6,0\n"));
+        assert!(lcov.ends_with("LF:7\nLH:4\nend_of_record\n"));
+    }
+
+    #[test]
+    fn test_profile_finds_slow_line() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::Duration;
+
+        let content = "@echo off\r\necho start\r\nping -n 2 127.0.0.1\r\necho end\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new().delay("ping", Duration::from_millis(300));
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::StepInto);
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+
+        // Sitting at every stop for a while must not count against a line
+        loop {
+            let StopInfo { reason, pc, .. } = events
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Executor stopped reporting");
+            if reason == "terminated" {
+                break;
+            }
+            wait_for_dap_stop(&ctx_arc, pc);
+            std::thread::sleep(Duration::from_millis(150));
+            let mut ctx = ctx_arc.lock().unwrap();
+            ctx.set_mode(RunMode::StepInto);
+            ctx.request_continue();
+        }
+        handle.join().expect("Executor thread panicked");
+
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+        server.set_program("profile.bat", pre.clone());
+        let lines = server.profile_report(10).expect("No profile");
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0].line, 3);
+        assert_eq!(lines[0].text, "ping -n 2 127.0.0.1");
+        assert_eq!(lines[0].hits, 1);
+        assert!(lines[0].total_ms >= 300.0, "{:?}", lines);
+        assert!(lines[1..].iter().all(|l| l.total_ms < 100.0), "{:?}", lines);
+
+        let trace = ctx_arc.lock().unwrap().profile().to_chrome_trace(&pre);
+        let events = trace["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[2]["name"], "ping -n 2 127.0.0.1");
+        assert_eq!(events[2]["ph"], "X");
+        assert!(events[2]["dur"].as_u64().unwrap() >= 300_000);
+    }
+
+    #[test]
+    fn test_command_not_found_warning() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let content = "@echo off\r\necho building\r\npyhton build.py\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = || {
+            MockShell::new()
+                .fail(
+                    "pyhton",
+                    "'pyhton' is not recognized as an internal or external command,\r\noperable program or batch file.\r\n",
+                    9009,
+                )
+                .respond(
+                    "where",
+                    "C:\\Python312\\python.exe\r\nC:\\Python312\\python312.dll\r\n",
+                    0,
+                )
+        };
+
+        let mut ctx = DebugContext::new(shell());
+        ctx.set_mode(RunMode::Continue);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let (event_tx, events) = channel();
+        let (output_tx, output) = channel();
+        let (stderr_tx, _stderr) = channel();
+        batch_debugger::executor::run_debugger_dap(
+            ctx_arc.clone(),
+            &pre,
+            &labels,
+            event_tx,
+            output_tx,
+            stderr_tx,
+        )
+        .expect("Executor failed");
+
+        let output: String = output.try_iter().map(|o| o.text).collect();
+        assert!(
+            output.contains(
+                "WARNING: Line 3: 'pyhton' is not a command or a program on PATH (ERRORLEVEL 9009). Did you mean 'python'?"
+            ),
+            "{}",
+            output
+        );
+        assert_eq!(ctx_arc.lock().unwrap().last_exit_code, 9009);
+        // Without the exception filter the run just carries on
+        let reasons: Vec<String> = events.try_iter().map(|stop| stop.reason).collect();
+        assert_eq!(reasons, ["terminated"]);
+
+        // Builtins are suggested too
+        assert_eq!(
+            ctx_arc.lock().unwrap().suggest_command("ecoh"),
+            Some("echo".to_string())
+        );
+
+        let mut ctx = DebugContext::new(shell());
+        ctx.set_mode(RunMode::Continue);
+        ctx.set_break_on_command_not_found(true);
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let StopInfo { reason, pc, .. } = events
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Executor should stop on the missing command");
+        assert_eq!(reason, "exception");
+        assert_eq!(pc, 2);
+        wait_for_dap_stop(&ctx_arc, pc);
+        assert_eq!(
+            ctx_arc.lock().unwrap().stop_text(),
+            Some("Command not found: pyhton")
+        );
+        resume_dap_executor(&ctx_arc, pc);
+        let StopInfo { reason, .. } = events
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Executor should finish");
+        assert_eq!(reason, "terminated");
+        handle.join().expect("Executor thread panicked");
+    }
+
+    #[test]
+    fn test_parse_start_command() {
+        use batch_debugger::parser::{parse_start_command, StartCommand};
+
+        assert_eq!(
+            parse_start_command("start /wait cmd /c exit 7"),
+            Some(StartCommand {
+                wait: true,
+                command: "cmd /c exit 7".to_string(),
+                ..Default::default()
+            })
+        );
+        // The first quoted argument is the title, not the program
+        assert_eq!(
+            parse_start_command("@START \"Build\" /B /D \"C:\\src\" build.exe --release"),
+            Some(StartCommand {
+                title: Some("Build".to_string()),
+                directory: Some("C:\\src".to_string()),
+                background: true,
+                command: "build.exe --release".to_string(),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            parse_start_command("start /min /DC:\\tmp notepad"),
+            Some(StartCommand {
+                directory: Some("C:\\tmp".to_string()),
+                command: "notepad".to_string(),
+                ..Default::default()
+            })
+        );
+        assert_eq!(parse_start_command("echo start /wait x"), None);
+        assert_eq!(parse_start_command("startup.exe"), None);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_start_wait_runs_outside_session() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::{Duration, Instant};
+
+        let content = "@echo off\r\nset NAME=value\r\nstart /wait cmd /c \"ping -n 3 127.0.0.1 & exit 7\"\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+
+        // Wait until the START line is running, then evaluate alongside it
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while ctx_arc.lock().unwrap().current_line != Some(2) {
+            assert!(Instant::now() < deadline, "START never ran");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::thread::sleep(Duration::from_millis(200));
+        let start = Instant::now();
+        let value = ctx_arc
+            .lock()
+            .unwrap()
+            .evaluate_expression("%NAME%")
+            .expect("Evaluate failed");
+        assert_eq!(value, "value");
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        let StopInfo { reason, .. } = events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should finish");
+        assert_eq!(reason, "terminated");
+        handle.join().expect("Executor thread panicked");
+        assert_eq!(ctx_arc.lock().unwrap().last_exit_code, 7);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_start_records_child_processes() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::Duration;
+
+        let content = "@echo off\r\nstart \"pinger\" /b ping -n 30 127.0.0.1\r\necho started\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let children = ctx.child_processes();
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let StopInfo { reason, .. } = events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Executor should finish without waiting for ping");
+        assert_eq!(reason, "terminated");
+        handle.join().expect("Executor thread panicked");
+        assert_eq!(ctx_arc.lock().unwrap().last_exit_code, 0);
+
+        let listed = children.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].command, "ping -n 30 127.0.0.1");
+        assert_eq!(listed[0].title.as_deref(), Some("pinger"));
+        assert!(listed[0].running);
+
+        children.kill_all();
+        assert!(!children.list()[0].running);
+    }
+
+    #[test]
+    fn test_fast_forward_delays() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::parser::{parse_delay, Delay};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, Instant};
+
+        let delay = |seconds, exit_code| Some(Delay { seconds, exit_code });
+        assert_eq!(parse_delay("timeout /t 30 /nobreak >nul"), delay(30, 0));
+        assert_eq!(parse_delay("@TIMEOUT 5"), delay(5, 0));
+        assert_eq!(parse_delay("timeout /t -1"), None);
+        assert_eq!(parse_delay("ping -n 6 127.0.0.1 >nul"), delay(5, 0));
+        assert_eq!(parse_delay("ping -n 3 localhost > NUL 2>&1"), delay(2, 0));
+        assert_eq!(parse_delay("waitfor /t 10 BuildDone"), delay(10, 1));
+        // Real connectivity checks and signals are left alone
+        assert_eq!(parse_delay("ping -n 1 example.com >nul"), None);
+        assert_eq!(parse_delay("ping -n 4 127.0.0.1"), None);
+        assert_eq!(parse_delay("ping -n 4 localhost >ping.log"), None);
+        assert_eq!(parse_delay("waitfor /si BuildDone"), None);
+
+        let content = "@echo off\r\ntimeout /t 5 >nul\r\necho done\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new().delay("timeout", Duration::from_secs(5));
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+        ctx.set_fast_forward_delays(true);
+        ctx.last_exit_code = 3;
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let (event_tx, _events) = channel();
+        let (output_tx, output) = channel();
+        let (stderr_tx, _stderr) = channel();
+
+        let started = Instant::now();
+        batch_debugger::executor::run_debugger_dap(
+            ctx_arc.clone(),
+            &pre,
+            &labels,
+            event_tx,
+            output_tx,
+            stderr_tx,
+        )
+        .expect("Executor failed");
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let output: String = output.try_iter().map(|o| o.text).collect();
+        assert!(output.contains("(skipped 5s delay)"), "{}", output);
+        assert!(!commands
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|c| c.contains("timeout")));
+        assert_eq!(ctx_arc.lock().unwrap().last_exit_code, 0);
+    }
+
+    #[test]
+    fn test_run_summary() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode, RunSummary, TerminatedReason};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+
+        let run = |content: &str| -> RunSummary {
+            let physical_lines: Vec<&str> = content.lines().collect();
+            let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+            let labels = batch_debugger::parser::build_label_map(&physical_lines);
+            let mut ctx = DebugContext::new(MockShell::new());
+            ctx.set_mode(RunMode::Continue);
+            let ctx_arc = Arc::new(Mutex::new(ctx));
+            let (event_tx, events) = channel();
+            let (output_tx, _output) = channel();
+            let (stderr_tx, _stderr) = channel();
+            let summary = batch_debugger::executor::run_debugger_dap(
+                ctx_arc.clone(),
+                &pre,
+                &labels,
+                event_tx,
+                output_tx,
+                stderr_tx,
+            )
+            .expect("Executor failed");
+            let reasons: Vec<String> = events.try_iter().map(|stop| stop.reason).collect();
+            assert_eq!(reasons, ["terminated"]);
+            assert_eq!(ctx_arc.lock().unwrap().run_summary(), Some(&summary));
+            summary
+        };
+
+        let summary = run("@echo off\r\ncall :inner\r\necho done\r\ngoto :eof\r\n:inner\r\ncall :deeper\r\ngoto :eof\r\n:deeper\r\necho deep\r\n");
+        assert_eq!(summary.terminated_reason, TerminatedReason::Completed);
+        assert_eq!(summary.exit_code, 0);
+        // echo off, call, call, echo deep, goto :eof, echo done, goto :eof
+        assert_eq!(summary.lines_executed, 7);
+        assert_eq!(summary.max_stack_depth, 2);
+
+        let summary = run("@echo off\r\ncall :missing\r\necho unreachable\r\n");
+        assert_eq!(
+            summary.terminated_reason,
+            TerminatedReason::UnknownLabel {
+                name: "missing".to_string(),
+                line: 2
+            }
+        );
+        assert_eq!(summary.lines_executed, 2);
+
+        let summary = run("@echo off\r\nexit /b 3\r\necho unreachable\r\n");
+        assert_eq!(summary.terminated_reason, TerminatedReason::Completed);
+        assert_eq!(summary.exit_code, 3);
+        assert_eq!(summary.lines_executed, 2);
+    }
+
+    #[test]
+    fn test_multi_line_block_runs_as_one_command() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::parser::{block_end, block_start, join_block};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+
+        let content = "@echo off\r\nif 1==1 (\r\n    set A=1\r\n    set B=two\r\n) else (\r\n    set C=3\r\n)\r\necho after\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        assert_eq!(block_end(&pre, 1), Some(6));
+        assert_eq!(block_end(&pre, 2), None);
+        assert_eq!(block_start(&pre, 3), Some(1));
+        assert_eq!(block_start(&pre, 6), Some(1));
+        assert_eq!(block_start(&pre, 7), None);
+        assert_eq!(
+            join_block(&pre, 1, 6),
+            "if 1==1 ( set A=1 & set B=two ) else ( set C=3 )"
+        );
+
+        let shell = MockShell::new().respond("echo 1", "1", 0);
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let (event_tx, _events) = channel();
+        let (output_tx, _output) = channel();
+        let (stderr_tx, _stderr) = channel();
+        batch_debugger::executor::run_debugger_dap(
+            ctx_arc.clone(),
+            &pre,
+            &labels,
+            event_tx,
+            output_tx,
+            stderr_tx,
+        )
+        .expect("Executor failed");
+
+        let commands = commands.lock().unwrap().clone();
+        assert!(
+            commands.iter().any(|c| c == "set A=1 & set B=two"),
+            "{:?}",
+            commands
+        );
+        // Neither the block's own lines nor its parentheses reach CMD alone
+        assert!(!commands
+            .iter()
+            .any(|c| c.trim() == ")" || c.trim() == "set B=two" || c.contains("set C=3")));
+        assert!(commands.iter().any(|c| c == "echo after"));
+
+        let ctx = ctx_arc.lock().unwrap();
+        let visible = ctx.get_visible_variables();
+        assert_eq!(visible.get("A").map(String::as_str), Some("1"));
+        assert_eq!(visible.get("B").map(String::as_str), Some("two"));
+        assert!(!visible.contains_key("C"));
+    }
+
+    #[test]
+    fn test_pause_interrupts_running_command() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode, INTERRUPTED_EXIT_CODE};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, Instant};
+
+        let content = "@echo off\r\nping -n 30 127.0.0.1\r\necho done\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new()
+            .respond(
+                "ping",
+                "Pinging 127.0.0.1 with 32 bytes of data:\r\nReply from 127.0.0.1: bytes=32 time<1ms TTL=128\r\n",
+                0,
+            )
+            .delay("ping", Duration::from_secs(30));
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let (event_tx, events) = channel();
+        let (output_tx, output) = channel();
+        let (stderr_tx, _stderr) = channel();
+        let exec_ctx = ctx_arc.clone();
+        let exec_pre = pre.clone();
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(
+                exec_ctx, &exec_pre, &labels, event_tx, output_tx, stderr_tx,
+            )
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !ctx_arc.lock().unwrap().session_busy() {
+            assert!(Instant::now() < deadline, "ping never started");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::thread::sleep(Duration::from_millis(200));
+
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+        let paused = Instant::now();
+        server.handle_pause(1, "pause".to_string());
+
+        // The ping is cut short and the script stops on its line
+        let stop = events
+            .recv_timeout(Duration::from_secs(2))
+            .expect("Pause didn't interrupt the command");
+        assert!(paused.elapsed() < Duration::from_secs(2));
+        assert_eq!((stop.reason.as_str(), stop.pc), ("pause", 1));
+        wait_for_dap_stop(&ctx_arc, stop.pc);
+        let body = server.stopped_body(&stop);
+        assert_eq!(
+            body["description"],
+            "Paused: the command on line 2 was interrupted"
+        );
+        let output: String = output.try_iter().map(|o| o.text).collect();
+        assert!(output.contains("Reply from 127.0.0.1"), "{}", output);
+        assert!(output.contains("Line 2 interrupted by pause"), "{}", output);
+
+        {
+            let mut ctx = ctx_arc.lock().unwrap();
+            assert_eq!(ctx.last_exit_code, INTERRUPTED_EXIT_CODE);
+            ctx.set_mode(RunMode::Continue);
+            ctx.request_continue();
+        }
+        let summary = handle
+            .join()
+            .expect("Executor thread panicked")
+            .expect("Executor failed");
+        assert_eq!(summary.lines_executed, 3);
+    }
+
+    #[test]
+    fn test_split_batch_arguments_follows_cmd_rules() {
+        use batch_debugger::parser::split_batch_arguments;
+
+        // Quotes stay on the argument and backslashes are plain characters
+        assert_eq!(
+            split_batch_arguments(":sub \"C:\\Program Files\\x\" arg^2"),
+            [":sub", "\"C:\\Program Files\\x\"", "arg2"]
+        );
+        assert_eq!(
+            split_batch_arguments("\"C:\\dir\\\" next"),
+            ["\"C:\\dir\\\"", "next"]
+        );
+        // Adjacent quoted parts make one argument
+        assert_eq!(split_batch_arguments("\"a\"\"b\" c"), ["\"a\"\"b\"", "c"]);
+        assert_eq!(
+            split_batch_arguments("pre\"fix x\"post"),
+            ["pre\"fix x\"post"]
+        );
+        // A caret escapes a separator outside quotes, not inside them
+        assert_eq!(
+            split_batch_arguments("one^ two three"),
+            ["one two", "three"]
+        );
+        assert_eq!(split_batch_arguments("a^,b \"c^ d\""), ["a,b", "\"c^ d\""]);
+        assert_eq!(split_batch_arguments("x^^y"), ["x^y"]);
+        // Empty quotes are an argument of their own
+        assert_eq!(
+            split_batch_arguments("\"\" second \"\""),
+            ["\"\"", "second", "\"\""]
+        );
+        assert!(split_batch_arguments("  ,;  ").is_empty());
+    }
+
+    #[test]
+    fn test_output_events_name_their_line() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::executor::ScriptOutput;
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+
+        let content = "@echo off\r\n\r\necho one\r\nrem between\r\necho two\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new()
+            .respond("echo one", "one\r\n", 0)
+            .respond("echo two", "two\r\n", 0);
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let (event_tx, _events) = channel();
+        let (output_tx, output) = channel();
+        let (stderr_tx, _stderr) = channel();
+        batch_debugger::executor::run_debugger_dap(
+            ctx_arc.clone(),
+            &pre,
+            &labels,
+            event_tx,
+            output_tx,
+            stderr_tx,
+        )
+        .expect("Executor failed");
+
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc);
+        server.set_program("C:/scripts/attrib.bat", pre.clone());
+        let bodies: Vec<_> = output
+            .try_iter()
+            .filter_map(|o| server.output_body(&o))
+            .collect();
+        assert_eq!(bodies.len(), 2, "{:?}", bodies);
+        assert_eq!(bodies[0]["output"], "one\r\n");
+        assert_eq!(bodies[0]["line"], 3);
+        assert_eq!(bodies[1]["output"], "two\r\n");
+        assert_eq!(bodies[1]["line"], 5);
+        assert_eq!(bodies[1]["source"]["name"], "attrib.bat");
+        assert_eq!(bodies[1]["source"]["path"], "C:/scripts/attrib.bat");
+
+        // The debugger's own notes aren't pinned to a line
+        let note = server
+            .output_body(&ScriptOutput::from("FOR: Loop: 3 iterations\r\n"))
+            .unwrap();
+        assert_eq!(bodies[0]["category"], "stdout");
+        assert_eq!(note["category"], "console");
+        assert!(note.get("line").is_none());
+        assert!(note.get("source").is_none());
+    }
+
+    #[test]
+    fn test_statement_granularity_steps_through_composite_line() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use serde_json::json;
+        use std::time::Duration;
+
+        let content = "@echo off\r\necho a & echo b & echo c\r\necho done\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        // Steps of the given granularity from entry to the end: the stops on
+        // the composite line, with the column of each, and the commands run
+        let run = |granularity: &str| {
+            let shell = MockShell::new();
+            let commands = shell.commands();
+            let mut ctx = DebugContext::new(shell);
+            ctx.set_mode(RunMode::StepInto);
+            let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+            let mut server = DapServer::new();
+            server.set_context(ctx_arc.clone());
+            server.set_program("steps.bat", pre.clone());
+
+            let mut columns = Vec::new();
+            loop {
+                let StopInfo { reason, pc, .. } = events
+                    .recv_timeout(Duration::from_secs(5))
+                    .expect("Executor stopped reporting");
+                if reason == "terminated" {
+                    break;
+                }
+                wait_for_dap_stop(&ctx_arc, pc);
+                if pc == 1 {
+                    columns.push(server.collect_stack_frames()[0]["column"].clone());
+                }
+                server.handle_step_in(
+                    1,
+                    "stepIn".to_string(),
+                    Some(json!({ "granularity": granularity })),
+                );
+            }
+            handle.join().expect("Executor thread panicked");
+            let commands = commands.lock().unwrap().clone();
+            (columns, commands)
+        };
+
+        let (columns, commands) = run("statement");
+        assert_eq!(columns, [json!(1), json!(10), json!(19)]);
+        assert_eq!(
+            commands,
+            ["@echo off", "echo a", "echo b", "echo c", "echo done"]
+        );
+
+        let (columns, commands) = run("line");
+        assert_eq!(columns, [json!(1)]);
+        assert_eq!(
+            commands,
+            ["@echo off", "echo a & echo b & echo c", "echo done"]
+        );
+    }
+
+    #[test]
+    fn test_statement_that_times_out_restarts_the_session() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use serde_json::json;
+        use std::time::Duration;
+
+        let content = "@echo off\r\necho a & slowcmd & echo c\r\necho done\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new().delay("slowcmd", Duration::from_secs(5));
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_command_timeout(Duration::from_secs(1));
+        ctx.set_mode(RunMode::StepInto);
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+        server.set_program("steps.bat", pre.clone());
+
+        let mut stops = Vec::new();
+        loop {
+            let StopInfo { reason, pc, .. } = events
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Executor stopped reporting");
+            stops.push((reason.clone(), pc));
+            if reason == "terminated" {
+                break;
+            }
+            wait_for_dap_stop(&ctx_arc, pc);
+            server.handle_step_in(
+                1,
+                "stepIn".to_string(),
+                Some(json!({ "granularity": "statement" })),
+            );
+        }
+        handle.join().expect("Executor thread panicked");
+
+        // The rest of the line is given up and the script goes on
+        let stops: Vec<(&str, usize)> = stops.iter().map(|(r, pc)| (r.as_str(), *pc)).collect();
+        assert_eq!(
+            stops,
+            [
+                ("step", 0),
+                ("step", 1),
+                ("step", 1),
+                ("timeout", 1),
+                ("step", 2),
+                ("terminated", 0)
+            ]
+        );
+        let commands = commands.lock().unwrap().clone();
+        assert!(!commands.iter().any(|c| c == "echo c"), "{:?}", commands);
+        assert_eq!(commands.last().map(String::as_str), Some("echo done"));
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_restart_keeps_breakpoints_and_resets_state() {
+        use batch_debugger::dap::DapServer;
+        use serde_json::json;
+        use std::fs;
+        use std::time::Duration;
+
+        let dir =
+            std::env::temp_dir().join(format!("batch-debugger-restart-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("restart.bat");
+        fs::write(
+            &program,
+            "@echo off\r\nset STEP=one\r\necho %STEP%\r\nset LATER=yes\r\n",
+        )
+        .unwrap();
+        let program_path = program.to_str().unwrap().to_string();
+
+        let mut server = DapServer::new();
+        server.handle_launch(
+            1,
+            "launch".to_string(),
+            Some(json!({ "program": program_path })),
+        );
+        server.handle_set_breakpoints(
+            2,
+            "setBreakpoints".to_string(),
+            Some(json!({ "source": { "path": program_path }, "breakpoints": [{ "line": 4 }] })),
+        );
+        server.handle_configuration_done(3, "configurationDone".to_string());
+
+        let value_of = |server: &DapServer, name: &str| {
+            server
+                .collect_variables(2)
+                .iter()
+                .find(|v| v["name"] == name)
+                .map(|v| v["value"].as_str().unwrap().to_string())
+        };
+        let next_stop = |server: &mut DapServer| {
+            server.handle_continue(3, "continue".to_string());
+            let stop = server
+                .event_receiver
+                .as_ref()
+                .unwrap()
+                .recv_timeout(Duration::from_secs(10))
+                .expect("Executor should stop");
+            (stop.reason, server.collect_stack_frames()[0]["line"].clone())
+        };
+
+        assert_eq!(next_stop(&mut server), ("breakpoint".to_string(), json!(4)));
+        assert_eq!(value_of(&server, "STEP"), Some("one".to_string()));
+
+        // The restarted run reads the edited file and starts with nothing set
+        fs::write(
+            &program,
+            "@echo off\r\nset STEP=two\r\necho %STEP%\r\nset LATER=yes\r\n",
+        )
+        .unwrap();
+        server.handle_restart(4, "restart".to_string(), None);
+        assert_eq!(server.collect_stack_frames()[0]["line"], 1);
+        assert_eq!(value_of(&server, "STEP"), None);
+
+        assert_eq!(next_stop(&mut server), ("breakpoint".to_string(), json!(4)));
+        assert_eq!(value_of(&server, "STEP"), Some("two".to_string()));
+
+        server.terminate_session();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_disconnect_stops_executor() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use serde_json::json;
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, Instant};
+
+        let content = "@echo off\r\necho one\r\necho two\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let start = |server: &mut DapServer| {
+            let mut ctx = DebugContext::new(MockShell::new());
+            ctx.set_mode(RunMode::StepInto);
+            let ctx_arc = Arc::new(Mutex::new(ctx));
+            server.start_executor(ctx_arc.clone(), &pre, &labels);
+            server
+                .event_receiver
+                .as_ref()
+                .unwrap()
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Executor should stop on entry");
+            assert!(server.executor_running());
+            ctx_arc
+        };
+
+        // Disconnecting ends the script waiting at its stop right away
+        let mut server = DapServer::new();
+        let ctx_arc = start(&mut server);
+        let started = Instant::now();
+        server.handle_disconnect(1, "disconnect".to_string(), None);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(!server.executor_running());
+        assert!(ctx_arc.lock().unwrap().is_cancelled());
+
+        // Unless the client asks for the debuggee to be left alone
+        let mut server = DapServer::new();
+        let ctx_arc = start(&mut server);
+        server.handle_disconnect(
+            2,
+            "disconnect".to_string(),
+            Some(json!({ "terminateDebuggee": false })),
+        );
+        assert!(server.executor_running());
+        assert!(!ctx_arc.lock().unwrap().is_cancelled());
+        server.handle_terminate(3, "terminate".to_string());
+        assert!(!server.executor_running());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_disconnect_kills_cmd_session() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::process::Command;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let content = "@echo off\r\nping -n 30 127.0.0.1 >nul\r\necho done\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let pid = session.process_id();
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let mut server = DapServer::new();
+        server.start_executor(ctx_arc.clone(), &pre, &labels);
+        std::thread::sleep(Duration::from_millis(500));
+
+        server.handle_disconnect(1, "disconnect".to_string(), None);
+        assert!(!server.executor_running());
+        let out = Command::new("powershell")
+            .args([
+                "-NoProfile",
+                "-Command",
+                &format!("Get-Process -Id {} -ErrorAction SilentlyContinue | Select-Object -ExpandProperty Id", pid),
+            ])
+            .output()
+            .expect("Failed to run powershell");
+        assert!(
+            String::from_utf8_lossy(&out.stdout).trim().is_empty(),
+            "cmd.exe should be killed on disconnect"
+        );
+    }
+
+    #[test]
+    fn test_launch_waits_for_configuration_done() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use serde_json::json;
+        use std::sync::{Arc, Mutex};
+
+        let content = "echo one\r\necho two\r\necho three\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        // initialize -> launch -> setBreakpoints -> configurationDone, no stopOnEntry
+        let run = |line: u64| {
+            let mut server = DapServer::new();
+            server.handle_initialize(1, "initialize".to_string(), None);
+            let mut ctx = DebugContext::new(MockShell::new());
+            ctx.set_mode(RunMode::Continue);
+            server.prepare_launch(Arc::new(Mutex::new(ctx)), &pre, &labels);
+            server.handle_set_breakpoints(
+                2,
+                "setBreakpoints".to_string(),
+                Some(json!({ "source": { "path": "" }, "breakpoints": [{ "line": line }] })),
+            );
+            assert!(!server.executor_running());
+            assert!(server.event_receiver.is_none());
+
+            server.handle_configuration_done(3, "configurationDone".to_string());
+            assert!(server.executor_running());
+            let frame_line = server.collect_stack_frames()[0]["line"].clone();
+            server.handle_terminate(4, "terminate".to_string());
+            frame_line
+        };
+
+        assert_eq!(run(2), json!(2));
+        assert_eq!(run(1), json!(1));
+    }
+
+    #[test]
+    fn test_dap_over_tcp_serves_reconnecting_clients() {
+        use batch_debugger::dap::{self, DapServer};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, DebugLog};
+        use serde_json::{json, Value};
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::{TcpListener, TcpStream};
+        use std::time::Duration;
+
+        let program =
+            std::env::temp_dir().join(format!("batch-debugger-tcp-{}.bat", std::process::id()));
+        std::fs::write(&program, "@echo off\r\necho hello\r\n").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            dap::serve_clients(listener, DebugLog::new(), || {
+                let mut server = DapServer::new();
+                server.set_session_factory(Box::new(|_options| {
+                    Ok(DebugContext::new(MockShell::new().respond(
+                        "echo hello",
+                        "hello\r\n",
+                        0,
+                    )))
+                }));
+                server
+            })
+        });
+
+        struct Client {
+            stream: TcpStream,
+            reader: BufReader<TcpStream>,
+            seq: u64,
+        }
+        impl Client {
+            fn connect(addr: std::net::SocketAddr) -> Self {
+                let stream = TcpStream::connect(addr).unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(10)))
+                    .unwrap();
+                let reader = BufReader::new(stream.try_clone().unwrap());
+                Client {
+                    stream,
+                    reader,
+                    seq: 0,
+                }
+            }
+            fn request(&mut self, command: &str, arguments: Value) {
+                self.seq += 1;
+                let body = json!({
+                    "seq": self.seq,
+                    "type": "request",
+                    "command": command,
+                    "arguments": arguments
+                })
+                .to_string();
+                write!(
+                    self.stream,
+                    "Content-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+            fn receive(&mut self) -> Value {
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    self.reader
+                        .read_line(&mut line)
+                        .expect("Adapter should answer");
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Content-Length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0u8; length];
+                self.reader.read_exact(&mut body).unwrap();
+                serde_json::from_slice(&body).unwrap()
+            }
+            // Skip messages up to the response to `command` or the event named `event`
+            fn wait_for(&mut self, kind: &str, name: &str) -> Value {
+                loop {
+                    let msg = self.receive();
+                    let key = if kind == "response" {
+                        "command"
+                    } else {
+                        "event"
+                    };
+                    if msg["type"] == kind && msg[key] == name {
+                        return msg;
+                    }
+                }
+            }
+        }
+
+        let mut client = Client::connect(addr);
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        assert_eq!(client.wait_for("response", "initialize")["success"], true);
+        client.request(
+            "launch",
+            json!({ "program": program.to_str().unwrap(), "stopOnEntry": true }),
+        );
+        assert_eq!(client.wait_for("response", "launch")["success"], true);
+        client.wait_for("event", "initialized");
+        client.request("configurationDone", json!({}));
+        client.wait_for("event", "stopped");
+        client.request("continue", json!({ "threadId": 1 }));
+        let mut printed = String::new();
+        loop {
+            let msg = client.receive();
+            if msg["event"] == "output" {
+                printed.push_str(msg["body"]["output"].as_str().unwrap());
+            }
+            if msg["event"] == "terminated" {
+                break;
+            }
+        }
+        assert!(printed.contains("hello"));
+        client.request("disconnect", json!({}));
+        assert_eq!(client.wait_for("response", "disconnect")["success"], true);
+        drop(client);
+
+        // The adapter takes the next client without being restarted
+        let mut client = Client::connect(addr);
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        assert_eq!(client.wait_for("response", "initialize")["success"], true);
+
+        let _ = std::fs::remove_file(&program);
+    }
+
+    #[test]
+    fn test_clients_attach_to_a_service_and_detach_without_ending_it() {
+        use batch_debugger::dap::{self, DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, DebugLog};
+        use serde_json::{json, Value};
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::{TcpListener, TcpStream};
+        use std::time::Duration;
+
+        // Nothing to attach to without a running script
+        let responses =
+            std::env::temp_dir().join(format!("batch-debugger-attach-{}.txt", std::process::id()));
+        let mut idle = DapServer::new();
+        idle.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        idle.handle_attach(1, "attach".to_string());
+        let written = std::fs::read_to_string(&responses).unwrap();
+        let (_, body) = written.split_once("\r\n\r\n").unwrap();
+        let response: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(response["success"], false);
+        assert!(response["message"]
+            .as_str()
+            .unwrap()
+            .contains("No script is running"));
+        let _ = std::fs::remove_file(&responses);
+
+        let program =
+            std::env::temp_dir().join(format!("batch-debugger-service-{}.bat", std::process::id()));
+        std::fs::write(
+            &program,
+            "@echo off\r\necho hello\r\nping -n 2 localhost\r\necho bye\r\n",
+        )
+        .unwrap();
+        let program_path = program.to_str().unwrap().to_string();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service_program = program_path.clone();
+        let service = std::thread::spawn(move || {
+            let mut server = DapServer::new();
+            server.set_session_factory(Box::new(|_options| {
+                Ok(DebugContext::new(
+                    MockShell::new().delay("ping", Duration::from_secs(2)),
+                ))
+            }));
+            server.set_transport(Box::new(StreamTransport::new(
+                std::io::empty(),
+                std::io::sink(),
+            )));
+            // Set before the service starts, bound when it launches
+            server.handle_set_breakpoints(
+                0,
+                "setBreakpoints".to_string(),
+                Some(json!({ "source": { "path": service_program }, "breakpoints": [{ "line": 2 }] })),
+            );
+            server
+                .start_service(json!({ "program": service_program, "stopOnEntry": false }))
+                .unwrap();
+            dap::serve_attached_clients(listener, DebugLog::new(), &mut server)
+        });
+
+        struct Client {
+            stream: TcpStream,
+            reader: BufReader<TcpStream>,
+            seq: u64,
+        }
+        impl Client {
+            fn connect(addr: std::net::SocketAddr) -> Self {
+                let stream = TcpStream::connect(addr).unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(10)))
+                    .unwrap();
+                let reader = BufReader::new(stream.try_clone().unwrap());
+                Client {
+                    stream,
+                    reader,
+                    seq: 0,
+                }
+            }
+            fn request(&mut self, command: &str, arguments: Value) {
+                self.seq += 1;
+                let body = json!({
+                    "seq": self.seq,
+                    "type": "request",
+                    "command": command,
+                    "arguments": arguments
+                })
+                .to_string();
+                write!(
+                    self.stream,
+                    "Content-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+            fn receive(&mut self) -> Value {
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    self.reader
+                        .read_line(&mut line)
+                        .expect("Adapter should answer");
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Content-Length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0u8; length];
+                self.reader.read_exact(&mut body).unwrap();
+                serde_json::from_slice(&body).unwrap()
+            }
+            // Messages up to and including the event named `event`
+            fn until_event(&mut self, event: &str) -> Vec<Value> {
+                let mut received = Vec::new();
+                loop {
+                    let msg = self.receive();
+                    let done = msg["type"] == "event" && msg["event"] == event;
+                    received.push(msg);
+                    if done {
+                        return received;
+                    }
+                }
+            }
+        }
+        let attach = |client: &mut Client| {
+            client.request("initialize", json!({ "adapterID": "batch" }));
+            client.request("attach", json!({}));
+            let received = client.until_event("initialized");
+            let attached = received
+                .iter()
+                .find(|m| m["command"] == "attach")
+                .expect("attach should be answered");
+            assert_eq!(attached["success"], true);
+            let breakpoints: Vec<Value> = received
+                .iter()
+                .filter(|m| m["event"] == "breakpoint")
+                .map(|m| m["body"]["breakpoint"].clone())
+                .collect();
+            assert_eq!(breakpoints.len(), 1);
+            assert_eq!(breakpoints[0]["line"], 2);
+            client.request("configurationDone", json!({}));
+        };
+
+        // The script waits at its breakpoint; the client is told so
+        let mut client = Client::connect(addr);
+        attach(&mut client);
+        let stopped = client.until_event("stopped");
+        assert_eq!(stopped.last().unwrap()["body"]["reason"], "breakpoint");
+        client.request("stackTrace", json!({ "threadId": 1 }));
+        let frames = loop {
+            let msg = client.receive();
+            if msg["command"] == "stackTrace" {
+                break msg;
+            }
+        };
+        assert_eq!(frames["body"]["stackFrames"][0]["line"], 2);
+
+        // Detaching lets it run on
+        client.request("disconnect", json!({}));
+        loop {
+            if client.receive()["command"] == "disconnect" {
+                break;
+            }
+        }
+        drop(client);
+
+        // Attached again while it runs: no stop, and it ends for this client
+        let mut client = Client::connect(addr);
+        attach(&mut client);
+        let received = client.until_event("terminated");
+        assert!(!received.iter().any(|m| m["event"] == "stopped"));
+        let printed: String = received
+            .iter()
+            .filter(|m| m["event"] == "output")
+            .filter_map(|m| m["body"]["output"].as_str())
+            .collect();
+        assert!(!printed.contains("hello"), "{}", printed);
+        client.request("disconnect", json!({}));
+        drop(client);
+
+        service.join().unwrap().unwrap();
+        let _ = std::fs::remove_file(&program);
+    }
+
+    #[test]
+    fn test_back_to_back_requests_all_arrive() {
+        use batch_debugger::dap::{DapMessageContent, DapServer, StreamTransport};
+        use serde_json::json;
+        use std::io::Cursor;
+
+        let framed = |headers: &str, command: &str, seq: u64, arguments: serde_json::Value| {
+            let body = json!({
+                "seq": seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments
+            })
+            .to_string();
+            format!("{}Content-Length: {}\r\n\r\n{}", headers, body.len(), body)
+        };
+        // Bigger than a single buffered read
+        let breakpoints: Vec<_> = (1..2000).map(|line| json!({ "line": line })).collect();
+
+        // One burst, like VS Code sends after launch
+        let mut burst = framed(
+            "",
+            "setBreakpoints",
+            1,
+            json!({ "source": { "path": "C:/scripts/burst.bat" }, "breakpoints": breakpoints }),
+        );
+        burst.push_str(&framed(
+            "Content-Type: application/vscode-jsonrpc; charset=utf-8\r\n",
+            "configurationDone",
+            2,
+            json!({}),
+        ));
+        burst.push_str(
+            &framed("", "threads", 3, json!({})).replace("Content-Length", "content-length"),
+        );
+
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            Cursor::new(burst.into_bytes()),
+            std::io::sink(),
+        )));
+        let mut received = Vec::new();
+        while let Some(msg) = server.read_message() {
+            if let DapMessageContent::Request { command, arguments } = msg.content {
+                received.push((msg.seq, command, arguments));
+            }
+        }
+        let commands: Vec<_> = received
+            .iter()
+            .map(|(seq, c, _)| (*seq, c.as_str()))
+            .collect();
+        assert_eq!(
+            commands,
+            [
+                (1, "setBreakpoints"),
+                (2, "configurationDone"),
+                (3, "threads")
+            ]
+        );
+        assert_eq!(
+            received[0].2.as_ref().unwrap()["breakpoints"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1999
+        );
+    }
+
+    #[test]
+    fn test_bad_content_length_closes_the_connection() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use std::io::Cursor;
+
+        let threads = r#"{"seq":2,"type":"request","command":"threads"}"#;
+        for length in ["abc", "-1", "99999999999999"] {
+            // Without a length the body can't be skipped, so nothing after
+            // it can be trusted
+            let burst = format!(
+                "Content-Length: {}\r\n\r\n{{}}\r\nContent-Length: {}\r\n\r\n{}",
+                length,
+                threads.len(),
+                threads
+            );
+            let mut server = DapServer::new();
+            server.set_transport(Box::new(StreamTransport::new(
+                Cursor::new(burst.into_bytes()),
+                std::io::sink(),
+            )));
+            assert!(server.read_message().is_none(), "{}", length);
+        }
+    }
+
+    #[test]
+    fn test_unknown_request_gets_failed_response() {
+        use batch_debugger::dap::{self, DapServer, StreamTransport};
+        use batch_debugger::debugger::DebugLog;
+        use serde_json::{json, Value};
+        use std::io::{self, Write};
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Captured(Arc<Mutex<Vec<u8>>>);
+        impl Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let captured = Captured::default();
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            io::empty(),
+            captured.clone(),
+        )));
+        let log = DebugLog::new();
+
+        assert!(dap::handle_request(
+            &mut server,
+            &log,
+            7,
+            "frobnicate".to_string(),
+            None
+        ));
+        assert!(dap::handle_request(
+            &mut server,
+            &log,
+            8,
+            "setFunctionBreakpoints".to_string(),
+            Some(json!({ "breakpoints": [] })),
+        ));
+
+        let written = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let responses: Vec<Value> = written
+            .split("Content-Length: ")
+            .filter_map(|framed| framed.split_once("\r\n\r\n"))
+            .map(|(_, body)| serde_json::from_str(body).unwrap())
+            .collect();
+        assert_eq!(responses.len(), 2);
+        for (response, (seq, command)) in responses
+            .iter()
+            .zip([(7, "frobnicate"), (8, "setFunctionBreakpoints")])
+        {
+            assert_eq!(response["type"], "response");
+            assert_eq!(response["request_seq"], seq);
+            assert_eq!(response["command"], command);
+            assert_eq!(response["success"], false);
+            assert_eq!(response["message"], "unsupported");
+        }
+    }
+
+    #[test]
+    fn test_breakpoints_bind_per_source() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{source_key, DebugContext};
+        use serde_json::{json, Value};
+        use std::sync::{Arc, Mutex};
+
+        let dir =
+            std::env::temp_dir().join(format!("batch-debugger-sources-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.bat");
+        let helper = dir.join("helper.bat");
+        std::fs::write(&main, "@echo off\r\necho main\r\ncall helper.bat\r\n").unwrap();
+        std::fs::write(
+            &helper,
+            "@echo off\r\n\r\n:: comment\r\n\r\necho helper\r\n",
+        )
+        .unwrap();
+
+        let contents = std::fs::read_to_string(&main).unwrap();
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let mut server = DapServer::new();
+        let responses = dir.join("responses.txt");
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        server.set_program(main.to_str().unwrap(), pre);
+        let ctx_arc = Arc::new(Mutex::new(DebugContext::new(MockShell::new())));
+        server.set_context(ctx_arc.clone());
+
+        let set = |server: &mut DapServer, path: &str, line: u64| {
+            server.handle_set_breakpoints(
+                1,
+                "setBreakpoints".to_string(),
+                Some(json!({ "source": { "path": path }, "breakpoints": [{ "line": line }] })),
+            );
+        };
+        set(&mut server, main.to_str().unwrap(), 2);
+
+        // A source that can't be read is refused, not mapped through main.bat
+        let missing = dir.join("missing.bat");
+        set(&mut server, missing.to_str().unwrap(), 2);
+        // Another file maps through its own lines: line 2 moves to its echo on 5
+        set(&mut server, helper.to_str().unwrap(), 2);
+
+        let ctx = ctx_arc.lock().unwrap();
+        assert!(
+            ctx.get_breakpoint(1).is_some(),
+            "main.bat keeps its breakpoint"
+        );
+        assert!(ctx.get_breakpoint(2).is_none());
+        assert!(ctx.get_breakpoint_in(&source_key(&helper), 4).is_some());
+        assert!(ctx.get_breakpoint_in(&source_key(&missing), 1).is_none());
+        drop(ctx);
+
+        let written = std::fs::read_to_string(&responses).unwrap();
+        let verified: Vec<Value> = written
+            .split("Content-Length: ")
+            .filter_map(|framed| framed.split_once("\r\n\r\n"))
+            .map(|(_, body)| serde_json::from_str::<Value>(body).unwrap())
+            .map(|response| response["body"]["breakpoints"][0].clone())
+            .collect();
+        assert_eq!(verified[0]["verified"], true);
+        assert_eq!(verified[1]["verified"], false);
+        assert!(verified[1]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Cannot read this file"));
+        assert_eq!(verified[2]["verified"], true);
+        assert_eq!(verified[2]["line"], 5);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_stopped_event_names_hit_breakpoint() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use serde_json::{json, Value};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let content = "@echo off\r\necho one\r\necho two\r\necho three\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let responses =
+            std::env::temp_dir().join(format!("batch-debugger-hit-ids-{}.txt", std::process::id()));
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        server.set_program("hits.bat", pre.clone());
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.set_mode(RunMode::Continue);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        server.set_context(ctx_arc.clone());
+        server.handle_set_breakpoints(
+            1,
+            "setBreakpoints".to_string(),
+            Some(json!({
+                "source": { "path": "hits.bat" },
+                "breakpoints": [{ "line": 2 }, { "line": 4, "condition": "1==1" }]
+            })),
+        );
+        let written = std::fs::read_to_string(&responses).unwrap();
+        let (_, body) = written.split_once("\r\n\r\n").unwrap();
+        let response: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(response["body"]["breakpoints"][0]["id"], 1);
+        assert_eq!(response["body"]["breakpoints"][1]["id"], 2);
+
+        server.start_executor(ctx_arc.clone(), &pre, &labels);
+        let next_stop = |server: &mut DapServer| {
+            let stop = server
+                .event_receiver
+                .as_ref()
+                .unwrap()
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Executor should stop");
+            server.stopped_body(&stop)
+        };
+
+        let body = next_stop(&mut server);
+        assert_eq!(body["reason"], "breakpoint");
+        assert_eq!(body["hitBreakpointIds"], json!([1]));
+        assert_eq!(body["description"], "Paused on breakpoint at line 2");
+
+        server.handle_continue(2, "continue".to_string());
+        let body = next_stop(&mut server);
+        assert_eq!(body["hitBreakpointIds"], json!([2]));
+        assert_eq!(body["description"], "Paused on breakpoint at line 4 (1==1)");
+
+        server.handle_terminate(3, "terminate".to_string());
+        let _ = std::fs::remove_file(&responses);
+    }
+
+    #[test]
+    fn test_variables_are_paged_and_long_values_truncated() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+        use serde_json::json;
+        use std::sync::{Arc, Mutex};
+
+        let long_path = "C:\\Tools\\bin;".repeat(800);
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.import_environment((0..50).map(|i| {
+            // Mixed case, sorted as if it weren't
+            let name = if i % 2 == 0 {
+                format!("var{:02}", i)
+            } else {
+                format!("VAR{:02}", i)
+            };
+            (name, i.to_string())
+        }));
+        ctx.import_environment([("PATH".to_string(), long_path.clone())]);
+        let mut server = DapServer::new();
+        server.set_context(Arc::new(Mutex::new(ctx)));
+
+        let all = server.collect_variables(2);
+        let first = all.iter().position(|v| v["name"] == "var00").unwrap();
+        let page = server.variables_page(2, Some(&json!({ "start": first + 20, "count": 10 })));
+        let names: Vec<_> = page.iter().map(|v| v["name"].as_str().unwrap()).collect();
+        assert_eq!(
+            names,
+            [
+                "var20", "VAR21", "var22", "VAR23", "var24", "VAR25", "var26", "VAR27", "var28",
+                "VAR29"
+            ]
+        );
+        assert_eq!(
+            server.variables_page(2, Some(&json!({ "count": 0 }))).len(),
+            all.len()
+        );
+        assert!(server
+            .variables_page(2, Some(&json!({ "filter": "indexed" })))
+            .is_empty());
+
+        let global = server
+            .collect_scopes(None)
+            .into_iter()
+            .find(|s| s["name"] == "Global")
+            .unwrap();
+        assert_eq!(global["namedVariables"], all.len());
+
+        // PATH shows its first 2000 characters and expands to the rest
+        let path = all.iter().find(|v| v["name"] == "PATH").unwrap();
+        let shown = path["value"].as_str().unwrap();
+        assert_eq!(shown.chars().count(), 2001);
+        assert!(shown.ends_with('…'));
+        let full = server.collect_variables(path["variablesReference"].as_u64().unwrap());
+        assert_eq!(full.len(), 1);
+        assert_eq!(full[0]["value"], long_path);
+    }
+
+    #[test]
+    fn test_source_request_serves_preprocessed_program() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+        use serde_json::{json, Value};
+        use std::sync::{Arc, Mutex};
+
+        let content = "@echo off\r\necho one ^\r\n  two\r\nif 1==1 (\r\n  echo three\r\n)\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let joined = pre.logical[1].text.clone();
+        assert!(joined.contains("two"));
+
+        let responses =
+            std::env::temp_dir().join(format!("batch-debugger-source-{}.txt", std::process::id()));
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        server.set_program("joined.bat", pre);
+        server.set_context(Arc::new(Mutex::new(DebugContext::new(MockShell::new()))));
+        server.set_show_preprocessed(true);
+
+        let frame = server.collect_stack_frames()[0].clone();
+        let reference = frame["source"]["sourceReference"].clone();
+        assert_eq!(frame["line"], 1);
+        server.handle_source(
+            1,
+            "source".to_string(),
+            Some(
+                json!({ "source": { "sourceReference": reference }, "sourceReference": reference }),
+            ),
+        );
+        server.handle_source(
+            2,
+            "source".to_string(),
+            Some(json!({ "sourceReference": 99 })),
+        );
+
+        let written = std::fs::read_to_string(&responses).unwrap();
+        let bodies: Vec<Value> = written
+            .split("Content-Length: ")
+            .filter_map(|framed| framed.split_once("\r\n\r\n"))
+            .map(|(_, body)| serde_json::from_str(body).unwrap())
+            .collect();
+        let source = bodies[0]["body"]["content"].as_str().unwrap();
+        let lines: Vec<&str> = source.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[1].starts_with("L2-3"));
+        assert!(lines[1].ends_with(&joined));
+        assert!(lines[3].contains("d1 | "));
+        assert_eq!(bodies[1]["success"], false);
+
+        let _ = std::fs::remove_file(&responses);
+    }
+
+    #[test]
+    fn test_breakpoint_locations_skip_labels_and_comments() {
+        use batch_debugger::dap::DapServer;
+        use serde_json::json;
+
+        let content = "@echo off\r\n:start\r\necho hello\r\n:: note\r\n\r\nif 1==1 (\r\n  echo inside\r\n)\r\necho a ^\r\n  b\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+
+        let mut server = DapServer::new();
+        let range = json!({ "source": { "path": "locations.bat" }, "line": 2, "endLine": 3 });
+        assert!(server.breakpoint_locations(Some(&range)).is_empty());
+
+        server.set_program("locations.bat", pre);
+        assert_eq!(
+            server.breakpoint_locations(Some(&range)),
+            [json!({ "line": 3 })]
+        );
+
+        let lines: Vec<_> = server
+            .breakpoint_locations(Some(
+                &json!({ "source": { "path": "locations.bat" }, "line": 1, "endLine": 10 }),
+            ))
+            .iter()
+            .map(|l| l["line"].as_u64().unwrap())
+            .collect();
+        assert_eq!(lines, [1, 3, 6, 9]);
+    }
+
+    #[test]
+    fn test_goto_moves_the_next_statement_within_the_frame() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use serde_json::{json, Value};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let content = "@echo off\r\necho one\r\ncall :sub\r\necho four\r\necho five\r\n\
+                       goto :eof\r\n:sub\r\necho in sub\r\ngoto :eof\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let responses =
+            std::env::temp_dir().join(format!("batch-debugger-goto-{}.txt", std::process::id()));
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        server.set_program("goto.bat", pre.clone());
+        let shell = MockShell::new();
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+        ctx.add_breakpoint(4);
+        ctx.add_breakpoint(7);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        server.set_context(ctx_arc.clone());
+        server.start_executor(ctx_arc.clone(), &pre, &labels);
+        let next_stop = |server: &mut DapServer| {
+            server
+                .event_receiver
+                .as_ref()
+                .unwrap()
+                .recv_timeout(Duration::from_secs(5))
+                .map(|stop| (stop.reason, stop.pc))
+                .expect("Executor should stop")
+        };
+        let last_response = || {
+            let written = std::fs::read_to_string(&responses).unwrap();
+            let (_, body) = written
+                .rsplit("Content-Length: ")
+                .next()
+                .unwrap()
+                .split_once("\r\n\r\n")
+                .unwrap();
+            serde_json::from_str::<Value>(body).unwrap()
+        };
+        let goto_targets = |server: &mut DapServer, seq: u64, line: u64| {
+            server.handle_goto_targets(
+                seq,
+                "gotoTargets".to_string(),
+                Some(json!({ "source": { "path": "goto.bat" }, "line": line })),
+            );
+            last_response()
+        };
+
+        // Inside :sub the script can't leave the routine, nor land on a label
+        assert_eq!(next_stop(&mut server), ("breakpoint".to_string(), 7));
+        let response = goto_targets(&mut server, 1, 4);
+        assert_eq!(response["success"], false);
+        assert!(response["message"].as_str().unwrap().contains(":sub"));
+        assert_eq!(goto_targets(&mut server, 2, 7)["success"], false);
+
+        server.handle_continue(3, "continue".to_string());
+        assert_eq!(next_stop(&mut server), ("breakpoint".to_string(), 4));
+        let response = goto_targets(&mut server, 4, 2);
+        assert_eq!(response["success"], true);
+        assert_eq!(response["body"]["targets"][0]["line"], 2);
+        let target = response["body"]["targets"][0]["id"].clone();
+
+        // The paused line doesn't run; the script stops at the target
+        server.handle_goto(5, "goto".to_string(), Some(json!({ "targetId": target })));
+        assert_eq!(last_response()["success"], true);
+        assert_eq!(next_stop(&mut server), ("goto".to_string(), 1));
+        let frames = server.collect_stack_frames();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0]["line"], 2);
+
+        server.handle_next(6, "next".to_string(), None);
+        next_stop(&mut server);
+        let ran = commands.lock().unwrap().clone();
+        assert_eq!(
+            ran.iter().filter(|c| *c == "echo one").count(),
+            2,
+            "{:?}",
+            ran
+        );
+        assert!(!ran.iter().any(|c| c == "echo five"), "{:?}", ran);
+
+        server.handle_terminate(7, "terminate".to_string());
+        let _ = std::fs::remove_file(&responses);
+    }
+
+    #[test]
+    fn test_set_expression_assigns_plain_variables() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use serde_json::{json, Value};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let content =
+            "@echo off\r\nset COUNT=1\r\nif %COUNT% EQU 7 (echo seven) else (echo other)\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let responses = std::env::temp_dir().join(format!(
+            "batch-debugger-set-expression-{}.txt",
+            std::process::id()
+        ));
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        server.set_program("count.bat", pre.clone());
+        let shell = MockShell::new();
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+        ctx.add_breakpoint(2);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        server.set_context(ctx_arc.clone());
+        server.start_executor(ctx_arc.clone(), &pre, &labels);
+        let events = server.event_receiver.take().unwrap();
+        events
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Executor should stop at the IF");
+
+        let set_expression = |server: &mut DapServer, seq: u64, expression: &str, value: &str| {
+            server.handle_set_expression(
+                seq,
+                "setExpression".to_string(),
+                Some(json!({ "expression": expression, "value": value, "frameId": 0 })),
+            );
+            let written = std::fs::read_to_string(&responses).unwrap();
+            let (_, body) = written
+                .rsplit("Content-Length: ")
+                .next()
+                .unwrap()
+                .split_once("\r\n\r\n")
+                .unwrap();
+            serde_json::from_str::<Value>(body).unwrap()
+        };
+
+        let response = set_expression(&mut server, 1, "%COUNT:~0,1%", "2");
+        assert_eq!(response["success"], false);
+        assert!(response["message"].as_str().unwrap().contains("substring"));
+        assert_eq!(
+            set_expression(&mut server, 2, "%A% & %B%", "2")["success"],
+            false
+        );
+        assert_eq!(
+            set_expression(&mut server, 3, "ERRORLEVEL", "2")["success"],
+            false
+        );
+
+        let response = set_expression(&mut server, 4, "%COUNT%", "7");
+        assert_eq!(response["success"], true);
+        assert_eq!(response["body"]["value"], "7");
+        let variables = server.collect_variables(2);
+        assert!(variables
+            .iter()
+            .any(|v| v["name"] == "COUNT" && v["value"] == "7"));
+
+        server.handle_continue(5, "continue".to_string());
+        while events.recv_timeout(Duration::from_secs(5)).is_ok() {}
+        let ran = commands.lock().unwrap().clone();
+        assert!(ran.iter().any(|c| c == "echo seven"), "{:?}", ran);
+        assert!(!ran.iter().any(|c| c == "echo other"), "{:?}", ran);
+
+        server.handle_terminate(6, "terminate".to_string());
+        let _ = std::fs::remove_file(&responses);
+    }
+
+    #[test]
+    fn test_completions_follow_the_cursor() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+        use std::sync::{Arc, Mutex};
+
+        let content = "@echo off\r\ncall :build\r\ngoto :eof\r\n:build\r\necho building\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.set_variable("BUILD_MODE", "release").unwrap();
+        ctx.set_variable("BUNDLE", "1").unwrap();
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let mut server = DapServer::new();
+        server.prepare_launch(ctx_arc, &pre, &labels);
+
+        let texts = |targets: &[serde_json::Value]| -> Vec<String> {
+            targets
+                .iter()
+                .map(|t| t["text"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        // Inside %...% the word before the cursor is replaced by a variable
+        let targets = server.completions("echo %BU", 9);
+        assert_eq!(texts(&targets), vec!["BUILD_MODE", "BUNDLE"]);
+        assert_eq!(targets[0]["type"], "variable");
+        assert_eq!(targets[0]["start"], 7);
+        assert_eq!(targets[0]["length"], 2);
+
+        // Only the text before the cursor counts
+        let targets = server.completions("echo %BUI% done", 10);
+        assert_eq!(texts(&targets), vec!["BUILD_MODE"]);
+        assert!(server.completions("echo %BU% done", 12).is_empty());
+
+        assert_eq!(texts(&server.completions("call :b", 8)), vec!["build"]);
+        assert_eq!(texts(&server.completions("goto ", 6)), vec!["build", "eof"]);
+        let targets = server.completions("  ec", 5);
+        assert_eq!(texts(&targets), vec!["ECHO"]);
+        assert_eq!(targets[0]["type"], "keyword");
+        assert_eq!(targets[0]["start"], 3);
+    }
+
+    #[test]
+    fn test_cancel_answers_a_slow_evaluation_right_away() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+        use serde_json::{json, Value};
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, Instant};
+
+        let responses =
+            std::env::temp_dir().join(format!("batch-debugger-cancel-{}.txt", std::process::id()));
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        let shell = MockShell::new()
+            .delay("ping", Duration::from_secs(30))
+            .respond("echo ready", "ready", 0);
+        let commands = shell.commands();
+        server.set_context(Arc::new(Mutex::new(DebugContext::new(shell))));
+        let response_to = |request_seq: u64| -> Vec<Value> {
+            std::fs::read_to_string(&responses)
+                .unwrap()
+                .split("Content-Length: ")
+                .filter_map(|m| m.split_once("\r\n\r\n"))
+                .map(|(_, body)| serde_json::from_str::<Value>(body).unwrap())
+                .filter(|m| m["request_seq"] == request_seq)
+                .collect()
+        };
+        let evaluate = |server: &mut DapServer, seq: u64, expression: &str| {
+            server.handle_evaluate(
+                seq,
+                "evaluate".to_string(),
+                Some(json!({ "expression": expression, "context": "repl" })),
+            );
+        };
+
+        // The slow command runs on a worker; requests keep being handled
+        let started = Instant::now();
+        evaluate(&mut server, 1, "ping -n 30 localhost");
+        assert!(server.request_in_flight(1));
+        while !commands.lock().unwrap().iter().any(|c| c.contains("ping")) {
+            assert!(started.elapsed() < Duration::from_secs(2));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        server.handle_cancel(2, "cancel".to_string(), Some(json!({ "requestId": 1 })));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let cancelled = response_to(1);
+        assert_eq!(cancelled.len(), 1);
+        assert_eq!(cancelled[0]["success"], false);
+        assert_eq!(cancelled[0]["message"], "cancelled");
+        assert_eq!(response_to(2)[0]["success"], true);
+
+        // The interrupted command's result is dropped, and the session is
+        // free for the next evaluation
+        evaluate(&mut server, 3, "echo ready");
+        while server.request_in_flight(3) {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            server.check_finished_requests();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!server.request_in_flight(1));
+        assert_eq!(response_to(1).len(), 1);
+        let ready = response_to(3);
+        assert_eq!(ready[0]["success"], true);
+        assert_eq!(ready[0]["body"]["result"], "ready");
+        let _ = std::fs::remove_file(&responses);
+    }
+
+    #[test]
+    fn test_requests_are_answered_while_an_evaluation_runs() {
+        use batch_debugger::dap::{self, DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, DebugLog};
+        use serde_json::{json, Value};
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::{TcpListener, TcpStream};
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, Instant};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let adapter = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut server = DapServer::new();
+            server.set_transport(Box::new(StreamTransport::new(
+                stream.try_clone().unwrap(),
+                stream,
+            )));
+            let shell = MockShell::new()
+                .delay("ping", Duration::from_secs(30))
+                .respond("echo ready", "ready", 0);
+            server.set_context(Arc::new(Mutex::new(DebugContext::new(shell))));
+            dap::run_session(&mut server, &DebugLog::new());
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request = |seq: u64, command: &str, arguments: Value| {
+            let body = json!({
+                "seq": seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments
+            })
+            .to_string();
+            write!(stream, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        };
+        let mut response = || -> Value {
+            loop {
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).expect("Adapter should answer");
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Content-Length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).unwrap();
+                let message: Value = serde_json::from_slice(&body).unwrap();
+                if message["type"] == "response" {
+                    return message;
+                }
+            }
+        };
+
+        // While the slow command runs, other requests are still answered
+        let started = Instant::now();
+        request(
+            1,
+            "evaluate",
+            json!({ "expression": "ping -n 30 localhost", "context": "repl" }),
+        );
+        std::thread::sleep(Duration::from_millis(200));
+        request(2, "variables", json!({ "variablesReference": 2 }));
+        let variables = response();
+        assert_eq!(variables["request_seq"], 2);
+        assert_eq!(variables["success"], true);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        request(3, "cancel", json!({ "requestId": 1 }));
+        let cancelled = response();
+        assert_eq!(cancelled["request_seq"], 1);
+        assert_eq!(cancelled["message"], "cancelled");
+        assert_eq!(response()["request_seq"], 3);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // The interrupted command's result is dropped; the next one runs
+        request(
+            4,
+            "evaluate",
+            json!({ "expression": "echo ready", "context": "repl" }),
+        );
+        let ready = response();
+        assert_eq!(ready["request_seq"], 4);
+        assert_eq!(ready["body"]["result"], "ready");
+
+        request(5, "disconnect", json!({}));
+        assert_eq!(response()["request_seq"], 5);
+        adapter.join().unwrap();
+    }
+
+    #[test]
+    fn test_lines_follow_the_clients_numbering() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use serde_json::{json, Value};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let content = "@echo off\r\n:start\r\necho one\r\necho two\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        // The label on the second line moves the breakpoint to `echo one`,
+        // the third line, however the client counts
+        for (start_at1, first) in [(true, 1), (false, 0)] {
+            let responses = std::env::temp_dir().join(format!(
+                "batch-debugger-lines-{}-{}.txt",
+                first,
+                std::process::id()
+            ));
+            let mut server = DapServer::new();
+            server.set_transport(Box::new(StreamTransport::new(
+                std::io::empty(),
+                std::fs::File::create(&responses).unwrap(),
+            )));
+            server.handle_initialize(
+                1,
+                "initialize".to_string(),
+                Some(json!({ "linesStartAt1": start_at1, "columnsStartAt1": start_at1 })),
+            );
+            server.set_program("lines.bat", pre.clone());
+            let mut ctx = DebugContext::new(MockShell::new());
+            ctx.set_mode(RunMode::Continue);
+            let ctx_arc = Arc::new(Mutex::new(ctx));
+            server.set_context(ctx_arc.clone());
+            server.handle_set_breakpoints(
+                2,
+                "setBreakpoints".to_string(),
+                Some(json!({
+                    "source": { "path": "lines.bat" },
+                    "breakpoints": [{ "line": first + 1 }]
+                })),
+            );
+            let written = std::fs::read_to_string(&responses).unwrap();
+            let (_, body) = written
+                .rsplit("Content-Length: ")
+                .next()
+                .unwrap()
+                .split_once("\r\n\r\n")
+                .unwrap();
+            let response: Value = serde_json::from_str(body).unwrap();
+            assert_eq!(response["body"]["breakpoints"][0]["line"], first + 2);
+            let locations = server.breakpoint_locations(Some(&json!({
+                "source": { "path": "lines.bat" },
+                "line": first,
+                "endLine": first + 3
+            })));
+            assert_eq!(
+                locations,
+                vec![
+                    json!({ "line": first }),
+                    json!({ "line": first + 2 }),
+                    json!({ "line": first + 3 })
+                ]
+            );
+
+            server.start_executor(ctx_arc.clone(), &pre, &labels);
+            let stop = server
+                .event_receiver
+                .as_ref()
+                .unwrap()
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Executor should stop at the breakpoint");
+            assert_eq!((stop.reason.as_str(), stop.pc), ("breakpoint", 2));
+            let frames = server.collect_stack_frames();
+            assert_eq!(frames[0]["line"], first + 2);
+            assert_eq!(frames[0]["column"], first);
+            assert_eq!(
+                server.stopped_body(&stop)["description"],
+                "Paused on breakpoint at line 3"
+            );
+
+            server.handle_terminate(3, "terminate".to_string());
+            let _ = std::fs::remove_file(&responses);
+        }
+    }
+
+    #[test]
+    fn test_lifecycle_events_arrive_in_order_once() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+        use serde_json::{json, Value};
+
+        let program = std::env::temp_dir().join(format!(
+            "batch-debugger-lifecycle-{}.bat",
+            std::process::id()
+        ));
+        std::fs::write(&program, "@echo off\r\nexit /b 3\r\n").unwrap();
+        let messages = std::env::temp_dir().join(format!(
+            "batch-debugger-lifecycle-{}.txt",
+            std::process::id()
+        ));
+
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&messages).unwrap(),
+        )));
+        server.set_session_factory(Box::new(|_options| Ok(DebugContext::new(MockShell::new()))));
+        server.handle_initialize(1, "initialize".to_string(), None);
+        server.handle_launch(
+            2,
+            "launch".to_string(),
+            Some(json!({ "program": program.display().to_string(), "stopOnEntry": false })),
+        );
+        server.handle_configuration_done(3, "configurationDone".to_string());
+        // The executor already reported the end; disconnecting must not
+        // announce it again
+        server.handle_disconnect(4, "disconnect".to_string(), None);
+
+        let events: Vec<Value> = std::fs::read_to_string(&messages)
+            .unwrap()
+            .split("Content-Length: ")
+            .filter_map(|m| m.split_once("\r\n\r\n"))
+            .map(|(_, body)| serde_json::from_str::<Value>(body).unwrap())
+            .filter(|m| m["type"] == "event" && m["event"] != "output")
+            .filter(|m| !m["event"].as_str().unwrap().starts_with("batch/"))
+            .collect();
+        let names: Vec<String> = events
+            .iter()
+            .map(|e| match e["body"]["reason"].as_str() {
+                Some(reason) if e["event"] == "thread" => format!("thread {}", reason),
+                _ => e["event"].as_str().unwrap().to_string(),
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "process",
+                "initialized",
+                "thread started",
+                "thread exited",
+                "exited",
+                "terminated"
+            ]
+        );
+        assert_eq!(events[0]["body"]["name"], program.display().to_string());
+        assert_eq!(events[0]["body"]["startMethod"], "launch");
+        assert!(events[0]["body"].get("pointerSize").is_none());
+        assert_eq!(events[4]["body"]["exitCode"], 3);
+
+        let _ = std::fs::remove_file(&program);
+        let _ = std::fs::remove_file(&messages);
+    }
+
+    #[test]
+    fn test_long_for_loop_reports_progress_before_stopping() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use serde_json::{json, Value};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let content = "@echo off\r\nfor /L %%i in (1,1,500) do echo %%i\r\necho done\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let messages = std::env::temp_dir().join(format!(
+            "batch-debugger-progress-{}.txt",
+            std::process::id()
+        ));
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&messages).unwrap(),
+        )));
+        server.handle_initialize(
+            1,
+            "initialize".to_string(),
+            Some(json!({ "supportsProgressReporting": true })),
+        );
+        server.set_program("progress.bat", pre.clone());
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.set_mode(RunMode::Continue);
+        ctx.add_breakpoint(2);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        server.set_context(ctx_arc.clone());
+        server.start_executor(ctx_arc.clone(), &pre, &labels);
+
+        // Passed on as run_session does
+        let next_event = |server: &mut DapServer| {
+            let stop = server
+                .event_receiver
+                .as_ref()
+                .unwrap()
+                .recv_timeout(Duration::from_secs(10))
+                .expect("Executor should report");
+            match stop.reason.as_str() {
+                "terminated" => server.send_terminated(),
+                _ => server.send_stopped(&stop),
+            }
+        };
+        next_event(&mut server);
+        server.handle_continue(2, "continue".to_string());
+        next_event(&mut server);
+
+        let events: Vec<String> = std::fs::read_to_string(&messages)
+            .unwrap()
+            .split("Content-Length: ")
+            .filter_map(|m| m.split_once("\r\n\r\n"))
+            .map(|(_, body)| serde_json::from_str::<Value>(body).unwrap())
+            .filter(|m| m["type"] == "event")
+            .map(|m| m["event"].as_str().unwrap().to_string())
+            .filter(|e| e.starts_with("progress") || e == "stopped" || e == "terminated")
+            .collect();
+        let updates = events.iter().filter(|e| *e == "progressUpdate").count();
+        assert!(updates >= 1, "{:?}", events);
+        let mut expected = vec!["progressStart".to_string()];
+        expected.extend(std::iter::repeat_n("progressUpdate".to_string(), updates));
+        expected.extend(["progressEnd", "stopped", "terminated"].map(String::from));
+        assert_eq!(events, expected);
+
+        server.handle_terminate(3, "terminate".to_string());
+        let _ = std::fs::remove_file(&messages);
+    }
+
+    #[test]
+    fn test_breakpoints_set_before_launch_bind_once_it_is_ready() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use serde_json::{json, Value};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let content = "@echo off\r\n\r\nset A=1\r\necho %A%\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let responses = std::env::temp_dir().join(format!(
+            "batch-debugger-pending-breakpoints-{}.txt",
+            std::process::id()
+        ));
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        let messages = || -> Vec<Value> {
+            std::fs::read_to_string(&responses)
+                .unwrap()
+                .split("Content-Length: ")
+                .filter_map(|m| m.split_once("\r\n\r\n"))
+                .map(|(_, body)| serde_json::from_str::<Value>(body).unwrap())
+                .collect()
+        };
+
+        // Nothing to bind to yet: the breakpoints wait, with ids
+        server.handle_set_breakpoints(
+            1,
+            "setBreakpoints".to_string(),
+            Some(json!({
+                "source": { "path": "pending.bat" },
+                "breakpoints": [{ "line": 2 }, { "line": 40 }]
+            })),
+        );
+        let response = messages().pop().unwrap();
+        let pending = response["body"]["breakpoints"].as_array().unwrap().clone();
+        assert_eq!(pending.len(), 2);
+        for bp in &pending {
+            assert_eq!(bp["verified"], false);
+            assert_eq!(bp["message"], "pending");
+            assert!(bp["id"].is_u64());
+        }
+
+        let shell = MockShell::new();
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        server.prepare_launch(ctx_arc.clone(), &pre, &labels);
+
+        // Launching binds each one, moved past the blank line
+        let changed: Vec<Value> = messages()
+            .into_iter()
+            .filter(|m| m["event"] == "breakpoint")
+            .map(|m| {
+                assert_eq!(m["body"]["reason"], "changed");
+                m["body"]["breakpoint"].clone()
+            })
+            .collect();
+        assert_eq!(changed.len(), 2, "{:?}", changed);
+        assert_eq!(changed[0]["id"], pending[0]["id"]);
+        assert_eq!(changed[0]["verified"], true);
+        assert_eq!(changed[0]["line"], 3);
+        assert_eq!(changed[1]["id"], pending[1]["id"]);
+        assert_eq!(changed[1]["verified"], false);
+
+        server.start_executor(ctx_arc.clone(), &pre, &labels);
+        let events = server.event_receiver.take().unwrap();
+        let stop = events
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Executor should stop at the SET");
+        assert_eq!(stop.reason, "breakpoint");
+        assert_eq!(server.collect_stack_frames()[0]["line"], 3);
+        let ran = commands.lock().unwrap().clone();
+        assert!(!ran.iter().any(|c| c.starts_with("echo 1")), "{:?}", ran);
+
+        server.handle_terminate(2, "terminate".to_string());
+        let _ = std::fs::remove_file(&responses);
+    }
+
+    #[test]
+    fn test_framing_counts_bytes_of_multi_byte_values() {
+        use batch_debugger::dap::{DapMessageContent, DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+        use serde_json::{json, Value};
+        use std::io::Cursor;
+        use std::sync::{Arc, Mutex};
+
+        let greeting = "héllo wörld — ✓ 🎉👍🏽";
+        let request = json!({
+            "seq": 1,
+            "type": "request",
+            "command": "setVariable",
+            "arguments": { "variablesReference": 2, "name": "GREETING", "value": greeting }
+        })
+        .to_string();
+        let incoming = format!("Content-Length: {}\r\n\r\n{}", request.len(), request);
+        assert!(request.len() > request.chars().count());
+
+        let responses =
+            std::env::temp_dir().join(format!("batch-debugger-utf8-{}.txt", std::process::id()));
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            Cursor::new(incoming.into_bytes()),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        server.set_context(Arc::new(Mutex::new(DebugContext::new(MockShell::new()))));
+
+        // The request arrives whole, however many bytes its characters take
+        let msg = server.read_message().expect("request should be read");
+        let arguments = match msg.content {
+            DapMessageContent::Request { arguments, .. } => arguments,
+            other => panic!("not a request: {:?}", other),
+        };
+        assert_eq!(arguments.as_ref().unwrap()["value"], greeting);
+        server.handle_set_variable(msg.seq, "setVariable".to_string(), arguments);
+        server.handle_variables(
+            2,
+            "variables".to_string(),
+            Some(json!({ "variablesReference": 2 })),
+        );
+        while server.request_in_flight(2) {
+            server.check_finished_requests();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        // Each Content-Length covers exactly the bytes of its body
+        let written = std::fs::read(&responses).unwrap();
+        let mut rest = written.as_slice();
+        let mut messages = Vec::new();
+        while !rest.is_empty() {
+            let header_end = rest
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .expect("header should end");
+            let header = std::str::from_utf8(&rest[..header_end]).unwrap();
+            let length: usize = header
+                .strip_prefix("Content-Length: ")
+                .expect("only Content-Length is sent")
+                .parse()
+                .unwrap();
+            let body = &rest[header_end + 4..header_end + 4 + length];
+            messages.push(serde_json::from_slice::<Value>(body).unwrap());
+            rest = &rest[header_end + 4 + length..];
+        }
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["success"], true);
+        assert_eq!(messages[0]["body"]["value"], greeting);
+        let variables = messages[1]["body"]["variables"].as_array().unwrap();
+        assert!(variables
+            .iter()
+            .any(|v| v["name"] == "GREETING" && v["value"] == greeting));
+
+        let _ = std::fs::remove_file(&responses);
+    }
+
+    #[test]
+    fn test_a_failing_watch_keeps_its_place_among_the_others() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, Instant};
+
+        let shell = MockShell::new().delay("slowquery", Duration::from_secs(20));
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_variable("FIRST", "1").unwrap();
+        ctx.set_variable("LAST", "3").unwrap();
+        let timeout = ctx.command_timeout();
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+        for watch in ["%FIRST%", "%SLOWQUERY:~0,1%", "%LAST%"] {
+            server.add_watch(watch.to_string());
+        }
+
+        // The slow watch gives up well before the session's own timeout
+        let started = Instant::now();
+        let watches = server.collect_variables(3);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let names: Vec<_> = watches.iter().map(|w| w["name"].clone()).collect();
+        assert_eq!(names, ["%FIRST%", "%SLOWQUERY:~0,1%", "%LAST%"]);
+        assert_eq!(watches[0]["value"], "1");
+        assert_eq!(watches[2]["value"], "3");
+        assert!(watches[1]["value"]
+            .as_str()
+            .unwrap()
+            .starts_with("<error: command timed out"));
+        assert_eq!(
+            watches[1]["presentationHint"]["attributes"][0],
+            "failedEvaluation"
+        );
+        assert!(watches[0]["presentationHint"].get("attributes").is_none());
+        assert_eq!(ctx_arc.lock().unwrap().command_timeout(), timeout);
+
+        // Even without the debugger's state every watch keeps an entry
+        let poisoner = ctx_arc.clone();
+        let _ = std::thread::spawn(move || {
+            let _ctx = poisoner.lock().unwrap();
+            panic!("poison the context");
+        })
+        .join();
+        let watches = server.collect_variables(3);
+        assert_eq!(watches.len(), 3);
+        assert!(watches
+            .iter()
+            .all(|w| w["value"].as_str().unwrap().starts_with("<error:")));
+    }
+
+    #[test]
+    fn test_executor_stops_reach_the_client_in_order_with_their_output() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+        use batch_debugger::executor::{ScriptOutput, StopInfo};
+        use serde_json::Value;
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, Instant};
+
+        let responses = std::env::temp_dir().join(format!(
+            "batch-debugger-forwarding-{}.txt",
+            std::process::id()
+        ));
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        server.set_context(Arc::new(Mutex::new(DebugContext::new(MockShell::new()))));
+        let messages = || -> Vec<Value> {
+            std::fs::read_to_string(&responses)
+                .unwrap()
+                .split("Content-Length: ")
+                .filter_map(|m| m.split_once("\r\n\r\n"))
+                .map(|(_, body)| serde_json::from_str::<Value>(body).unwrap())
+                .collect()
+        };
+
+        // A scripted executor: output, then a stop, then wait to be resumed
+        let (event_tx, event_rx) = channel();
+        let (output_tx, output_rx) = channel();
+        let (resume_tx, resume_rx) = channel::<()>();
+        server.event_receiver = Some(event_rx);
+        server.output_receiver = Some(output_rx);
+        let executor = std::thread::spawn(move || {
+            for (i, reason) in ["breakpoint", "step", "data breakpoint"].iter().enumerate() {
+                let text = format!("before stop {}\r\n", i + 1);
+                output_tx.send(ScriptOutput::at(text, i, None)).unwrap();
+                event_tx.send(StopInfo::new(reason, i)).unwrap();
+                resume_rx.recv().unwrap();
+            }
+            output_tx
+                .send(ScriptOutput::at("last words\r\n", 3, None))
+                .unwrap();
+            event_tx.send(StopInfo::new("terminated", 3)).unwrap();
+            event_tx.send(StopInfo::new("terminated", 3)).unwrap();
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut stops = 0;
+        while Instant::now() < deadline {
+            server.forward_executor_events();
+            let sent = messages();
+            if sent.iter().any(|m| m["event"] == "terminated") {
+                break;
+            }
+            let stopped = sent.iter().filter(|m| m["event"] == "stopped").count();
+            if stopped > stops {
+                stops = stopped;
+                let _ = resume_tx.send(());
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        executor.join().unwrap();
+        server.forward_executor_events();
+
+        let sequence: Vec<String> = messages()
+            .iter()
+            .filter_map(|m| match m["event"].as_str()? {
+                "output" => Some(format!("output {}", m["body"]["output"].as_str()?.trim())),
+                "stopped" => Some(format!("stopped {}", m["body"]["reason"].as_str()?)),
+                event => Some(event.to_string()),
+            })
+            .collect();
+        assert_eq!(
+            sequence,
+            [
+                "output before stop 1",
+                "stopped breakpoint",
+                "output before stop 2",
+                "stopped step",
+                "output before stop 3",
+                "stopped data breakpoint",
+                "output last words",
+                "exited",
+                "terminated"
+            ]
+        );
+        let _ = std::fs::remove_file(&responses);
+    }
+
+    #[test]
+    fn test_diagnostics_snapshot_the_stopped_state() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let content = "@echo off\r\nset NAME=short\r\ncall :sub one two\r\ngoto :eof\r\n:sub\r\necho in sub\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let mut server = DapServer::new();
+        server.set_program("diag.bat", pre.clone());
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.set_mode(RunMode::Continue);
+        ctx.set_variable("LONG", &"x".repeat(1000)).unwrap();
+        ctx.add_breakpoint(5);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        server.set_context(ctx_arc.clone());
+        server.add_watch("%NAME%".to_string());
+        server.start_executor(ctx_arc.clone(), &pre, &labels);
+        let events = server.event_receiver.take().unwrap();
+        events
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Executor should stop in :sub");
+
+        let snapshot = server.diagnostics(true);
+        assert_eq!(snapshot["mode"], "Continue");
+        assert_eq!(snapshot["pc"], 5);
+        assert_eq!(snapshot["line"], 6);
+        assert_eq!(snapshot["watches"], 1);
+        assert!(snapshot["version"].is_string());
+        let frames = snapshot["frames"].as_array().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0]["label"], "sub");
+        assert_eq!(frames[0]["args"][1], "two");
+        assert_eq!(snapshot["variables"]["NAME"], "short");
+        let long = snapshot["variables"]["LONG"].as_str().unwrap();
+        assert!(
+            long.len() < 300 && long.ends_with("(1000 characters)"),
+            "{}",
+            long
+        );
+
+        let snapshot = server.diagnostics(false);
+        assert!(snapshot.get("variables").is_none());
+        assert_eq!(snapshot["frames"].as_array().unwrap().len(), 2);
+
+        server.handle_terminate(1, "terminate".to_string());
+    }
+
+    #[test]
+    fn test_stack_trace_pages_through_a_deep_stack() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use serde_json::{json, Value};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let mut content = String::from("@echo off\r\ncall :a\r\ngoto :eof\r\n");
+        for (label, next) in [("a", "b"), ("b", "c"), ("c", "d"), ("d", "e")] {
+            content.push_str(&format!(":{}\r\ncall :{}\r\ngoto :eof\r\n", label, next));
+        }
+        content.push_str(":e\r\necho deep\r\n");
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let responses = std::env::temp_dir().join(format!(
+            "batch-debugger-stack-paging-{}.txt",
+            std::process::id()
+        ));
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        server.set_program("deep.bat", pre.clone());
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.set_mode(RunMode::Continue);
+        ctx.add_breakpoint(16);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        server.set_context(ctx_arc.clone());
+        server.start_executor(ctx_arc.clone(), &pre, &labels);
+        let events = server.event_receiver.take().unwrap();
+        events
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Executor should stop in :e");
+
+        let stack_trace = |server: &mut DapServer, seq: u64, args: Value| -> Value {
+            server.handle_stack_trace(seq, "stackTrace".to_string(), Some(args));
+            let written = std::fs::read_to_string(&responses).unwrap();
+            let (_, body) = written
+                .rsplit("Content-Length: ")
+                .next()
+                .unwrap()
+                .split_once("\r\n\r\n")
+                .unwrap();
+            serde_json::from_str::<Value>(body).unwrap()["body"].clone()
+        };
+
+        // Frames 1 and 2: the CALL sites in :d and :c
+        let page = stack_trace(&mut server, 1, json!({ "startFrame": 1, "levels": 2 }));
+        assert_eq!(page["totalFrames"], 6);
+        let frames = page["stackFrames"].as_array().unwrap();
+        let names: Vec<_> = frames.iter().map(|f| f["name"].clone()).collect();
+        assert_eq!(names, [":d", ":c"]);
+        assert_eq!(frames[0]["line"], 14);
+        assert_eq!(frames[1]["line"], 11);
+        assert!(frames[0].get("presentationHint").is_none());
+
+        // Past the end there is nothing; no levels means the rest
+        let page = stack_trace(&mut server, 2, json!({ "startFrame": 6, "levels": 2 }));
+        assert_eq!(page["stackFrames"].as_array().unwrap().len(), 0);
+        assert_eq!(page["totalFrames"], 6);
+        let page = stack_trace(&mut server, 3, json!({ "startFrame": 4 }));
+        let names: Vec<_> = page["stackFrames"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["name"].clone())
+            .collect();
+        assert_eq!(names, [":a", "main"]);
+        assert_eq!(page["stackFrames"][1]["line"], 2);
+
+        server.handle_terminate(4, "terminate".to_string());
+        let _ = std::fs::remove_file(&responses);
+    }
+
+    #[test]
+    fn test_breakpoint_conditions_are_checked_when_set() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use serde_json::{json, Value};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let content = "@echo off\r\ncall :sub\r\ncall :sub\r\ngoto :eof\r\n:sub\r\necho in sub\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let responses = std::env::temp_dir().join(format!(
+            "batch-debugger-bad-condition-{}.txt",
+            std::process::id()
+        ));
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        server.set_program("conditions.bat", pre.clone());
+        let messages = || -> Vec<Value> {
+            std::fs::read_to_string(&responses)
+                .unwrap()
+                .split("Content-Length: ")
+                .filter_map(|m| m.split_once("\r\n\r\n"))
+                .map(|(_, body)| serde_json::from_str::<Value>(body).unwrap())
+                .collect()
+        };
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.set_mode(RunMode::Continue);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        server.prepare_launch(ctx_arc.clone(), &pre, &labels);
+
+        // A variable that doesn't exist yet is fine; a broken condition isn't
+        server.handle_set_breakpoints(
+            1,
+            "setBreakpoints".to_string(),
+            Some(json!({
+                "source": { "path": "conditions.bat" },
+                "breakpoints": [
+                    { "line": 2, "condition": "NOT_YET_SET GEQ 5" },
+                    { "line": 6, "condition": "COUNT >= 5" }
+                ]
+            })),
+        );
+        let response = messages().pop().unwrap();
+        let breakpoints = response["body"]["breakpoints"].as_array().unwrap();
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert!(breakpoints[0].get("message").is_none());
+        assert_eq!(breakpoints[1]["verified"], true);
+        let message = breakpoints[1]["message"].as_str().unwrap();
+        assert!(message.starts_with("Invalid condition"), "{}", message);
+
+        // Both CALLs stop in :sub; the console is told once
+        server.start_executor(ctx_arc.clone(), &pre, &labels);
+        let events = server.event_receiver.take().unwrap();
+        for seq in [2, 3] {
+            let stop = events
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Executor should stop in :sub");
+            assert_eq!(stop.reason, "breakpoint");
+            server.send_stopped(&stop);
+            server.handle_continue(seq, "continue".to_string());
+        }
+        let warnings: Vec<String> = messages()
+            .iter()
+            .filter(|m| m["event"] == "output" && m["body"]["category"] == "important")
+            .map(|m| m["body"]["output"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(
+            warnings[0].starts_with("WARNING: Breakpoint on line 6:"),
+            "{:?}",
+            warnings
+        );
+
+        server.handle_terminate(4, "terminate".to_string());
+        let _ = std::fs::remove_file(&responses);
+    }
+
+    #[test]
+    fn test_condition_error_spots_malformed_conditions() {
+        use batch_debugger::parser::condition_error;
+
+        for valid in [
+            "COUNTER GEQ 5",
+            "NOT DEFINED BUILD",
+            "\"%MODE%\"==\"release\"",
+            "%FLAG%",
+            "ERRORLEVEL 1",
+        ] {
+            assert_eq!(condition_error(valid), None, "{}", valid);
+        }
+        for invalid in [
+            "COUNT >= 5",
+            "COUNT EQU 5 6",
+            "%COUNT GEQ 5",
+            "\"a==b",
+            "  ",
+        ] {
+            assert!(condition_error(invalid).is_some(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_loaded_sources_list_each_called_script_once() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+        use serde_json::{json, Value};
+        use std::time::{Duration, Instant};
+
+        let dir =
+            std::env::temp_dir().join(format!("batch-debugger-loaded-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.bat");
+        std::fs::write(
+            &main,
+            "@echo off\r\ncall helper.bat\r\ncall helper.bat\r\necho done\r\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("helper.bat"), "echo helping\r\nexit /b 0\r\n").unwrap();
+
+        let responses = dir.join("responses.txt");
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        server.set_session_factory(Box::new(|_options| Ok(DebugContext::new(MockShell::new()))));
+        let messages = || -> Vec<Value> {
+            std::fs::read_to_string(&responses)
+                .unwrap()
+                .split("Content-Length: ")
+                .filter_map(|m| m.split_once("\r\n\r\n"))
+                .map(|(_, body)| serde_json::from_str::<Value>(body).unwrap())
+                .collect()
+        };
+
+        server.handle_launch(
+            1,
+            "launch".to_string(),
+            Some(json!({ "program": main.to_str().unwrap(), "stopOnEntry": false })),
+        );
+        server.handle_configuration_done(2, "configurationDone".to_string());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !messages().iter().any(|m| m["event"] == "terminated") {
+            assert!(Instant::now() < deadline, "The script should finish");
+            server.forward_executor_events();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        // Two CALLs of helper.bat, one event
+        let loaded: Vec<Value> = messages()
+            .into_iter()
+            .filter(|m| m["event"] == "loadedSource")
+            .map(|m| m["body"].clone())
+            .collect();
+        assert_eq!(loaded.len(), 1, "{:?}", loaded);
+        assert_eq!(loaded[0]["reason"], "new");
+        assert_eq!(loaded[0]["source"]["name"], "helper.bat");
+
+        server.handle_loaded_sources(3, "loadedSources".to_string());
+        let response = messages().pop().unwrap();
+        assert_eq!(response["command"], "loadedSources");
+        let sources = response["body"]["sources"].as_array().unwrap();
+        let names: Vec<&str> = sources.iter().filter_map(|s| s["name"].as_str()).collect();
+        assert_eq!(names, ["main.bat", "helper.bat"]);
+        assert_eq!(sources[1], loaded[0]["source"]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_console_verbosity_off_leaves_only_the_scripts_output() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+        use serde_json::{json, Value};
+        use std::time::{Duration, Instant};
+
+        let dir =
+            std::env::temp_dir().join(format!("batch-debugger-verbosity-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("chatty.bat");
+        std::fs::write(
+            &program,
+            "@echo off\r\necho one\r\nif 1==1 echo two\r\nfor %%i in (a b) do echo %%i\r\ntimeout /t 5\r\ntype missing.txt\r\n",
+        )
+        .unwrap();
+
+        // Category and text of each output event of a run
+        let run = |verbosity: &str| -> Vec<(String, String)> {
+            let responses = dir.join(format!("responses-{}.txt", verbosity));
+            let mut server = DapServer::new();
+            server.set_transport(Box::new(StreamTransport::new(
+                std::io::empty(),
+                std::fs::File::create(&responses).unwrap(),
+            )));
+            server.set_session_factory(Box::new(|_options| {
+                Ok(DebugContext::new(
+                    MockShell::new()
+                        .respond("echo one", "one\r\n", 0)
+                        .respond("echo two", "two\r\n", 0)
+                        .respond("echo a", "a\r\n", 0)
+                        .respond("echo b", "b\r\n", 0)
+                        .fail(
+                            "missing.txt",
+                            "The system cannot find the file specified.\r\n",
+                            1,
+                        ),
+                ))
+            }));
+            let messages = || -> Vec<Value> {
+                std::fs::read_to_string(&responses)
+                    .unwrap()
+                    .split("Content-Length: ")
+                    .filter_map(|m| m.split_once("\r\n\r\n"))
+                    .map(|(_, body)| serde_json::from_str::<Value>(body).unwrap())
+                    .collect()
+            };
+            server.handle_launch(
+                1,
+                "launch".to_string(),
+                Some(json!({
+                    "program": program.to_str().unwrap(),
+                    "stopOnEntry": false,
+                    "fastForwardDelays": true,
+                    "verboseConsole": true,
+                    "consoleVerbosity": verbosity
+                })),
+            );
+            server.handle_configuration_done(2, "configurationDone".to_string());
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !messages().iter().any(|m| m["event"] == "terminated") {
+                assert!(Instant::now() < deadline, "The script should finish");
+                server.forward_executor_events();
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            messages()
+                .iter()
+                .filter(|m| m["event"] == "output")
+                .map(|m| {
+                    (
+                        m["body"]["category"].as_str().unwrap().to_string(),
+                        m["body"]["output"].as_str().unwrap().to_string(),
+                    )
+                })
+                .collect()
+        };
+
+        // The debugger explains itself on the console
+        let debug = run("debug");
+        let notes: Vec<&str> = debug
+            .iter()
+            .filter(|(category, _)| category == "console")
+            .map(|(_, output)| output.as_str())
+            .collect();
+        assert!(notes.iter().any(|n| n.starts_with("IF:")), "{:?}", notes);
+        assert!(notes.iter().any(|n| n.starts_with("FOR:")), "{:?}", notes);
+        assert!(
+            notes.iter().any(|n| n.contains("skipped 5s delay")),
+            "{:?}",
+            notes
+        );
+
+        // Off leaves the script's own output, each stream in its category
+        let off = run("off");
+        let script_output: Vec<(&str, &str)> = off
+            .iter()
+            .filter(|(category, _)| category != "telemetry")
+            .map(|(category, output)| (category.as_str(), output.as_str()))
+            .collect();
+        assert_eq!(
+            script_output,
+            [
+                ("stdout", "one\r\n"),
+                ("stdout", "two\r\n"),
+                ("stdout", "a\r\n"),
+                ("stdout", "b\r\n"),
+                ("stderr", "The system cannot find the file specified.\r\n"),
+            ]
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_debug_session_api_drives_a_whole_session() {
+        use batch_debugger::api::{DebugSession, StackFrame};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::TerminatedReason;
+
+        let path =
+            std::env::temp_dir().join(format!("batch-debugger-api-{}.bat", std::process::id()));
+        std::fs::write(
+            &path,
+            "@echo off\r\nset COUNT=1\r\ncall :bump\r\necho done\r\nexit /b 0\r\n\r\n:bump\r\nset NAME=inner\r\nexit /b 0\r\n",
+        )
+        .unwrap();
+        let mut session =
+            DebugSession::with_shell(&path, MockShell::new().respond("echo done", "done\r\n", 0))
+                .unwrap();
+
+        // A breakpoint on the label lands on the subroutine's first statement
+        assert_eq!(session.set_breakpoint(7, None).unwrap(), 8);
+        assert!(session.set_breakpoint(40, None).is_err());
+
+        let stop = session
+            .run_until_stop()
+            .unwrap()
+            .expect("Should stop in :bump");
+        assert_eq!(stop.reason, "breakpoint");
+        assert_eq!(stop.line, 8);
+        assert_eq!(stop.script, None);
+        let frames = session.call_stack().unwrap();
+        let frame = |name: &str, line| StackFrame {
+            name: name.to_string(),
+            line,
+            script: None,
+        };
+        assert_eq!(frames, [frame(":bump", 8), frame("main", 3)]);
+        assert_eq!(session.variables().unwrap().get("COUNT").unwrap(), "1");
+        assert_eq!(session.eval("%COUNT%").unwrap(), "1");
+
+        // Out of the subroutine and over the echo
+        let stop = session.step().unwrap().unwrap();
+        assert_eq!(stop.line, 9);
+        let stop = session.step().unwrap().unwrap();
+        assert_eq!((stop.reason.as_str(), stop.line), ("step", 4));
+        assert_eq!(session.call_stack().unwrap(), [frame("main", 4)]);
+        assert_eq!(session.variables().unwrap().get("NAME").unwrap(), "inner");
+        session.step().unwrap().unwrap();
+        let printed: String = session
+            .take_output()
+            .iter()
+            .map(|o| o.text.clone())
+            .collect();
+        assert_eq!(printed, "done\r\n");
+
+        let summary = session.finish().unwrap();
+        assert_eq!(summary.exit_code, 0);
+        assert_eq!(summary.terminated_reason, TerminatedReason::Completed);
+        assert_eq!(summary.max_stack_depth, 1);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_cli_repl_stops_at_a_breakpoint_and_prints_values() {
+        use batch_debugger::api::DebugSession;
+        use batch_debugger::cli::run_repl;
+        use batch_debugger::debugger::test_support::MockShell;
+
+        let path =
+            std::env::temp_dir().join(format!("batch-debugger-cli-{}.bat", std::process::id()));
+        std::fs::write(
+            &path,
+            "@echo off\r\nset NAME=world\r\necho hello\r\nrem greet\r\necho %NAME%\r\necho bye\r\n",
+        )
+        .unwrap();
+        let mut session = DebugSession::with_shell(
+            &path,
+            MockShell::new()
+                .respond("echo hello", "hello\r\n", 0)
+                .respond("echo bye", "bye\r\n", 0),
+        )
+        .unwrap();
+
+        let commands = "b 5\nwatch %NAME%\nc\np %NAME%\nbt\nfrob\nc\n";
+        let mut output = Vec::new();
+        run_repl(&mut session, commands.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.contains("Breakpoint at line 5\n"), "{}", output);
+        // The script's output, then the stop with the lines around it
+        let stop = output
+            .find("Stopped (breakpoint) at line 5")
+            .expect(&output);
+        assert!(output[..stop].contains("hello\r\n"), "{}", output);
+        assert!(output[stop..].contains("     3 echo hello\n"), "{}", output);
+        assert!(
+            output[stop..].contains("->    5 echo %NAME%\n"),
+            "{}",
+            output
+        );
+        assert!(output[stop..].contains("%NAME% = world\n"), "{}", output);
+        assert!(output.contains("> world\n"), "{}", output);
+        assert!(output.contains("#0 main at line 5\n"), "{}", output);
+        assert!(output.contains("Unknown command 'frob'"), "{}", output);
+        assert!(output.contains("bye\r\n"), "{}", output);
+        assert!(
+            output
+                .trim_end()
+                .ends_with("Script finished (completed) with exit code 0"),
+            "{}",
+            output
+        );
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_run_report_covers_a_whole_run() {
+        use batch_debugger::api::DebugSession;
+        use batch_debugger::debugger::test_support::MockShell;
+        use serde_json::{json, Value};
+
+        let path =
+            std::env::temp_dir().join(format!("batch-debugger-report-{}.bat", std::process::id()));
+        std::fs::write(
+            &path,
+            "@echo off\r\nset COUNT=1\r\nset COUNT=2\r\nrobocopy src dst\r\ngoto done\r\ncall :nowhere\r\n:done\r\necho done\r\nexit /b 3\r\n:done\r\nif 1==1 (\r\necho never closed\r\n",
+        )
+        .unwrap();
+        let mut session = DebugSession::with_shell(
+            &path,
+            MockShell::new()
+                .respond("robocopy src dst", "copied\r\n", 1)
+                .respond("echo done", "done\r\n", 0),
+        )
+        .unwrap();
+        while session.run_until_stop().unwrap().is_some() {}
+
+        let report = serde_json::to_value(session.report().unwrap()).unwrap();
+        for key in [
+            "program",
+            "exitCode",
+            "terminatedReason",
+            "linesExecuted",
+            "coverage",
+            "timing",
+            "variables",
+            "externalCommands",
+            "warnings",
+        ] {
+            assert!(report.get(key).is_some(), "{} missing from {}", key, report);
+        }
+        assert_eq!(report["exitCode"], 3, "{}", report);
+
+        // Lines 6 and 12-13 never ran
+        let hits = |line: u64| {
+            report["coverage"]["lines"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|l| l["line"] == line)
+                .map(|l| l["hits"].as_u64().unwrap())
+        };
+        assert_eq!(hits(4), Some(1), "{}", report);
+        assert_eq!(hits(6), Some(0), "{}", report);
+        assert_eq!(hits(7), None, "{}", report);
+        let timed: Vec<&Value> = report["timing"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|l| &l["line"])
+            .collect();
+        assert!(timed.contains(&&json!(4)), "{}", report);
+        assert!(report["timing"][0]["totalMs"].is_f64(), "{}", report);
+
+        assert_eq!(
+            report["variables"],
+            json!([
+                {"name": "COUNT", "line": 2, "oldValue": null, "newValue": "1"},
+                {"name": "COUNT", "line": 3, "oldValue": "1", "newValue": "2"},
+            ])
+        );
+        assert_eq!(
+            report["externalCommands"],
+            json!([{"line": 4, "command": "robocopy src dst"}])
+        );
+        let warnings: Vec<u64> = report["warnings"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|w| w["line"].as_u64().unwrap())
+            .collect();
+        assert_eq!(warnings, vec![6, 10, 11], "{}", report);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_errors_say_which_kind_of_failure_they_are() {
+        use batch_debugger::api::DebugSession;
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, SessionError};
+        use batch_debugger::error::BatchDbgError;
+        use serde_json::{json, Value};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        // A run that CALLs a label that isn't there ends with UnknownLabel
+        let path =
+            std::env::temp_dir().join(format!("batch-debugger-errors-{}.bat", std::process::id()));
+        std::fs::write(
+            &path,
+            "@echo off\r\necho start\r\ncall :missing\r\necho unreachable\r\n",
+        )
+        .unwrap();
+        let mut session = DebugSession::with_shell(&path, MockShell::new()).unwrap();
+        assert!(session.run_until_stop().unwrap().is_none());
+        match session.summary().unwrap().terminated_reason.error() {
+            Some(BatchDbgError::UnknownLabel { name, line }) => {
+                assert_eq!(name, "missing");
+                assert_eq!(line, 3);
+            }
+            other => panic!("Expected UnknownLabel, got {:?}", other),
+        }
+        let condition = session.set_breakpoint(2, Some("%X% GEQ")).unwrap_err();
+        assert!(matches!(
+            BatchDbgError::from(condition),
+            BatchDbgError::ParseError { line: 2, .. }
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        // Session failures keep their kind through io::Error
+        let timeout = std::io::Error::from(SessionError::Timeout {
+            timeout: Duration::from_secs(5),
+            partial_output: String::new(),
+        });
+        assert!(matches!(
+            BatchDbgError::from(timeout),
+            BatchDbgError::SessionTimeout { .. }
+        ));
+
+        // Over DAP each kind fails the request with an id of its own
+        let responses =
+            std::env::temp_dir().join(format!("batch-debugger-errors-{}.txt", std::process::id()));
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        let shell = MockShell::new();
+        let commands = shell.commands();
+        server.set_context(Arc::new(Mutex::new(DebugContext::new(shell))));
+        server.handle_evaluate(
+            1,
+            "evaluate".to_string(),
+            Some(json!({ "expression": "%NAME% & del *.txt", "context": "hover" })),
+        );
+        while server.request_in_flight(1) {
+            server.check_finished_requests();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let response: Value = std::fs::read_to_string(&responses)
+            .unwrap()
+            .split("Content-Length: ")
+            .filter_map(|m| m.split_once("\r\n\r\n"))
+            .map(|(_, body)| serde_json::from_str::<Value>(body).unwrap())
+            .find(|m| m["request_seq"] == 1)
+            .unwrap();
+        assert_eq!(response["success"], false);
+        let unsafe_id = BatchDbgError::EvalUnsafe(String::new()).dap_id();
+        assert_eq!(response["body"]["error"]["id"], unsafe_id);
+        assert_ne!(unsafe_id, BatchDbgError::LockPoisoned.dap_id());
+        assert_ne!(
+            unsafe_id,
+            BatchDbgError::SessionIo(std::io::Error::other("")).dap_id()
+        );
+        // The expression never reached the session
+        assert!(!commands.lock().unwrap().iter().any(|c| c.contains("del")));
+        let _ = std::fs::remove_file(&responses);
+    }
+
+    #[test]
+    fn test_breakpoints_file_carries_breakpoints_to_the_next_session() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{BreakpointsFile, DebugContext};
+        use serde_json::{json, Value};
+        use std::path::Path;
+        use std::time::{Duration, Instant};
+
+        let dir = std::env::temp_dir().join(format!(
+            "batch-debugger-breakpoints-file-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("build.bat");
+        std::fs::write(
+            &program,
+            "@echo off\r\nset MODE=release\r\necho building\r\necho packaging\r\necho done\r\n",
+        )
+        .unwrap();
+        let file = dir.join("breakpoints.json");
+        let launch = json!({
+            "program": program.display().to_string(),
+            "stopOnEntry": false,
+            "breakpointsFile": file.display().to_string(),
+        });
+        let server = |responses: &Path| {
+            let mut server = DapServer::new();
+            server.set_transport(Box::new(StreamTransport::new(
+                std::io::empty(),
+                std::fs::File::create(responses).unwrap(),
+            )));
+            server
+                .set_session_factory(Box::new(|_options| Ok(DebugContext::new(MockShell::new()))));
+            server
+        };
+        let messages = |responses: &Path| -> Vec<Value> {
+            std::fs::read_to_string(responses)
+                .unwrap()
+                .split("Content-Length: ")
+                .filter_map(|m| m.split_once("\r\n\r\n"))
+                .map(|(_, body)| serde_json::from_str::<Value>(body).unwrap())
+                .collect()
+        };
+
+        // The first session saves the two conditional breakpoints it's given
+        let first = dir.join("first.txt");
+        let mut server_a = server(&first);
+        server_a.handle_launch(1, "launch".to_string(), Some(launch.clone()));
+        server_a.handle_set_breakpoints(
+            2,
+            "setBreakpoints".to_string(),
+            Some(json!({
+                "source": { "path": program.display().to_string() },
+                "breakpoints": [
+                    { "line": 3, "condition": "%MODE%==debug" },
+                    { "line": 4, "condition": "%MODE%==release" },
+                ],
+            })),
+        );
+        server_a.handle_disconnect(3, "disconnect".to_string(), None);
+        let saved = BreakpointsFile::load(&file).unwrap();
+        let specs = saved.get(&program).unwrap();
+        assert_eq!(specs.len(), 2, "{:?}", saved);
+        assert_eq!(specs[0].line, 3);
+        assert_eq!(specs[0].condition.as_deref(), Some("%MODE%==debug"));
+        assert_eq!(specs[1].condition.as_deref(), Some("%MODE%==release"));
+
+        // A fresh session binds them at launch, with nothing from the client
+        let second = dir.join("second.txt");
+        let mut server_b = server(&second);
+        server_b.handle_launch(1, "launch".to_string(), Some(launch));
+        let bound: Vec<Value> = messages(&second)
+            .into_iter()
+            .filter(|m| m["event"] == "breakpoint")
+            .map(|m| m["body"]["breakpoint"].clone())
+            .collect();
+        assert_eq!(bound.len(), 2, "{:?}", bound);
+        assert!(bound.iter().all(|bp| bp["verified"] == true));
+        assert_eq!(bound[0]["line"], 3);
+        assert_eq!(bound[1]["line"], 4);
+
+        // Only the breakpoint whose condition holds stops the script
+        server_b.handle_configuration_done(2, "configurationDone".to_string());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !messages(&second).iter().any(|m| m["event"] == "stopped") {
+            assert!(Instant::now() < deadline, "The script should stop");
+            server_b.forward_executor_events();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        server_b.handle_stack_trace(3, "stackTrace".to_string(), Some(json!({ "threadId": 1 })));
+        let trace = messages(&second)
+            .into_iter()
+            .find(|m| m["command"] == "stackTrace")
+            .unwrap();
+        assert_eq!(trace["body"]["stackFrames"][0]["line"], 4, "{}", trace);
+        server_b.handle_disconnect(4, "disconnect".to_string(), None);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_saved_breakpoints_keep_the_line_they_were_set_on() {
+        use batch_debugger::debugger::{BreakpointSpec, Breakpoints};
+        use batch_debugger::parser::preprocess_lines;
+
+        let pre = preprocess_lines(&["@echo off", "", "rem build", "echo hi", "echo bye"]);
+        let mut moved = BreakpointSpec::at(2);
+        moved.condition = Some("1==1".to_string());
+        let (breakpoints, unbound) = Breakpoints::from_spec(&[moved, BreakpointSpec::at(5)], &pre);
+        assert!(unbound.is_empty());
+        assert!(breakpoints.contains(pre.phys_to_logical[3]));
+
+        // Saved where the client put it, not on the echo it stops at
+        let lines: Vec<usize> = breakpoints.to_spec(&pre).iter().map(|s| s.line).collect();
+        assert_eq!(lines, [2, 5]);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_parser_output_round_trips_and_dumps_as_json() {
+        use batch_debugger::parser::{dump_ast, preprocess_lines, PreprocessResult};
+        use serde_json::Value;
+
+        let content = std::fs::read_to_string("tests/batch_files/test_dump_ast.bat").unwrap();
+        let physical_lines: Vec<&str> = content.lines().collect();
+
+        let pre = preprocess_lines(&physical_lines);
+        let json = serde_json::to_string(&pre).unwrap();
+        let back: PreprocessResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.phys_to_logical, pre.phys_to_logical);
+        assert_eq!(serde_json::to_string(&back).unwrap(), json);
+
+        // An IF, a FOR /F and redirections, as --dump-ast prints them
+        let snapshot: Value = serde_json::from_str(
+            &std::fs::read_to_string("tests/batch_files/test_dump_ast.json").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(dump_ast(&physical_lines), snapshot);
+    }
+
+    #[test]
+    fn test_lint_reports_each_rule_on_its_lines() {
+        use batch_debugger::api::lint;
+        use batch_debugger::parser::Severity;
+
+        let fixtures = [
+            ("lint_unknown_label", "unknown-label", vec![2, 5, 6]),
+            ("lint_duplicate_label", "duplicate-label", vec![5]),
+            ("lint_comment_in_block", "comment-in-block", vec![4]),
+            ("lint_undefined_variable", "undefined-variable", vec![6, 7]),
+            ("lint_set_arithmetic", "set-arithmetic", vec![6, 7, 8, 9]),
+            ("lint_else_without_if", "else-without-if", vec![10, 15]),
+        ];
+        for (fixture, code, lines) in fixtures {
+            let diagnostics = lint(format!("tests/batch_files/{}.bat", fixture)).unwrap();
+            let found: Vec<(&str, usize)> = diagnostics.iter().map(|d| (d.code, d.line)).collect();
+            let expected: Vec<(&str, usize)> = lines.iter().map(|&line| (code, line)).collect();
+            assert_eq!(found, expected, "{}: {:?}", fixture, diagnostics);
+        }
+
+        let labels = lint("tests/batch_files/lint_unknown_label.bat").unwrap();
+        assert!(
+            labels[0].message.ends_with("did you mean :build?"),
+            "{}",
+            labels[0]
+        );
+        assert!(
+            labels[1].message.ends_with("did you mean :package?"),
+            "{}",
+            labels[1]
+        );
+        assert_eq!(labels[0].severity, Severity::Error);
+        let undefined = lint("tests/batch_files/lint_undefined_variable.bat").unwrap();
+        assert!(undefined[0].message.contains("%OUTDIR%"));
+        assert_eq!(undefined[0].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn test_call_graph_follows_calls_and_gotos_between_labels() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use batch_debugger::parser::{call_graph, preprocess_lines, EdgeKind};
+        use serde_json::Value;
+
+        let content = std::fs::read_to_string("tests/batch_files/test_call_graph.bat").unwrap();
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = preprocess_lines(&physical_lines);
+        let graph = call_graph(&pre);
+
+        let edges: Vec<(&str, &str, EdgeKind, usize)> = graph
+            .edges
+            .iter()
+            .map(|e| (e.from.as_str(), e.to.as_str(), e.kind, e.line))
+            .collect();
+        assert_eq!(
+            edges,
+            vec![
+                ("main", ":first", EdgeKind::Goto, 2),
+                (":first", ":second", EdgeKind::Goto, 5),
+                (":second", "helper.bat", EdgeKind::Call, 8),
+                (":second", ":eof", EdgeKind::Goto, 9),
+                (":orphan", ":first", EdgeKind::Goto, 12),
+            ]
+        );
+        let unreachable: Vec<&str> = graph
+            .nodes
+            .iter()
+            .filter(|n| n.unreachable)
+            .map(|n| n.name.as_str())
+            .collect();
+        assert_eq!(unreachable, vec![":orphan"]);
+        let dot = graph.to_dot("test_call_graph.bat");
+        assert!(
+            dot.contains("\"main\" -> \":first\" [label=\"goto\"];"),
+            "{}",
+            dot
+        );
+
+        // The same graph for an extension, over DAP
+        let responses = std::env::temp_dir().join(format!(
+            "batch-debugger-callgraph-{}.txt",
+            std::process::id()
+        ));
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        server.set_program("test_call_graph.bat", pre);
+        server.handle_call_graph(1, "batch/callGraph".to_string());
+        let response: Value = std::fs::read_to_string(&responses)
+            .unwrap()
+            .split("Content-Length: ")
+            .filter_map(|m| m.split_once("\r\n\r\n"))
+            .map(|(_, body)| serde_json::from_str::<Value>(body).unwrap())
+            .find(|m| m["request_seq"] == 1)
+            .unwrap();
+        assert_eq!(response["success"], true);
+        assert_eq!(response["body"]["edges"].as_array().unwrap().len(), 5);
+        assert_eq!(response["body"]["nodes"][5]["name"], ":orphan");
+        assert_eq!(response["body"]["nodes"][5]["unreachable"], true);
+        assert_eq!(response["body"]["dot"], dot);
+        let _ = std::fs::remove_file(&responses);
+    }
+
+    #[test]
+    fn test_lines_starting_with_non_ascii_words_parse() {
+        use batch_debugger::api::DebugSession;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::parser::{call_graph, goto_target, lint_script, preprocess_lines};
+
+        assert_eq!(goto_target("日本語.exe"), None);
+        assert_eq!(goto_target("é goto x"), None);
+        assert_eq!(goto_target("goto :fin"), Some("fin".to_string()));
+
+        let content = "@echo off\r\n日本語.exe\r\nif 1==1 (echo a) 日本\r\ngoto :eof\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = preprocess_lines(&physical_lines);
+        assert!(lint_script(&pre).is_empty());
+        assert_eq!(call_graph(&pre).edges.len(), 1);
+
+        // And the line runs under the debugger like any other
+        let path = std::env::temp_dir().join(format!(
+            "batch-debugger-non-ascii-{}.bat",
+            std::process::id()
+        ));
+        std::fs::write(&path, content).unwrap();
+        let shell = MockShell::new();
+        let commands = shell.commands();
+        let session = DebugSession::with_shell(&path, shell).unwrap();
+        assert_eq!(session.finish().unwrap().exit_code, 0);
+        assert!(commands.lock().unwrap().iter().any(|c| c == "日本語.exe"));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_call_in_a_multi_line_block_returns_after_the_block() {
+        use batch_debugger::api::DebugSession;
+        use batch_debugger::debugger::test_support::MockShell;
+
+        let path = std::env::temp_dir().join(format!(
+            "batch-debugger-block-call-{}.bat",
+            std::process::id()
+        ));
+        for opener in ["(", "if \"a\"==\"a\" ("] {
+            std::fs::write(
+                &path,
+                format!(
+                    "@echo off\r\n{}\r\n  call :sub\r\n)\r\necho after\r\ngoto :eof\r\n:sub\r\necho in sub\r\ngoto :eof\r\n",
+                    opener
+                ),
+            )
+            .unwrap();
+            // The IF operands are expanded through the shell first
+            let shell = MockShell::new().respond("echo \"a\"", "\"a\"", 0);
+            let commands = shell.commands();
+            let session = DebugSession::with_shell(&path, shell).unwrap();
+            session.finish().unwrap();
+            let sent: Vec<String> = commands
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|c| !c.eq_ignore_ascii_case("@echo off") && *c != "echo \"a\"")
+                .cloned()
+                .collect();
+            assert_eq!(sent, vec!["echo in sub", "echo after"], "{}", opener);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_uri_paths_and_capabilities_on_new_settings() {
+        use batch_debugger::dap::{DapServer, StreamTransport};
+        use serde_json::{json, Value};
+
+        let responses = std::env::temp_dir().join(format!(
+            "batch-debugger-uri-{}.txt",
+            std::process::id()
+        ));
+        let mut server = DapServer::new();
+        server.set_transport(Box::new(StreamTransport::new(
+            std::io::empty(),
+            std::fs::File::create(&responses).unwrap(),
+        )));
+        server.handle_initialize(
+            1,
+            "initialize".to_string(),
+            Some(json!({ "pathFormat": "uri" })),
+        );
+
+        // Non-ASCII and reserved characters are encoded as UTF-8 bytes
+        let path = "C:\\Scripts\\été [1]#2.bat";
+        let uri = server.path_to_client(path);
+        assert_eq!(uri, "file:///C:/Scripts/%C3%A9t%C3%A9%20%5B1%5D%232.bat");
+        assert_eq!(server.path_from_client(&uri), path);
+
+        let capabilities_events = || {
+            std::fs::read_to_string(&responses)
+                .unwrap()
+                .split("Content-Length: ")
+                .filter_map(|m| m.split_once("\r\n\r\n"))
+                .map(|(_, body)| serde_json::from_str::<Value>(body).unwrap())
+                .filter(|m| m["event"] == "capabilities")
+                .count()
+        };
+        server.handle_initialize(
+            2,
+            "initialize".to_string(),
+            Some(json!({ "pathFormat": "uri" })),
+        );
+        assert_eq!(capabilities_events(), 0);
+        server.handle_initialize(
+            3,
+            "initialize".to_string(),
+            Some(json!({ "pathFormat": "path", "linesStartAt1": false })),
+        );
+        assert_eq!(capabilities_events(), 1);
+        assert_eq!(server.path_to_client(path), path);
+
+        let _ = std::fs::remove_file(&responses);
+    }
+
+    #[test]
+    fn test_debug_log_is_optional() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, DebugLog, RunMode};
+
+        let content = "@echo off\r\necho one\r\necho two\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        // Off by default: the run must not create any log file
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.set_mode(RunMode::Continue);
+        assert!(!ctx.log().is_enabled());
+        let (_ctx_arc, _events, handle) = start_dap_executor(ctx, &pre, &labels);
+        handle.join().expect("Executor thread panicked");
+        assert!(!std::path::Path::new("C:\\temp\\batch-debugger-vscode.log").exists());
+
+        // A given path gets its directory created and receives the trace
+        let dir = std::env::temp_dir().join(format!("batch-debugger-log-{}", std::process::id()));
+        let path = dir.join("nested").join("debug.log");
+        let _ = fs::remove_dir_all(&dir);
+        let log = DebugLog::new();
+        log.open(&path).expect("Failed to open log file");
+
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.set_mode(RunMode::Continue);
+        ctx.set_log(log);
+        let (_ctx_arc, _events, handle) = start_dap_executor(ctx, &pre, &labels);
+        handle.join().expect("Executor thread panicked");
+
+        let written = fs::read_to_string(&path).expect("Log file was not written");
+        assert!(written.contains("echo two"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_evaluate_during_slow_command() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::time::{Duration, Instant};
+
+        let content = "@echo off\r\nset NAME=value\r\nping -n 3 127.0.0.1 >nul\r\necho done\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let (ctx_arc, _events, handle) = start_dap_executor(ctx, &pre, &labels);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !ctx_arc.lock().unwrap().session_busy() {
+            assert!(Instant::now() < deadline, "ping never started");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let start = Instant::now();
+        let value = ctx_arc
+            .lock()
+            .unwrap()
+            .evaluate_expression("%NAME%")
+            .expect("Evaluate failed");
+        assert_eq!(value, "value");
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        handle.join().expect("Executor thread panicked");
+    }
+
+    #[test]
+    fn test_goto_scans_forward() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::parser::goto_target;
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+
+        assert_eq!(goto_target("goto:eof").as_deref(), Some("eof"));
+        assert_eq!(goto_target("GOTO :EoF").as_deref(), Some("eof"));
+        assert_eq!(goto_target("goto Done extra").as_deref(), Some("done"));
+        assert_eq!(goto_target("gotodone"), None);
+
+        let content = "@echo off\r\ngoto dup\r\n:dup\r\necho first dup\r\ngoto dup\r\n:dup\r\necho second dup\r\ncall :lost\r\nif errorlevel 1 (echo lost label) else (echo no error)\r\ngoto:eof\r\n:lost\r\ngoto nowhere\r\necho not reached\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new();
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let (event_tx, _events) = channel();
+        let (output_tx, _output) = channel();
+        let (stderr_tx, stderr) = channel();
+        batch_debugger::executor::run_debugger_dap(
+            ctx_arc.clone(),
+            &pre,
+            &labels,
+            event_tx,
+            output_tx,
+            stderr_tx,
+        )
+        .expect("Executor failed");
+
+        let commands: Vec<String> = commands
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| c.starts_with("echo "))
+            .cloned()
+            .collect();
+        assert_eq!(
+            commands,
+            ["echo first dup", "echo second dup", "echo lost label"]
+        );
+        let errors: String = stderr.try_iter().map(|o| o.text).collect();
+        assert!(errors.contains("cannot find the batch label specified - nowhere"));
+        assert!(ctx_arc.lock().unwrap().call_stack.is_empty());
+    }
+
+    #[test]
+    fn test_exit_b_without_a_space() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::parser::exit_command;
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+
+        assert_eq!(exit_command("exit/b 3"), Some((true, " 3")));
+        assert_eq!(exit_command("EXIT /B"), Some((true, "")));
+        assert_eq!(exit_command("exit 2"), Some((false, "2")));
+        assert_eq!(exit_command("exitcode.exe"), None);
+
+        let content = "@echo off\r\ncall :sub\r\necho back\r\ngoto:eof\r\n:sub\r\nexit/b 3\r\necho not reached\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new();
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let (event_tx, _events) = channel();
+        let (output_tx, _output) = channel();
+        let (stderr_tx, _stderr) = channel();
+        batch_debugger::executor::run_debugger_dap(
+            ctx_arc.clone(),
+            &pre,
+            &labels,
+            event_tx,
+            output_tx,
+            stderr_tx,
+        )
+        .expect("Executor failed");
+
+        let commands = commands.lock().unwrap();
+        assert!(commands.iter().any(|c| c == "echo back"));
+        assert!(!commands.iter().any(|c| c.contains("not reached")));
+        assert!(!commands.iter().any(|c| c.to_lowercase().starts_with("exit")));
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::Duration;
+
+        let content = "@echo off\r\nset ERR=3\r\ncall :fail\r\nif errorlevel 3 (echo got three) else (echo wrong code)\r\ncall :keep\r\nif errorlevel 4 (echo kept four) else (echo lost code)\r\ncall :quit\r\necho not reached\r\n:fail\r\nexit /b %ERR%\r\n:keep\r\nfailing-tool\r\nexit /b\r\n:quit\r\nexit 5\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new().respond("failing-tool", "", 4);
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let StopInfo { reason, .. } = events
+            .recv_timeout(Duration::from_secs(5))
+            .expect("EXIT should end the script");
+        assert_eq!(reason, "terminated");
+        handle.join().expect("Executor thread panicked");
+
+        let commands = commands.lock().unwrap();
+        assert!(commands.iter().any(|c| c == "echo got three"));
+        assert!(commands.iter().any(|c| c == "echo kept four"));
+        assert!(!commands.iter().any(|c| c.starts_with("exit")));
+        assert!(!commands.iter().any(|c| c == "echo not reached"));
+        let mut ctx = ctx_arc.lock().unwrap();
+        assert_eq!(ctx.last_exit_code, 5);
+        assert!(ctx.call_stack.is_empty());
+        assert!(ctx.is_cancelled());
+
+        ctx.last_exit_code = 7;
+        assert_eq!(ctx.parse_exit_code(" "), 7);
+        assert_eq!(ctx.parse_exit_code("%ERRORLEVEL%"), 7);
+        assert_eq!(ctx.parse_exit_code("12 trailing"), 12);
+    }
+
+    #[test]
+    fn test_subroutine_argument_placeholders() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{expand_argument_modifiers, DebugContext, RunMode};
+        use batch_debugger::parser::split_batch_arguments;
+        use std::path::Path;
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+
+        assert_eq!(
+            split_batch_arguments("\"a b\",c;d=e  f"),
+            ["\"a b\"", "c", "d", "e", "f"]
+        );
+        let cwd = Path::new("C:\\work");
+        assert_eq!(
+            expand_argument_modifiers("dp", "\"C:\\data\\report.txt\"", cwd),
+            "C:\\data\\"
+        );
+        assert_eq!(
+            expand_argument_modifiers("nx", "C:\\data\\report.txt", cwd),
+            "report.txt"
+        );
+        assert_eq!(expand_argument_modifiers("x", "archive", cwd), "");
+
+        let content = "@echo off\r\ncall :copy \"C:\\data\\report.txt\" \"D:\\out dir\"\r\ngoto :eof\r\n:copy\r\necho %1\r\necho %~n1\r\necho all=%*\r\nset \"DST=%~2\"\r\nfor %%f in (%~x1) do echo %%f\r\nexit /b 0\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new();
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let (event_tx, _events) = channel();
+        let (output_tx, _output) = channel();
+        let (stderr_tx, _stderr) = channel();
+        batch_debugger::executor::run_debugger_dap(
+            ctx_arc.clone(),
+            &pre,
+            &labels,
+            event_tx,
+            output_tx,
+            stderr_tx,
+        )
+        .expect("Executor failed");
+
+        let commands = commands.lock().unwrap();
+        assert!(commands
+            .iter()
+            .any(|c| c == "echo \"C:\\data\\report.txt\""));
+        assert!(commands.iter().any(|c| c == "echo report"));
+        assert!(commands
+            .iter()
+            .any(|c| c == "echo all=\"C:\\data\\report.txt\" \"D:\\out dir\""));
+        assert!(commands.iter().any(|c| c == "echo .txt"));
+        assert_eq!(
+            ctx_arc
+                .lock()
+                .unwrap()
+                .variables
+                .get("DST")
+                .map(String::as_str),
+            Some("D:\\out dir")
+        );
+    }
+
+    #[test]
+    fn test_if_runs_only_taken_branch() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::parser::parse_if_statement;
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+
+        let stmt = parse_if_statement("if \"%A%\"==\"1\" (echo one & echo x^)) else (echo other)")
+            .expect("Failed to parse");
+        assert_eq!(stmt.then_command, "echo one & echo x^)");
+        assert_eq!(stmt.else_command.as_deref(), Some("echo other"));
+        assert!(parse_if_statement("if \"%A%\"==\"1\" (").is_none());
+
+        let content = "@echo off\r\nif \"%MODE%\"==\"release\" (set PICKED=yes) else (set PICKED=no)\r\nif \"%MODE%\"==\"debug\" (set OTHER=1)\r\nif \"%MODE%\"==\"release\" cmd /c exit 3\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new()
+            .respond("echo \"%MODE%\"", "\"release\"", 0)
+            .respond("echo \"release\"", "\"release\"", 0)
+            .respond("echo \"debug\"", "\"debug\"", 0)
+            .respond("cmd /c exit 3", "", 3);
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_mode(RunMode::Continue);
+
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let (event_tx, _events) = channel();
+        let (output_tx, _output) = channel();
+        let (stderr_tx, _stderr) = channel();
+        batch_debugger::executor::run_debugger_dap(
+            ctx_arc.clone(),
+            &pre,
+            &labels,
+            event_tx,
+            output_tx,
+            stderr_tx,
+        )
+        .expect("Executor failed");
+
+        let commands = commands.lock().unwrap();
+        assert!(!commands.iter().any(|c| c.to_uppercase().starts_with("IF ")));
+        assert!(commands.iter().any(|c| c == "set PICKED=yes"));
+        assert!(!commands.iter().any(|c| c == "set PICKED=no"));
+
+        let ctx = ctx_arc.lock().unwrap();
+        assert_eq!(ctx.variables.get("PICKED"), Some(&"yes".to_string()));
+        assert!(!ctx.variables.contains_key("OTHER"));
+        assert_eq!(ctx.last_exit_code, 3);
+    }
+
+    #[test]
+    fn test_for_loop_stops_per_iteration() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use std::time::Duration;
+
+        let content =
+            "@echo off\r\nfor %%i in (one two three four five) do echo item %%i\r\necho done\r\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+        let for_pc = pre.phys_to_logical[1];
+        let items = ["one", "two", "three", "four", "five"];
+
+        // Step Into stops at every iteration with the loop variable set;
+        // Step Over from the first iteration runs the rest
+        for step in [RunMode::StepInto, RunMode::StepOver] {
+            let mut shell = MockShell::new();
+            for item in items {
+                shell = shell.respond(&format!("echo {}", item), item, 0);
+            }
+            let commands = shell.commands();
+            let mut ctx = DebugContext::new(shell);
+            ctx.set_mode(RunMode::StepInto);
+
+            let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+            let mut values = Vec::new();
+            while let Ok(stop) = events.recv_timeout(Duration::from_secs(5)) {
+                let pc = stop.pc;
+                if stop.reason == "terminated" {
+                    break;
+                }
+                wait_for_dap_stop(&ctx_arc, pc);
+                let mut ctx = ctx_arc.lock().unwrap();
+                if pc == for_pc {
+                    values.push(ctx.variables.get("%%i").cloned().unwrap_or_default());
+                    assert!(ctx.stop_text().unwrap().starts_with("FOR iteration"));
+                    ctx.set_mode(step);
+                } else {
+                    ctx.set_mode(RunMode::StepInto);
+                }
+                ctx.request_continue();
+            }
+            handle.join().expect("Executor thread panicked");
+
+            let expected: &[&str] = if step == RunMode::StepInto {
+                &items
+            } else {
+                &items[..1]
+            };
+            assert_eq!(values, expected);
+            let echoed = commands
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|c| c.starts_with("echo item "))
+                .count();
+            assert_eq!(echoed, items.len());
+        }
+    }
+
+    #[test]
+    fn test_step_into_called_script() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
+        use batch_debugger::executor::StopInfo;
+        use std::time::Duration;
+
+        let helper = create_test_batch(
+            "@echo off\r\necho in helper %1\r\nexit /b 4\r\necho not reached\r\n",
+            "called_helper",
+        );
+        let main = create_test_batch(
+            "@echo off\r\ncall test_called_helper.bat first\r\necho back\r\n",
+            "called_main",
+        );
+        let contents = fs::read_to_string(&main).expect("Could not read test file");
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+
+        let shell = MockShell::new().with_working_dir("tests/batch_files");
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.set_script_args(&main, Vec::new());
+        ctx.set_mode(RunMode::Continue);
+
+        let (ctx_arc, events, handle) = start_dap_executor(ctx, &pre, &labels);
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+        server.set_program(&main, pre.clone());
+        server.handle_set_breakpoints(
+            1,
+            "setBreakpoints".to_string(),
+            Some(serde_json::json!({
+                "source": { "path": helper },
+                "breakpoints": [{ "line": 3 }]
+            })),
+        );
+        ctx_arc.lock().unwrap().request_continue();
+
+        let StopInfo { pc, .. } = events
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Executor should stop in the called script");
+        wait_for_dap_stop(&ctx_arc, pc);
+        let frames = server.collect_stack_frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0]["name"], "test_called_helper.bat");
+        assert_eq!(frames[0]["line"], 3);
+        assert!(frames[0]["source"]["path"]
+            .as_str()
+            .unwrap()
+            .ends_with("test_called_helper.bat"));
+        assert_eq!(frames[1]["line"], 2);
+
+        resume_dap_executor(&ctx_arc, pc);
+        handle.join().expect("Executor thread panicked");
+
+        let commands = commands.lock().unwrap();
+        assert!(commands.iter().any(|c| c == "echo in helper first"));
+        assert!(!commands.iter().any(|c| c == "echo not reached"));
+        assert_eq!(commands.last().map(String::as_str), Some("echo back"));
+        assert!(ctx_arc.lock().unwrap().call_stack.is_empty());
+
+        cleanup_test_batch(&main);
+        cleanup_test_batch(&helper);
+    }
+
+    #[test]
+    fn test_repl_commands_tracked() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+
+        let shell = MockShell::new()
+            .respond("set /a COUNT=2*3", "6", 0)
+            .respond("dir /b", "a.bat\r\nb.bat\r\n", 0)
+            .respond("type missing.txt", "", 1);
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+
+        ctx.run_repl_command("set FOO=1")
+            .expect("Failed to run command");
+        assert_eq!(ctx.evaluate_expression("%FOO%").unwrap(), "1");
+
+        // SET /A runs once, its echoed value is both the output and the value
+        let result = ctx
+            .run_repl_command("set /a COUNT=2*3")
+            .expect("Failed to run command");
+        assert_eq!(result.stdout, "6");
+        assert_eq!(ctx.variables.get("COUNT"), Some(&"6".to_string()));
+
+        let listing = ctx
+            .run_repl_command("dir /b")
+            .expect("Failed to run command");
+        assert_eq!(listing.stdout.lines().count(), 2);
+
+        ctx.run_repl_command("type missing.txt")
+            .expect("Failed to run command");
+        assert_eq!(ctx.last_exit_code, 1);
+
+        let commands = commands.lock().unwrap();
+        let set_a_runs = commands.iter().filter(|c| c.contains("COUNT")).count();
+        assert_eq!(set_a_runs, 1);
+    }
+
+    #[test]
+    fn test_repl_directory_commands_are_tracked() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+
+        let start = std::env::temp_dir();
+        let sub = start.join(format!("batch-debugger-repl-cd-{}", std::process::id()));
+        std::fs::create_dir_all(&sub).unwrap();
+        let mut ctx = DebugContext::new(MockShell::new().with_working_dir(&start));
+
+        ctx.run_repl_command(&format!("pushd {}", sub.display()))
+            .expect("Failed to run command");
+        assert_eq!(ctx.get_current_dir(), sub.as_path());
+        assert_eq!(ctx.get_directory_stack().len(), 1);
+
+        ctx.run_repl_command("cd ..")
+            .expect("Failed to run command");
+        assert_eq!(ctx.get_current_dir(), start.as_path());
+
+        ctx.run_repl_command("popd").expect("Failed to run command");
+        assert_eq!(ctx.get_current_dir(), start.as_path());
+        assert!(ctx.get_directory_stack().is_empty());
+
+        assert!(ctx.run_repl_command("cd no-such-dir").is_err());
+        assert_eq!(ctx.last_exit_code, 1);
+
+        let _ = std::fs::remove_dir_all(&sub);
+    }
+
+    #[test]
+    fn test_cd_to_non_ascii_directory() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+
+        let start = std::env::temp_dir().join(format!("batch-debugger-cd-{}", std::process::id()));
+        let sub = start.join("xé");
+        std::fs::create_dir_all(sub.join("€")).unwrap();
+        let mut ctx = DebugContext::new(MockShell::new().with_working_dir(&start));
+
+        // The second byte of both names is inside a character
+        ctx.handle_cd(Some("xé")).expect("Failed to CD");
+        assert_eq!(ctx.get_current_dir(), sub.as_path());
+        ctx.handle_cd(Some("€")).expect("Failed to CD");
+        assert_eq!(ctx.get_current_dir(), sub.join("€").as_path());
+        ctx.handle_cd(Some("/d ..")).expect("Failed to CD");
+        assert_eq!(ctx.get_current_dir(), sub.as_path());
+
+        let _ = std::fs::remove_dir_all(&start);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_repl_dir_output() {
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        let result = ctx.run_repl_command("dir").expect("Failed to run command");
+        assert!(result.stdout.trim().lines().count() > 1);
+
+        ctx.run_repl_command("set FOO=1")
+            .expect("Failed to run command");
+        assert_eq!(ctx.evaluate_expression("%FOO%").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_internal_queries_keep_errorlevel() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+
+        // The helper query itself ends with ERRORLEVEL 0
+        let shell = MockShell::new().respond("echo %PATHEXT%", ".BAT", 0);
+        let commands = shell.commands();
+        let mut ctx = DebugContext::new(shell);
+        ctx.last_exit_code = 2;
+
+        let result = ctx.run_internal("echo %PATHEXT%").unwrap();
+        assert_eq!(result.stdout, ".BAT");
+        assert_eq!(ctx.last_exit_code, 2);
+        assert_eq!(
+            commands.lock().unwrap().last().map(String::as_str),
+            Some("cmd /c exit 2")
+        );
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_hovers_dont_clobber_errorlevel() {
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.run_repl_command("set A=1").unwrap();
+
+        let result = ctx.run_command("cmd /c exit 2").unwrap();
+        ctx.last_exit_code = result.exit_code;
+
+        for expression in ["%A%", "%PATHEXT%", "%NOT_DEFINED_ANYWHERE%"] {
+            let _ = ctx.evaluate_expression(expression);
+        }
+        let _ = ctx.resolve_executable("findstr");
+
+        let result = ctx
+            .run_command("if errorlevel 2 (echo still 2) else (echo lost)")
+            .unwrap();
+        assert_eq!(result.stdout.trim(), "still 2");
+        assert_eq!(ctx.last_exit_code, 2);
+    }
+
+    #[test]
+    fn test_ansi_stripping() {
+        use batch_debugger::debugger::ansi::{strip, AnsiMode};
+
+        assert_eq!(strip("\x1b[32mPASS\x1b[0m done"), "PASS done");
+        assert_eq!(strip("\x1b[1;38;5;208mbold\x1b[K"), "bold");
+        assert_eq!(strip("\x1b]0;title\x07text"), "text");
+        assert_eq!(strip("\x1b]8;;http://x\x1b\\link\x1b]8;;\x1b\\"), "link");
+        assert_eq!(strip("\u{9b}31mred"), "red");
+        assert_eq!(strip("\x1b=keypad"), "keypad");
+        assert_eq!(strip("plain ←text"), "plain ←text");
+        assert_eq!(AnsiMode::from_name("Strip"), Some(AnsiMode::Strip));
+        assert_eq!(AnsiMode::from_name("colors"), None);
+        assert_eq!(AnsiMode::default(), AnsiMode::Passthrough);
+    }
+
+    #[test]
+    fn test_ansi_stripped_from_values() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+
+        let shell = MockShell::new()
+            .respond("set /a N=5", "\x1b[1m5\x1b[0m", 0)
+            .respond("echo %TOOL_STATUS%", "\x1b[32mok\x1b[0m", 0);
+        let mut ctx = DebugContext::new(shell);
+
+        ctx.track_set_command("SET COLOR=\x1b[31mred\x1b[0m");
+        assert_eq!(ctx.variables.get("COLOR"), Some(&"red".to_string()));
+
+        ctx.track_set_command("set /a N=5");
+        assert_eq!(ctx.variables.get("N"), Some(&"5".to_string()));
+
+        assert_eq!(ctx.evaluate_expression("%TOOL_STATUS%").unwrap(), "ok");
+    }
+
+    #[test]
+    fn test_run_parts_exit_codes() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::Shell;
+        use batch_debugger::parser::split_composite_command;
+
+        let mut shell = MockShell::new()
+            .respond("findstr x missing.txt", "", 1)
+            .respond("echo next", "next\r\n", 0);
+
+        let parts = split_composite_command("findstr x missing.txt & echo next");
+        let codes: Vec<i32> = shell
+            .run_parts(&parts)
+            .unwrap()
+            .iter()
+            .map(|r| r.exit_code)
+            .collect();
+        assert_eq!(codes, vec![1, 0]);
+
+        let parts = split_composite_command("echo next || echo fallback");
+        assert_eq!(shell.run_parts(&parts).unwrap().len(), 1);
+
+        // && stops after the failure, a later & part still runs
+        let parts = split_composite_command("findstr x missing.txt && echo skipped & echo next");
+        let results = shell.run_parts(&parts).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].stdout.trim(), "next");
+    }
+
+    #[test]
+    fn test_set_a_runs_once_and_is_tracked_from_its_result() {
+        use batch_debugger::api::DebugSession;
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::parser::{part_runs, CommandOp};
+
+        assert!(part_runs(None, None));
+        assert!(part_runs(Some(CommandOp::And), Some(0)));
+        assert!(!part_runs(Some(CommandOp::And), Some(1)));
+        assert!(part_runs(Some(CommandOp::Or), Some(1)));
+        assert!(!part_runs(Some(CommandOp::Or), Some(0)));
+        assert!(part_runs(Some(CommandOp::Unconditional), Some(1)));
+
+        let path =
+            std::env::temp_dir().join(format!("batch-debugger-set-a-{}.bat", std::process::id()));
+        std::fs::write(
+            &path,
+            "@echo off\r\nset /a N+=1\r\nset /a M=2 & echo next\r\nexit /b 0\r\n",
+        )
+        .unwrap();
+        let shell = MockShell::new()
+            .respond("set /a N+=1", "1", 0)
+            .respond("set /a M=2 & echo next", "2next\r\n", 0)
+            .respond("echo %M%", "2\r\n", 0);
+        let commands = shell.commands();
+        let mut session = DebugSession::with_shell(&path, shell).unwrap();
+        session.set_breakpoint(4, None).unwrap();
+        session
+            .run_until_stop()
+            .unwrap()
+            .expect("Should stop at EXIT");
+
+        let variables = session.variables().unwrap();
+        assert_eq!(variables.get("N").map(String::as_str), Some("1"));
+        assert_eq!(variables.get("M").map(String::as_str), Some("2"));
+        let sent = commands.lock().unwrap().clone();
+        for command in ["set /a N+=1", "set /a M=2 & echo next"] {
+            assert_eq!(
+                sent.iter().filter(|c| c.as_str() == command).count(),
+                1,
+                "{} should run once: {:?}",
+                command,
+                sent
+            );
+        }
+
+        session.finish().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_run_parts_in_cmd() {
+        use batch_debugger::debugger::{CmdSession, Shell};
+        use batch_debugger::parser::split_composite_command;
+
+        let mut session = CmdSession::start().expect("Failed to start CMD session");
+
+        let parts = split_composite_command("findstr x no_such_file.txt & echo next");
+        let results = session.run_parts(&parts).expect("Failed to run parts");
+        assert_eq!(results.len(), 2);
+        assert_ne!(results[0].exit_code, 0);
+        assert_eq!(results[1].exit_code, 0);
+        assert_eq!(results[1].stdout.trim(), "next");
+
+        let parts = split_composite_command("echo ok || echo fallback");
+        let results = session.run_parts(&parts).expect("Failed to run parts");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].stdout.trim(), "ok");
+    }
+
+    #[test]
+    fn test_session_stats_split_helpers() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+
+        let shell =
+            MockShell::new()
+                .respond("set COUNT=1", "", 0)
+                .respond("echo %COUNT%", "1\r\n", 0);
+        let mut ctx = DebugContext::new(shell);
+
+        ctx.run_command("set COUNT=1").unwrap();
+        ctx.run_command("echo %COUNT%").unwrap();
+        ctx.run_internal("echo %PATHEXT%").unwrap();
+
+        let stats = ctx.session_stats();
+        assert_eq!(stats.script_commands, 2);
+        assert!(stats.helper_commands > 0);
+        assert!(ctx.ping_session().is_ok());
+    }
+
+    #[test]
+    fn test_session_details_are_read_while_a_command_runs() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{lock_shell, DebugContext};
+        use std::time::{Duration, Instant};
+
+        let mut ctx = DebugContext::new(MockShell::new().delay("ping", Duration::from_secs(2)));
+        let timeout = ctx.command_timeout();
+        let shell = ctx.shared_session();
+        let runner = std::thread::spawn(move || lock_shell(&shell).run("ping -n 3 localhost"));
+        let started = Instant::now();
+        while !ctx.session_busy() {
+            assert!(started.elapsed() < Duration::from_secs(1));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // None of these wait for the command to finish
+        let started = Instant::now();
+        assert_eq!(ctx.command_timeout(), timeout);
+        assert_eq!(ctx.shell_config().path.to_str(), Some("mock"));
+        assert_eq!(ctx.session_stats().script_commands, 0);
+        assert!(ctx.transcript(10).is_empty());
+        assert!(ctx.ping_idle_session().is_none());
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        runner.join().unwrap().unwrap();
+        assert_eq!(ctx.session_stats().script_commands, 1);
+        assert!(ctx.ping_idle_session().unwrap().is_ok());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_session_stats_in_cmd() {
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.run_command("set COUNT=1").unwrap();
+        let _ = ctx.evaluate_expression("%COUNT%");
+
+        let stats = ctx.session_stats();
+        assert!(stats.script_commands > 0);
+        assert!(stats.helper_commands > 0);
+        assert!(stats.slowest_command.is_some());
+        assert!(ctx.ping_session().is_ok());
+    }
+
+    #[test]
+    fn test_local_condition_evaluation() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.track_set_command("SET X=abc");
+        ctx.track_set_command("SET FLAG=1");
+
+        assert_eq!(ctx.evaluate_condition_locally("\"%X%\"==\"abc\""), Some(true));
+        assert_eq!(ctx.evaluate_condition_locally("\"%X%\"==\"xyz\""), Some(false));
+        assert_eq!(ctx.evaluate_condition_locally("DEFINED FLAG"), Some(true));
+        assert_eq!(ctx.evaluate_condition_locally("NOT DEFINED MISSING"), Some(true));
+        assert_eq!(ctx.evaluate_condition_locally("FLAG"), Some(true));
+
+        // Needs CMD: substring operations and file checks
+        assert_eq!(ctx.evaluate_condition_locally("\"%X:~0,1%\"==\"a\""), None);
+        assert_eq!(ctx.evaluate_condition_locally("EXIST foo.txt"), None);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_set_breakpoints_replaces_previous_set() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::{Arc, Mutex};
+
+        let content = r#"@echo off
+echo one
+echo two
+echo three
+"#;
+
+        let path = create_test_batch(content, "replace_breakpoints");
+        let contents = fs::read_to_string(&path).expect("Could not read test file");
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+        server.set_program(&path, pre.clone());
+
+        server.handle_set_breakpoints(
+            1,
+            "setBreakpoints".to_string(),
+            Some(serde_json::json!({
+                "source": { "path": path },
+                "breakpoints": [{ "line": 2 }, { "line": 4 }]
+            })),
+        );
+        server.handle_set_breakpoints(
+            2,
+            "setBreakpoints".to_string(),
+            Some(serde_json::json!({
+                "source": { "path": path },
+                "breakpoints": [{ "line": 2 }]
+            })),
+        );
+
+        let mut ctx = ctx_arc.lock().unwrap();
+        assert!(ctx.should_stop_at(pre.phys_to_logical[1]), "Kept breakpoint");
+        assert!(
+            !ctx.should_stop_at(pre.phys_to_logical[3]),
+            "Removed breakpoint should not stop"
+        );
+        drop(ctx);
+
+        cleanup_test_batch(&path);
+    }
+
+    #[test]
+    fn test_breakpoint_enable_disable() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+        use batch_debugger::debugger::RunMode;
+
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.set_mode(RunMode::Continue);
+
+        ctx.add_breakpoint(10);
+        assert!(ctx.set_breakpoint_enabled(10, false));
+        assert!(!ctx.should_stop_at(10), "Disabled breakpoint should not stop");
+        assert!(ctx.get_breakpoint(10).is_some(), "Breakpoint is kept");
+
+        assert!(ctx.set_breakpoint_enabled(10, true));
+        assert!(ctx.should_stop_at(10), "Re-enabled breakpoint should stop");
+        assert_eq!(ctx.get_breakpoint(10).unwrap().hit_count, 1);
+
+        assert!(!ctx.set_breakpoint_enabled(20, false), "No breakpoint on 20");
+    }
+
+    #[test]
+    fn test_unconditional_breakpoint_still_works() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+        use batch_debugger::debugger::RunMode;
+
+        let mut ctx = DebugContext::new(MockShell::new());
+
+        // Add unconditional breakpoint (no condition)
+        ctx.add_breakpoint(10);
+
+        // Set mode to Continue
+        ctx.set_mode(RunMode::Continue);
+
+        // Should stop unconditionally
+        assert!(
+            ctx.should_stop_at(10),
+            "Should stop at unconditional breakpoint"
+        );
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_set_a_simple_arithmetic() {
+        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::DebugContext;
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        // Test simple addition
+        ctx.track_set_command("SET /A RESULT=10+20");
+
+        let vars = ctx.get_visible_variables();
+        assert_eq!(vars.get("RESULT"), Some(&"30".to_string()));
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_set_a_multiplication() {
+        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::DebugContext;
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        // Test multiplication
+        ctx.track_set_command("SET /A COUNTER=5");
+        ctx.track_set_command("SET /A RESULT=COUNTER*2");
+
+        let vars = ctx.get_visible_variables();
+        assert_eq!(vars.get("COUNTER"), Some(&"5".to_string()));
+        assert_eq!(vars.get("RESULT"), Some(&"10".to_string()));
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_set_a_complex_expression() {
+        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::DebugContext;
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        // Test complex expression with precedence
+        ctx.track_set_command("SET /A BASE=5");
+        ctx.track_set_command("SET /A RESULT=BASE*2+3");
+
+        let vars = ctx.get_visible_variables();
+        assert_eq!(vars.get("BASE"), Some(&"5".to_string()));
+        assert_eq!(vars.get("RESULT"), Some(&"13".to_string())); // 5*2=10, 10+3=13
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_set_a_compound_assignment() {
+        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::DebugContext;
 
         let session = CmdSession::start().expect("Failed to start CMD session");
         let mut ctx = DebugContext::new(session);
@@ -897,6 +7223,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_set_a_with_setlocal() {
         use batch_debugger::debugger::CmdSession;
         use batch_debugger::debugger::DebugContext;
@@ -906,12 +7233,7 @@ exit /b 0
         let mut ctx = DebugContext::new(session);
 
         // Create a call frame with SETLOCAL
-        ctx.call_stack.push(Frame {
-            return_pc: 0,
-            args: None,
-            locals: std::collections::HashMap::new(),
-            has_setlocal: false,
-        });
+        ctx.call_stack.push(Frame::new(0, None));
         ctx.handle_setlocal();
 
         // SET /A in local scope
@@ -925,6 +7247,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_set_p_with_file_input() {
         use batch_debugger::debugger::CmdSession;
         use batch_debugger::debugger::DebugContext;
@@ -951,6 +7274,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_set_p_with_setlocal() {
         use batch_debugger::debugger::CmdSession;
         use batch_debugger::debugger::DebugContext;
@@ -961,12 +7285,7 @@ exit /b 0
         let mut ctx = DebugContext::new(session);
 
         // Create a call frame with SETLOCAL
-        ctx.call_stack.push(Frame {
-            return_pc: 0,
-            args: None,
-            locals: std::collections::HashMap::new(),
-            has_setlocal: false,
-        });
+        ctx.call_stack.push(Frame::new(0, None));
         ctx.handle_setlocal();
 
         // Create a temp file with input
@@ -1045,6 +7364,34 @@ exit /b 0
     }
 
     #[test]
+    fn test_stale_watches_pruned_on_stop() {
+        use batch_debugger::dap::DapServer;
+
+        let mut server = DapServer::new();
+
+        // First stop: the client evaluates two watches
+        server.on_stopped();
+        server.record_watch_request("COUNTER");
+        server.record_watch_request("NAME");
+        assert_eq!(server.get_watches(), ["COUNTER", "NAME"]);
+
+        // Second stop: the user deleted COUNTER and only NAME is evaluated
+        server.on_stopped();
+        server.record_watch_request("NAME");
+
+        // The next stop prunes the watch nobody asked for
+        server.on_stopped();
+        assert_eq!(server.get_watches(), ["NAME"]);
+
+        // Explicitly managed watches survive stops without watch evaluations
+        server.clear_watches();
+        server.add_watch("VALUE".to_string());
+        server.on_stopped();
+        assert_eq!(server.get_watches(), ["VALUE"]);
+    }
+
+    #[test]
+    #[cfg(windows)]
     fn test_watch_expressions_evaluation() {
         use batch_debugger::dap::DapServer;
         use batch_debugger::debugger::CmdSession;
@@ -1088,6 +7435,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_watch_with_complex_expressions() {
         use batch_debugger::dap::DapServer;
         use batch_debugger::debugger::CmdSession;
@@ -1120,12 +7468,11 @@ exit /b 0
 
     #[test]
     fn test_if_errorlevel_condition() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
         use batch_debugger::parser::{parse_if_statement, IfCondition};
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set exit code to 5
         ctx.last_exit_code = 5;
@@ -1164,6 +7511,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_if_string_equal_condition() {
         use batch_debugger::debugger::CmdSession;
         use batch_debugger::debugger::DebugContext;
@@ -1206,6 +7554,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_if_exist_condition() {
         use batch_debugger::debugger::CmdSession;
         use batch_debugger::debugger::DebugContext;
@@ -1242,12 +7591,11 @@ exit /b 0
 
     #[test]
     fn test_if_defined_condition() {
-        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::test_support::MockShell;
         use batch_debugger::debugger::DebugContext;
         use batch_debugger::parser::parse_if_statement;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set a variable
         ctx.set_variable("MYVAR", "value")
@@ -1279,6 +7627,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_if_compare_numeric() {
         use batch_debugger::debugger::CmdSession;
         use batch_debugger::debugger::DebugContext;
@@ -1574,6 +7923,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_for_basic_expansion() {
         use batch_debugger::debugger::{CmdSession, DebugContext};
         use batch_debugger::parser::{parse_for_statement, ForLoopType};
@@ -1601,11 +7951,11 @@ exit /b 0
 
     #[test]
     fn test_for_numeric_expansion() {
-        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
         use batch_debugger::parser::{parse_for_statement, ForLoopType};
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Test FOR /L expansion
         let stmt = parse_for_statement("FOR /L %%n IN (1,1,3) DO echo %%n").expect("Parse failed");
@@ -1634,11 +7984,11 @@ exit /b 0
 
     #[test]
     fn test_for_loop_variable_tracking() {
-        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
         use batch_debugger::parser::parse_for_statement;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Expand a basic FOR loop
         let stmt =
@@ -1661,11 +8011,11 @@ exit /b 0
 
     #[test]
     fn test_for_with_setlocal() {
-        use batch_debugger::debugger::{CmdSession, DebugContext, Frame};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, Frame};
         use batch_debugger::parser::parse_for_statement;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Create a SETLOCAL scope
         ctx.call_stack.push(Frame::new(0, None));
@@ -1811,10 +8161,10 @@ exit /b 0
 
     #[test]
     fn test_data_breakpoint_add_and_check() {
-        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set a variable
         ctx.run_command("SET COUNTER=0")
@@ -1847,10 +8197,10 @@ exit /b 0
 
     #[test]
     fn test_data_breakpoint_multiple_variables() {
-        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set variables
         ctx.run_command("SET VAR1=A").expect("Failed to set VAR1");
@@ -1887,10 +8237,10 @@ exit /b 0
 
     #[test]
     fn test_data_breakpoint_removal() {
-        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set a variable
         ctx.run_command("SET VALUE=10")
@@ -1916,11 +8266,290 @@ exit /b 0
     }
 
     #[test]
-    fn test_data_breakpoint_get_list() {
+    fn test_data_breakpoint_condition() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+
+        let mut ctx = DebugContext::new(MockShell::new());
+
+        ctx.track_set_command("SET COUNTER=99");
+        ctx.add_data_breakpoint_with_condition(
+            "COUNTER".to_string(),
+            Some("$NEW GTR 100".to_string()),
+            false,
+        );
+
+        // 100 is a change, but the condition is false
+        ctx.track_set_command("SET COUNTER=100");
+        assert!(!ctx.check_data_breakpoints(), "Should not hit at 100");
+
+        ctx.track_set_command("SET COUNTER=101");
+        assert!(ctx.check_data_breakpoints(), "Should hit at 101");
+        assert_eq!(
+            ctx.data_breakpoint_hit,
+            Some((
+                "COUNTER".to_string(),
+                "100".to_string(),
+                "101".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_data_breakpoint_on_delete() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+
+        let mut ctx = DebugContext::new(MockShell::new());
+
+        ctx.track_set_command("SET TEMP_VAR=1");
+        ctx.add_data_breakpoint_with_condition("TEMP_VAR".to_string(), None, true);
+
+        // Plain changes do not fire a delete breakpoint
+        ctx.track_set_command("SET TEMP_VAR=2");
+        assert!(!ctx.check_data_breakpoints(), "Should not hit on change");
+
+        ctx.track_set_command("SET TEMP_VAR=");
+        assert!(ctx.check_data_breakpoints(), "Should hit on delete");
+        assert_eq!(ctx.data_breakpoint_hit.as_ref().unwrap().2, "");
+    }
+
+    #[test]
+    fn test_variable_cleared_under_setlocal_stays_undefined() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, Frame};
+
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.track_set_command("SET TEMP_VAR=outer");
+        ctx.add_data_breakpoint_with_condition("TEMP_VAR".to_string(), None, true);
+
+        // Clearing it in a SETLOCAL hides the outer value until ENDLOCAL
+        ctx.handle_setlocal();
+        ctx.track_set_command("SET TEMP_VAR=");
+        assert_eq!(ctx.get_visible_variables().get("TEMP_VAR"), None);
+        assert!(ctx.check_data_breakpoints(), "Should hit on delete");
+        ctx.handle_endlocal();
+        assert_eq!(
+            ctx.get_visible_variables()
+                .get("TEMP_VAR")
+                .map(String::as_str),
+            Some("outer")
+        );
+
+        // The same in a CALLed subroutine's scope, which can set it again
+        ctx.call_stack.push(Frame::new(0, None));
+        ctx.handle_setlocal();
+        ctx.track_set_command("SET TEMP_VAR=");
+        assert_eq!(ctx.get_visible_variables().get("TEMP_VAR"), None);
+        assert_eq!(ctx.get_frame_visible_variables(1).get("TEMP_VAR"), None);
+        ctx.track_set_command("SET TEMP_VAR=inner");
+        assert_eq!(
+            ctx.get_visible_variables()
+                .get("TEMP_VAR")
+                .map(String::as_str),
+            Some("inner")
+        );
+        ctx.handle_endlocal();
+        assert_eq!(
+            ctx.get_visible_variables()
+                .get("TEMP_VAR")
+                .map(String::as_str),
+            Some("outer")
+        );
+    }
+
+    #[test]
+    fn test_step_back_restores_variable_origins() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, VariableOrigin};
+
+        let mut ctx = DebugContext::new(MockShell::new());
+        ctx.import_environment(vec![("TOOL".to_string(), "from-env".to_string())]);
+
+        ctx.record_snapshot(1, "set TOOL=mine");
+        ctx.track_set_command("set TOOL=mine");
+        assert_eq!(ctx.variable_origin("TOOL", None), VariableOrigin::Script);
+
+        ctx.step_back().unwrap().expect("Should step back");
+        assert_eq!(
+            ctx.variables.get("TOOL").map(String::as_str),
+            Some("from-env")
+        );
+        assert_eq!(
+            ctx.variable_origin("TOOL", None),
+            VariableOrigin::Environment
+        );
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_variable_history() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use std::sync::{Arc, Mutex};
+
+        let content = "@echo off\nset COUNT=1\nset COUNT=2\nset COUNT=3\n";
+        let physical_lines: Vec<&str> = content.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        for (pc, value) in [(1, "1"), (2, "2"), (3, "3")] {
+            ctx.track_pc(pc);
+            ctx.track_set_command(&format!("set COUNT={}", value));
+        }
+
+        let history = ctx.variable_history("count");
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].old_value, None);
+        assert_eq!(history[1].old_value.as_deref(), Some("1"));
+        assert_eq!(history[2].new_value.as_deref(), Some("3"));
+        assert_eq!(
+            history.iter().map(|c| c.line).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(ctx.line_where_set("COUNT", Some("2")), Some(2));
+
+        let mut server = DapServer::new();
+        server.set_context(Arc::new(Mutex::new(ctx)));
+        server.set_program("history.bat", pre);
+
+        let entries = server.collect_variable_history("COUNT");
+        let lines: Vec<u64> = entries.iter().map(|e| e["line"].as_u64().unwrap()).collect();
+        let values: Vec<&str> = entries.iter().map(|e| e["value"].as_str().unwrap()).collect();
+        assert_eq!(lines, vec![2, 3, 4]);
+        assert_eq!(values, vec!["1", "2", "3"]);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_variable_origin_hints() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::{CmdSession, DebugContext, Frame, VariableOrigin};
+        use std::sync::{Arc, Mutex};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.import_environment(vec![("SEEDED".to_string(), "from-env".to_string())]);
+        ctx.track_set_command("SET SCRIPTED=1");
+        ctx.set_loop_variable("%%i", "a.txt");
+
+        ctx.call_stack
+            .push(Frame::new(0, None).with_label("sub", 0));
+        ctx.handle_setlocal();
+        ctx.track_set_command("SET LOCAL_ONLY=2");
+        assert_eq!(
+            ctx.variable_origin("LOCAL_ONLY", Some(0)),
+            VariableOrigin::FrameLocal
+        );
+
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+
+        let attributes = |vars: &[serde_json::Value], name: &str| -> Vec<String> {
+            let var = vars
+                .iter()
+                .find(|v| v["name"] == name)
+                .unwrap_or_else(|| panic!("{} should be listed", name));
+            var["presentationHint"]["attributes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|a| a.as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let globals = server.collect_variables(2);
+        assert!(attributes(&globals, "SEEDED").contains(&"environment".to_string()));
+        assert!(attributes(&globals, "SCRIPTED").contains(&"script".to_string()));
+
+        // Loop variables are grouped under their own node
+        assert!(globals.iter().all(|v| v["name"] != "%%i"));
+        let loop_node = globals
+            .iter()
+            .find(|v| v["name"] == "Loop Variables")
+            .expect("Loop variables node should exist");
+        let loop_vars = server.collect_variables(loop_node["variablesReference"].as_u64().unwrap());
+        assert!(attributes(&loop_vars, "%%i").contains(&"loopVariable".to_string()));
+
+        let locals = server.collect_variables(1000);
+        assert!(attributes(&locals, "LOCAL_ONLY").contains(&"local".to_string()));
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_evaluate_in_frame() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, Frame};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.track_set_command("SET X=caller");
+
+        ctx.call_stack
+            .push(Frame::new(2, Some(vec!["first".to_string()])).with_label("sub", 4));
+        ctx.handle_setlocal();
+        ctx.track_set_command("SET X=callee");
+
+        // Frame 0 is the top-level script, frame 1 the subroutine
+        assert_eq!(ctx.evaluate_expression_in_frame("X", 0).unwrap(), "caller");
+        assert_eq!(ctx.evaluate_expression_in_frame("X", 1).unwrap(), "callee");
+        assert_eq!(ctx.evaluate_expression_in_frame("%X%", 0).unwrap(), "caller");
+        assert_eq!(ctx.evaluate_expression_in_frame("%1", 1).unwrap(), "first");
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_variables_marked_changed_since_last_stop() {
+        use batch_debugger::dap::DapServer;
         use batch_debugger::debugger::{CmdSession, DebugContext};
+        use std::sync::{Arc, Mutex};
 
         let session = CmdSession::start().expect("Failed to start CMD session");
         let mut ctx = DebugContext::new(session);
+        ctx.track_set_command("SET KEEP=1");
+        ctx.track_set_command("SET STEPPED=a");
+        ctx.mark_stop();
+
+        // Step over a single SET
+        ctx.track_set_command("SET STEPPED=b");
+        ctx.mark_stop();
+
+        let ctx_arc = Arc::new(Mutex::new(ctx));
+        let mut server = DapServer::new();
+        server.set_context(ctx_arc.clone());
+
+        let changed: Vec<String> = server
+            .collect_variables(2)
+            .iter()
+            .filter(|v| {
+                v["presentationHint"]["attributes"]
+                    .as_array()
+                    .is_some_and(|a| a.iter().any(|x| x == "hasChanged"))
+            })
+            .map(|v| v["name"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(changed, vec!["STEPPED".to_string()]);
+
+        // ERRORLEVEL changes are flagged too
+        ctx_arc.lock().unwrap().last_exit_code = 1;
+        ctx_arc.lock().unwrap().mark_stop();
+        let vars = server.collect_variables(2);
+        let errorlevel = vars.iter().find(|v| v["name"] == "ERRORLEVEL").unwrap();
+        assert!(errorlevel["presentationHint"]["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|x| x == "hasChanged"));
+    }
+
+    #[test]
+    fn test_data_breakpoint_get_list() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Add multiple data breakpoints
         ctx.add_data_breakpoint("VAR_A".to_string());
@@ -1937,10 +8566,10 @@ exit /b 0
 
     #[test]
     fn test_hover_variable_preview() {
-        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set some variables
         ctx.run_command("SET NAME=John")
@@ -1969,11 +8598,11 @@ exit /b 0
 
     #[test]
     fn test_pushd_changes_directory() {
-        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
         use std::env;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Get current directory
         let original_dir = env::current_dir().expect("Failed to get current dir");
@@ -1985,9 +8614,12 @@ exit /b 0
             ctx.handle_pushd(Some("tests"))
                 .expect("Failed to PUSHD to tests");
 
-            // Check directory changed
-            let new_dir = env::current_dir().expect("Failed to get new dir");
-            assert_eq!(new_dir, test_dir, "Directory should have changed");
+            // Check tracked directory changed
+            assert_eq!(
+                ctx.get_current_dir(),
+                test_dir.as_path(),
+                "Tracked directory should have changed"
+            );
 
             // Check stack has entry
             let stack = ctx.get_directory_stack();
@@ -1997,19 +8629,16 @@ exit /b 0
                 original_dir.to_str().unwrap(),
                 "Stack should contain original directory"
             );
-
-            // Clean up - go back
-            env::set_current_dir(&original_dir).ok();
         }
     }
 
     #[test]
     fn test_pushd_without_argument() {
-        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
         use std::env;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // PUSHD without argument (should fail or do nothing)
         let result = ctx.handle_pushd(None);
@@ -2021,11 +8650,11 @@ exit /b 0
 
     #[test]
     fn test_popd_restores_directory() {
-        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
         use std::env;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Get current directory
         let original_dir = env::current_dir().expect("Failed to get current dir");
@@ -2038,15 +8667,21 @@ exit /b 0
                 .expect("Failed to PUSHD to tests");
 
             // Verify we're in the new directory
-            let new_dir = env::current_dir().expect("Failed to get new dir");
-            assert_eq!(new_dir, test_dir, "Should be in tests directory");
+            assert_eq!(
+                ctx.get_current_dir(),
+                test_dir.as_path(),
+                "Should be in tests directory"
+            );
 
             // POPD back
             ctx.handle_popd().expect("Failed to POPD");
 
             // Check directory restored
-            let restored_dir = env::current_dir().expect("Failed to get restored dir");
-            assert_eq!(restored_dir, original_dir, "Directory should be restored");
+            assert_eq!(
+                ctx.get_current_dir(),
+                original_dir.as_path(),
+                "Directory should be restored"
+            );
 
             // Check stack is empty
             let stack = ctx.get_directory_stack();
@@ -2056,10 +8691,10 @@ exit /b 0
 
     #[test]
     fn test_popd_empty_stack() {
-        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // POPD with empty stack should fail gracefully
         let result = ctx.handle_popd();
@@ -2070,11 +8705,11 @@ exit /b 0
 
     #[test]
     fn test_pushd_popd_multiple_levels() {
-        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
         use std::env;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Get current directory
         let original_dir = env::current_dir().expect("Failed to get current dir");
@@ -2118,25 +8753,24 @@ exit /b 0
                 assert_eq!(stack.len(), 0, "Stack should be empty after second POPD");
 
                 // Verify back to original
-                let current = env::current_dir().expect("Failed to get current dir");
                 assert_eq!(
-                    current, original_dir,
+                    ctx.get_current_dir(),
+                    original_dir.as_path(),
                     "Should be back to original directory"
                 );
             } else {
                 // Clean up if batch_files doesn't exist
                 ctx.handle_popd().ok();
-                env::set_current_dir(&original_dir).ok();
             }
         }
     }
 
     #[test]
     fn test_shift_basic() {
-        use batch_debugger::debugger::{CmdSession, DebugContext, Frame};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, Frame};
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Create a call frame with arguments
         let mut frame = Frame::new(
@@ -2171,10 +8805,10 @@ exit /b 0
 
     #[test]
     fn test_shift_multiple() {
-        use batch_debugger::debugger::{CmdSession, DebugContext, Frame};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, Frame};
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Create a call frame with arguments
         ctx.call_stack.push(Frame::new(
@@ -2201,10 +8835,10 @@ exit /b 0
 
     #[test]
     fn test_shift_empty_args() {
-        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // No call frame - should handle gracefully
         ctx.handle_shift(1);
@@ -2215,10 +8849,10 @@ exit /b 0
 
     #[test]
     fn test_shift_no_frame_args() {
-        use batch_debugger::debugger::{CmdSession, DebugContext, Frame};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, Frame};
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Create frame with no args
         ctx.call_stack.push(Frame::new(10, None));
@@ -2233,10 +8867,10 @@ exit /b 0
 
     #[test]
     fn test_shift_beyond_args() {
-        use batch_debugger::debugger::{CmdSession, DebugContext, Frame};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, Frame};
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Create frame with 2 args
         ctx.call_stack
@@ -2254,10 +8888,10 @@ exit /b 0
 
     #[test]
     fn test_shift_with_setlocal() {
-        use batch_debugger::debugger::{CmdSession, DebugContext, Frame};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, Frame};
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Create frame with SETLOCAL and args
         ctx.call_stack.push(Frame::new(
@@ -2285,11 +8919,11 @@ exit /b 0
 
     #[test]
     fn test_directory_stack_tracking() {
-        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
         use std::env;
 
-        let session = CmdSession::start().expect("Failed to start CMD session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         let original_dir = env::current_dir().expect("Failed to get current dir");
         let test_dir = original_dir.join("tests");
@@ -2322,15 +8956,112 @@ exit /b 0
             while !ctx.get_directory_stack().is_empty() {
                 ctx.handle_popd().ok();
             }
-            env::set_current_dir(&original_dir).ok();
         }
     }
 
+    #[test]
+    #[cfg(windows)]
+    fn test_cwd_and_directory_stack_in_variables() {
+        use batch_debugger::dap::DapServer;
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use std::env;
+        use std::sync::{Arc, Mutex};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        let original_dir = env::current_dir().expect("Failed to get current dir");
+        let test_dir = original_dir.join("tests");
+        let batch_dir = test_dir.join("batch_files");
+
+        ctx.handle_cd(Some("tests")).expect("Failed to CD");
+        ctx.handle_pushd(Some("batch_files"))
+            .expect("Failed to PUSHD");
+
+        let mut server = DapServer::new();
+        server.set_context(Arc::new(Mutex::new(ctx)));
+
+        // Global scope shows CWD and an expandable DIRSTACK
+        let globals = server.collect_variables(2);
+        let cwd = globals
+            .iter()
+            .find(|v| v["name"] == "CWD")
+            .expect("CWD should be in Global scope");
+        assert_eq!(cwd["value"], batch_dir.to_str().unwrap());
+
+        let dirstack = globals
+            .iter()
+            .find(|v| v["name"] == "DIRSTACK")
+            .expect("DIRSTACK should be in Global scope");
+        let stack_ref = dirstack["variablesReference"].as_u64().unwrap();
+        assert!(stack_ref > 0, "DIRSTACK should be expandable");
+
+        // Deepest first: current directory, then the directory PUSHD saved
+        let stack = server.collect_variables(stack_ref);
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack[0]["value"], batch_dir.to_str().unwrap());
+        assert_eq!(stack[1]["value"], test_dir.to_str().unwrap());
+
+        assert_eq!(
+            env::current_dir().expect("Failed to get current dir"),
+            original_dir
+        );
+    }
+
+    #[test]
+    fn test_pushd_popd_leave_process_cwd_untouched() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+        use std::env;
+
+        let mut ctx = DebugContext::new(MockShell::new());
+
+        let original_dir = env::current_dir().expect("Failed to get current dir");
+
+        ctx.handle_pushd(Some("tests")).expect("Failed to PUSHD");
+        assert_eq!(
+            env::current_dir().expect("Failed to get current dir"),
+            original_dir,
+            "PUSHD must not change the process working directory"
+        );
+
+        // Relative targets resolve against the tracked directory, not the process cwd
+        ctx.handle_pushd(Some("batch_files"))
+            .expect("Failed to second PUSHD");
+        assert_eq!(
+            ctx.get_current_dir(),
+            original_dir.join("tests").join("batch_files").as_path()
+        );
+
+        ctx.handle_popd().expect("Failed to POPD");
+        ctx.handle_popd().expect("Failed to POPD");
+        assert_eq!(
+            env::current_dir().expect("Failed to get current dir"),
+            original_dir,
+            "POPD must not change the process working directory"
+        );
+        assert_eq!(ctx.get_current_dir(), original_dir.as_path());
+    }
+
+    #[test]
+    fn test_pushd_missing_directory_keeps_state() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+        use std::env;
+
+        let mut ctx = DebugContext::new(MockShell::new());
+
+        let original_dir = env::current_dir().expect("Failed to get current dir");
+
+        let result = ctx.handle_pushd(Some("does_not_exist_dir"));
+        assert!(result.is_err(), "PUSHD to a missing directory should error");
+        assert_eq!(ctx.get_directory_stack().len(), 0);
+        assert_eq!(ctx.get_current_dir(), original_dir.as_path());
+    }
+
     #[test]
     fn test_builtin_command_detection() {
-        // This test verifies that is_builtin_command() correctly identifies built-in commands
-        // The function is in dap_runner.rs and is private, so we test indirectly
-        // by verifying that the list of built-ins is comprehensive
+        use batch_debugger::parser::is_builtin_command;
 
         let builtins = vec![
             "ECHO", "SET", "IF", "FOR", "CALL", "GOTO", "EXIT", "REM", "CD", "CHDIR", "DIR",
@@ -2340,29 +9071,37 @@ exit /b 0
             "BREAK", "VERIFY",
         ];
 
-        // All of these should be recognized as built-ins
-        // (We can't directly test the function since it's private, but we document the expected list)
-        assert!(
-            builtins.len() > 30,
-            "Should have comprehensive list of built-ins"
-        );
+        for builtin in builtins {
+            assert!(
+                is_builtin_command(builtin),
+                "{} should be built-in",
+                builtin
+            );
+            assert!(is_builtin_command(&builtin.to_lowercase()));
+        }
+        assert!(is_builtin_command("@echo off"));
+        assert!(is_builtin_command("echo."));
+        assert!(is_builtin_command("dir/s"));
     }
 
     #[test]
     fn test_external_command_examples() {
-        // Examples of commands that should be detected as external
+        use batch_debugger::parser::is_builtin_command;
+
+        // Common external commands that must not be classified as built-in
         let externals = vec![
             "python", "node", "git", "npm", "cargo", "javac", "gcc", "cl.exe", "notepad", "calc",
             "explorer", "tasklist", "netstat", "ping", "ipconfig",
             "findstr", // This is actually built-in to find.exe, but often used like external
         ];
 
-        // These are common external commands that should NOT be in the built-in list
-        // (We document this for reference, actual detection happens in dap_runner.rs)
-        assert!(
-            externals.len() > 10,
-            "Should have examples of external commands"
-        );
+        for external in externals {
+            assert!(
+                !is_builtin_command(external),
+                "{} should be external",
+                external
+            );
+        }
     }
 
     #[test]
@@ -2377,10 +9116,17 @@ exit /b 0
             ("FOR %%i IN (a b c) DO ECHO %%i", "FOR"),
             ("python script.py arg1 arg2", "python"),
             ("git commit -m \"message\"", "git"),
+            ("@echo off", "echo"),
+            ("echo.", "echo"),
+            ("cl.exe /c main.c", "cl.exe"),
+            (
+                "\"C:\\Program Files\\Tool\\x.exe\" /q",
+                "\"C:\\Program Files\\Tool\\x.exe\"",
+            ),
         ];
 
         for (full_cmd, expected_name) in test_cases {
-            let extracted = full_cmd.split_whitespace().next().unwrap();
+            let extracted = batch_debugger::parser::command_name(full_cmd);
             assert_eq!(
                 extracted.to_uppercase(),
                 expected_name.to_uppercase(),
@@ -2392,6 +9138,24 @@ exit /b 0
     }
 
     #[test]
+    fn test_resolve_executable_with_a_quoted_path() {
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::DebugContext;
+
+        let shell = MockShell::new().respond(
+            "where \"C:\\Program Files\\Tool:x.exe\"",
+            "C:\\Program Files\\Tool\\x.exe\r\n",
+            0,
+        );
+        let mut ctx = DebugContext::new(shell);
+        assert_eq!(
+            ctx.resolve_executable("\"C:\\Program Files\\Tool\\x.exe\" /q").as_deref(),
+            Some("C:\\Program Files\\Tool\\x.exe")
+        );
+    }
+
+    #[test]
+    #[cfg(windows)]
     fn test_string_operation_substring() {
         use batch_debugger::debugger::{CmdSession, DebugContext};
 
@@ -2422,6 +9186,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_string_operation_replacement() {
         use batch_debugger::debugger::{CmdSession, DebugContext};
 
@@ -2454,6 +9219,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_string_operation_replace_from_start() {
         use batch_debugger::debugger::{CmdSession, DebugContext};
 
@@ -2475,6 +9241,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_string_operation_combined() {
         use batch_debugger::debugger::{CmdSession, DebugContext};
 
@@ -2496,6 +9263,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_string_operation_empty_replacement() {
         use batch_debugger::debugger::{CmdSession, DebugContext};
 
@@ -2518,6 +9286,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_string_operation_with_spaces() {
         use batch_debugger::debugger::{CmdSession, DebugContext};
 
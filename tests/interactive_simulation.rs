@@ -41,10 +41,10 @@ exit /b 0
         let labels = batch_debugger::parser::build_label_map(&physical_lines);
 
         // Simulate execution with StepInto mode
-        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
 
-        let session = CmdSession::start().expect("Failed to start session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
         ctx.set_mode(RunMode::StepInto);
 
         // Verify we're in StepInto mode
@@ -75,10 +75,10 @@ exit /b 0
 
         let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
 
-        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
 
-        let session = CmdSession::start().expect("Failed to start session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
         ctx.set_mode(RunMode::StepOver);
 
         assert_eq!(ctx.mode(), RunMode::StepOver);
@@ -103,10 +103,10 @@ exit /b 0
 
         let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
 
-        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
 
-        let session = CmdSession::start().expect("Failed to start session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set breakpoints at lines 2 and 4
         ctx.add_breakpoint(2);
@@ -129,10 +129,10 @@ exit /b 0
 
     #[test]
     fn test_continue_mode_with_no_breakpoints() {
-        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
 
-        let session = CmdSession::start().expect("Failed to start session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         ctx.set_mode(RunMode::Continue);
 
@@ -144,10 +144,10 @@ exit /b 0
 
     #[test]
     fn test_step_out_with_call_stack() {
-        use batch_debugger::debugger::{CmdSession, DebugContext, Frame, RunMode};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, Frame, RunMode};
 
-        let session = CmdSession::start().expect("Failed to start session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Simulate being inside nested calls
         ctx.call_stack.push(Frame::new(10, None));
@@ -206,10 +206,10 @@ exit /b 0
 
     #[test]
     fn test_mode_transitions() {
-        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
 
-        let session = CmdSession::start().expect("Failed to start session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Test all mode transitions
         let modes = vec![
@@ -226,6 +226,7 @@ exit /b 0
     }
 
     #[test]
+    #[cfg(windows)]
     fn test_quit_behavior() {
         // Quitting is handled by breaking out of the execution loop
         // We can test that the context can be dropped cleanly
@@ -243,10 +244,10 @@ exit /b 0
 
     #[test]
     fn test_breakpoint_with_continue_resume() {
-        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::debugger::test_support::MockShell;
+        use batch_debugger::debugger::{DebugContext, RunMode};
 
-        let session = CmdSession::start().expect("Failed to start session");
-        let mut ctx = DebugContext::new(session);
+        let mut ctx = DebugContext::new(MockShell::new());
 
         // Set breakpoint
         ctx.add_breakpoint(5);